@@ -101,6 +101,20 @@ fn try_from_attrset_impl(
 
         let field_name = field.ident.as_ref().expect("fields are named");
 
+        field_names.push(field_name);
+
+        // Flattened fields are read from the same attribute set as the
+        // struct, instead of from one of its attributes.
+        if field_attrs.flatten {
+            field_initializers.extend(quote! {
+                let #field_name = ::nix_bindings::value::TryFromValue::try_from_value(
+                    ::nix_bindings::value::NixValue::from(#attrset),
+                    #ctx,
+                )?;
+            });
+            continue;
+        }
+
         let mut key_name_str = field_name.to_string();
 
         if let Some(rename) =
@@ -118,8 +132,6 @@ fn try_from_attrset_impl(
             })
             .map(|name| Literal::c_string(&name))?;
 
-        field_names.push(field_name);
-
         let default_attr =
             field_attrs.default.as_ref().or(struct_attrs.default.as_ref());
 
@@ -163,6 +175,7 @@ fn try_from_attrset_impl(
 struct Attributes {
     rename: Option<Rename>,
     default: Option<DefaultAttr>,
+    flatten: bool,
     with: Option<Expr>,
 }
 
@@ -230,6 +243,16 @@ impl Attributes {
                     } else {
                         this.default = Some(DefaultAttr::Default);
                     }
+                } else if meta.path.is_ident("flatten") {
+                    match pos {
+                        AttributePosition::Struct => {
+                            return Err(meta.error(
+                                "`flatten` attribute is only allowed on \
+                                 struct fields",
+                            ));
+                        },
+                        AttributePosition::Field => this.flatten = true,
+                    }
                 } else if meta.path.is_ident("with") {
                     match pos {
                         AttributePosition::Struct => {
@@ -17,7 +17,7 @@
     let
       mkPackage =
         {
-          release ? true,
+          profile ? "release",
           targetPkgs ? pkgs,
         }:
         let
@@ -27,7 +27,7 @@
           jettison = self.lib.buildPackage {
             pkgs = targetPkgs;
             src = ../.;
-            inherit release;
+            inherit profile;
             rustc = rust.mkToolchain targetPkgs;
             crateOverrides = targetPkgs.defaultCrateOverrides // {
               nix-bindings-cpp = attrs: {
@@ -43,9 +43,14 @@
             };
           };
         in
-        jettison;
+        jettison.lib;
 
-      bootstrapped =
+      # Builds the plugin with Cargo, compiling it with the given features
+      # instead of the default ones.
+      mkBootstrapped =
+        {
+          features ? [ "cargo" ],
+        }:
         let
           rustPlatform = pkgs.makeRustPlatform {
             cargo = rust.mkToolchain pkgs;
@@ -63,6 +68,7 @@
                 || (lib.hasSuffix ".cpp" file.name)
                 || (file.name == "Cargo.toml")
                 || (file.name == "Cargo.lock")
+                || (file.name == "clippy.toml")
               ) ../.;
             };
             cargoLock = {
@@ -75,15 +81,47 @@
             buildInputs = common.mkBuildInputs pkgs ++ [ pkgs.curl.dev ];
             env = common.mkEnv pkgs;
             buildType = "release";
+            buildNoDefaultFeatures = true;
+            buildFeatures = features;
           };
         in
         jettison;
+
+      bootstrapped = mkBootstrapped { };
+
+      # Runs clippy on the plugin compiled with the given features, failing on
+      # any warning.
+      mkClippy =
+        features:
+        (mkBootstrapped { inherit features; }).overrideAttrs {
+          pname = "nix-jettison-clippy";
+          buildPhase = ''
+            runHook preBuild
+            cargo clippy --all-targets --offline \
+              --no-default-features --features ${lib.concatStringsSep "," features} \
+              -- -D warnings
+            runHook postBuild
+          '';
+          doCheck = false;
+          dontCargoInstall = true;
+          installPhase = "touch $out";
+        };
     in
     {
       packages = {
         inherit bootstrapped;
-        default = mkPackage { release = true; };
-        dev = mkPackage { release = false; };
+        default = mkPackage { profile = "release"; };
+        dev = mkPackage { profile = "dev"; };
+      };
+
+      # Both ways of resolving the build graph have to build, pass clippy and
+      # pass their tests, which `cargo test` runs as part of the bootstrapped
+      # builds.
+      checks = {
+        clippy = mkClippy [ "cargo" ];
+        clippy-forbid-cargo = mkClippy [ "forbid-cargo" ];
+        tests = bootstrapped;
+        tests-forbid-cargo = mkBootstrapped { features = [ "forbid-cargo" ]; };
       };
     };
 }
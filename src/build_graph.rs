@@ -1,20 +1,39 @@
 use core::mem;
 use std::borrow::Cow;
 use std::collections::{HashMap, hash_map};
-use std::path::{Path, PathBuf};
-
-use cargo::core::compiler::{CompileKind, CrateType};
-use cargo::core::dependency::DepKind;
-use cargo::core::manifest::TargetSourcePath;
-use cargo::core::profiles::UnitFor;
-use cargo::core::{Edition, Package, PackageId, SourceKind, TargetKind};
-use cargo::util::OptVersionReq;
-use compact_str::{CompactString, ToCompactString};
+use std::path::PathBuf;
+
+use cargo_platform::Cfg;
+use cargo_util_schemas::manifest::TomlDebugInfo;
+use compact_str::{CompactString, ToCompactString, format_compact};
 use either::Either;
 use nix_bindings::prelude::*;
+use sha2::Digest;
 use smallvec::{SmallVec, smallvec};
 
-use crate::resolve_build_graph::WorkspaceResolve;
+use crate::cargo_types::profiles::{
+    DebugInfo,
+    Lto as ProfileLto,
+    PanicStrategy,
+    Profile,
+    UnitFor,
+};
+use crate::cargo_types::{
+    CompileKind,
+    CrateType,
+    DepKind,
+    Edition,
+    InternedString,
+    OptVersionReq,
+    Package,
+    PackageId,
+    SourceKind,
+    Target,
+    TargetKind,
+    TargetSourcePath,
+};
+use crate::lto::{Lto, LtoCrateTypes};
+use crate::resolve_build_graph::{ResolveBuildGraphError, WorkspaceResolve};
 use crate::vendor_deps::SourceId;
 
 /// A map from the package name of a given dependency to its renaming spec.
@@ -28,18 +47,62 @@ pub(crate) struct BuildGraph {
     /// `U`, then `U`'s index is guaranteed to be smaller than `T`s. Note
     /// however that the opposite is not true, i.e. just because `T` precedes
     /// `U` doesn't necessarily mean that `U` depends on it. It follows that
-    /// the root of the build graph is always the last node in the vector.
+    /// the root of the build graph is always the last node in the vector,
+    /// unless the root's dev-dependencies have also been resolved, in which
+    /// case they (and their dependencies) are placed after it, or the graph
+    /// has more than one root.
     ///
-    /// Each node maps 1:1 to a specific Cargo
-    /// [`Package`](cargo::core::Package).
+    /// Like Cargo's units, each node is identified by a
+    /// [`Package`], the kind it's compiled for, and its
+    /// enabled features (see [`NodeKey`]), so the same package can appear
+    /// more than once if it's compiled both for the host and for the target.
     pub(crate) nodes: Vec<BuildGraphNode>,
 
     /// TODO: docs.
     pub(crate) edges: Vec<NodeEdges>,
 
-    /// Map from a package's ID to the index in the [`nodes`](Self::nodes)
-    /// vector poining to the corresponding [`BuildGraphNode`].
-    pkg_id_to_idx: HashMap<PackageId, usize>,
+    /// The indices of the root packages in the [`nodes`](Self::nodes) vector,
+    /// in the same order as [`WorkspaceResolve::root_ids`].
+    ///
+    /// This contains a single element unless the graph was resolved for
+    /// multiple members of a workspace.
+    pub(crate) root_idxs: Vec<usize>,
+
+    /// Map from a node's key to its index in the [`nodes`](Self::nodes)
+    /// vector.
+    key_to_idx: HashMap<NodeKey, usize>,
+
+    /// The stable identifiers of the nodes' package sources (see
+    /// [`stable_source_id`]), in the same order as [`nodes`](Self::nodes).
+    source_ids: Vec<CompactString>,
+
+    /// Identifies the `rustc` the crates are compiled with, which is hashed
+    /// into the libraries' metadata.
+    ///
+    /// This is the verbose version of the `rustc` the graph was resolved
+    /// with (i.e. the output of `rustc -vV`), until the builders replace it
+    /// with the store path of the one they compile with (see
+    /// [`set_rustc`](Self::set_rustc)).
+    rustc_id: CompactString,
+}
+
+/// What identifies a node in the [`BuildGraph`].
+///
+/// With resolver v2, the features of build dependencies and proc-macros are
+/// unified separately from the ones of normal dependencies, so the same
+/// package can be compiled once for the host and once for the target, with
+/// different features. Like Cargo, we only share a node if both the compile
+/// kind and the features match, and if the `build-override` settings don't
+/// change the profile of the one compiled for the host.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct NodeKey {
+    package_id: PackageId,
+    compile_kind: CompileKind,
+    features: Vec<InternedString>,
+
+    /// Whether the node's library is compiled with the profile of the units
+    /// compiled for the host (i.e. with the `build-override` settings).
+    uses_host_profile: bool,
 }
 
 /// A single node in the [`BuildGraph`].
@@ -54,10 +117,27 @@ pub(crate) struct BuildGraphNode {
     #[attrset(skip_if = Option::is_none)]
     pub(crate) build_script: Option<BuildScript>,
 
+    /// The environment variables set by the `[env]` table of the Cargo
+    /// configuration, which are set when compiling the package's crates and
+    /// when running its build script.
+    #[attrset(skip_if = Vec::is_empty)]
+    pub(crate) config_env: Vec<ConfigEnvVar>,
+
     /// TODO: docs.
     #[attrset(skip_if = DependencyRenames::is_empty)]
     pub(crate) dependency_renames: DependencyRenames,
 
+    /// Whether the package is only compiled for the host, i.e. as (a
+    /// dependency of) a build dependency or a proc-macro.
+    pub(crate) is_for_host: bool,
+
+    /// Whether the package is one of the roots of the build graph, which is
+    /// when Cargo sets `CARGO_PRIMARY_PACKAGE` while compiling it.
+    pub(crate) is_primary_package: bool,
+
+    /// Whether the package is a member of the workspace.
+    pub(crate) is_workspace_member: bool,
+
     /// TODO: docs.
     #[attrset(skip_if = Option::is_none)]
     pub(crate) library: Option<LibraryCrate>,
@@ -67,6 +147,26 @@ pub(crate) struct BuildGraphNode {
 
     /// TODO: docs.
     pub(crate) package_src: PackageSource,
+
+    /// The package's test targets. This is only set for the root packages, and
+    /// only if their dev-dependencies have been resolved.
+    #[attrset(skip_if = Option::is_none)]
+    pub(crate) tests: Option<Tests>,
+}
+
+/// An environment variable set by the `[env]` table of the Cargo
+/// configuration.
+#[derive(nix_bindings::Attrset, Clone)]
+#[attrset(rename_all = camelCase)]
+pub(crate) struct ConfigEnvVar {
+    pub(crate) name: CompactString,
+
+    /// The variable's value, with relative paths already resolved.
+    pub(crate) value: CompactString,
+
+    /// Whether the variable should override the one already set in the
+    /// environment, if any.
+    pub(crate) force: bool,
 }
 
 /// Edges from a node to its dependencies in the build graph.
@@ -78,6 +178,11 @@ pub(crate) struct NodeEdges {
 
     /// The indices of the node's build script dependencies in the build graph.
     pub(crate) build_dependencies: Vec<usize>,
+
+    /// The indices of the node's dev-dependencies in the build graph. This is
+    /// only populated for the root nodes, and only if their dev-dependencies
+    /// have been resolved.
+    pub(crate) dev_dependencies: Vec<usize>,
 }
 
 #[derive(nix_bindings::Attrset, Clone)]
@@ -85,6 +190,21 @@ pub(crate) struct NodeEdges {
 pub(crate) struct BuildScript {
     pub(crate) build_opts: BuildOpts,
 
+    /// A map from the names of the `CARGO_CFG_*` environment variables to set
+    /// when running the build script to their values, as derived from the
+    /// `cfg`s of the platform the package is compiled for.
+    ///
+    /// This doesn't include `CARGO_CFG_DEBUG_ASSERTIONS` and
+    /// `CARGO_CFG_PANIC`, which depend on the profile the package is compiled
+    /// with.
+    pub(crate) cfg_env: HashMap<CompactString, CompactString>,
+
+    /// The flags passed to `rustc` when compiling for the platform the
+    /// package is compiled for, which are exposed to the build script via
+    /// `CARGO_ENCODED_RUSTFLAGS`.
+    #[attrset(skip_if = Vec::is_empty)]
+    pub(crate) rustflags: Vec<CompactString>,
+
     /// TODO: docs.
     pub(crate) dependency_renames: DependencyRenames,
 
@@ -97,8 +217,34 @@ pub(crate) struct BuildScript {
 #[derive(nix_bindings::Attrset, Clone)]
 #[attrset(rename_all = camelCase)]
 pub(crate) struct BuildOpts {
-    pub(crate) codegen_units: Option<u32>,
+    /// The platform the crate is compiled for.
+    #[attrset(with_value = compile_kind_to_value)]
+    pub(crate) compile_kind: CompileKind,
+
     pub(crate) extra_rustc_args: Vec<CompactString>,
+
+    /// The metadata hash passed to `rustc` via `-C metadata`, which also
+    /// suffixes the names of the crate's artifacts.
+    ///
+    /// This is only set for libraries, and it's computed by
+    /// [`BuildGraph::resolve_metadata`] once the whole graph is known.
+    #[attrset(skip_if = Option::is_none)]
+    pub(crate) metadata: Option<CompactString>,
+
+    /// The linker set for the platform the crate is compiled for via the
+    /// `target.<triple>.linker` key of the Cargo configuration, if any.
+    #[attrset(skip_if = Option::is_none)]
+    pub(crate) linker: Option<CompactString>,
+
+    /// How the crate takes part in link-time optimization.
+    #[attrset(with_value = |lto: &Lto| lto.name())]
+    pub(crate) lto: Lto,
+
+    /// The profile the crate is compiled with, after all the overrides
+    /// (`[profile.<name>.package.<spec>]`, `build-override`, etc.) have been
+    /// applied.
+    #[attrset(with_value = profile_to_value)]
+    pub(crate) profile: Profile,
 }
 
 #[derive(nix_bindings::Attrset)]
@@ -128,6 +274,51 @@ pub(crate) struct LibraryCrate {
     pub(crate) formats: SmallVec<[LibraryFormat; 1]>,
 }
 
+/// The test targets of a package.
+#[derive(nix_bindings::Attrset)]
+#[attrset(rename_all = camelCase)]
+pub(crate) struct Tests {
+    /// The renames of the package's normal and dev-dependencies.
+    #[attrset(skip_if = DependencyRenames::is_empty)]
+    pub(crate) dependency_renames: DependencyRenames,
+
+    /// Whether the package's library has documentation tests that should be
+    /// run via `rustdoc --test`.
+    pub(crate) doctests: bool,
+
+    /// The test harnesses to compile and run.
+    #[attrset(skip_if = Vec::is_empty)]
+    pub(crate) targets: Vec<TestCrate>,
+}
+
+#[derive(nix_bindings::Attrset)]
+#[attrset(rename_all = camelCase)]
+pub(crate) struct TestCrate {
+    pub(crate) build_opts: BuildOpts,
+
+    /// Whether the test should be compiled with libtest's harness (i.e. with
+    /// `--test`). This is `false` for tests that set `harness = false` in
+    /// their manifest, which provide their own `main` function.
+    pub(crate) harness: bool,
+
+    pub(crate) kind: TestKind,
+    pub(crate) name: CompactString,
+    pub(crate) path: CompactString,
+}
+
+/// The kind of target a [`TestCrate`] is compiled from.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub(crate) enum TestKind {
+    /// The unit tests of a binary target.
+    Bin,
+
+    /// An integration test under the `tests` directory.
+    Integration,
+
+    /// The unit tests of the library target.
+    Lib,
+}
+
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub(crate) enum LibraryFormat {
     Cdylib,
@@ -144,6 +335,8 @@ pub(crate) struct PackageAttrs {
     #[attrset(skip_if = Vec::is_empty)]
     pub(crate) authors: Vec<String>,
     #[attrset(skip_if = Option::is_none)]
+    pub(crate) default_run: Option<CompactString>,
+    #[attrset(skip_if = Option::is_none)]
     pub(crate) description: Option<String>,
     #[attrset(with_value = |&ed| edition_as_str(ed))]
     pub(crate) edition: Edition,
@@ -190,75 +383,615 @@ pub(crate) struct RenameWithVersion {
 
 impl BuildGraph {
     pub(crate) fn new(
-        root_package_id: PackageId,
         resolve: &WorkspaceResolve,
-        vendor_dir: &Path,
-    ) -> Self {
+    ) -> Result<Self, ResolveBuildGraphError> {
         let mut this = Self::empty();
-        this.insert_package(root_package_id, resolve, vendor_dir);
-        this
+
+        this.rustc_id =
+            resolve.target_data().rustc.verbose_version.as_str().into();
+
+        for &root_package_id in resolve.root_ids() {
+            let root_idx = this.insert_package(root_package_id, false, resolve);
+
+            this.root_idxs.push(root_idx);
+
+            // Dev-dependencies are inserted after the root node because
+            // they're allowed to depend on the root package itself.
+            if resolve.has_dev_units() {
+                this.insert_dev_dependencies(
+                    root_package_id,
+                    root_idx,
+                    resolve,
+                );
+            }
+        }
+
+        this.resolve_panic_strategy(resolve.has_dev_units());
+        this.check_panic_strategy()?;
+        this.resolve_rpath();
+        this.resolve_lto();
+        this.resolve_metadata();
+
+        Ok(this)
+    }
+
+    /// Computes the metadata hash of every library in the graph.
+    ///
+    /// Like Cargo's, the hash covers everything that identifies the library
+    /// (its package's name, version and source, its features, the platform
+    /// it's compiled for, its profile and `rustc` arguments, and the `rustc`
+    /// it's compiled with), together with the hashes of all its dependencies,
+    /// so that two different builds of the same package never end up with
+    /// the same symbol names.
+    ///
+    /// This has to be called again whenever the build options of a library
+    /// change, since it relies on a library always coming after its
+    /// dependencies in the [`nodes`](Self::nodes) vector.
+    pub(crate) fn resolve_metadata(&mut self) {
+        for node_idx in 0..self.nodes.len() {
+            let Some(library) = &self.nodes[node_idx].library else { continue };
+
+            let edges = &self.edges[node_idx];
+
+            let deps_metadata = edges
+                .dependencies
+                .iter()
+                .chain(&edges.build_dependencies)
+                .filter_map(|&dep_idx| {
+                    self.nodes[dep_idx]
+                        .library
+                        .as_ref()?
+                        .build_opts
+                        .metadata
+                        .as_deref()
+                });
+
+            let metadata = library_metadata(
+                &self.nodes[node_idx].package_attrs,
+                library,
+                &self.source_ids[node_idx],
+                &self.rustc_id,
+                deps_metadata,
+            );
+
+            if let Some(library) = &mut self.nodes[node_idx].library {
+                library.build_opts.metadata = Some(metadata);
+            }
+        }
+    }
+
+    /// Sets the identifier of the `rustc` the crates are compiled with, like
+    /// the store path of its derivation.
+    ///
+    /// The `rustc` that's queried when resolving the graph is the one
+    /// available at evaluation time, which isn't necessarily the one the
+    /// crates are compiled with, so its version alone doesn't identify the
+    /// compiler. Like after changing any other build option,
+    /// [`resolve_metadata`](Self::resolve_metadata) has to be called again
+    /// afterwards.
+    pub(crate) fn set_rustc(&mut self, rustc_id: CompactString) {
+        self.rustc_id = rustc_id;
+    }
+
+    /// Returns the index of the root package in the [`nodes`](Self::nodes)
+    /// vector.
+    ///
+    /// If the graph has more than one root, this returns the first one.
+    pub(crate) fn root_idx(&self) -> usize {
+        *self.root_idxs.first().expect("build graph always has a root")
+    }
+
+    /// Returns the sorted indices of the given nodes together with all the
+    /// nodes they transitively (and normally) depend on.
+    pub(crate) fn dependency_closure(
+        &self,
+        idxs: impl IntoIterator<Item = usize>,
+    ) -> Vec<usize> {
+        let mut is_in_closure = vec![false; self.nodes.len()];
+
+        let mut stack = idxs.into_iter().collect::<Vec<_>>();
+
+        while let Some(idx) = stack.pop() {
+            if !mem::replace(&mut is_in_closure[idx], true) {
+                stack.extend(&self.edges[idx].dependencies);
+            }
+        }
+
+        is_in_closure
+            .into_iter()
+            .enumerate()
+            .filter_map(|(idx, is_in_closure)| is_in_closure.then_some(idx))
+            .collect()
+    }
+
+    /// Inserts the dev-dependencies of the root package with the given ID
+    /// into the build graph, adding them to the root's
+    /// [`dev_dependencies`](NodeEdges::dev_dependencies).
+    fn insert_dev_dependencies(
+        &mut self,
+        root_package_id: PackageId,
+        root_idx: usize,
+        resolve: &WorkspaceResolve,
+    ) {
+        let for_host = self.nodes[root_idx].is_for_host;
+
+        for (dep_pkg_id, dep) in resolve.deps(root_package_id, for_host) {
+            if dep.kind() != DepKind::Development {
+                continue;
+            }
+
+            let node_idx = self.insert_package(dep_pkg_id, for_host, resolve);
+
+            let root_edges = &mut self.edges[root_idx];
+
+            if !root_edges.dependencies.contains(&node_idx)
+                && !root_edges.dev_dependencies.contains(&node_idx)
+            {
+                root_edges.dev_dependencies.push(node_idx);
+            }
+        }
     }
 
     /// Inserts the package with the given ID (and all its dependencies,
     /// recursively) into the build graph, returning the index of the
     /// corresponding node.
+    ///
+    /// The package is compiled for the host if `for_host` is true, or if it's
+    /// a proc-macro.
+    #[expect(clippy::too_many_lines)]
     fn insert_package(
         &mut self,
         pkg_id: PackageId,
+        for_host: bool,
         resolve: &WorkspaceResolve,
-        vendor_dir: &Path,
     ) -> usize {
-        // Return early if we've already inserted this package.
-        if let Some(&node_idx) = self.pkg_id_to_idx.get(&pkg_id) {
-            return node_idx;
-        }
-
         let package =
             resolve.package(pkg_id).expect("package ID not found in workspace");
 
+        let is_proc_macro = package.targets().iter().any(Target::proc_macro);
+
+        let for_host = for_host || is_proc_macro;
+
+        let compile_kind = resolve.unit_compile_kind(for_host);
+
+        // When not cross-compiling, a package compiled for the host can only
+        // share its node with the one compiled for the target if the
+        // `build-override` settings don't change its profile.
+        let is_cross_compiling = !resolve.unit_compile_kind(false).is_host();
+
+        let uses_host_profile = for_host
+            && (is_cross_compiling
+                || BuildOpts::has_host_overrides(pkg_id, resolve));
+
+        let key = NodeKey {
+            package_id: pkg_id,
+            compile_kind,
+            features: resolve.features(pkg_id, for_host),
+            uses_host_profile,
+        };
+
+        // Return early if we've already inserted this node.
+        if let Some(&node_idx) = self.key_to_idx.get(&key) {
+            // When not cross-compiling, a package with the same features on
+            // the host and on the target shares a single node.
+            self.nodes[node_idx].is_for_host &= for_host;
+            return node_idx;
+        }
+
         let mut edges = NodeEdges::default();
 
-        for (dep_pkg_id, dep) in resolve.deps(pkg_id) {
+        for (dep_pkg_id, dep) in resolve.deps(pkg_id, for_host) {
             match dep.kind() {
                 DepKind::Normal => {
                     let node_idx =
-                        self.insert_package(dep_pkg_id, resolve, vendor_dir);
+                        self.insert_package(dep_pkg_id, for_host, resolve);
                     edges.dependencies.push(node_idx);
                 },
                 DepKind::Build => {
                     let node_idx =
-                        self.insert_package(dep_pkg_id, resolve, vendor_dir);
+                        self.insert_package(dep_pkg_id, true, resolve);
                     edges.build_dependencies.push(node_idx);
                 },
                 DepKind::Development => {},
             }
         }
 
-        let package_attrs = PackageAttrs::new(package, resolve);
+        let package_attrs = PackageAttrs::new(package, &key.features);
+
+        // Binaries and tests are only compiled for the unit that was
+        // requested by the user, i.e. for the target (unless the package is a
+        // proc-macro) and with the features enabled on the command line. This
+        // doesn't depend on `for_host` because, when not cross-compiling, a
+        // root package can be reached as a build dependency first.
+        let is_root = resolve.is_root(pkg_id)
+            && key.compile_kind == resolve.unit_compile_kind(is_proc_macro)
+            && key.features == resolve.features(pkg_id, is_proc_macro);
+
+        let binaries = if is_root {
+            BinaryCrate::new(
+                package,
+                &package_attrs.features,
+                compile_kind,
+                resolve,
+            )
+            .collect()
+        } else {
+            Vec::new()
+        };
 
-        let binaries =
-            BinaryCrate::new(package, &package_attrs.features, resolve)
-                .map(Iterator::collect)
-                .unwrap_or_default();
+        let tests = if is_root {
+            Tests::new(package, &package_attrs.features, compile_kind, resolve)
+        } else {
+            None
+        };
 
         let node = BuildGraphNode {
             binaries,
-            build_script: BuildScript::new(package, resolve),
-            dependency_renames: dependency_renames::<true>(pkg_id, resolve),
-            library: LibraryCrate::new(package, resolve),
+            build_script: BuildScript::new(package, compile_kind, resolve),
+            config_env: resolve.config_env().to_vec(),
+            dependency_renames: dependency_renames(
+                pkg_id,
+                for_host,
+                &[DepKind::Normal],
+                resolve,
+            ),
+            is_for_host: for_host,
+            is_primary_package: is_root,
+            is_workspace_member: resolve.workspace().is_member_id(pkg_id),
+            library: LibraryCrate::new(
+                package,
+                compile_kind,
+                uses_host_profile,
+                resolve,
+            ),
+            package_src: PackageSource::new(package),
+            tests,
             package_attrs,
-            package_src: PackageSource::new(package, vendor_dir),
         };
 
         let node_idx = self.nodes.len();
 
         self.nodes.push(node);
         self.edges.push(edges);
-        self.pkg_id_to_idx.insert(pkg_id, node_idx);
+        self.key_to_idx.insert(key, node_idx);
+        self.source_ids.push(stable_source_id(pkg_id, resolve));
 
         node_idx
     }
 
+    /// Forces the `unwind` panic strategy on every library that has to be
+    /// linked into a crate compiled with it.
+    ///
+    /// Build scripts and proc-macros are always compiled with `unwind`
+    /// because they're loaded by the compiler (which relies on unwinding), so
+    /// the libraries they depend on have to be compiled with it too, since a
+    /// crate compiled with `abort` can't be linked into one compiled with
+    /// `unwind`. The same goes for test harnesses, which need to unwind to
+    /// catch the panics of failing tests.
+    ///
+    /// Like Cargo, the libraries that are depended on by both the host and the
+    /// target are compiled twice, once per panic strategy, since their nodes
+    /// aren't shared if their profiles differ (see [`NodeKey`]).
+    fn resolve_panic_strategy(&mut self, has_dev_units: bool) {
+        let mut needs_unwind = vec![has_dev_units; self.nodes.len()];
+
+        for (node_idx, node) in self.nodes.iter().enumerate() {
+            for &dep_idx in &self.edges[node_idx].build_dependencies {
+                needs_unwind[dep_idx] = true;
+            }
+
+            if node.library.as_ref().is_some_and(LibraryCrate::is_proc_macro) {
+                for &dep_idx in &self.edges[node_idx].dependencies {
+                    needs_unwind[dep_idx] = true;
+                }
+            }
+        }
+
+        // Dependencies always come before their dependents, so iterating in
+        // reverse is enough to propagate the requirement transitively.
+        for node_idx in (0..self.nodes.len()).rev() {
+            if !needs_unwind[node_idx] {
+                continue;
+            }
+
+            for &dep_idx in &self.edges[node_idx].dependencies {
+                needs_unwind[dep_idx] = true;
+            }
+        }
+
+        for (node, needs_unwind) in self.nodes.iter_mut().zip(needs_unwind) {
+            if !needs_unwind {
+                continue;
+            }
+
+            let library_opts =
+                node.library.iter_mut().map(|library| &mut library.build_opts);
+
+            // Test harnesses can only be built when the dev-dependencies have
+            // been resolved, so they're always marked.
+            let tests_opts = node
+                .tests
+                .iter_mut()
+                .flat_map(|tests| &mut tests.targets)
+                .map(|test| &mut test.build_opts);
+
+            for build_opts in library_opts.chain(tests_opts) {
+                build_opts.profile.panic = PanicStrategy::Unwind;
+            }
+        }
+    }
+
+    /// Checks that every crate compiled with the `abort` panic strategy can be
+    /// linked, which isn't the case if it (transitively) depends on a Rust
+    /// dylib.
+    ///
+    /// Rust dylibs link the standard library dynamically, which is always
+    /// compiled with `unwind`, so rustc refuses to link them into a crate
+    /// that uses `abort`. Cargo only reports this once it fails to compile
+    /// the crate, but since we know the whole graph upfront we can report it
+    /// at evaluation time instead.
+    fn check_panic_strategy(&self) -> Result<(), ResolveBuildGraphError> {
+        let linked_dylibs = self.linked_dylibs();
+
+        for (node_idx, node) in self.nodes.iter().enumerate() {
+            let Some(dylib_idx) = linked_dylibs[node_idx] else { continue };
+
+            let aborts = node
+                .library
+                .iter()
+                .map(|library| &library.build_opts)
+                .chain(node.binaries.iter().map(|binary| &binary.build_opts))
+                .any(|build_opts| {
+                    build_opts.profile.panic == PanicStrategy::Abort
+                });
+
+            if aborts {
+                let package_id = |idx: usize| {
+                    let package = &self.nodes[idx].package_attrs;
+                    format!("{} v{}", package.name, package.version)
+                };
+
+                return Err(ResolveBuildGraphError::PanicAbortWithDylib {
+                    package: package_id(node_idx),
+                    dylib: package_id(dylib_idx),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Compiles every crate that links a Rust dylib with `-C rpath`, like
+    /// setting `rpath = true` in its profile.
+    ///
+    /// The dylibs (and the standard library they link dynamically) aren't in
+    /// any of the directories searched by the dynamic loader, and the final
+    /// artifacts don't keep their `-deps` derivation in their closure, so the
+    /// relative rpath entries added by `rustc` are what keeps the dylibs both
+    /// reachable at runtime and in the closure.
+    fn resolve_rpath(&mut self) {
+        let linked_dylibs = self.linked_dylibs();
+
+        // Whether the library, the binaries, the tests and the build script of
+        // every node link a Rust dylib.
+        let links_dylib = (0..self.nodes.len())
+            .map(|node_idx| {
+                let library = linked_dylibs[node_idx].is_some();
+
+                // Binaries and tests also link the package's own library.
+                let binaries = library
+                    || self.nodes[node_idx]
+                        .library
+                        .as_ref()
+                        .is_some_and(LibraryCrate::is_dylib);
+
+                let tests = binaries
+                    || self
+                        .find_linked_dylib(
+                            &self.edges[node_idx].dev_dependencies,
+                            &linked_dylibs,
+                        )
+                        .is_some();
+
+                let build_script = self
+                    .find_linked_dylib(
+                        &self.edges[node_idx].build_dependencies,
+                        &linked_dylibs,
+                    )
+                    .is_some();
+
+                (library, binaries, tests, build_script)
+            })
+            .collect::<Vec<_>>();
+
+        for (node, (library, binaries, tests, build_script)) in
+            self.nodes.iter_mut().zip(links_dylib)
+        {
+            let library_opts = node
+                .library
+                .iter_mut()
+                .filter(|lib| library && lib.needs_linking())
+                .map(|lib| &mut lib.build_opts);
+
+            let binaries_opts = node
+                .binaries
+                .iter_mut()
+                .filter(|_| binaries)
+                .map(|binary| &mut binary.build_opts);
+
+            let tests_opts = node
+                .tests
+                .iter_mut()
+                .filter(|_| tests)
+                .flat_map(|tests| &mut tests.targets)
+                .map(|test| &mut test.build_opts);
+
+            let build_script_opts = node
+                .build_script
+                .iter_mut()
+                .filter(|_| build_script)
+                .map(|script| &mut script.build_opts);
+
+            for build_opts in library_opts
+                .chain(binaries_opts)
+                .chain(tests_opts)
+                .chain(build_script_opts)
+            {
+                build_opts.profile.rpath = true;
+            }
+        }
+    }
+
+    /// Returns the index of a Rust dylib that the library of every node
+    /// links, either directly or through its other dependencies, if any.
+    ///
+    /// This relies on a library always coming before the crates depending on
+    /// it in the [`nodes`](Self::nodes) vector.
+    fn linked_dylibs(&self) -> Vec<Option<usize>> {
+        let mut linked_dylibs = Vec::with_capacity(self.nodes.len());
+
+        for edges in &self.edges {
+            let linked_dylib =
+                self.find_linked_dylib(&edges.dependencies, &linked_dylibs);
+            linked_dylibs.push(linked_dylib);
+        }
+
+        linked_dylibs
+    }
+
+    /// Returns the index of a Rust dylib linked by a crate with the given
+    /// dependencies, given the result of [`linked_dylibs`](Self::linked_dylibs)
+    /// for the nodes before it.
+    fn find_linked_dylib(
+        &self,
+        dep_idxs: &[usize],
+        linked_dylibs: &[Option<usize>],
+    ) -> Option<usize> {
+        dep_idxs.iter().find_map(|&dep_idx| {
+            let is_dylib = self.nodes[dep_idx]
+                .library
+                .as_ref()
+                .is_some_and(LibraryCrate::is_dylib);
+            if is_dylib { Some(dep_idx) } else { linked_dylibs[dep_idx] }
+        })
+    }
+
+    /// Computes how every crate in the graph takes part in link-time
+    /// optimization, propagating the requirements of the final artifacts
+    /// down to their dependencies like Cargo does.
+    ///
+    /// This relies on a library always coming before the crates depending on
+    /// it in the [`nodes`](Self::nodes) vector.
+    fn resolve_lto(&mut self) {
+        // The merged LTO requirements of each node's library crate.
+        let mut lib_ltos: Vec<Option<Lto>> = vec![None; self.nodes.len()];
+
+        // Binaries and tests can't be depended on, so we start by propagating
+        // their requirements.
+        for (node_idx, node) in self.nodes.iter().enumerate() {
+            let edges = &self.edges[node_idx];
+
+            let own_library = node.library.is_some().then_some(node_idx);
+
+            for binary in &node.binaries {
+                let lto = Lto::for_root(
+                    binary.build_opts.profile.lto,
+                    LtoCrateTypes::BIN,
+                    false,
+                );
+                for &dep_idx in edges.dependencies.iter().chain(&own_library) {
+                    merge_dependency_lto(
+                        &mut lib_ltos,
+                        &self.nodes,
+                        dep_idx,
+                        lto,
+                    );
+                }
+            }
+
+            for test in node.tests.iter().flat_map(|tests| &tests.targets) {
+                let lto = Lto::for_root(
+                    test.build_opts.profile.lto,
+                    LtoCrateTypes::BIN,
+                    false,
+                );
+                let own_library =
+                    own_library.filter(|_| test.kind != TestKind::Lib);
+                for &dep_idx in edges
+                    .dependencies
+                    .iter()
+                    .chain(&edges.dev_dependencies)
+                    .chain(&own_library)
+                {
+                    merge_dependency_lto(
+                        &mut lib_ltos,
+                        &self.nodes,
+                        dep_idx,
+                        lto,
+                    );
+                }
+            }
+        }
+
+        // Then we go from the dependents to their dependencies.
+        for (node_idx, node) in self.nodes.iter().enumerate().rev() {
+            let edges = &self.edges[node_idx];
+
+            // Build scripts are compiled for the host, so they never take part
+            // in LTO.
+            for &dep_idx in &edges.build_dependencies {
+                merge_dependency_lto(
+                    &mut lib_ltos,
+                    &self.nodes,
+                    dep_idx,
+                    Lto::OnlyObject,
+                );
+            }
+
+            let Some(library) = &node.library else { continue };
+
+            if self.root_idxs.contains(&node_idx) {
+                let lto = Lto::for_root(
+                    library.build_opts.profile.lto,
+                    LtoCrateTypes::library(&library.formats),
+                    library.is_proc_macro(),
+                );
+                let entry = &mut lib_ltos[node_idx];
+                *entry = Some(entry.map_or(lto, |prev| prev.merge(lto)));
+            }
+
+            let lto = lib_ltos[node_idx]
+                .expect("every library is either a root or a dependency");
+
+            for &dep_idx in &edges.dependencies {
+                merge_dependency_lto(&mut lib_ltos, &self.nodes, dep_idx, lto);
+            }
+        }
+
+        for (node, lib_lto) in self.nodes.iter_mut().zip(lib_ltos) {
+            if let Some((library, lto)) = node.library.as_mut().zip(lib_lto) {
+                library.build_opts.lto = lto;
+            }
+
+            let binaries_opts =
+                node.binaries.iter_mut().map(|binary| &mut binary.build_opts);
+
+            let tests_opts = node
+                .tests
+                .iter_mut()
+                .flat_map(|tests| &mut tests.targets)
+                .map(|test| &mut test.build_opts);
+
+            for build_opts in binaries_opts.chain(tests_opts) {
+                build_opts.lto = Lto::for_root(
+                    build_opts.profile.lto,
+                    LtoCrateTypes::BIN,
+                    false,
+                );
+            }
+        }
+    }
+
     /// Returns a new, empty build graph.
     ///
     /// Note that we don't provide a `Default` impl for `BuildGraph` because a
@@ -267,15 +1000,88 @@ impl BuildGraph {
         Self {
             nodes: Vec::new(),
             edges: Vec::new(),
-            pkg_id_to_idx: HashMap::new(),
+            root_idxs: Vec::new(),
+            key_to_idx: HashMap::new(),
+            source_ids: Vec::new(),
+            rustc_id: CompactString::default(),
         }
     }
 }
 
+impl BuildGraphNode {
+    /// Returns the build options of the package's library, binaries and test
+    /// harnesses, i.e. of all its crates except the build script.
+    pub(crate) fn build_opts_mut(
+        &mut self,
+    ) -> impl Iterator<Item = &mut BuildOpts> {
+        let library_opts =
+            self.library.iter_mut().map(|library| &mut library.build_opts);
+
+        let binaries_opts =
+            self.binaries.iter_mut().map(|binary| &mut binary.build_opts);
+
+        let tests_opts = self
+            .tests
+            .iter_mut()
+            .flat_map(|tests| &mut tests.targets)
+            .map(|test| &mut test.build_opts);
+
+        library_opts.chain(binaries_opts).chain(tests_opts)
+    }
+
+    /// Returns the profile the package's build script should be run with.
+    ///
+    /// Like in Cargo, this is the profile of the targets the build script is
+    /// run for (and not the one it's compiled with), so that e.g. `OPT_LEVEL`
+    /// matches the optimization level of the package's library.
+    pub(crate) fn build_script_run_profile(&self) -> &Profile {
+        self.library
+            .as_ref()
+            .map(|library| &library.build_opts)
+            .or_else(|| self.binaries.first().map(|bin| &bin.build_opts))
+            .or_else(|| {
+                self.tests
+                    .as_ref()?
+                    .targets
+                    .first()
+                    .map(|test| &test.build_opts)
+            })
+            .or_else(|| self.build_script.as_ref().map(|bs| &bs.build_opts))
+            .map(|build_opts| &build_opts.profile)
+            .expect("every package has at least one target")
+    }
+}
+
 impl LibraryCrate {
     pub(crate) fn is_proc_macro(&self) -> bool {
         LibraryFormat::is_proc_macro(&*self.formats)
     }
+
+    pub(crate) fn is_usable_as_dependency(&self) -> bool {
+        self.formats.iter().any(LibraryFormat::is_usable_as_dependency)
+    }
+
+    /// Returns whether the library is only compiled as a Rust dylib, in
+    /// which case its dependents link it dynamically (otherwise they're
+    /// pointed at its `.rlib`, like `rustc` does without `-C prefer-dynamic`).
+    pub(crate) fn is_dylib(&self) -> bool {
+        self.formats.contains(&LibraryFormat::Dylib)
+            && !self.formats.iter().any(|format| {
+                matches!(format, LibraryFormat::Lib | LibraryFormat::Rlib)
+            })
+    }
+
+    /// Returns whether the library has a metadata-only (i.e. `.rmeta`)
+    /// artifact that its dependents can be compiled against when pipelining.
+    pub(crate) fn has_metadata(&self) -> bool {
+        self.is_usable_as_dependency() && !self.is_proc_macro()
+    }
+
+    /// Returns whether compiling the library involves linking, which needs
+    /// the full artifacts of all its transitive dependencies.
+    pub(crate) fn needs_linking(&self) -> bool {
+        LibraryFormat::needs_linking(&self.formats)
+    }
 }
 
 impl LibraryFormat {
@@ -294,6 +1100,15 @@ impl LibraryFormat {
         formats == &[Self::ProcMacro]
     }
 
+    pub(crate) fn needs_linking(formats: &[Self]) -> bool {
+        formats.iter().any(|format| match format {
+            Self::Cdylib | Self::Dylib | Self::ProcMacro | Self::Staticlib => {
+                true
+            },
+            Self::Lib | Self::Rlib => false,
+        })
+    }
+
     pub(crate) fn is_usable_as_dependency(&self) -> bool {
         match self {
             Self::Dylib | Self::Lib | Self::ProcMacro | Self::Rlib => true,
@@ -302,20 +1117,26 @@ impl LibraryFormat {
     }
 }
 
+impl TestKind {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::Bin => "bin",
+            Self::Integration => "integration",
+            Self::Lib => "lib",
+        }
+    }
+}
+
 impl BinaryCrate {
-    /// Returns an iterator over all the binary crates in the given package, or
-    /// `None` if the package is not the root of the build graph.
+    /// Returns an iterator over all the binary crates in the given package.
     fn new(
         package: &Package,
         enabled_features: &[impl PartialEq<str>],
+        compile_kind: CompileKind,
         resolve: &WorkspaceResolve,
-    ) -> Option<impl Iterator<Item = Self>> {
+    ) -> impl Iterator<Item = Self> {
         let package_id = package.package_id();
 
-        if &package_id != resolve.root_id() {
-            return None;
-        }
-
         let bin_targets = package
             .targets()
             .iter()
@@ -328,21 +1149,10 @@ impl BinaryCrate {
             // Filter out binary targets with at least one required feature
             // that's not enabled.
             .filter(|(target, _)| {
-                let required_features = target
-                    .required_features()
-                    .map(Vec::as_slice)
-                    .unwrap_or_default();
-
-                for feature in required_features {
-                    if !enabled_features.iter().any(|f| f == &**feature) {
-                        return false;
-                    }
-                }
-
-                true
+                has_required_features(target, enabled_features)
             });
 
-        Some(bin_targets.map(move |(target, src_path)| {
+        bin_targets.map(move |(target, src_path)| {
             let path = src_path
                 .strip_prefix(package.root())
                 .expect("binary path is under package root")
@@ -350,17 +1160,100 @@ impl BinaryCrate {
                 .to_compact_string();
 
             Self {
-                build_opts: BuildOpts::new(package_id, false, resolve),
+                build_opts: BuildOpts::new(
+                    package_id,
+                    false,
+                    compile_kind,
+                    resolve,
+                ),
                 name: target.name().into(),
                 path,
             }
-        }))
+        })
+    }
+}
+
+impl Tests {
+    /// Returns the test targets of the given package, or `None` if its
+    /// dev-dependencies haven't been resolved.
+    fn new(
+        package: &Package,
+        enabled_features: &[impl PartialEq<str>],
+        compile_kind: CompileKind,
+        resolve: &WorkspaceResolve,
+    ) -> Option<Self> {
+        let package_id = package.package_id();
+
+        if !resolve.has_dev_units() {
+            return None;
+        }
+
+        let targets = package
+            .targets()
+            .iter()
+            .filter(|target| target.tested())
+            .filter(|target| has_required_features(target, enabled_features))
+            .filter_map(|target| {
+                let kind = match target.kind() {
+                    TargetKind::Lib(_) => TestKind::Lib,
+                    TargetKind::Bin => TestKind::Bin,
+                    TargetKind::Test => TestKind::Integration,
+                    _ => return None,
+                };
+
+                let path = match target.src_path() {
+                    TargetSourcePath::Path(src_path) => src_path
+                        .strip_prefix(package.root())
+                        .expect("test path is under package root")
+                        .display()
+                        .to_compact_string(),
+                    TargetSourcePath::Metabuild => return None,
+                };
+
+                let is_proc_macro = target.proc_macro();
+
+                Some(TestCrate {
+                    build_opts: BuildOpts::new(
+                        package_id,
+                        is_proc_macro,
+                        compile_kind,
+                        resolve,
+                    ),
+                    harness: target.harness(),
+                    kind,
+                    name: target.name().into(),
+                    path,
+                })
+            })
+            .collect();
+
+        // Doctests can only be run on libraries that other crates can link
+        // against.
+        let doctests = package.targets().iter().any(|target| {
+            target.doctested() && target.is_linkable() && !target.proc_macro()
+        });
+
+        Some(Self {
+            dependency_renames: dependency_renames(
+                package_id,
+                package.targets().iter().any(Target::proc_macro),
+                &[DepKind::Normal, DepKind::Development],
+                resolve,
+            ),
+            doctests,
+            targets,
+        })
     }
 }
 
 impl BuildScript {
-    /// Returns the build script of the given package, if any.
-    fn new(package: &Package, resolve: &WorkspaceResolve) -> Option<Self> {
+    /// Returns the build script of the given package, if any, which is run for
+    /// the platform the package is compiled for.
+    fn new(
+        package: &Package,
+        run_kind: CompileKind,
+        resolve: &WorkspaceResolve,
+    ) -> Option<Self> {
         let build_script_target =
             package.targets().iter().find(|t| t.is_custom_build())?;
 
@@ -379,9 +1272,25 @@ impl BuildScript {
         let package_id = package.package_id();
 
         Some(Self {
-            build_opts: BuildOpts::new(package_id, true, resolve),
-            dependency_renames: dependency_renames::<false>(
-                package_id, resolve,
+            build_opts: BuildOpts::new(
+                package_id,
+                true,
+                CompileKind::Host,
+                resolve,
+            ),
+            cfg_env: cfg_env(resolve.target_data().cfg(run_kind)),
+            rustflags: resolve
+                .target_data()
+                .get_info(run_kind)
+                .map_or(&[][..], |info| &*info.rustflags)
+                .iter()
+                .map(|flag| flag.as_str().into())
+                .collect(),
+            dependency_renames: dependency_renames(
+                package_id,
+                true,
+                &[DepKind::Build],
+                resolve,
             ),
             path,
         })
@@ -389,30 +1298,36 @@ impl BuildScript {
 }
 
 impl BuildOpts {
+    /// Returns the build options of a crate of the given package, compiled
+    /// with the profile of the units compiled for the host (i.e. build
+    /// scripts, proc-macros and their dependencies) if `for_host` is true.
     fn new(
         package_id: PackageId,
-        is_build_script_or_proc_macro: bool,
+        for_host: bool,
+        compile_kind: CompileKind,
         resolve: &WorkspaceResolve,
     ) -> Self {
-        let unit_for = if is_build_script_or_proc_macro {
-            UnitFor::new_host(true, CompileKind::Host)
-        } else {
-            UnitFor::new_normal(resolve.compile_kind())
-        };
-
-        let compile_kind = if is_build_script_or_proc_macro {
-            CompileKind::Host
-        } else {
-            resolve.compile_kind()
-        };
+        let mut profile = Self::profile(package_id, for_host, resolve);
+
+        // Cargo defers the debuginfo of build dependencies whose profile
+        // doesn't explicitly set it, and only turns it on for units that are
+        // shared with the target. A node only uses the host's profile if it's
+        // not shared (see `NodeKey::uses_host_profile`), so the deferred value
+        // is never used.
+        if let DebugInfo::Deferred(_) = profile.debuginfo {
+            profile.debuginfo = DebugInfo::Resolved(TomlDebugInfo::None);
+        }
 
-        let profile = resolve.profiles().get_profile(
-            package_id,
-            resolve.workspace().is_member_id(package_id),
-            package_id.source_id().is_path(),
-            unit_for,
-            compile_kind,
-        );
+        // Like Cargo, silently drop the `split-debuginfo` setting if the
+        // target doesn't support it.
+        if let Some(split_debuginfo) = profile.split_debuginfo
+            && !resolve
+                .target_data()
+                .info(compile_kind)
+                .supports_debuginfo_split(split_debuginfo)
+        {
+            profile.split_debuginfo = None;
+        }
 
         let extra_rustc_args = profile
             .rustflags
@@ -429,13 +1344,65 @@ impl BuildOpts {
             .map(Into::into)
             .collect();
 
-        Self { codegen_units: profile.codegen_units, extra_rustc_args }
+        let linker = resolve.linker(compile_kind);
+
+        Self {
+            compile_kind,
+            extra_rustc_args,
+            metadata: None,
+            linker,
+            // This is only a placeholder for crates that aren't build scripts,
+            // and it's overwritten by `BuildGraph::resolve_lto()` once the
+            // whole graph is known.
+            lto: Lto::OnlyObject,
+            profile,
+        }
+    }
+
+    /// Returns whether the `build-override` settings change the profile of
+    /// the given package, ignoring the debuginfo level they defer.
+    fn has_host_overrides(
+        package_id: PackageId,
+        resolve: &WorkspaceResolve,
+    ) -> bool {
+        let host_profile = Self::profile(package_id, true, resolve);
+        let normal_profile = Self::profile(package_id, false, resolve);
+        !profile_metadata(&host_profile).eq(profile_metadata(&normal_profile))
+    }
+
+    /// Returns the profile of the given package when it's compiled for the
+    /// host (if `for_host` is true) or for the target.
+    fn profile(
+        package_id: PackageId,
+        for_host: bool,
+        resolve: &WorkspaceResolve,
+    ) -> Profile {
+        let compile_kind = resolve.unit_compile_kind(for_host);
+
+        let unit_for = if for_host {
+            UnitFor::new_host(true, CompileKind::Host)
+        } else {
+            UnitFor::new_normal(compile_kind)
+        };
+
+        resolve.profiles().get_profile(
+            package_id,
+            resolve.workspace().is_member_id(package_id),
+            package_id.source_id().is_path(),
+            unit_for,
+            compile_kind,
+        )
     }
 }
 
 impl LibraryCrate {
     /// Returns the library crate of the given package, if any.
-    fn new(package: &Package, resolve: &WorkspaceResolve) -> Option<Self> {
+    fn new(
+        package: &Package,
+        compile_kind: CompileKind,
+        uses_host_profile: bool,
+        resolve: &WorkspaceResolve,
+    ) -> Option<Self> {
         let (lib_target, crate_types) =
             package.targets().iter().find_map(|target| {
                 match target.kind() {
@@ -458,8 +1425,6 @@ impl LibraryCrate {
             },
         };
 
-        let mut is_proc_macro = false;
-
         let lib_formats = crate_types
             .iter()
             .map(|crate_type| match crate_type {
@@ -468,10 +1433,7 @@ impl LibraryCrate {
                 CrateType::Dylib => LibraryFormat::Dylib,
                 CrateType::Cdylib => LibraryFormat::Cdylib,
                 CrateType::Staticlib => LibraryFormat::Staticlib,
-                CrateType::ProcMacro => {
-                    is_proc_macro = true;
-                    LibraryFormat::ProcMacro
-                },
+                CrateType::ProcMacro => LibraryFormat::ProcMacro,
                 other => unreachable!("{other:?} is not a library crate type"),
             })
             .collect();
@@ -479,7 +1441,8 @@ impl LibraryCrate {
         Some(Self {
             build_opts: BuildOpts::new(
                 package.package_id(),
-                is_proc_macro,
+                uses_host_profile,
+                compile_kind,
                 resolve,
             ),
             name: lib_target.name().into(),
@@ -497,18 +1460,16 @@ impl PackageAttrs {
         }
     }
 
-    fn new(package: &Package, resolve: &WorkspaceResolve) -> Self {
+    fn new(package: &Package, features: &[InternedString]) -> Self {
         let manifest = package.manifest();
         let metadata = manifest.metadata();
 
         Self {
             authors: metadata.authors.clone(),
+            default_run: manifest.default_run().map(Into::into),
             description: metadata.description.clone(),
             edition: manifest.edition(),
-            features: resolve
-                .features(package.package_id())
-                .map(Into::into)
-                .collect(),
+            features: features.iter().map(|f| f.as_str().into()).collect(),
             homepage: metadata.homepage.clone(),
             license: metadata.license.as_deref().map(Into::into),
             license_file: metadata.license_file.as_deref().map(Into::into),
@@ -526,33 +1487,42 @@ impl PackageAttrs {
 }
 
 impl PackageSource {
-    fn new(package: &Package, vendor_dir: &Path) -> Self {
+    fn new(package: &Package) -> Self {
         match package.package_id().source_id().kind() {
-            SourceKind::Git(_) => {
-                let package_root_is_repo_root = package
-                    .root()
-                    .strip_prefix(vendor_dir)
-                    .expect("dependency has been vendored")
-                    .components()
-                    .count()
-                    == 1;
-
-                if package_root_is_repo_root {
-                    Self::Vendored
-                } else {
-                    Self::Path(package.root().to_owned())
-                }
-            },
             SourceKind::Path => Self::Path(package.root().to_owned()),
-            SourceKind::Registry
-            | SourceKind::SparseRegistry
-            | SourceKind::LocalRegistry
-            | SourceKind::Directory => Self::Vendored,
+            // Like `cargo vendor`, `vendorDeps` extracts every package in a
+            // git repository into its own directory.
+            _ => Self::Vendored,
         }
     }
 }
 
 #[inline]
+fn profile_to_value(profile: &Profile) -> impl Value + use<> {
+    attrset! {
+        name: profile.name.as_str(),
+        optLevel: profile.opt_level.as_str(),
+        codegenUnits: profile.codegen_units,
+        debuginfo: profile.debuginfo.into_inner().to_compact_string(),
+        debugAssertions: profile.debug_assertions,
+        overflowChecks: profile.overflow_checks,
+        panic: profile.panic.to_compact_string(),
+        incremental: profile.incremental,
+        rpath: profile.rpath,
+        strip: profile.strip.into_inner().to_compact_string(),
+    }
+}
+
+/// Returns the value of [`BuildOpts::compile_kind`] exposed to Nix, which is
+/// either `"host"` or the target triple.
+#[inline]
+fn compile_kind_to_value(compile_kind: &CompileKind) -> CompactString {
+    match compile_kind {
+        CompileKind::Host => CompactString::const_new("host"),
+        CompileKind::Target(target) => target.rustc_target().as_str().into(),
+    }
+}
+
 pub(crate) fn edition_as_str(edition: Edition) -> &'static str {
     match edition {
         Edition::Edition2015 => "2015",
@@ -563,24 +1533,204 @@ pub(crate) fn edition_as_str(edition: Edition) -> &'static str {
     }
 }
 
-/// Constructs the [`DependencyRenames`] for the package with the given ID.
+/// Returns an identifier of the given package's source that doesn't depend on
+/// where the workspace is located, i.e. the path relative to the workspace
+/// root for path dependencies, or the source's URL for all the others.
+fn stable_source_id(
+    package_id: PackageId,
+    resolve: &WorkspaceResolve,
+) -> CompactString {
+    let source_id = package_id.source_id();
+
+    let relative_path = source_id
+        .local_path()
+        .filter(|_| source_id.is_path())
+        .and_then(|path| {
+            path.strip_prefix(resolve.workspace().root())
+                .ok()
+                .map(|path| path.display().to_compact_string())
+        });
+
+    match relative_path {
+        Some(path) => format_compact!("path+{path}"),
+        None => source_id.as_url().to_compact_string(),
+    }
+}
+
+/// Returns the metadata hash of the given library (see
+/// [`BuildGraph::resolve_metadata`]).
+#[expect(clippy::too_many_arguments)]
+fn library_metadata<'a>(
+    package: &PackageAttrs,
+    library: &LibraryCrate,
+    source_id: &str,
+    rustc_id: &str,
+    deps_metadata: impl IntoIterator<Item = &'a str>,
+) -> CompactString {
+    let build_opts = &library.build_opts;
+
+    // Make the hash independent of the order of the dependencies.
+    let mut deps_metadata = deps_metadata.into_iter().collect::<Vec<_>>();
+    deps_metadata.sort_unstable();
+
+    let mut hasher = sha2::Sha256::new();
+
+    let mut update = |bytes: &[u8]| {
+        hasher.update(bytes);
+        // Separate the fields so that their boundaries are unambiguous.
+        hasher.update([0]);
+    };
+
+    update(library.name.as_bytes());
+    update(package.version.to_string().as_bytes());
+    update(source_id.as_bytes());
+    for feature in &package.features {
+        update(feature.as_bytes());
+    }
+    update(compile_kind_to_value(&build_opts.compile_kind).as_bytes());
+    for setting in profile_metadata(&build_opts.profile) {
+        update(setting.as_bytes());
+    }
+    update(build_opts.lto.name().as_bytes());
+    for arg in &build_opts.extra_rustc_args {
+        update(arg.as_bytes());
+    }
+    update(rustc_id.as_bytes());
+    for dep_metadata in deps_metadata {
+        update(dep_metadata.as_bytes());
+    }
+
+    let hash = hasher.finalize();
+    let short_hash = u64::from_le_bytes(
+        hash[..8].try_into().expect("slice is 8 bytes long"),
+    );
+    format_compact!("{short_hash:x}")
+}
+
+/// Returns the settings of the given profile that are hashed into the metadata
+/// of the libraries built with it.
 ///
-/// The `IS_NORMAL` constant should be `true` if the renames should only include
-/// [normal](DepKind::Normal) dependencies, and `false` if they should only
-/// include [build](DepKind::Build) dependencies.
+/// Like Cargo's `compute_metadata`, these are listed one by one instead of
+/// hashing the whole profile, so that the hash is the same whether the
+/// profile comes from the `cargo` crate or not.
+fn profile_metadata(profile: &Profile) -> impl Iterator<Item = CompactString> {
+    let lto = match profile.lto {
+        ProfileLto::Off => CompactString::const_new("off"),
+        ProfileLto::Bool(lto) => lto.to_compact_string(),
+        ProfileLto::Named(lto) => lto.as_str().into(),
+    };
+
+    let settings = [
+        profile.opt_level.as_str().into(),
+        lto,
+        profile.codegen_backend.map_or("", |backend| backend.as_str()).into(),
+        profile
+            .codegen_units
+            .map(|units| units.to_compact_string())
+            .unwrap_or_default(),
+        profile.debuginfo.into_inner().to_compact_string(),
+        profile.split_debuginfo.map_or("", |split| split.as_str()).into(),
+        profile.debug_assertions.to_compact_string(),
+        profile.overflow_checks.to_compact_string(),
+        profile.rpath.to_compact_string(),
+        profile.panic.to_compact_string(),
+        profile.strip.into_inner().to_compact_string(),
+    ];
+
+    let rustflags = profile.rustflags.iter().map(|flag| flag.as_str().into());
+
+    settings.into_iter().chain(rustflags)
+}
+
+/// Returns the `CARGO_CFG_*` environment variables Cargo sets for build scripts
+/// from the given `cfg`s (i.e. the output of `rustc --print cfg`).
+fn cfg_env(cfgs: &[Cfg]) -> HashMap<CompactString, CompactString> {
+    let mut env = HashMap::<_, CompactString>::new();
+
+    for cfg in cfgs {
+        let (name, value) = match cfg {
+            Cfg::Name(name) => (name.as_str(), None),
+            Cfg::KeyPair(key, value) => (key.as_str(), Some(value.as_str())),
+        };
+
+        // Like Cargo, skip `debug_assertions` because it's always set when
+        // querying rustc, which would be misleading. We set it from the
+        // profile instead.
+        if name == "debug_assertions" {
+            continue;
+        }
+
+        let env_name = format_compact!(
+            "CARGO_CFG_{}",
+            name.to_uppercase().replace('-', "_")
+        );
+
+        let env_value = env.entry(env_name).or_default();
+
+        // Cfgs with multiple values (e.g. `target_feature`) are joined with
+        // commas.
+        if let Some(value) = value {
+            if !env_value.is_empty() {
+                env_value.push(',');
+            }
+            env_value.push_str(value);
+        }
+    }
+
+    env
+}
+
+/// Merges the LTO requirements that a dependent with the given requirements
+/// places on the library of the node at `dep_idx` into `lib_ltos`.
+fn merge_dependency_lto(
+    lib_ltos: &mut [Option<Lto>],
+    nodes: &[BuildGraphNode],
+    dep_idx: usize,
+    dependent_lto: Lto,
+) {
+    let library = nodes[dep_idx]
+        .library
+        .as_ref()
+        .expect("only library crates can be dependencies");
+
+    let lto = dependent_lto.for_dependency(
+        library.build_opts.profile.lto,
+        LtoCrateTypes::library(&library.formats),
+        library.is_proc_macro(),
+    );
+
+    let entry = &mut lib_ltos[dep_idx];
+    *entry = Some(entry.map_or(lto, |prev| prev.merge(lto)));
+}
+
+/// Returns whether all the features required by the given target are enabled.
+#[inline]
+fn has_required_features(
+    target: &Target,
+    enabled_features: &[impl PartialEq<str>],
+) -> bool {
+    let required_features =
+        target.required_features().map(Vec::as_slice).unwrap_or_default();
+
+    required_features.iter().all(|feature| {
+        enabled_features.iter().any(|enabled| enabled == &**feature)
+    })
+}
+
+/// Constructs the [`DependencyRenames`] for the package with the given ID,
+/// only considering the dependencies whose kind is in `dep_kinds`.
 #[inline]
-fn dependency_renames<const IS_NORMAL: bool>(
+fn dependency_renames(
     package_id: PackageId,
+    for_host: bool,
+    dep_kinds: &[DepKind],
     resolve: &WorkspaceResolve,
 ) -> DependencyRenames {
     let mut renames = DependencyRenames::default();
 
-    for (_dep_pkg_id, dep) in resolve.deps(package_id) {
-        match dep.kind() {
-            DepKind::Normal if !IS_NORMAL => continue,
-            DepKind::Build if IS_NORMAL => continue,
-            DepKind::Development => continue,
-            _ => {},
+    for (_dep_pkg_id, dep) in resolve.deps(package_id, for_host) {
+        if !dep_kinds.contains(&dep.kind()) {
+            continue;
         }
 
         let Some(name_in_toml) = dep.explicit_name_in_toml() else {
@@ -633,6 +1783,8 @@ impl IntoValue for BuildGraph {
 
         for (node_idx, node) in self.nodes.into_iter().enumerate() {
             let dependencies = self.edges[node_idx].dependencies.clone();
+            let dev_dependencies =
+                self.edges[node_idx].dev_dependencies.clone();
 
             // Add a `dependencies` attribute to the build script if it has any.
             let build_script = node.build_script.clone().map(|script| {
@@ -651,6 +1803,12 @@ impl IntoValue for BuildGraph {
                     (!dependencies.is_empty())
                         .then(|| attrset! { dependencies }),
                 )
+                .merge(
+                    // Add a `devDependencies` attribute if the node has any.
+                    (!dev_dependencies.is_empty()).then(
+                        || attrset! { devDependencies: dev_dependencies },
+                    ),
+                )
                 .merge(attrset! { buildScript: build_script });
 
             nodes.push(node);
@@ -666,6 +1824,12 @@ impl ToValue for LibraryFormat {
     }
 }
 
+impl ToValue for TestKind {
+    fn to_value(&self, _: &mut Context) -> impl Value + use<> {
+        self.as_str()
+    }
+}
+
 impl ToValue for PackageSource {
     fn to_value<'this>(
         &'this self,
@@ -677,3 +1841,138 @@ impl ToValue for PackageSource {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cargo_types::CompileTarget;
+
+    /// The inputs of [`library_metadata`].
+    struct MetadataInputs {
+        package: PackageAttrs,
+        library: LibraryCrate,
+        source_id: CompactString,
+        rustc_id: CompactString,
+        deps_metadata: Vec<&'static str>,
+    }
+
+    impl MetadataInputs {
+        fn metadata(&self) -> CompactString {
+            library_metadata(
+                &self.package,
+                &self.library,
+                &self.source_id,
+                &self.rustc_id,
+                self.deps_metadata.iter().copied(),
+            )
+        }
+    }
+
+    fn inputs() -> MetadataInputs {
+        let package = PackageAttrs {
+            authors: Vec::new(),
+            default_run: None,
+            description: None,
+            edition: Edition::Edition2021,
+            features: vec!["default".into()],
+            homepage: None,
+            license: None,
+            license_file: None,
+            links: None,
+            name: "foo".into(),
+            readme: None,
+            repository: None,
+            rust_version: None,
+            version: semver::Version::new(1, 0, 0),
+        };
+
+        let library = LibraryCrate {
+            build_opts: BuildOpts {
+                compile_kind: CompileKind::Host,
+                extra_rustc_args: Vec::new(),
+                metadata: None,
+                linker: None,
+                lto: Lto::OnlyObject,
+                profile: Profile::default(),
+            },
+            name: "foo".into(),
+            path: "src/lib.rs".into(),
+            formats: smallvec![LibraryFormat::Lib],
+        };
+
+        MetadataInputs {
+            package,
+            library,
+            source_id: "registry+https://github.com/rust-lang/crates.io-index"
+                .into(),
+            rustc_id: "rustc 1.90.0 (1159e78c4 2025-09-14)".into(),
+            deps_metadata: vec!["0123456789abcdef", "fedcba9876543210"],
+        }
+    }
+
+    /// Asserts that applying `change` to the inputs changes the metadata.
+    fn assert_changes(change: impl FnOnce(&mut MetadataInputs)) {
+        let mut changed = inputs();
+        change(&mut changed);
+        assert_ne!(inputs().metadata(), changed.metadata());
+    }
+
+    #[test]
+    fn metadata_depends_on_source_id() {
+        assert_changes(|inputs| {
+            inputs.source_id = "git+https://github.com/foo/foo".into();
+        });
+    }
+
+    #[test]
+    fn metadata_depends_on_features() {
+        assert_changes(|inputs| inputs.package.features.push("std".into()));
+    }
+
+    #[test]
+    fn metadata_depends_on_compile_kind() {
+        assert_changes(|inputs| {
+            let target = CompileTarget::new("aarch64-unknown-linux-gnu");
+            inputs.library.build_opts.compile_kind =
+                CompileKind::Target(target.unwrap());
+        });
+    }
+
+    #[test]
+    fn metadata_depends_on_profile() {
+        assert_changes(|inputs| {
+            inputs.library.build_opts.profile.opt_level = "3".into();
+        });
+    }
+
+    #[test]
+    fn metadata_depends_on_lto() {
+        assert_changes(|inputs| inputs.library.build_opts.lto = Lto::Run(None));
+    }
+
+    #[test]
+    fn metadata_depends_on_extra_rustc_args() {
+        assert_changes(|inputs| {
+            inputs.library.build_opts.extra_rustc_args.push("--cfg=foo".into());
+        });
+    }
+
+    #[test]
+    fn metadata_depends_on_rustc() {
+        assert_changes(|inputs| {
+            inputs.rustc_id = "rustc 1.91.0 (f8297e351 2025-10-28)".into();
+        });
+    }
+
+    #[test]
+    fn metadata_depends_on_dependencies_metadata() {
+        assert_changes(|inputs| inputs.deps_metadata[1] = "0000000000000000");
+    }
+
+    #[test]
+    fn metadata_ignores_dependencies_order() {
+        let mut reordered = inputs();
+        reordered.deps_metadata.reverse();
+        assert_eq!(inputs().metadata(), reordered.metadata());
+    }
+}
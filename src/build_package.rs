@@ -1,12 +1,16 @@
 use core::result::Result;
+use std::collections::HashMap;
 use std::path::Path;
 
-use compact_str::CompactString;
+use compact_str::{CompactString, ToCompactString, format_compact};
 use nix_bindings::prelude::{Error as NixError, *};
 
+use crate::build_graph::BuildGraph;
+use crate::cargo_types::CompileTarget;
 use crate::make_derivation::{
     self,
     DerivationType,
+    Overrides,
     make_deps,
     make_derivation,
 };
@@ -14,10 +18,13 @@ use crate::resolve_build_graph::{
     ResolveBuildGraph,
     ResolveBuildGraphArgs,
     ResolveBuildGraphError,
+    VendorDir,
 };
 use crate::vendor_deps::{VendorDeps, VendorDepsError, VendoredSources};
 
-/// Builds a Rust package.
+/// Builds a Rust package, returning an attribute set with its `lib`, `bin` and
+/// `buildScript` derivations, together with a `crates` map containing the
+/// derivations of every crate in its build graph.
 #[derive(nix_bindings::PrimOp)]
 pub(crate) struct BuildPackage;
 
@@ -29,47 +36,150 @@ pub(crate) struct BuildPackageArgs<'a> {
     pub(crate) pkgs: NixAttrset<'a>,
 
     /// The path to the root of the workspace the package is in.
-    src: &'a Path,
+    pub(crate) src: &'a Path,
 
     /// Whether to enable all features (equivalent to calling Cargo with the
     /// `--all-features` CLI flag).
     #[try_from(default)]
-    all_features: bool,
-
-    /// TODO: docs.
+    pub(crate) all_features: bool,
+
+    /// A map from package names to functions taking the attributes of the
+    /// crate's derivation and returning the ones to override.
+    ///
+    /// This can also be a function taking the package set the crate is
+    /// compiled with and returning the map, which is needed when
+    /// cross-compiling to get the native libraries of the crates compiled
+    /// for the build platform from `pkgs.buildPackages`.
     #[try_from(default)]
-    pub(crate) crate_overrides: Option<NixAttrset<'a>>,
+    pub(crate) crate_overrides: Option<Overrides<'a>>,
 
     /// The list of the package's features to enable.
     #[try_from(default)]
-    features: Vec<String>,
+    pub(crate) features: Vec<String>,
 
-    /// TODO: docs.
+    /// The attributes to override in the derivations of every crate, or a
+    /// function taking the package set the crate is compiled with and
+    /// returning them.
     #[try_from(default)]
-    pub(crate) global_overrides: Option<NixAttrset<'a>>,
+    pub(crate) global_overrides: Option<Overrides<'a>>,
+
+    /// The local sources to vendor the `local-registry` and `directory`
+    /// dependencies from (see
+    /// [`VendorDepsArgs::local_sources`](crate::vendor_deps::VendorDepsArgs::local_sources)).
+    #[try_from(default)]
+    pub(crate) local_sources: Option<NixAttrset<'a>>,
 
     /// Whether to disable the default features (equivalent to calling Cargo
     /// with the `--no-default-features` CLI flag).
     #[try_from(default)]
-    no_default_features: bool,
+    pub(crate) no_default_features: bool,
 
     /// The package's name.
     #[try_from(default)]
-    package: Option<CompactString>,
+    pub(crate) package: Option<CompactString>,
 
-    /// TODO: docs.
-    #[try_from(default = true)]
-    pub(crate) release: bool,
+    /// The custom registries the package's dependencies come from (see
+    /// [`VendorDepsArgs::registries`](crate::vendor_deps::VendorDepsArgs::registries)).
+    #[try_from(default)]
+    pub(crate) registries: Option<NixAttrset<'a>>,
+
+    /// Whether to pipeline the compilation like Cargo does, i.e. to build a
+    /// separate metadata-only derivation for every library, and to compile
+    /// the libraries that aren't linked against their dependencies' metadata
+    /// instead of waiting for their full artifacts.
+    #[try_from(default)]
+    pub(crate) pipelined: bool,
+
+    /// The name of the Cargo profile to build the package with (e.g.
+    /// `"release"`, `"dev"`, or any custom profile defined in the workspace's
+    /// manifest).
+    #[try_from(default = CompactString::const_new("release"))]
+    pub(crate) profile: CompactString,
 
     /// TODO: docs.
     #[try_from(default)]
     pub(crate) rustc: Option<NixDerivation<'a>>,
+
+    /// Extra flags to pass to `rustc`, like Cargo's `RUSTFLAGS` environment
+    /// variable.
+    ///
+    /// Like with Cargo, when cross-compiling these are only passed to the
+    /// crates compiled for the target, and not to build scripts or
+    /// proc-macros. Extra flags for a single package can be set via the
+    /// `rustflags` attribute of its `crateOverrides`.
+    #[try_from(default)]
+    pub(crate) rustflags: Vec<CompactString>,
+
+    /// Whether to only pass the `rustflags` to the crates of the workspace
+    /// members, and not to their dependencies.
+    #[try_from(default)]
+    pub(crate) rustflags_members_only: bool,
+}
+
+/// The attribute set returned by [`BuildPackage`].
+#[derive(nix_bindings::Attrset)]
+#[attrset(rename_all = camelCase)]
+pub(crate) struct BuildPackageOutput {
+    /// The derivation for the package's binary targets, if it has any.
+    #[attrset(skip_if = Option::is_none)]
+    bin: Option<NixDerivation<'static>>,
+
+    /// The derivation for the package's build script, if it has one.
+    #[attrset(skip_if = Option::is_none)]
+    build_script: Option<NixDerivation<'static>>,
+
+    /// A map from the `<name>-<version>` of every package in the build graph
+    /// (including the requested one) to its derivations.
+    ///
+    /// Packages that are only compiled for the host (i.e. as (dependencies of)
+    /// build dependencies or proc-macros) are keyed by
+    /// `<name>-<version>-host`, since the same package can also be compiled
+    /// for the target with a different set of features.
+    ///
+    /// If more than one node still has the same key (e.g. a git fork next to
+    /// the registry package with the same version), the metadata hash of
+    /// their libraries is appended to it, as in `<name>-<version>-<metadata>`.
+    crates: HashMap<CompactString, CrateDerivations>,
+
+    /// The derivation for the package's library target, if it has one.
+    #[attrset(skip_if = Option::is_none)]
+    lib: Option<NixDerivation<'static>>,
+}
+
+/// The derivations built for a single node in the build graph.
+#[derive(Copy, Clone, nix_bindings::Attrset)]
+#[attrset(rename_all = camelCase)]
+pub(crate) struct CrateDerivations {
+    /// The derivation for the package's binary targets, if it has any.
+    #[attrset(skip_if = Option::is_none)]
+    pub(crate) bin: Option<NixDerivation<'static>>,
+
+    /// The derivation for the package's build script, if it has one.
+    #[attrset(skip_if = Option::is_none)]
+    pub(crate) build_script: Option<NixDerivation<'static>>,
+
+    /// The derivation for the package's library target, if it has one.
+    #[attrset(skip_if = Option::is_none)]
+    pub(crate) lib: Option<NixDerivation<'static>>,
+
+    /// The derivation for the metadata of the package's library target, if
+    /// compiling with pipelining.
+    #[attrset(skip_if = Option::is_none)]
+    pub(crate) metadata: Option<NixDerivation<'static>>,
 }
 
 /// The type of error that can occur when building a package fails.
 #[derive(Debug, derive_more::Display, cauchy::From)]
 #[display("{_0}")]
 pub(crate) enum BuildPackageError {
+    /// More than one node in the build graph would be exposed under the given
+    /// key of [`BuildPackageOutput::crates`].
+    #[display(
+        "more than one crate in the build graph is keyed by {_0:?}, which \
+         would make them overwrite each other"
+    )]
+    DuplicateCrateKey(CompactString),
+
     /// A Nix runtime error occurred.
     Nix(#[from] NixError),
 
@@ -83,135 +193,447 @@ pub(crate) enum BuildPackageError {
 impl Function for BuildPackage {
     type Args<'a> = BuildPackageArgs<'a>;
 
-    #[expect(clippy::too_many_lines)]
     fn call<'a: 'a>(
         args: Self::Args<'a>,
         ctx: &mut Context,
-    ) -> Result<NixDerivation<'static>, BuildPackageError> {
-        let cargo_lock =
-            VendorDeps::read_cargo_lock(&args.src.join("Cargo.lock"))?;
-
-        let vendored_sources =
-            VendoredSources::new(&cargo_lock, args.pkgs, ctx)?;
-
-        let vendor_dir = vendored_sources.to_dir(args.pkgs, ctx)?;
-
-        vendor_dir.realise(ctx)?;
-
-        let global_args =
-            make_derivation::GlobalArgs::new(&args, &vendored_sources, ctx)?;
-
-        let build_graph = {
-            let args = ResolveBuildGraphArgs {
-                src: args.src,
-                vendor_dir: vendor_dir.out_path(ctx)?.into(),
-                all_features: args.all_features,
-                compile_target: global_args.compile_target,
-                features: args.features,
-                no_default_features: args.no_default_features,
-                package: args.package,
-                profile: CompactString::const_new(if args.release {
-                    "release"
+    ) -> Result<BuildPackageOutput, BuildPackageError> {
+        build_graph(
+            args,
+            false,
+            <ResolveBuildGraph as Function>::call,
+            ctx,
+            |build_graph, _global_args, node_derivations, _ctx| {
+                BuildPackageOutput::new(build_graph, node_derivations)
+            },
+        )
+    }
+}
+
+impl BuildPackageOutput {
+    fn new(
+        build_graph: &BuildGraph,
+        node_derivations: Vec<CrateDerivations>,
+    ) -> Result<Self, BuildPackageError> {
+        let root = node_derivations[build_graph.root_idx()];
+
+        let keys = build_graph
+            .nodes
+            .iter()
+            .map(|node| {
+                let source_id = node.package_attrs.source_id();
+                if node.is_for_host {
+                    format_compact!("{source_id}-host")
                 } else {
-                    "dev"
-                }),
+                    source_id.to_compact_string()
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut key_counts = HashMap::<&str, usize>::new();
+
+        for key in &keys {
+            *key_counts.entry(key.as_str()).or_default() += 1;
+        }
+
+        let mut crates = HashMap::with_capacity(keys.len());
+
+        for ((node, drvs), key) in
+            build_graph.nodes.iter().zip(node_derivations).zip(&keys)
+        {
+            let metadata = node
+                .library
+                .as_ref()
+                .and_then(|library| library.build_opts.metadata.as_deref());
+
+            let key = match metadata {
+                Some(metadata) if key_counts[key.as_str()] > 1 => {
+                    format_compact!("{key}-{metadata}")
+                },
+                _ => key.clone(),
             };
 
-            <ResolveBuildGraph as Function>::call(args, ctx)?
+            if crates.insert(key.clone(), drvs).is_some() {
+                return Err(BuildPackageError::DuplicateCrateKey(key));
+            }
+        }
+
+        Ok(Self {
+            bin: root.bin,
+            build_script: root.build_script,
+            crates,
+            lib: root.lib,
+        })
+    }
+}
+
+impl<'a> BuildPackageArgs<'a> {
+    /// Converts these arguments into the ones needed to resolve the package's
+    /// build graph.
+    fn into_resolve_args(
+        self,
+        vendor_dir: VendorDir<'a>,
+        compile_target: Option<CompileTarget>,
+        dev_dependencies: bool,
+    ) -> ResolveBuildGraphArgs<'a> {
+        ResolveBuildGraphArgs {
+            src: self.src,
+            vendor_dir,
+            all_features: self.all_features,
+            compile_target,
+            dev_dependencies,
+            features: self.features,
+            no_default_features: self.no_default_features,
+            package: self.package,
+            profile: self.profile,
+        }
+    }
+}
+
+impl CrateDerivations {
+    /// Returns the derivation of the crate's library.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the crate doesn't have a library target, which can never
+    /// happen for crates that are dependencies of other crates.
+    pub(crate) fn expect_lib(&self) -> NixDerivation<'static> {
+        self.lib.expect("only library crates can be dependencies")
+    }
+
+    /// Returns the derivation that the crate's dependents should be compiled
+    /// against if they don't need its full artifacts, i.e. the library's
+    /// metadata when pipelining, or the library itself otherwise.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the crate doesn't have a library target.
+    pub(crate) fn expect_metadata_or_lib(&self) -> NixDerivation<'static> {
+        self.metadata.unwrap_or_else(|| self.expect_lib())
+    }
+}
+
+impl From<BuildPackageError> for NixError {
+    fn from(err: BuildPackageError) -> Self {
+        match err {
+            BuildPackageError::Nix(nix_err) => nix_err,
+            other => Self::from_message(other),
+        }
+    }
+}
+
+/// Vendors the dependencies of the workspace at [`BuildPackageArgs::src`],
+/// resolves its build graph with the given function, and builds the
+/// derivations of every node in it.
+///
+/// The graph and its derivations (in the same order as
+/// [`BuildGraph::nodes`]) are then passed to `f` together with the arguments
+/// they were built with, which can't outlive this function since they borrow
+/// the vendored sources.
+#[expect(clippy::too_many_arguments)]
+pub(crate) fn build_graph<'a, T>(
+    args: BuildPackageArgs<'a>,
+    dev_dependencies: bool,
+    resolve: impl FnOnce(
+        ResolveBuildGraphArgs<'a>,
+        &mut Context,
+    ) -> Result<BuildGraph, ResolveBuildGraphError>,
+    ctx: &mut Context,
+    f: impl FnOnce(
+        &BuildGraph,
+        &make_derivation::GlobalArgs,
+        Vec<CrateDerivations>,
+        &mut Context,
+    ) -> Result<T, BuildPackageError>,
+) -> Result<T, BuildPackageError> {
+    let cargo_lock = VendorDeps::read_cargo_lock(&args.src.join("Cargo.lock"))?;
+
+    let vendored_sources = VendoredSources::new(
+        &cargo_lock,
+        args.src,
+        args.pkgs,
+        args.registries,
+        args.local_sources,
+        ctx,
+    )?;
+
+    // Resolving the build graph only reads the packages' manifests, so
+    // there's no need to realise every crate's sources at evaluation time.
+    let manifests_dir = vendored_sources.to_manifests_dir(args.pkgs, ctx)?;
+
+    let global_args =
+        make_derivation::GlobalArgs::new(&args, &vendored_sources, ctx)?;
+
+    let mut build_graph = {
+        let vendor_dir = VendorDir::Derivation(manifests_dir);
+        let compile_target = global_args.compile_target;
+        let args = args.into_resolve_args(
+            vendor_dir,
+            compile_target,
+            dev_dependencies,
+        );
+        resolve(args, ctx)?
+    };
+
+    apply_rustflags(&mut build_graph, &global_args, ctx)?;
+
+    let node_derivations = build_nodes(&build_graph, &global_args, ctx)?;
+
+    f(&build_graph, &global_args, node_derivations, ctx)
+}
+
+/// Appends the extra `rustc` flags set from Nix, i.e. the `rustflags` argument
+/// and the `rustflags` attribute of the crate overrides, to the arguments of
+/// the crates in the build graph.
+///
+/// This has to happen before building any derivation because the flags (and
+/// the `rustc` they're compiled with) are hashed into the crates' metadata,
+/// which their dependents also need to know.
+fn apply_rustflags(
+    build_graph: &mut BuildGraph,
+    global_args: &make_derivation::GlobalArgs,
+    ctx: &mut Context,
+) -> Result<(), NixError> {
+    let is_cross_compiling = global_args.compile_target.is_some();
+
+    for node in &mut build_graph.nodes {
+        let override_rustflags = global_args
+            .platform(node.is_for_host)
+            .override_rustflags(&node.package_attrs, ctx)?;
+
+        let global_rustflags = if global_args.rustflags_members_only
+            && !node.is_workspace_member
+        {
+            &[][..]
+        } else {
+            &*global_args.rustflags
         };
 
-        let mut library_derivations: Vec<NixDerivation<'static>> =
-            Vec::with_capacity(build_graph.nodes.len());
+        for build_opts in node.build_opts_mut() {
+            // Like `RUSTFLAGS`, the global flags aren't passed to the crates
+            // compiled for the host when cross-compiling.
+            let global_rustflags =
+                if is_cross_compiling && build_opts.compile_kind.is_host() {
+                    &[][..]
+                } else {
+                    global_rustflags
+                };
+
+            build_opts.extra_rustc_args.extend(
+                global_rustflags.iter().chain(&override_rustflags).cloned(),
+            );
+        }
+
+        let Some(build_script) = &mut node.build_script else { continue };
+
+        // The build script is always compiled for the host...
+        if !is_cross_compiling {
+            build_script
+                .build_opts
+                .extra_rustc_args
+                .extend_from_slice(global_rustflags);
+        }
+
+        // ...but it sees the flags of the crates it's run for via
+        // `CARGO_ENCODED_RUSTFLAGS`.
+        if !is_cross_compiling || !node.is_for_host {
+            build_script.rustflags.extend_from_slice(global_rustflags);
+        }
+    }
 
-        for (node_idx, node) in build_graph.nodes.iter().enumerate() {
-            let edges = &build_graph.edges[node_idx];
+    build_graph.set_rustc(global_args.rustc.out_path_as_string(ctx)?.into());
 
-            let build_deps = edges.build_dependencies.iter().map(|&idx| {
-                let node = &build_graph.nodes[idx];
-                let drv = library_derivations[idx].clone();
-                (node, drv)
-            });
+    build_graph.resolve_metadata();
 
-            let normal_deps = edges.dependencies.iter().map(|&idx| {
-                let node = &build_graph.nodes[idx];
-                let drv = library_derivations[idx].clone();
-                (node, drv)
-            });
+    Ok(())
+}
 
+/// Builds the derivations of every node in the build graph, returning them in
+/// the same order as [`BuildGraph::nodes`].
+#[expect(clippy::too_many_lines)]
+fn build_nodes(
+    build_graph: &BuildGraph,
+    global_args: &make_derivation::GlobalArgs,
+    ctx: &mut Context,
+) -> Result<Vec<CrateDerivations>, NixError> {
+    let mut node_derivations: Vec<CrateDerivations> =
+        Vec::with_capacity(build_graph.nodes.len());
+
+    for (node_idx, node) in build_graph.nodes.iter().enumerate() {
+        let edges = &build_graph.edges[node_idx];
+
+        let build_deps = edges.build_dependencies.iter().map(|&idx| {
+            let node = &build_graph.nodes[idx];
+            let drv = node_derivations[idx].expect_lib();
+            (node, drv)
+        });
+
+        let normal_deps = edges.dependencies.iter().map(|&idx| {
+            let node = &build_graph.nodes[idx];
+            let drv = node_derivations[idx].expect_lib();
+            (node, drv)
+        });
+
+        let metadata_deps = edges.dependencies.iter().map(|&idx| {
+            let node = &build_graph.nodes[idx];
+            let drv = node_derivations[idx].expect_metadata_or_lib();
+            (node, drv)
+        });
+
+        // Without pipelining, all the crates in the node share the same
+        // `-deps` derivation, unless we're cross-compiling and the build
+        // script is compiled for a different platform than the other crates.
+        let shared_deps_drv = if global_args.pipelined
+            || (global_args.build_platform.is_some() && !node.is_for_host)
+        {
+            None
+        } else {
             let all_direct_deps = build_deps
                 .clone()
                 .chain_exact(normal_deps.clone())
                 .map(|(_node, drv)| drv);
 
-            let deps_drv = make_deps(
+            Some(make_deps(
                 &node.package_attrs,
                 all_direct_deps,
-                &global_args,
+                global_args.platform(node.is_for_host),
                 ctx,
-            )?;
+            )?)
+        };
 
-            let build_script = if let Some(build_script) = &node.build_script {
-                Some(make_derivation(
-                    DerivationType::BuildScript(build_script),
-                    node,
-                    deps_drv.clone(),
-                    build_deps,
-                    &global_args,
+        // Returns the `-deps` derivation of a crate compiled for the host (if
+        // `for_host` is true) that's linked against the given dependencies.
+        // When pipelining, the full artifacts of the dependencies don't
+        // reference the ones of their own dependencies, so we need to include
+        // the whole closure.
+        let link_deps_drv =
+            |dep_idxs: &[usize], for_host: bool, ctx: &mut Context| {
+                if let Some(deps_drv) = shared_deps_drv {
+                    return Ok(deps_drv);
+                }
+                let closure =
+                    build_graph.dependency_closure(dep_idxs.iter().copied());
+                make_deps(
+                    &node.package_attrs,
+                    closure
+                        .into_iter()
+                        .map(|idx| node_derivations[idx].expect_lib()),
+                    global_args.platform(for_host),
                     ctx,
-                )?)
-            } else {
-                None
+                )
             };
 
-            let library = if let Some(library) = &node.library {
+        let build_script = if let Some(build_script) = &node.build_script {
+            let links_dependencies = edges
+                .dependencies
+                .iter()
+                .filter(|&&idx| {
+                    build_graph.nodes[idx].package_attrs.links.is_some()
+                })
+                .filter_map(|&idx| node_derivations[idx].build_script)
+                .collect();
+
+            Some(make_derivation(
+                DerivationType::BuildScript {
+                    build_script,
+                    links_dependencies,
+                },
+                node,
+                link_deps_drv(&edges.build_dependencies, true, ctx)?,
+                build_deps,
+                global_args,
+                ctx,
+            )?)
+        } else {
+            None
+        };
+
+        let metadata_deps_drv = match (&node.library, shared_deps_drv) {
+            (_, Some(deps_drv)) => Some(deps_drv),
+            (Some(library), None) if library.has_metadata() => Some(make_deps(
+                &node.package_attrs,
+                metadata_deps.clone().map(|(_node, drv)| drv),
+                global_args.platform(node.is_for_host),
+                ctx,
+            )?),
+            _ => None,
+        };
+
+        let metadata = match (&node.library, metadata_deps_drv) {
+            (Some(library), Some(deps_drv))
+                if global_args.pipelined && library.has_metadata() =>
+            {
                 Some(make_derivation(
-                    DerivationType::Library { build_script, library },
+                    DerivationType::Library {
+                        build_script,
+                        library,
+                        metadata_only: true,
+                    },
                     node,
-                    deps_drv.clone(),
-                    normal_deps.clone(),
-                    &global_args,
+                    deps_drv,
+                    metadata_deps.clone(),
+                    global_args,
                     ctx,
                 )?)
-            } else {
-                None
-            };
+            },
+            _ => None,
+        };
 
-            let _binaries = if !node.binaries.is_empty() {
+        let library = match (&node.library, metadata_deps_drv) {
+            (Some(library), Some(deps_drv))
+                if global_args.pipelined && !library.needs_linking() =>
+            {
                 Some(make_derivation(
-                    DerivationType::Binaries {
+                    DerivationType::Library {
                         build_script,
                         library,
-                        binaries: &node.binaries,
+                        metadata_only: false,
                     },
                     node,
                     deps_drv,
-                    normal_deps,
-                    &global_args,
+                    metadata_deps,
+                    global_args,
                     ctx,
                 )?)
-            } else {
-                None
-            };
+            },
+            (Some(library), _) => Some(make_derivation(
+                DerivationType::Library {
+                    build_script,
+                    library,
+                    metadata_only: false,
+                },
+                node,
+                link_deps_drv(&edges.dependencies, node.is_for_host, ctx)?,
+                normal_deps.clone(),
+                global_args,
+                ctx,
+            )?),
+            (None, _) => None,
+        };
 
-            if let Some(drv) = library {
-                library_derivations.push(drv);
-            }
-        }
+        let binaries = if !node.binaries.is_empty() {
+            Some(make_derivation(
+                DerivationType::Binaries {
+                    build_script,
+                    library,
+                    binaries: &node.binaries,
+                },
+                node,
+                link_deps_drv(&edges.dependencies, node.is_for_host, ctx)?,
+                normal_deps,
+                global_args,
+                ctx,
+            )?)
+        } else {
+            None
+        };
 
-        // The derivation for the requested package is the root of the build
-        // graph, which is the last element in the vector.
-        Ok(library_derivations
-            .into_iter()
-            .next_back()
-            .expect("build graph is never empty"))
+        node_derivations.push(CrateDerivations {
+            bin: binaries,
+            build_script,
+            lib: library,
+            metadata,
+        });
     }
-}
 
-impl From<BuildPackageError> for NixError {
-    fn from(err: BuildPackageError) -> Self {
-        match err {
-            BuildPackageError::Nix(nix_err) => nix_err,
-            other => Self::from_message(other),
-        }
-    }
+    Ok(node_derivations)
 }
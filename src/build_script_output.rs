@@ -0,0 +1,686 @@
+// This file is compiled on its own into the `parse-build-script-output`
+// executable, which runs right after a build script inside its derivation, so
+// it must only depend on the standard library.
+//
+// It's also included in the plugin as a module when testing, so that its unit
+// tests can be run with `cargo test`.
+
+use core::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::{env, fs, process};
+
+/// The instructions printed by a build script, parsed according to Cargo's
+/// build script protocol (both the `cargo::` and the older `cargo:` syntax).
+#[derive(Debug, Default, PartialEq, Eq)]
+struct BuildScriptOutput {
+    /// Arguments to pass to `rustc` when compiling any of the package's
+    /// targets.
+    rustc_args: Vec<String>,
+
+    /// Arguments to pass to the linker, together with the targets they apply
+    /// to.
+    link_args: Vec<(LinkArgTarget, String)>,
+
+    /// Environment variables to set when compiling the package's targets.
+    env: Vec<(String, String)>,
+
+    /// The `KEY=VALUE` metadata pairs to pass to the build scripts of the
+    /// package's dependents.
+    metadata: Vec<(String, String)>,
+
+    /// Warnings to show to the user.
+    warnings: Vec<String>,
+
+    /// Errors to show to the user, which fail the build.
+    errors: Vec<String>,
+}
+
+/// The targets a `cargo::rustc-link-arg*` instruction applies to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum LinkArgTarget {
+    /// `cargo::rustc-link-arg`.
+    All,
+
+    /// `cargo::rustc-link-arg-cdylib` (or `cargo::rustc-cdylib-link-arg`).
+    Cdylib,
+
+    /// `cargo::rustc-link-arg-bins`.
+    Bins,
+
+    /// `cargo::rustc-link-arg-bin=NAME=FLAG`.
+    SingleBin(String),
+
+    /// `cargo::rustc-link-arg-tests`.
+    Tests,
+
+    /// `cargo::rustc-link-arg-benches`.
+    Benches,
+
+    /// `cargo::rustc-link-arg-examples`.
+    Examples,
+}
+
+/// A target of the package that gets its own file of `rustc` arguments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target<'a> {
+    /// The library.
+    Lib,
+
+    /// The unit tests of the library.
+    LibTest,
+
+    /// The library's doctests, which are compiled by `rustdoc --test`.
+    Doctest,
+
+    /// A binary (or its unit tests).
+    Bin(&'a str),
+
+    /// An integration test.
+    Test,
+
+    /// A benchmark.
+    Bench,
+
+    /// An example.
+    Example,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ParseError {
+    /// A `cargo::` instruction that Cargo doesn't know about.
+    UnknownInstruction { line: String },
+
+    /// An instruction whose value doesn't have the expected `KEY=VALUE`
+    /// format.
+    MissingKeyValue { line: String },
+
+    /// A `cargo::rustc-flags` instruction containing a flag other than `-l`
+    /// and `-L`, or one missing its value.
+    InvalidRustcFlags { line: String },
+}
+
+/// The command line arguments of the executable.
+struct Args {
+    /// The path to the file containing the build script's stdout.
+    output_file: PathBuf,
+
+    /// The directory to write the generated files in.
+    out_dir: PathBuf,
+
+    /// The `<name>@<version>` of the package the build script belongs to,
+    /// used to prefix its warnings and errors.
+    package: String,
+
+    /// The `links` key from the package's manifest, if any.
+    links: Option<String>,
+
+    /// The names of the package's binaries.
+    bins: Vec<String>,
+}
+
+fn main() {
+    let Some(args) = Args::from_env() else {
+        eprintln!(
+            "usage: parse-build-script-output <OUTPUT_FILE> <OUT_DIR> \
+             <NAME@VERSION> [--links NAME] [--bin NAME]..."
+        );
+        process::exit(2);
+    };
+
+    if let Err(err) = run(&args) {
+        eprintln!("error: {err}");
+        process::exit(1);
+    }
+}
+
+fn run(args: &Args) -> Result<(), Box<dyn core::error::Error>> {
+    let output = fs::read_to_string(&args.output_file)?;
+
+    let output = BuildScriptOutput::parse(&output)?;
+
+    let mut stderr = io::stderr().lock();
+
+    for diagnostic in output.diagnostics(&args.package) {
+        writeln!(stderr, "{diagnostic}")?;
+    }
+
+    // Like Cargo, fail the build if the build script emitted any errors, even
+    // if it exited successfully.
+    if !output.errors.is_empty() {
+        return Err(format!(
+            "build script of `{}` emitted {} error(s)",
+            args.package,
+            output.errors.len()
+        )
+        .into());
+    }
+
+    output.write_files(&args.out_dir, args.links.as_deref(), &args.bins)?;
+
+    Ok(())
+}
+
+impl Args {
+    fn from_env() -> Option<Self> {
+        let mut args = env::args().skip(1);
+
+        let mut this = Self {
+            output_file: args.next()?.into(),
+            out_dir: args.next()?.into(),
+            package: args.next()?,
+            links: None,
+            bins: Vec::new(),
+        };
+
+        while let Some(flag) = args.next() {
+            match &*flag {
+                "--links" => this.links = Some(args.next()?),
+                "--bin" => this.bins.push(args.next()?),
+                _ => return None,
+            }
+        }
+
+        Some(this)
+    }
+}
+
+impl BuildScriptOutput {
+    fn parse(output: &str) -> Result<Self, ParseError> {
+        let mut this = Self::default();
+
+        for line in output.lines() {
+            let (is_new_syntax, instruction) =
+                if let Some(instruction) = line.strip_prefix("cargo::") {
+                    (true, instruction)
+                } else if let Some(instruction) = line.strip_prefix("cargo:") {
+                    (false, instruction)
+                } else {
+                    // Everything else is just regular output.
+                    continue;
+                };
+
+            let missing_key_value =
+                || ParseError::MissingKeyValue { line: line.to_owned() };
+
+            let (key, value) =
+                instruction.split_once('=').ok_or_else(missing_key_value)?;
+
+            let (key, value) = (key.trim(), value.trim());
+
+            match key {
+                "rustc-flags" => this.parse_rustc_flags(value, line)?,
+                "rustc-link-lib" => this.push_rustc_args("-l", value),
+                "rustc-link-search" => this.push_rustc_args("-L", value),
+                "rustc-cfg" => this.push_rustc_args("--cfg", value),
+                "rustc-check-cfg" => this.push_rustc_args("--check-cfg", value),
+                "rustc-link-arg" => {
+                    this.push_link_arg(LinkArgTarget::All, value)
+                },
+                "rustc-link-arg-cdylib" | "rustc-cdylib-link-arg" => {
+                    this.push_link_arg(LinkArgTarget::Cdylib, value)
+                },
+                "rustc-link-arg-bins" => {
+                    this.push_link_arg(LinkArgTarget::Bins, value)
+                },
+                "rustc-link-arg-bin" => {
+                    let (bin, arg) =
+                        value.split_once('=').ok_or_else(missing_key_value)?;
+                    this.push_link_arg(
+                        LinkArgTarget::SingleBin(bin.into()),
+                        arg,
+                    );
+                },
+                "rustc-link-arg-tests" => {
+                    this.push_link_arg(LinkArgTarget::Tests, value)
+                },
+                "rustc-link-arg-benches" => {
+                    this.push_link_arg(LinkArgTarget::Benches, value)
+                },
+                "rustc-link-arg-examples" => {
+                    this.push_link_arg(LinkArgTarget::Examples, value)
+                },
+                "rustc-env" => {
+                    let (name, value) =
+                        value.split_once('=').ok_or_else(missing_key_value)?;
+                    this.env.push((name.into(), value.into()));
+                },
+                "warning" => this.warnings.push(value.into()),
+                "error" => this.errors.push(value.into()),
+                // The build script is only ever run once, so there's nothing
+                // to do here.
+                "rerun-if-changed" | "rerun-if-env-changed" => {},
+                "metadata" if is_new_syntax => {
+                    let (key, value) =
+                        value.split_once('=').ok_or_else(missing_key_value)?;
+                    this.metadata.push((key.into(), value.into()));
+                },
+                // With the old syntax, unknown keys are metadata.
+                _ if !is_new_syntax => {
+                    this.metadata.push((key.into(), value.into()));
+                },
+                _ => {
+                    return Err(ParseError::UnknownInstruction {
+                        line: line.to_owned(),
+                    });
+                },
+            }
+        }
+
+        Ok(this)
+    }
+
+    /// Returns the build script's warnings and errors formatted like Cargo
+    /// does, i.e. prefixed with the package they come from.
+    fn diagnostics<'a>(
+        &'a self,
+        package: &'a str,
+    ) -> impl Iterator<Item = String> + 'a {
+        let warnings = self
+            .warnings
+            .iter()
+            .map(move |warning| format!("warning: {package}: {warning}"));
+
+        let errors = self
+            .errors
+            .iter()
+            .map(move |error| format!("error: {package}: {error}"));
+
+        warnings.chain(errors)
+    }
+
+    /// Returns the arguments to pass to `rustc` when compiling the given
+    /// target.
+    fn target_rustc_args<'a>(
+        &'a self,
+        target: Target<'a>,
+    ) -> impl Iterator<Item = String> + 'a {
+        // Like Cargo, only pass the `cfg`s and the search paths to `rustdoc`,
+        // since the doctests link against the library, which already links
+        // the native libraries.
+        let rustc_args = self
+            .rustc_args
+            .chunks_exact(2)
+            .filter(move |flag_and_value| {
+                target != Target::Doctest || flag_and_value[0] != "-l"
+            })
+            .flatten()
+            .cloned();
+
+        let link_args = self
+            .link_args
+            .iter()
+            .filter(move |(link_target, _)| link_target.applies_to(target))
+            .flat_map(|(_, arg)| ["-C".to_owned(), format!("link-arg={arg}")]);
+
+        rustc_args.chain(link_args)
+    }
+
+    /// Writes the files consumed by the derivations of the package's
+    /// targets in `out_dir`:
+    ///
+    /// - `rustc-args/<target>`: one file per target containing the arguments
+    ///   to pass to `rustc` when compiling it, one per line, to be passed to
+    ///   `rustc` as `@<path>`;
+    ///
+    /// - `env.sh`: a shell script exporting the environment variables to set
+    ///   when compiling any of the package's targets;
+    ///
+    /// - `metadata.sh`: a shell script exporting the `DEP_<LINKS>_<KEY>`
+    ///   variables for the build scripts of the package's dependents.
+    fn write_files(
+        &self,
+        out_dir: &Path,
+        links: Option<&str>,
+        bins: &[String],
+    ) -> io::Result<()> {
+        let rustc_args_dir = out_dir.join("rustc-args");
+
+        fs::create_dir_all(&rustc_args_dir)?;
+
+        let targets = [
+            Target::Lib,
+            Target::LibTest,
+            Target::Doctest,
+            Target::Test,
+            Target::Bench,
+            Target::Example,
+        ]
+        .into_iter()
+        .chain(bins.iter().map(|bin| Target::Bin(bin)));
+
+        for target in targets {
+            let mut file =
+                fs::File::create(rustc_args_dir.join(target.file_name()))?;
+            for arg in self.target_rustc_args(target) {
+                writeln!(file, "{arg}")?;
+            }
+        }
+
+        let mut env_file = fs::File::create(out_dir.join("env.sh"))?;
+
+        for (name, value) in &self.env {
+            writeln!(env_file, "export {name}={}", shell_quote(value))?;
+        }
+
+        let mut metadata_file = fs::File::create(out_dir.join("metadata.sh"))?;
+
+        // Cargo only passes the metadata to the dependents of packages that
+        // set the `links` key.
+        if let Some(links) = links {
+            for (key, value) in &self.metadata {
+                writeln!(
+                    metadata_file,
+                    "export DEP_{}_{}={}",
+                    envify(links),
+                    envify(key),
+                    shell_quote(value)
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn parse_rustc_flags(
+        &mut self,
+        value: &str,
+        line: &str,
+    ) -> Result<(), ParseError> {
+        let invalid_flags =
+            || ParseError::InvalidRustcFlags { line: line.to_owned() };
+
+        let mut flags = value.split_whitespace();
+
+        while let Some(flag) = flags.next() {
+            let (flag, value) = match flag.split_at_checked(2) {
+                Some((flag @ ("-l" | "-L"), "")) => {
+                    (flag, flags.next().ok_or_else(invalid_flags)?)
+                },
+                Some((flag @ ("-l" | "-L"), value)) => (flag, value),
+                _ => return Err(invalid_flags()),
+            };
+            self.push_rustc_args(flag, value);
+        }
+
+        Ok(())
+    }
+
+    fn push_link_arg(&mut self, target: LinkArgTarget, arg: &str) {
+        self.link_args.push((target, arg.to_owned()));
+    }
+
+    fn push_rustc_args(&mut self, flag: &str, value: &str) {
+        self.rustc_args.push(flag.to_owned());
+        self.rustc_args.push(value.to_owned());
+    }
+}
+
+impl LinkArgTarget {
+    fn applies_to(&self, target: Target) -> bool {
+        match (self, target) {
+            (_, Target::Doctest) => false,
+            (Self::All, _) => true,
+            (Self::Cdylib, Target::Lib) => true,
+            (Self::Bins, Target::Bin(_)) => true,
+            (Self::SingleBin(name), Target::Bin(bin)) => name == bin,
+            (Self::Tests, Target::Test) => true,
+            (Self::Benches, Target::Bench) => true,
+            (Self::Examples, Target::Example) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Target<'_> {
+    fn file_name(self) -> String {
+        match self {
+            Self::Lib => "lib".to_owned(),
+            Self::LibTest => "lib-test".to_owned(),
+            Self::Doctest => "doctest".to_owned(),
+            Self::Bin(name) => format!("bin-{name}"),
+            Self::Test => "test".to_owned(),
+            Self::Bench => "bench".to_owned(),
+            Self::Example => "example".to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownInstruction { line } => {
+                write!(f, "unknown build script instruction: `{line}`")
+            },
+            Self::MissingKeyValue { line } => write!(
+                f,
+                "invalid build script instruction: `{line}`, expected a \
+                 `KEY=VALUE` pair"
+            ),
+            Self::InvalidRustcFlags { line } => write!(
+                f,
+                "invalid build script instruction: `{line}`, only `-l` and \
+                 `-L` flags (followed by a value) are allowed in `rustc-flags`"
+            ),
+        }
+    }
+}
+
+impl core::error::Error for ParseError {}
+
+/// Converts the given string to the format Cargo uses for the names of
+/// environment variables.
+fn envify(s: &str) -> String {
+    s.chars()
+        .flat_map(char::to_uppercase)
+        .map(|c| if c == '-' { '_' } else { c })
+        .collect()
+}
+
+/// Quotes the given string so that it's interpreted literally by the shell.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(output: &str) -> BuildScriptOutput {
+        BuildScriptOutput::parse(output).unwrap()
+    }
+
+    fn args(target: Target, output: &BuildScriptOutput) -> Vec<String> {
+        output.target_rustc_args(target).collect()
+    }
+
+    #[test]
+    fn ignores_regular_output() {
+        let output = parse("hello\n  cargo::rustc-cfg=foo\nworld");
+        assert_eq!(output, BuildScriptOutput::default());
+    }
+
+    #[test]
+    fn old_and_new_syntax() {
+        let output = parse("cargo:rustc-cfg=foo\ncargo::rustc-cfg=bar");
+        assert_eq!(output.rustc_args, ["--cfg", "foo", "--cfg", "bar"]);
+    }
+
+    #[test]
+    fn cfg_with_quoted_value() {
+        let output = parse(r#"cargo::rustc-cfg=feature="foo bar""#);
+        assert_eq!(output.rustc_args, ["--cfg", r#"feature="foo bar""#]);
+    }
+
+    #[test]
+    fn check_cfg() {
+        let output =
+            parse(r#"cargo::rustc-check-cfg=cfg(foo, values("a", "b"))"#);
+        assert_eq!(
+            output.rustc_args,
+            ["--check-cfg", r#"cfg(foo, values("a", "b"))"#]
+        );
+    }
+
+    #[test]
+    fn link_lib_with_modifiers() {
+        let output = parse("cargo::rustc-link-lib=static:+whole-archive=foo");
+        assert_eq!(output.rustc_args, ["-l", "static:+whole-archive=foo"]);
+    }
+
+    #[test]
+    fn link_search_with_spaces() {
+        let output = parse("cargo::rustc-link-search=native=/a dir/lib");
+        assert_eq!(output.rustc_args, ["-L", "native=/a dir/lib"]);
+    }
+
+    #[test]
+    fn rustc_flags() {
+        let output = parse("cargo::rustc-flags=-lfoo -L /bar  -l baz");
+        assert_eq!(output.rustc_args, ["-l", "foo", "-L", "/bar", "-l", "baz"]);
+    }
+
+    #[test]
+    fn doctest_args() {
+        let output = parse(
+            &[
+                "cargo::rustc-cfg=foo",
+                "cargo::rustc-check-cfg=cfg(foo)",
+                "cargo::rustc-link-lib=bar",
+                "cargo::rustc-link-search=/baz",
+                "cargo::rustc-link-arg=-qux",
+            ]
+            .join("\n"),
+        );
+
+        assert_eq!(
+            args(Target::Doctest, &output),
+            ["--cfg", "foo", "--check-cfg", "cfg(foo)", "-L", "/baz"]
+        );
+    }
+
+    #[test]
+    fn rustc_flags_rejects_other_flags() {
+        let err = BuildScriptOutput::parse("cargo::rustc-flags=-C lto");
+        assert!(matches!(err, Err(ParseError::InvalidRustcFlags { .. })));
+
+        let err = BuildScriptOutput::parse("cargo::rustc-flags=-l");
+        assert!(matches!(err, Err(ParseError::InvalidRustcFlags { .. })));
+    }
+
+    #[test]
+    fn link_args_per_target() {
+        let output = parse(
+            &[
+                "cargo::rustc-link-arg=-all",
+                "cargo::rustc-link-arg-cdylib=-cdylib",
+                "cargo:rustc-cdylib-link-arg=-cdylib-old",
+                "cargo::rustc-link-arg-bins=-bins",
+                "cargo::rustc-link-arg-bin=foo=-foo=bar",
+                "cargo::rustc-link-arg-tests=-tests",
+                "cargo::rustc-link-arg-benches=-benches",
+                "cargo::rustc-link-arg-examples=-examples",
+            ]
+            .join("\n"),
+        );
+
+        let link_args = |target| {
+            args(target, &output)
+                .into_iter()
+                .filter(|arg| arg != "-C")
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            link_args(Target::Lib),
+            ["link-arg=-all", "link-arg=-cdylib", "link-arg=-cdylib-old"]
+        );
+        assert_eq!(link_args(Target::LibTest), ["link-arg=-all"]);
+        assert!(link_args(Target::Doctest).is_empty());
+        assert_eq!(
+            link_args(Target::Bin("foo")),
+            ["link-arg=-all", "link-arg=-bins", "link-arg=-foo=bar"]
+        );
+        assert_eq!(
+            link_args(Target::Bin("baz")),
+            ["link-arg=-all", "link-arg=-bins"]
+        );
+        assert_eq!(
+            link_args(Target::Test),
+            ["link-arg=-all", "link-arg=-tests"]
+        );
+        assert_eq!(
+            link_args(Target::Bench),
+            ["link-arg=-all", "link-arg=-benches"]
+        );
+        assert_eq!(
+            link_args(Target::Example),
+            ["link-arg=-all", "link-arg=-examples"]
+        );
+    }
+
+    #[test]
+    fn link_arg_bin_without_flag() {
+        let err = BuildScriptOutput::parse("cargo::rustc-link-arg-bin=foo");
+        assert!(matches!(err, Err(ParseError::MissingKeyValue { .. })));
+    }
+
+    #[test]
+    fn rustc_env() {
+        let output = parse("cargo::rustc-env=FOO=bar = 'baz'");
+        assert_eq!(output.env, [("FOO".to_owned(), "bar = 'baz'".to_owned())]);
+    }
+
+    #[test]
+    fn warnings_and_errors() {
+        let output =
+            parse("cargo:warning=old\ncargo::warning=new\ncargo::error=oh no");
+        assert_eq!(output.warnings, ["old", "new"]);
+        assert_eq!(output.errors, ["oh no"]);
+    }
+
+    #[test]
+    fn diagnostics() {
+        let output = parse("cargo::error=oh no\ncargo::warning=careful");
+        assert_eq!(
+            output.diagnostics("foo@0.1.0").collect::<Vec<_>>(),
+            ["warning: foo@0.1.0: careful", "error: foo@0.1.0: oh no",]
+        );
+    }
+
+    #[test]
+    fn metadata() {
+        let output = parse("cargo::metadata=include=/foo\ncargo:root=/bar");
+        assert_eq!(
+            output.metadata,
+            [
+                ("include".to_owned(), "/foo".to_owned()),
+                ("root".to_owned(), "/bar".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_new_syntax_instruction() {
+        let err = BuildScriptOutput::parse("cargo::foo=bar");
+        assert!(matches!(err, Err(ParseError::UnknownInstruction { .. })));
+    }
+
+    #[test]
+    fn rerun_if_is_ignored() {
+        let output = parse(
+            "cargo::rerun-if-changed=build.rs\ncargo:rerun-if-env-changed=FOO",
+        );
+        assert_eq!(output, BuildScriptOutput::default());
+    }
+
+    #[test]
+    fn envify_links() {
+        assert_eq!(envify("foo-sys"), "FOO_SYS");
+    }
+
+    #[test]
+    fn shell_quote_single_quotes() {
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+    }
+}
@@ -0,0 +1,71 @@
+use core::result::Result;
+use std::collections::HashMap;
+
+use compact_str::CompactString;
+use nix_bindings::prelude::*;
+
+use crate::build_package::{
+    BuildPackageArgs,
+    BuildPackageError,
+    CrateDerivations,
+    build_graph,
+};
+use crate::resolve_build_graph::resolve_workspace_members;
+
+/// Builds all the members of a Cargo workspace (or a subset of them) at once,
+/// returning an attribute set mapping each member's name to its `lib`, `bin`
+/// and `buildScript` derivations.
+///
+/// The workspace is only vendored and resolved once, and crates that are
+/// shared by more than one member are only built once.
+#[derive(nix_bindings::PrimOp)]
+pub(crate) struct BuildWorkspace;
+
+#[derive(nix_bindings::Args, nix_bindings::TryFromValue)]
+#[args(flatten, name = "args")]
+#[try_from(rename_all = camelCase)]
+pub(crate) struct BuildWorkspaceArgs<'a> {
+    /// The same arguments as `buildPackage`, except for `package`, which is
+    /// ignored in favor of [`members`](Self::members).
+    #[try_from(flatten)]
+    package_args: BuildPackageArgs<'a>,
+
+    /// The names of the workspace members to build, or `None` to build all of
+    /// them.
+    #[try_from(default)]
+    members: Option<Vec<CompactString>>,
+}
+
+impl Function for BuildWorkspace {
+    type Args<'a> = BuildWorkspaceArgs<'a>;
+
+    fn call<'a: 'a>(
+        args: Self::Args<'a>,
+        ctx: &mut Context,
+    ) -> Result<HashMap<CompactString, CrateDerivations>, BuildPackageError>
+    {
+        let BuildWorkspaceArgs { package_args, members } = args;
+
+        build_graph(
+            package_args,
+            false,
+            |args, ctx| {
+                resolve_workspace_members(&args, members.as_deref(), ctx)
+            },
+            ctx,
+            |build_graph, _global_args, node_derivations, _ctx| {
+                Ok(build_graph
+                    .root_idxs
+                    .iter()
+                    .map(|&root_idx| {
+                        let name = build_graph.nodes[root_idx]
+                            .package_attrs
+                            .name
+                            .clone();
+                        (name, node_derivations[root_idx])
+                    })
+                    .collect())
+            },
+        )
+    }
+}
@@ -0,0 +1,537 @@
+use std::path::{Path, PathBuf};
+use std::{env, fs, io};
+
+use cargo_platform::{Cfg, Platform};
+use cargo_util_schemas::manifest::TomlProfiles;
+use compact_str::{CompactString, ToCompactString};
+
+use crate::build_graph::ConfigEnvVar;
+
+/// The Cargo configuration, read without the `cargo` crate.
+///
+/// Only the keys that affect how the build graph is resolved or how the
+/// dependencies are vendored are supported, i.e. `[env]`, `[profile]`,
+/// `[source]`, `build.rustc`, `build.rustflags`, and the `rustflags` and
+/// `linker` of the `[target]` tables.
+pub(crate) struct CargoConfig {
+    /// The merged contents of all the configuration files, with the relative
+    /// paths they contain already resolved.
+    table: toml::Table,
+}
+
+/// A source on the local filesystem that a registry is replaced with via the
+/// `replace-with` key of its `[source]` table.
+pub(crate) enum ReplacementSource {
+    /// A `directory` source, containing the unpacked packages.
+    Directory(PathBuf),
+
+    /// A `local-registry` source, containing the packages' `.crate` files.
+    LocalRegistry(PathBuf),
+}
+
+/// The type of error that can occur when reading the Cargo configuration.
+#[derive(Debug, derive_more::Display, cauchy::Error)]
+pub(crate) enum CargoConfigError {
+    #[display("expected {key:?} in the Cargo config to be {expected}")]
+    InvalidType { key: CompactString, expected: &'static str },
+
+    #[display("failed to parse the Cargo config at {path:?}: {err}")]
+    Parse { path: PathBuf, err: toml::de::Error },
+
+    #[display("failed to read the Cargo config at {path:?}: {err}")]
+    Read { path: PathBuf, err: io::Error },
+
+    #[display("the `replace-with` keys of the Cargo config form a cycle")]
+    ReplacementCycle,
+}
+
+impl CargoConfig {
+    /// Reads the configuration files that Cargo would read if it was run from
+    /// the given directory, i.e. the `.cargo/config.toml` files in it and in
+    /// all its ancestors, with the closest ones taking precedence.
+    pub(crate) fn new(cwd: &Path) -> Result<Self, CargoConfigError> {
+        let mut table = toml::Table::new();
+
+        let ancestors = cwd.ancestors().collect::<Vec<_>>();
+
+        for dir in ancestors.into_iter().rev() {
+            let Some(mut file_table) = read_config_file(dir)? else {
+                continue;
+            };
+            resolve_relative_paths(&mut file_table, dir);
+            merge_tables(&mut table, file_table);
+        }
+
+        Ok(Self { table })
+    }
+
+    /// Returns the environment variables set by the `[env]` table, sorted by
+    /// name.
+    pub(crate) fn env(&self) -> Result<Vec<ConfigEnvVar>, CargoConfigError> {
+        let Some(env) = self.get(&["env"]) else { return Ok(Vec::new()) };
+
+        let env =
+            env.as_table().ok_or_else(|| invalid_type("env", "a table"))?;
+
+        let mut config_env = env
+            .iter()
+            .map(|(name, value)| {
+                let key = format!("env.{name}");
+                let (value, force) = match value {
+                    toml::Value::String(value) => (value, false),
+                    toml::Value::Table(table) => (
+                        table
+                            .get("value")
+                            .and_then(toml::Value::as_str)
+                            .ok_or_else(|| invalid_type(&key, "a string"))?,
+                        table
+                            .get("force")
+                            .and_then(toml::Value::as_bool)
+                            .unwrap_or(false),
+                    ),
+                    _ => return Err(invalid_type(&key, "a string or a table")),
+                };
+                Ok(ConfigEnvVar {
+                    name: name.into(),
+                    value: value.into(),
+                    force,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        config_env.sort_unstable_by(|lhs, rhs| lhs.name.cmp(&rhs.name));
+
+        Ok(config_env)
+    }
+
+    /// Returns the linker set for the given target triple, either via the
+    /// `CARGO_TARGET_<triple>_LINKER` environment variable or the
+    /// `target.<triple>.linker` key.
+    pub(crate) fn linker(
+        &self,
+        triple: &str,
+    ) -> Result<Option<CompactString>, CargoConfigError> {
+        if let Ok(linker) = env::var(target_env_var(triple, "LINKER")) {
+            return Ok(Some(linker.into()));
+        }
+
+        self.get(&["target", triple, "linker"])
+            .map(|linker| {
+                linker.as_str().map(Into::into).ok_or_else(|| {
+                    invalid_type(&format!("target.{triple}.linker"), "a string")
+                })
+            })
+            .transpose()
+    }
+
+    /// Returns the `[profile]` tables.
+    pub(crate) fn profiles(
+        &self,
+    ) -> Result<Option<TomlProfiles>, CargoConfigError> {
+        self.get(&["profile"])
+            .cloned()
+            .map(|profiles| {
+                profiles
+                    .try_into::<TomlProfiles>()
+                    .map_err(|_| invalid_type("profile", "a table of profiles"))
+            })
+            .transpose()
+    }
+
+    /// Returns the path to (or the name of) the `rustc` to use.
+    pub(crate) fn rustc(&self) -> PathBuf {
+        env::var_os("RUSTC")
+            .map(PathBuf::from)
+            .or_else(|| {
+                self.get(&["build", "rustc"])
+                    .and_then(toml::Value::as_str)
+                    .map(PathBuf::from)
+            })
+            .unwrap_or_else(|| PathBuf::from("rustc"))
+    }
+
+    /// Returns the flags to pass to `rustc` when compiling for the given
+    /// target triple, in the same order of precedence as Cargo:
+    ///
+    /// 1. the `CARGO_ENCODED_RUSTFLAGS` environment variable;
+    /// 2. the `RUSTFLAGS` environment variable;
+    /// 3. the `rustflags` of all the `[target]` tables matching the triple
+    ///    (either by name or by one of the given `cfg`s);
+    /// 4. `build.rustflags`.
+    ///
+    /// The first one that's set is the only one that's used.
+    pub(crate) fn rustflags(
+        &self,
+        triple: &str,
+        cfgs: &[Cfg],
+    ) -> Result<Vec<String>, CargoConfigError> {
+        if let Ok(flags) = env::var("CARGO_ENCODED_RUSTFLAGS") {
+            return Ok(flags
+                .split('\x1f')
+                .filter(|flag| !flag.is_empty())
+                .map(Into::into)
+                .collect());
+        }
+
+        if let Ok(flags) = env::var("RUSTFLAGS") {
+            return Ok(flags.split_whitespace().map(Into::into).collect());
+        }
+
+        let mut target_flags = Vec::new();
+
+        if let Ok(flags) = env::var(target_env_var(triple, "RUSTFLAGS")) {
+            target_flags.extend(flags.split_whitespace().map(Into::into));
+        }
+
+        let targets = self.get(&["target"]).and_then(toml::Value::as_table);
+
+        for (key, target) in targets.into_iter().flatten() {
+            let Ok(platform) = key.parse::<Platform>() else { continue };
+            if !platform.matches(triple, cfgs) {
+                continue;
+            }
+            if let Some(flags) = target.get("rustflags") {
+                let key = format!("target.{key}.rustflags");
+                target_flags.extend(parse_flags(flags, &key)?);
+            }
+        }
+
+        if !target_flags.is_empty() {
+            return Ok(target_flags);
+        }
+
+        if let Ok(flags) = env::var("CARGO_BUILD_RUSTFLAGS") {
+            return Ok(flags.split_whitespace().map(Into::into).collect());
+        }
+
+        self.get(&["build", "rustflags"])
+            .map(|flags| parse_flags(flags, "build.rustflags"))
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    /// Returns the `local-registry` or `directory` source that the registry
+    /// with the given index URL (or crates.io, if `None`) is replaced with,
+    /// following the chain of `replace-with` keys like Cargo.
+    ///
+    /// Replacements with any other kind of source are ignored, in which case
+    /// the packages are still fetched from the original registry.
+    pub(crate) fn source_replacement(
+        &self,
+        registry_url: Option<&str>,
+    ) -> Result<Option<ReplacementSource>, CargoConfigError> {
+        let Some(sources) = self.get(&["source"]) else { return Ok(None) };
+
+        let sources = sources
+            .as_table()
+            .ok_or_else(|| invalid_type("source", "a table"))?;
+
+        let source_name = match registry_url {
+            Some(registry_url) => sources.iter().find_map(|(name, source)| {
+                let url = source.get("registry")?.as_str()?;
+                let url = url.strip_prefix("sparse+").unwrap_or(url);
+                (url.trim_end_matches('/')
+                    == registry_url.trim_end_matches('/'))
+                .then_some(name.as_str())
+            }),
+            None => Some("crates-io"),
+        };
+
+        let Some(mut source_name) = source_name else { return Ok(None) };
+
+        // Every replacement has to lead to a different source, so the chain
+        // can't be longer than the number of sources.
+        for _ in 0..=sources.len() {
+            let Some(source) = sources.get(source_name) else {
+                return Ok(None);
+            };
+
+            let key = |key: &str| format!("source.{source_name}.{key}");
+
+            let path = |path: &toml::Value, key_name: &str| {
+                path.as_str()
+                    .map(PathBuf::from)
+                    .ok_or_else(|| invalid_type(&key(key_name), "a string"))
+            };
+
+            if let Some(replace_with) = source.get("replace-with") {
+                source_name = replace_with.as_str().ok_or_else(|| {
+                    invalid_type(&key("replace-with"), "a string")
+                })?;
+            } else if let Some(root) = source.get("local-registry") {
+                return path(root, "local-registry")
+                    .map(|root| Some(ReplacementSource::LocalRegistry(root)));
+            } else if let Some(root) = source.get("directory") {
+                return path(root, "directory")
+                    .map(|root| Some(ReplacementSource::Directory(root)));
+            } else {
+                return Ok(None);
+            }
+        }
+
+        Err(CargoConfigError::ReplacementCycle)
+    }
+
+    /// Returns the value at the given path of keys, if any.
+    fn get(&self, path: &[&str]) -> Option<&toml::Value> {
+        let (last, parents) = path.split_last()?;
+
+        let mut table = &self.table;
+
+        for key in parents {
+            table = table.get(*key)?.as_table()?;
+        }
+
+        table.get(*last)
+    }
+}
+
+/// Reads the configuration file in the `.cargo` directory under the given
+/// one, if there is one.
+fn read_config_file(
+    dir: &Path,
+) -> Result<Option<toml::Table>, CargoConfigError> {
+    // Like Cargo, prefer the legacy file name if both files exist.
+    let Some(path) = ["config", "config.toml"]
+        .into_iter()
+        .map(|file_name| dir.join(".cargo").join(file_name))
+        .find(|path| path.is_file())
+    else {
+        return Ok(None);
+    };
+
+    let contents = fs::read_to_string(&path)
+        .map_err(|err| CargoConfigError::Read { path: path.clone(), err })?;
+
+    toml::from_str::<toml::Table>(&contents)
+        .map(Some)
+        .map_err(|err| CargoConfigError::Parse { path, err })
+}
+
+/// Makes the relative paths in the given configuration file absolute, using
+/// the directory containing its `.cargo` directory as the base.
+///
+/// These are the `[env]` values with `relative = true`, the roots of the
+/// `local-registry` and `directory` sources, and the programs (i.e.
+/// `build.rustc` and the linkers) given as paths instead of names.
+fn resolve_relative_paths(table: &mut toml::Table, base: &Path) {
+    let resolve_program = |program: &mut toml::Value| {
+        if let toml::Value::String(program) = program
+            && program.contains('/')
+        {
+            *program = base.join(&*program).display().to_string();
+        }
+    };
+
+    if let Some(rustc) = table
+        .get_mut("build")
+        .and_then(toml::Value::as_table_mut)
+        .and_then(|build| build.get_mut("rustc"))
+    {
+        resolve_program(rustc);
+    }
+
+    let targets = table
+        .get_mut("target")
+        .and_then(toml::Value::as_table_mut)
+        .into_iter()
+        .flat_map(|targets| targets.values_mut())
+        .filter_map(toml::Value::as_table_mut);
+
+    for target in targets {
+        if let Some(linker) = target.get_mut("linker") {
+            resolve_program(linker);
+        }
+    }
+
+    let sources = table
+        .get_mut("source")
+        .and_then(toml::Value::as_table_mut)
+        .into_iter()
+        .flat_map(|sources| sources.values_mut())
+        .filter_map(toml::Value::as_table_mut);
+
+    for source in sources {
+        for key in ["local-registry", "directory"] {
+            if let Some(toml::Value::String(root)) = source.get_mut(key) {
+                *root = base.join(&*root).display().to_string();
+            }
+        }
+    }
+
+    let env_tables = table
+        .get_mut("env")
+        .and_then(toml::Value::as_table_mut)
+        .into_iter()
+        .flat_map(|env| env.values_mut())
+        .filter_map(toml::Value::as_table_mut);
+
+    for env_table in env_tables {
+        let is_relative = env_table
+            .remove("relative")
+            .and_then(|relative| relative.as_bool())
+            .unwrap_or(false);
+
+        if let Some(toml::Value::String(value)) = env_table.get_mut("value")
+            && is_relative
+        {
+            *value = base.join(&*value).display().to_string();
+        }
+    }
+}
+
+/// Merges the `higher` table into the `lower` one, with the same semantics
+/// as Cargo: tables are merged recursively, arrays are concatenated (with the
+/// elements of the higher-precedence one coming last), and any other value
+/// is overwritten.
+fn merge_tables(lower: &mut toml::Table, higher: toml::Table) {
+    for (key, higher_value) in higher {
+        match (lower.get_mut(&key), higher_value) {
+            (
+                Some(toml::Value::Table(lower_table)),
+                toml::Value::Table(higher_table),
+            ) => merge_tables(lower_table, higher_table),
+            (
+                Some(toml::Value::Array(lower_array)),
+                toml::Value::Array(higher_array),
+            ) => lower_array.extend(higher_array),
+            (_, higher_value) => {
+                lower.insert(key, higher_value);
+            },
+        }
+    }
+}
+
+/// Parses a list of flags, which can either be a string of whitespace
+/// separated flags or an array of strings.
+fn parse_flags(
+    flags: &toml::Value,
+    key: &str,
+) -> Result<Vec<String>, CargoConfigError> {
+    let invalid = || invalid_type(key, "a string or an array of strings");
+
+    match flags {
+        toml::Value::String(flags) => {
+            Ok(flags.split_whitespace().map(Into::into).collect())
+        },
+        toml::Value::Array(flags) => flags
+            .iter()
+            .map(|flag| flag.as_str().map(Into::into).ok_or_else(invalid))
+            .collect(),
+        _ => Err(invalid()),
+    }
+}
+
+/// Returns the name of the environment variable overriding the given key of
+/// the `target.<triple>` table.
+fn target_env_var(triple: &str, key: &str) -> String {
+    let triple = triple.to_uppercase().replace(['-', '.'], "_");
+    format!("CARGO_TARGET_{triple}_{key}")
+}
+
+fn invalid_type(key: &str, expected: &'static str) -> CargoConfigError {
+    CargoConfigError::InvalidType { key: key.to_compact_string(), expected }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(contents: &str) -> CargoConfig {
+        let mut table = toml::from_str::<toml::Table>(contents).unwrap();
+        resolve_relative_paths(&mut table, Path::new("/project"));
+        CargoConfig { table }
+    }
+
+    fn replacement_root(
+        config: &CargoConfig,
+        registry_url: Option<&str>,
+    ) -> Option<(&'static str, PathBuf)> {
+        config.source_replacement(registry_url).unwrap().map(|source| {
+            match source {
+                ReplacementSource::Directory(root) => ("directory", root),
+                ReplacementSource::LocalRegistry(root) => {
+                    ("local-registry", root)
+                },
+            }
+        })
+    }
+
+    #[test]
+    fn crates_io_replaced_with_local_registry() {
+        let config = config(
+            r#"
+[source.crates-io]
+replace-with = "mirror"
+
+[source.mirror]
+replace-with = "local"
+
+[source.local]
+local-registry = "registry"
+"#,
+        );
+
+        assert_eq!(
+            replacement_root(&config, None),
+            Some(("local-registry", PathBuf::from("/project/registry")))
+        );
+    }
+
+    #[test]
+    fn custom_registry_replaced_with_directory() {
+        let config = config(
+            r#"
+[source.my-registry]
+registry = "sparse+https://registry.example.com/index/"
+replace-with = "vendored"
+
+[source.vendored]
+directory = "/vendor"
+"#,
+        );
+
+        assert_eq!(
+            replacement_root(
+                &config,
+                Some("https://registry.example.com/index/")
+            ),
+            Some(("directory", PathBuf::from("/vendor")))
+        );
+
+        assert_eq!(replacement_root(&config, None), None);
+    }
+
+    #[test]
+    fn remote_replacements_are_ignored() {
+        let config = config(
+            r#"
+[source.crates-io]
+replace-with = "mirror"
+
+[source.mirror]
+registry = "https://mirror.example.com/index"
+"#,
+        );
+
+        assert_eq!(replacement_root(&config, None), None);
+    }
+
+    #[test]
+    fn replacement_cycle() {
+        let config = config(
+            r#"
+[source.crates-io]
+replace-with = "a"
+
+[source.a]
+replace-with = "crates-io"
+"#,
+        );
+
+        assert!(matches!(
+            config.source_replacement(None),
+            Err(CargoConfigError::ReplacementCycle)
+        ));
+    }
+}
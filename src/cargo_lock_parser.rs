@@ -1,11 +1,23 @@
 use core::fmt;
 use std::borrow::Cow;
+use std::path::PathBuf;
 
 use compact_str::CompactString;
 
-const CRATES_IO_REGISTRY_URL: &str = cargo::sources::CRATES_IO_INDEX;
+const CRATES_IO_REGISTRY_URL: &str =
+    "https://github.com/rust-lang/crates.io-index";
 const CRATES_IO_SPARSE_URL: &str = "https://index.crates.io/";
 
+/// The headers an entry can start with, up to and including the opening quote
+/// of the `name` field.
+///
+/// Entries under `[[patch.unused]]` are `[patch]`es that didn't match any
+/// dependency. They have the same fields as `[[package]]` entries, and we
+/// still yield them because Cargo needs their sources to be available to
+/// check that they're unused.
+const ENTRY_HEADERS: [&str; 2] =
+    ["[[package]]\nname = \"", "[[patch.unused]]\nname = \""];
+
 /// A simple, no-allocation parser for the subset of the Cargo.lock format that
 /// we need to vendor dependencies.
 pub(crate) struct CargoLockParser<'lock> {
@@ -31,7 +43,8 @@ pub(crate) struct CargoLockParser<'lock> {
 /// - `source`: optional, not present for path dependencies;
 ///
 /// - `checksum`: optional, only present for dependencies from registries (like
-///   crates.io);
+///   crates.io) and, if the vendored packages have one, from directory
+///   sources;
 ///
 /// - `dependencies` OR `replace`: both of them are optional, and they're
 ///   mutually exclusive. `replace` is present when using the `[replace]`
@@ -58,19 +71,61 @@ pub(crate) struct CargoLockParser<'lock> {
 ///   ... other fields ...
 ///   ```
 ///
-///   Only the replacement package is actually compiled, so we skip entries
-///   with a `replace` field. We also always skip parsing the `dependencies`
-///   field since we don't need it for vendoring.
+///   Only the replacement package is actually compiled, so entries with a
+///   `replace` field (which never have a checksum) shouldn't be vendored.
+///   The elements of the `dependencies` field are only parsed on demand,
+///   since they're not needed for vendoring.
+///
+/// Packages coming from a `[patch]` section are regular `[[package]]` entries
+/// whose source is the patch's source. Patches that aren't used by any
+/// dependency are listed under `[[patch.unused]]` instead, and we parse them
+/// as if they were `[[package]]` entries (see
+/// [`is_unused_patch`](Self::is_unused_patch)).
 pub(crate) struct PackageEntry<'lock> {
     pub(crate) name: &'lock str,
     pub(crate) version: &'lock str,
     pub(crate) source: Option<PackageSource<'lock>>,
+    /// The unparsed [`source`](Self::source), which is how the other entries
+    /// refer to it in their `dependencies`.
+    #[cfg_attr(not(feature = "forbid-cargo"), expect(dead_code))]
+    pub(crate) raw_source: Option<&'lock str>,
+    #[cfg_attr(not(feature = "forbid-cargo"), expect(dead_code))]
+    pub(crate) dependencies: PackageDependencies<'lock>,
+    /// The package replacing this one, in the same format as the elements of
+    /// [`dependencies`](Self::dependencies).
+    pub(crate) replace: Option<&'lock str>,
+    /// Whether the entry is listed under `[[patch.unused]]`.
+    #[cfg_attr(not(feature = "forbid-cargo"), expect(dead_code))]
+    pub(crate) is_unused_patch: bool,
+}
+
+/// The unparsed contents of the `dependencies` array of a [`PackageEntry`].
+#[derive(Copy, Clone, Debug, Default)]
+pub(crate) struct PackageDependencies<'lock> {
+    #[cfg_attr(not(feature = "forbid-cargo"), expect(dead_code))]
+    src: &'lock str,
+}
+
+/// An element of the `dependencies` array of a [`PackageEntry`], which
+/// identifies another entry in the `Cargo.lock`.
+///
+/// Cargo only writes as many fields as needed to make the reference
+/// unambiguous, so the version is omitted if there's a single package with
+/// that name, and the source is omitted if there's a single package with that
+/// name and version.
+#[cfg(feature = "forbid-cargo")]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct PackageDependency<'lock> {
+    pub(crate) name: &'lock str,
+    pub(crate) version: Option<&'lock str>,
+    pub(crate) source: Option<&'lock str>,
 }
 
 #[derive(Copy, Clone, Debug)]
 pub(crate) enum PackageSource<'lock> {
     Registry(RegistrySource<'lock>),
     Git(GitSource<'lock>),
+    Directory(DirectorySource<'lock>),
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -89,8 +144,12 @@ pub(crate) enum RegistryKind<'lock> {
     /// For other registries we need to store both the protocol and the URL to
     /// know where to get the `config.json` file containing the download URL
     /// template.
-    #[expect(unused, reason = "we can't handle custom registries yet")]
     Other { protocol: RegistryProtocol, url: &'lock str },
+
+    /// A `local-registry` source, i.e. a directory on the local filesystem
+    /// containing an index and the registry's `.crate` files. The URL is a
+    /// `file://` URL.
+    Local { url: &'lock str },
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -102,6 +161,17 @@ pub(crate) enum RegistryProtocol {
     Sparse,
 }
 
+/// A `directory` source, i.e. a directory on the local filesystem containing
+/// unpacked crates, like the one created by `cargo vendor`.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct DirectorySource<'lock> {
+    /// The `file://` URL of the directory.
+    pub(crate) url: &'lock str,
+
+    /// The package's checksum, if the `Cargo.lock` has one.
+    pub(crate) checksum: Option<&'lock str>,
+}
+
 #[derive(Copy, Clone, Debug)]
 pub(crate) struct GitSource<'lock> {
     pub(crate) url: &'lock str,
@@ -128,7 +198,7 @@ pub(crate) enum GitSourceRef<'lock> {
 pub(crate) enum CargoLockParseError {
     #[display(
         "invalid source protocol: {protocol:?}, expected one of 'registry+', \
-         'sparse+' or 'git+'"
+         'sparse+', 'local-registry+', 'directory+' or 'git+'"
     )]
     InvalidSourceProtocol { protocol: CompactString },
 
@@ -138,6 +208,9 @@ pub(crate) enum CargoLockParseError {
     )]
     InvalidGitSourceRefKey { key: CompactString },
 
+    #[display("missing closing bracket for field {field_name:?}")]
+    MissingClosingBracket { field_name: &'static str },
+
     #[display("missing closing quote for field {field_name:?}")]
     MissingClosingQuote { field_name: &'static str },
 
@@ -183,19 +256,31 @@ impl<'lock> CargoLockParser<'lock> {
         &mut self,
     ) -> Result<Option<PackageEntry<'lock>>, CargoLockParseError> {
         // Start with a dummy entry, we'll fill its fields as we parse them.
-        let mut entry = PackageEntry { name: "", version: "", source: None };
+        let mut entry = PackageEntry {
+            name: "",
+            version: "",
+            source: None,
+            raw_source: None,
+            dependencies: PackageDependencies::default(),
+            replace: None,
+            is_unused_patch: false,
+        };
 
         loop {
             match self.cursor_position {
                 CursorPosition::StartOfEntry => {
-                    let expected = "[[package]]\nname = \"";
-                    if !self.src_after_cursor().starts_with(expected) {
+                    let Some(header_idx) =
+                        ENTRY_HEADERS.iter().position(|header| {
+                            self.src_after_cursor().starts_with(header)
+                        })
+                    else {
                         return Err(CargoLockParseError::MissingField {
                             field_name: "name",
                             after: "[[package]]",
                         });
-                    }
-                    self.cursor_offset += expected.len();
+                    };
+                    self.cursor_offset += ENTRY_HEADERS[header_idx].len();
+                    entry.is_unused_patch = header_idx == 1;
                     let name_start = self.cursor_offset;
                     let Some(name_end) = self.search_from_cursor(b'"') else {
                         return Err(CargoLockParseError::MissingClosingQuote {
@@ -243,18 +328,32 @@ impl<'lock> CargoLockParser<'lock> {
                     )?;
                     let source = &self.src[source_start..source_end];
                     entry.source = Some(PackageSource::parse(source)?);
+                    entry.raw_source = Some(source);
                     self.cursor_offset = source_end + 1;
                     self.cursor_position = CursorPosition::EndOfSource;
                 },
 
                 CursorPosition::EndOfSource => {
-                    let Some(PackageSource::Registry(src)) = &mut entry.source
-                    else {
-                        self.cursor_position = CursorPosition::EndOfChecksum;
-                        continue;
+                    let is_checksum_required = match entry.source {
+                        Some(PackageSource::Registry(_)) => true,
+                        Some(PackageSource::Directory(_)) => false,
+                        _ => {
+                            self.cursor_position =
+                                CursorPosition::EndOfChecksum;
+                            continue;
+                        },
                     };
                     let expected = "\nchecksum = \"";
                     if !self.src_after_cursor().starts_with(expected) {
+                        // Replaced packages don't have a checksum, and
+                        // they're skipped anyway.
+                        let is_replaced =
+                            self.src_after_cursor().starts_with("\nreplace = ");
+                        if !is_checksum_required || is_replaced {
+                            self.cursor_position =
+                                CursorPosition::EndOfChecksum;
+                            continue;
+                        }
                         return Err(CargoLockParseError::MissingField {
                             field_name: "checksum",
                             after: "source",
@@ -267,18 +366,47 @@ impl<'lock> CargoLockParser<'lock> {
                             field_name: "checksum",
                         },
                     )?;
-                    src.checksum = &self.src[checksum_start..checksum_end];
+                    let checksum = &self.src[checksum_start..checksum_end];
+                    match &mut entry.source {
+                        Some(PackageSource::Registry(src)) => {
+                            src.checksum = checksum;
+                        },
+                        Some(PackageSource::Directory(src)) => {
+                            src.checksum = Some(checksum);
+                        },
+                        _ => unreachable!("checked above"),
+                    }
                     self.cursor_offset = checksum_end + 1;
                     self.cursor_position = CursorPosition::EndOfChecksum;
                 },
 
                 CursorPosition::EndOfChecksum => {
-                    if !self.src_after_cursor().starts_with('\n') {
-                        self.cursor_position = CursorPosition::EndOfFile;
-                        break;
+                    let expected = "\ndependencies = [";
+                    if self.src_after_cursor().starts_with(expected) {
+                        self.cursor_offset += expected.len();
+                        let deps_start = self.cursor_offset;
+                        let deps_end = self.search_from_cursor("\n]").ok_or(
+                            CargoLockParseError::MissingClosingBracket {
+                                field_name: "dependencies",
+                            },
+                        )?;
+                        entry.dependencies = PackageDependencies {
+                            src: &self.src[deps_start..deps_end],
+                        };
+                        self.cursor_offset = deps_end + 2;
+                    }
+                    if self.src_after_cursor().starts_with("\nreplace = \"") {
+                        self.cursor_offset += "\nreplace = \"".len();
+                        let replace_start = self.cursor_offset;
+                        let replace_end = self.search_from_cursor(b'"').ok_or(
+                            CargoLockParseError::MissingClosingQuote {
+                                field_name: "replace",
+                            },
+                        )?;
+                        entry.replace =
+                            Some(&self.src[replace_start..replace_end]);
+                        self.cursor_offset = replace_end + 1;
                     }
-                    self.cursor_offset += 1;
-                    let skip_entry = self.src_after_cursor().starts_with('r');
                     match self.search_from_cursor("[[") {
                         Some(offset) => {
                             self.cursor_offset = offset;
@@ -288,9 +416,7 @@ impl<'lock> CargoLockParser<'lock> {
                             self.cursor_position = CursorPosition::EndOfFile;
                         },
                     }
-                    if !skip_entry {
-                        break;
-                    }
+                    break;
                 },
 
                 CursorPosition::EndOfFile => return Ok(None),
@@ -321,6 +447,41 @@ impl<'lock> CargoLockParser<'lock> {
     }
 }
 
+#[cfg(feature = "forbid-cargo")]
+impl<'lock> PackageDependencies<'lock> {
+    /// Returns an iterator over the elements of the `dependencies` array.
+    pub(crate) fn iter(self) -> impl Iterator<Item = PackageDependency<'lock>> {
+        self.src.lines().filter_map(|line| {
+            let line = line.trim().trim_end_matches(',');
+            line.strip_prefix('"')?
+                .strip_suffix('"')
+                .map(PackageDependency::parse)
+        })
+    }
+}
+
+#[cfg(feature = "forbid-cargo")]
+impl<'lock> PackageDependency<'lock> {
+    /// Parses a string in the `name [version] [(source)]` format.
+    pub(crate) fn parse(src: &'lock str) -> Self {
+        let (name, rest) = match src.split_once(' ') {
+            Some((name, rest)) => (name, Some(rest)),
+            None => (src, None),
+        };
+
+        let (version, source) = match rest.map(|rest| rest.split_once(' ')) {
+            Some(Some((version, source))) => (
+                Some(version),
+                source.strip_prefix('(').and_then(|s| s.strip_suffix(')')),
+            ),
+            Some(None) => (rest, None),
+            None => (None, None),
+        };
+
+        Self { name, version, source }
+    }
+}
+
 impl<'lock> PackageSource<'lock> {
     /// Parses a package source string into a [`PackageSource`].
     ///
@@ -336,6 +497,19 @@ impl<'lock> PackageSource<'lock> {
             "registry" => RegistryProtocol::Registry,
             "sparse" => RegistryProtocol::Sparse,
             "git" => return GitSource::try_from(url).map(Self::Git),
+            "local-registry" => {
+                let kind = RegistryKind::Local { url };
+                return Ok(Self::Registry(RegistrySource {
+                    checksum: "",
+                    kind,
+                }));
+            },
+            "directory" => {
+                return Ok(Self::Directory(DirectorySource {
+                    url,
+                    checksum: None,
+                }));
+            },
             _ => {
                 return Err(CargoLockParseError::InvalidSourceProtocol {
                     protocol: protocol.into(),
@@ -357,6 +531,81 @@ impl<'lock> PackageSource<'lock> {
     }
 }
 
+impl RegistrySource<'_> {
+    /// Returns a value that formats `self` as a Cargo config entry that
+    /// replaces this registry source with the given `replace_with` source.
+    ///
+    /// Nothing is written for crates.io, whose replacement is always part of
+    /// the `config.toml`.
+    pub(crate) fn into_cargo_config_entry(
+        self,
+        replace_with: &str,
+    ) -> impl fmt::Display {
+        struct RegistrySourceConfigEntry<'lock, 'vendor> {
+            source: RegistrySource<'lock>,
+            replace_with: &'vendor str,
+        }
+
+        impl fmt::Display for RegistrySourceConfigEntry<'_, '_> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                let Self { source, replace_with } = self;
+
+                // Like for git sources, the key just has to be unique.
+                match source.kind {
+                    RegistryKind::CratesIo => return Ok(()),
+                    RegistryKind::Other { protocol, url } => {
+                        let prefix = match protocol {
+                            RegistryProtocol::Registry => "",
+                            RegistryProtocol::Sparse => "sparse+",
+                        };
+                        writeln!(f, "\n[source.\"{url}\"]")?;
+                        writeln!(f, "registry = \"{prefix}{url}\"")?;
+                    },
+                    RegistryKind::Local { url } => {
+                        let path = file_url_to_path(url);
+                        writeln!(f, "\n[source.\"{url}\"]")?;
+                        writeln!(f, "local-registry = \"{}\"", path.display())?;
+                    },
+                }
+
+                writeln!(f, "replace-with = \"{}\"", replace_with)
+            }
+        }
+
+        RegistrySourceConfigEntry { source: self, replace_with }
+    }
+}
+
+impl<'lock> DirectorySource<'lock> {
+    /// Returns a value that formats `self` as a Cargo config entry that
+    /// replaces this directory source with the given `replace_with` source.
+    pub(crate) fn into_cargo_config_entry(
+        self,
+        replace_with: &str,
+    ) -> impl fmt::Display {
+        struct DirectorySourceConfigEntry<'lock, 'vendor> {
+            source: DirectorySource<'lock>,
+            replace_with: &'vendor str,
+        }
+
+        impl fmt::Display for DirectorySourceConfigEntry<'_, '_> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                let Self { source, replace_with } = self;
+                writeln!(f, "\n[source.\"{}\"]", source.url)?;
+                writeln!(f, "directory = \"{}\"", source.path().display())?;
+                writeln!(f, "replace-with = \"{}\"", replace_with)
+            }
+        }
+
+        DirectorySourceConfigEntry { source: self, replace_with }
+    }
+
+    /// Returns the path of the directory.
+    pub(crate) fn path(&self) -> PathBuf {
+        file_url_to_path(self.url)
+    }
+}
+
 impl GitSource<'_> {
     /// Returns a value that formats `self` as a Cargo config entry that
     /// replaces this git source with the given `replace_with` source.
@@ -473,6 +722,14 @@ impl<'lock> TryFrom<&'lock str> for GitSource<'lock> {
     }
 }
 
+/// Converts a `file://` URL (like the ones of local sources in a `Cargo.lock`)
+/// into the path it points to.
+pub(crate) fn file_url_to_path(url: &str) -> PathBuf {
+    let path = url.strip_prefix("file://").unwrap_or(url);
+    let path = percent_encoding::percent_decode_str(path).decode_utf8_lossy();
+    PathBuf::from(&*path)
+}
+
 impl Search<u8> for &str {
     #[inline]
     fn search(&self, needle: u8) -> Option<usize> {
@@ -517,4 +774,142 @@ mod tests {
         assert_eq!(git_src.rev, "abc123");
         assert_eq!(git_src.r#ref, Some(GitSourceRef::Branch("main")));
     }
+
+    #[test]
+    fn local_sources() {
+        let cargo_lock = r#"
+[[package]]
+name = "foo"
+version = "0.1.0"
+source = "local-registry+file:///srv/my%20registry"
+checksum = "abc123"
+
+[[package]]
+name = "bar"
+version = "0.2.0"
+source = "directory+file:///srv/vendor"
+
+[[package]]
+name = "baz"
+version = "0.3.0"
+source = "directory+file:///srv/vendor"
+checksum = "def456"
+"#;
+        let entries = CargoLockParser::new(cargo_lock)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(entries.len(), 3);
+
+        let Some(PackageSource::Registry(foo)) = entries[0].source else {
+            panic!("expected a registry source");
+        };
+        let RegistryKind::Local { url } = foo.kind else {
+            panic!("expected a local registry");
+        };
+        assert_eq!(foo.checksum, "abc123");
+        assert_eq!(file_url_to_path(url), PathBuf::from("/srv/my registry"));
+
+        let Some(PackageSource::Directory(bar)) = entries[1].source else {
+            panic!("expected a directory source");
+        };
+        assert_eq!(bar.path(), PathBuf::from("/srv/vendor"));
+        assert_eq!(bar.checksum, None);
+
+        let Some(PackageSource::Directory(baz)) = entries[2].source else {
+            panic!("expected a directory source");
+        };
+        assert_eq!(baz.checksum, Some("def456"));
+    }
+
+    #[test]
+    fn patches_and_replacements() {
+        let cargo_lock = r#"
+[[package]]
+name = "foo"
+version = "0.1.0"
+source = "git+https://github.com/user/foo.git?branch=fix#abc123"
+
+[[package]]
+name = "serde"
+version = "0.8.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+replace = "serde 0.8.0 (git+https://github.com/user/serde.git#def456)"
+
+[[package]]
+name = "serde"
+version = "0.8.0"
+source = "git+https://github.com/user/serde.git#def456"
+
+[[patch.unused]]
+name = "bar"
+version = "0.2.0"
+source = "git+https://github.com/user/bar.git#789abc"
+"#;
+        let entries = CargoLockParser::new(cargo_lock)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let names = entries.iter().map(|entry| entry.name).collect::<Vec<_>>();
+        assert_eq!(names, ["foo", "serde", "serde", "bar"]);
+
+        assert_eq!(
+            entries[1].replace,
+            Some("serde 0.8.0 (git+https://github.com/user/serde.git#def456)")
+        );
+
+        let Some(PackageSource::Git(serde)) = entries[2].source else {
+            panic!("expected the replacement's git source");
+        };
+        assert_eq!(serde.rev, "def456");
+        assert_eq!(entries[2].replace, None);
+
+        let Some(PackageSource::Git(bar)) = entries[3].source else {
+            panic!("expected a git source");
+        };
+        assert_eq!(bar.url, "https://github.com/user/bar.git");
+    }
+
+    #[cfg(feature = "forbid-cargo")]
+    #[test]
+    fn dependencies() {
+        let cargo_lock = r#"
+[[package]]
+name = "foo"
+version = "0.1.0"
+dependencies = [
+ "bar",
+ "baz 0.2.0",
+ "baz 0.3.0 (registry+https://github.com/rust-lang/crates.io-index)",
+]
+
+[[package]]
+name = "bar"
+version = "0.1.0"
+"#;
+        let entries = CargoLockParser::new(cargo_lock)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(entries.len(), 2);
+
+        let deps = entries[0].dependencies.iter().collect::<Vec<_>>();
+        assert_eq!(
+            deps,
+            [
+                PackageDependency { name: "bar", version: None, source: None },
+                PackageDependency {
+                    name: "baz",
+                    version: Some("0.2.0"),
+                    source: None,
+                },
+                PackageDependency {
+                    name: "baz",
+                    version: Some("0.3.0"),
+                    source: Some(
+                        "registry+https://github.com/rust-lang/crates.io-index"
+                    ),
+                },
+            ]
+        );
+
+        assert_eq!(entries[1].dependencies.iter().count(), 0);
+    }
 }
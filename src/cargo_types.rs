@@ -0,0 +1,49 @@
+// The types shared by the code resolving and building the build graph, taken
+// either from the `cargo` crate or, if the `forbid-cargo` feature is enabled,
+// from the modules that read the workspace and its `Cargo.lock` without it.
+
+#[cfg(not(feature = "forbid-cargo"))]
+pub(crate) use cargo::core::compiler::{CompileKind, CompileTarget, CrateType};
+#[cfg(not(feature = "forbid-cargo"))]
+pub(crate) use cargo::core::dependency::DepKind;
+#[cfg(not(feature = "forbid-cargo"))]
+pub(crate) use cargo::core::manifest::TargetSourcePath;
+#[cfg(not(feature = "forbid-cargo"))]
+pub(crate) use cargo::core::profiles;
+#[cfg(not(feature = "forbid-cargo"))]
+pub(crate) use cargo::core::{
+    Edition,
+    MaybePackage,
+    Package,
+    PackageId,
+    SourceKind,
+    Target,
+    TargetKind,
+};
+#[cfg(not(feature = "forbid-cargo"))]
+pub(crate) use cargo::util::OptVersionReq;
+#[cfg(not(feature = "forbid-cargo"))]
+pub(crate) use cargo::util::interning::InternedString;
+#[cfg(feature = "forbid-cargo")]
+pub(crate) use semver::VersionReq as OptVersionReq;
+
+#[cfg(feature = "forbid-cargo")]
+pub(crate) use crate::interning::InternedString;
+#[cfg(feature = "forbid-cargo")]
+pub(crate) use crate::manifest::{
+    CrateType,
+    DepKind,
+    Edition,
+    Package,
+    PackageId,
+    SourceKind,
+    Target,
+    TargetKind,
+    TargetSourcePath,
+};
+#[cfg(feature = "forbid-cargo")]
+pub(crate) use crate::profiles;
+#[cfg(feature = "forbid-cargo")]
+pub(crate) use crate::target_data::{CompileKind, CompileTarget};
+#[cfg(feature = "forbid-cargo")]
+pub(crate) use crate::workspace::MaybePackage;
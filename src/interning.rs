@@ -0,0 +1,101 @@
+use core::borrow::Borrow;
+use core::fmt;
+use core::ops::Deref;
+use std::collections::BTreeSet;
+use std::sync::{Mutex, PoisonError};
+
+/// A string that's leaked and deduplicated when it's created, which makes it
+/// `Copy` and cheap to compare.
+///
+/// This is the cargo-free equivalent of Cargo's own `InternedString`, and it
+/// has the same tradeoff: the strings are never freed, which is fine since
+/// there's only ever a bounded number of package names, features, etc.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) struct InternedString(&'static str);
+
+impl InternedString {
+    pub(crate) fn new(str: &str) -> Self {
+        static STRINGS: Mutex<BTreeSet<&'static str>> =
+            Mutex::new(BTreeSet::new());
+
+        Self(intern(&STRINGS, str, |str| Box::leak(str.into())))
+    }
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        self.0
+    }
+}
+
+/// Returns the value in `set` that's equal to `value`, leaking it and
+/// inserting it into the set first if there isn't one.
+pub(crate) fn intern<T: Ord + ?Sized>(
+    set: &Mutex<BTreeSet<&'static T>>,
+    value: &T,
+    leak: impl FnOnce(&T) -> &'static T,
+) -> &'static T {
+    let mut set = set.lock().unwrap_or_else(PoisonError::into_inner);
+
+    if let Some(&interned) = set.get(value) {
+        return interned;
+    }
+
+    let interned = leak(value);
+    set.insert(interned);
+    interned
+}
+
+impl AsRef<str> for InternedString {
+    fn as_ref(&self) -> &str {
+        self.0
+    }
+}
+
+impl Borrow<str> for InternedString {
+    fn borrow(&self) -> &str {
+        self.0
+    }
+}
+
+impl Deref for InternedString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.0
+    }
+}
+
+impl fmt::Debug for InternedString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.0, f)
+    }
+}
+
+impl fmt::Display for InternedString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.0, f)
+    }
+}
+
+impl From<&str> for InternedString {
+    fn from(str: &str) -> Self {
+        Self::new(str)
+    }
+}
+
+impl From<&String> for InternedString {
+    fn from(str: &String) -> Self {
+        Self::new(str)
+    }
+}
+
+impl PartialEq<str> for InternedString {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&str> for InternedString {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
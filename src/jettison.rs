@@ -1,7 +1,9 @@
 use nix_bindings::prelude::*;
 
 use crate::build_package::BuildPackage;
+use crate::build_workspace::BuildWorkspace;
 use crate::resolve_build_graph::ResolveBuildGraph;
+use crate::test_package::TestPackage;
 use crate::vendor_deps::VendorDeps;
 
 /// nix-jettison's library functions.
@@ -12,7 +14,9 @@ impl Constant for Jettison {
     fn value() -> impl Value {
         attrset! {
             { <BuildPackage as PrimOp>::NAME }: BuildPackage,
+            { <BuildWorkspace as PrimOp>::NAME }: BuildWorkspace,
             { <ResolveBuildGraph as PrimOp>::NAME }: ResolveBuildGraph,
+            { <TestPackage as PrimOp>::NAME }: TestPackage,
             { <VendorDeps as PrimOp>::NAME }: VendorDeps,
         }
     }
@@ -2,14 +2,43 @@
 
 mod build_graph;
 mod build_package;
+// Compiled as a standalone executable by the derivations running build
+// scripts, only included here to run its tests.
+#[cfg(test)]
+#[expect(dead_code)]
+mod build_script_output;
+mod build_workspace;
+// Only the source replacement is read from it when resolving with Cargo.
+#[cfg_attr(not(feature = "forbid-cargo"), expect(dead_code))]
+mod cargo_config;
 mod cargo_lock_parser;
+mod cargo_types;
+#[cfg(feature = "forbid-cargo")]
+mod interning;
 mod jettison;
+#[cfg(feature = "forbid-cargo")]
+mod lock_resolve;
+mod lto;
 mod make_derivation;
+#[cfg(feature = "forbid-cargo")]
+mod manifest;
+#[cfg(feature = "forbid-cargo")]
+mod profiles;
 mod resolve_build_graph;
+#[cfg(feature = "forbid-cargo")]
+mod target_data;
+mod test_package;
 mod vendor_deps;
+#[cfg(feature = "forbid-cargo")]
+mod workspace;
 
 use nix_bindings::context::{Context, Entrypoint};
 
+#[cfg(not(any(feature = "cargo", feature = "forbid-cargo")))]
+compile_error!(
+    "either the `cargo` or the `forbid-cargo` feature has to be enabled"
+);
+
 #[nix_bindings::entry]
 fn jettison(ctx: &mut Context<Entrypoint>) {
     ctx.register_primop::<jettison::Jettison>()
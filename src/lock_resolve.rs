@@ -0,0 +1,1973 @@
+use core::result::Result;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+use compact_str::CompactString;
+use nix_bindings::prelude::{Context, Error as NixError};
+
+use crate::build_graph::ConfigEnvVar;
+use crate::cargo_config::CargoConfig;
+use crate::cargo_lock_parser::{
+    CargoLockParseError,
+    CargoLockParser,
+    PackageDependency,
+    PackageEntry,
+};
+use crate::interning::InternedString;
+use crate::manifest::{
+    DepKind,
+    Dependency,
+    DependencySource,
+    FeatureValue,
+    ManifestError,
+    Package,
+    PackageId,
+    SourceId,
+    Target,
+    WorkspaceInheritance,
+};
+use crate::profiles::Profiles;
+use crate::resolve_build_graph::{
+    ResolveBuildGraphArgs,
+    ResolveBuildGraphError,
+    VendorDir,
+};
+use crate::target_data::{CompileKind, RustcTargetData};
+use crate::workspace::{Workspace, find_workspace};
+
+/// The resolved dependency graph of a workspace, built from its `Cargo.lock`
+/// and the manifests of the packages in it without the `cargo` crate.
+pub(crate) struct WorkspaceResolve<'ws> {
+    compile_kind: CompileKind,
+    config_env: Vec<ConfigEnvVar>,
+    deps: ResolvedDeps,
+    features: ResolvedFeatures,
+    has_dev_units: bool,
+    host_linker: Option<CompactString>,
+
+    /// The packages in the graph that aren't members of the workspace.
+    packages: HashMap<PackageId, Package>,
+
+    profiles: Profiles,
+    root_ids: Vec<PackageId>,
+    target_data: RustcTargetData,
+    target_linker: Option<CompactString>,
+    workspace: Workspace<'ws>,
+}
+
+/// The dependencies of every package in the graph, as the ID of the package
+/// each one resolved to and its index in [`Package::dependencies`], sorted by
+/// package ID.
+type ResolvedDeps = HashMap<PackageId, Vec<(PackageId, usize)>>;
+
+/// The features requested on the command line, i.e. via the `features`,
+/// `allFeatures` and `noDefaultFeatures` arguments.
+struct CliFeatures {
+    all_features: bool,
+    features: Vec<FeatureValue>,
+    uses_default_features: bool,
+}
+
+/// The type of error that can occur when resolving the dependency graph from
+/// the `Cargo.lock`.
+#[derive(Debug, derive_more::Display, cauchy::Error)]
+pub(crate) enum LockResolveError {
+    #[display("invalid version {version:?} of {name:?} in the Cargo.lock")]
+    InvalidVersion { name: String, version: String },
+
+    #[display("failed to realise {name} v{version}: {err}")]
+    FetchPackage { name: String, version: String, err: NixError },
+
+    #[display("{_0}")]
+    Manifest(ManifestError),
+
+    #[display(
+        "the Cargo.lock has no entry for {dependency:?}, a dependency of \
+         {package}"
+    )]
+    MissingLockEntry { package: String, dependency: String },
+
+    #[display(
+        "the Cargo.lock refers to the path package {name} v{version}, which \
+         is not part of the workspace"
+    )]
+    MissingPathPackage { name: String, version: String },
+
+    #[display("failed to parse the Cargo.lock: {_0}")]
+    Parse(CargoLockParseError),
+
+    #[display("failed to read {path:?}: {err}")]
+    ReadLock { path: PathBuf, err: io::Error },
+
+    #[display(
+        "{package} depends on {dependency:?}, but the Cargo.lock doesn't lock \
+         it; make sure the Cargo.lock is up to date"
+    )]
+    UnlockedDependency { package: String, dependency: String },
+
+    #[display(
+        "{_0} has no entry in the Cargo.lock; make sure the Cargo.lock is up \
+         to date"
+    )]
+    UnlockedPackage(String),
+
+    #[display("unsupported source {_0:?} in the Cargo.lock")]
+    UnsupportedSource(String),
+}
+
+/// The features and optional dependencies activated on each package.
+///
+/// Like Cargo's, these are keyed by whether the package is compiled for the
+/// host, which is always false unless the workspace decouples the features
+/// of build dependencies and proc-macros from the others.
+#[derive(Default)]
+struct ResolvedFeatures {
+    activated_deps: HashMap<(PackageId, bool), BTreeSet<InternedString>>,
+    activated_features: HashMap<(PackageId, bool), BTreeSet<InternedString>>,
+    decouples_host_deps: bool,
+}
+
+/// Resolves the dependencies of the packages in the `Cargo.lock`, reading
+/// their manifests as they're reached.
+struct LockResolver<'lock, 'a, 'ws, F> {
+    compile_kind: CompileKind,
+    deps: ResolvedDeps,
+    entries: Vec<PackageEntry<'lock>>,
+
+    /// The indices of the [`entries`](Self::entries), keyed by package name.
+    entries_by_name: HashMap<&'lock str, Vec<usize>>,
+
+    /// The ID of each entry's package, once its manifest has been read.
+    entry_ids: Vec<Option<PackageId>>,
+
+    id_to_entry: HashMap<PackageId, usize>,
+    packages: HashMap<PackageId, Package>,
+
+    /// The IDs of all the path packages, including the workspace members,
+    /// keyed by name and version.
+    path_ids: HashMap<(InternedString, semver::Version), PackageId>,
+
+    /// Returns the path to the vendored package in the directory with the
+    /// given name, realising it first if needed.
+    package_dir: F,
+
+    target_data: &'a RustcTargetData,
+    workspace: &'a Workspace<'ws>,
+}
+
+/// Mirrors Cargo's feature resolver (with resolver v2 semantics if the
+/// workspace decouples host dependencies).
+struct FeatureResolver<'a, 'ws> {
+    /// Features of optional dependencies enabled via `dep?/feature` before
+    /// the dependency itself was activated.
+    deferred_weak_deps:
+        HashMap<(PackageId, bool, InternedString), BTreeSet<InternedString>>,
+
+    processed_deps: HashSet<(PackageId, bool)>,
+    resolve: &'a WorkspaceResolve<'ws>,
+    resolved: ResolvedFeatures,
+}
+
+impl ResolveBuildGraphArgs<'_> {
+    fn compile_kind(&self) -> CompileKind {
+        self.compile_target
+            .map(CompileKind::Target)
+            .unwrap_or(CompileKind::Host)
+    }
+
+    pub(crate) fn workspace<'cfg>(
+        &self,
+        config: &'cfg CargoConfig,
+    ) -> Result<Workspace<'cfg>, ResolveBuildGraphError> {
+        Workspace::new(self.src, config)
+            .map_err(|err| ResolveBuildGraphError::CreateWorkspace(err.into()))
+    }
+
+    fn features(&self) -> Result<CliFeatures, ResolveBuildGraphError> {
+        let features = self
+            .features
+            .iter()
+            .flat_map(|features| features.split([' ', ',']))
+            .filter(|feature| !feature.is_empty())
+            .map(|feature| {
+                if feature.starts_with("dep:") {
+                    return Err(ResolveBuildGraphError::ParseFeatures(
+                        anyhow::anyhow!(
+                            "feature `{feature}` is not allowed to use \
+                             explicit `dep:` syntax"
+                        ),
+                    ));
+                }
+                Ok(FeatureValue::parse(feature))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(CliFeatures {
+            all_features: self.all_features,
+            features,
+            uses_default_features: !self.no_default_features,
+        })
+    }
+}
+
+impl<'ws> WorkspaceResolve<'ws> {
+    /// The environment variables set by the `[env]` table of the Cargo
+    /// configuration, sorted by name.
+    pub(crate) fn config_env(&self) -> &[ConfigEnvVar] {
+        &self.config_env
+    }
+
+    /// Returns the dependencies of the given package when it's compiled for
+    /// the host (if `for_host` is true) or for the compile target, in the
+    /// same way Cargo computes the dependencies of a unit.
+    ///
+    /// With resolver v1, this includes the dev-dependencies of workspace
+    /// members even if [`has_dev_units`](Self::has_dev_units) is false.
+    pub(crate) fn deps(
+        &self,
+        pkg_id: PackageId,
+        for_host: bool,
+    ) -> impl Iterator<Item = (PackageId, &Dependency)> {
+        let dependencies =
+            self.package(pkg_id).map_or(&[][..], Package::dependencies);
+
+        self.deps.get(&pkg_id).into_iter().flatten().filter_map(
+            move |&(dep_pkg_id, dep_idx)| {
+                let dep = &dependencies[dep_idx];
+
+                // Build dependencies are used by the build script, which is
+                // always compiled for the host.
+                let kind = self.unit_compile_kind(for_host || dep.is_build());
+
+                // Filter out dependencies that don't match the platform the
+                // package is compiled for, and optional dependencies that
+                // the feature resolver didn't enable for it.
+                let is_activated =
+                    self.target_data.dep_platform_activated(dep, kind)
+                        && (!dep.is_optional()
+                            || self.features.is_dep_activated(
+                                pkg_id,
+                                for_host,
+                                dep.name_in_toml(),
+                            ));
+
+                is_activated.then_some((dep_pkg_id, dep))
+            },
+        )
+    }
+
+    /// Returns the features that are enabled on the given package when it's
+    /// compiled for the host (if `for_host` is true) or for the compile
+    /// target.
+    ///
+    /// With resolver v2 these can differ, since the features of build
+    /// dependencies and proc-macros are unified separately.
+    pub(crate) fn features(
+        &self,
+        pkg_id: PackageId,
+        for_host: bool,
+    ) -> Vec<InternedString> {
+        self.features.activated_features(pkg_id, for_host)
+    }
+
+    /// Whether the dev-dependencies of the root package have been resolved.
+    pub(crate) fn has_dev_units(&self) -> bool {
+        self.has_dev_units
+    }
+
+    /// Returns the linker set in the Cargo configuration for the platform
+    /// the given kind compiles for, if any.
+    pub(crate) fn linker(&self, kind: CompileKind) -> Option<CompactString> {
+        match kind {
+            CompileKind::Host => self.host_linker.clone(),
+            CompileKind::Target(_) => self.target_linker.clone(),
+        }
+    }
+
+    pub(crate) fn package(&self, pkg_id: PackageId) -> Option<&Package> {
+        self.workspace
+            .members()
+            .find(|member| member.package_id() == pkg_id)
+            .or_else(|| self.packages.get(&pkg_id))
+    }
+
+    pub(crate) fn profiles(&self) -> &Profiles {
+        &self.profiles
+    }
+
+    /// Returns whether the given package is one of the roots of the build
+    /// graph, i.e. one of the packages that were explicitly requested.
+    pub(crate) fn is_root(&self, pkg_id: PackageId) -> bool {
+        self.root_ids.contains(&pkg_id)
+    }
+
+    /// The [`PackageId`]s of the packages at the roots of the build graph.
+    pub(crate) fn root_ids(&self) -> &[PackageId] {
+        &self.root_ids
+    }
+
+    pub(crate) fn target_data(&self) -> &RustcTargetData {
+        &self.target_data
+    }
+
+    /// Returns the kind of the units compiled for the host (if `for_host` is
+    /// true) or for the compile target.
+    pub(crate) fn unit_compile_kind(&self, for_host: bool) -> CompileKind {
+        if for_host { CompileKind::Host } else { self.compile_kind }
+    }
+
+    pub(crate) fn workspace(&self) -> &Workspace<'ws> {
+        &self.workspace
+    }
+
+    pub(crate) fn new(
+        workspace: Workspace<'ws>,
+        root_ids: Vec<PackageId>,
+        args: &ResolveBuildGraphArgs,
+        ctx: &mut Context,
+    ) -> Result<Self, ResolveBuildGraphError> {
+        let target_data =
+            RustcTargetData::new(workspace.config(), args.compile_kind())
+                .map_err(|err| {
+                    ResolveBuildGraphError::CreateTargetData(err.into())
+                })?;
+
+        let vendor_dir = &args.vendor_dir;
+
+        Self::with_target_data(workspace, root_ids, args, target_data, |dir| {
+            vendor_dir.package_dir(dir, ctx)
+        })
+    }
+
+    /// Like [`new`](Self::new), but with the given target data instead of
+    /// querying `rustc` for it, and reading the vendored packages from the
+    /// directories returned by `package_dir`.
+    #[expect(clippy::too_many_arguments)]
+    fn with_target_data(
+        workspace: Workspace<'ws>,
+        root_ids: Vec<PackageId>,
+        args: &ResolveBuildGraphArgs,
+        target_data: RustcTargetData,
+        package_dir: impl FnMut(&str) -> Result<PathBuf, NixError>,
+    ) -> Result<Self, ResolveBuildGraphError> {
+        let config = workspace.config();
+
+        let compile_kind = args.compile_kind();
+
+        let host_triple = target_data.short_name(CompileKind::Host);
+
+        let (deps, packages) = resolve_lock(
+            &workspace,
+            &root_ids,
+            args,
+            &target_data,
+            package_dir,
+        )?;
+
+        let config_profiles = config.profiles().map_err(|err| {
+            ResolveBuildGraphError::ResolveProfiles(err.into())
+        })?;
+
+        let profiles = Profiles::new(
+            workspace.profiles(),
+            config_profiles.as_ref(),
+            &args.profile,
+            host_triple,
+        )
+        .map_err(|err| ResolveBuildGraphError::ResolveProfiles(err.into()))?;
+
+        let config_env = config
+            .env()
+            .map_err(|err| ResolveBuildGraphError::ReadEnvConfig(err.into()))?;
+
+        let linker = |triple: InternedString| {
+            config.linker(&triple).map_err(|err| {
+                ResolveBuildGraphError::ConfigureCargoContext(err.into())
+            })
+        };
+
+        let host_linker = linker(host_triple)?;
+
+        let target_linker = linker(target_data.short_name(compile_kind))?;
+
+        let mut this = Self {
+            compile_kind,
+            config_env,
+            deps,
+            features: ResolvedFeatures::default(),
+            has_dev_units: args.dev_dependencies,
+            host_linker,
+            packages,
+            profiles,
+            root_ids,
+            target_data,
+            target_linker,
+            workspace,
+        };
+
+        this.features = FeatureResolver::resolve(&this, &args.features()?);
+
+        Ok(this)
+    }
+
+    fn is_proc_macro(&self, pkg_id: PackageId) -> bool {
+        self.package(pkg_id).is_some_and(|package| {
+            package.targets().iter().any(Target::proc_macro)
+        })
+    }
+}
+
+/// Creates the Cargo configuration used to resolve the workspace at the given
+/// root.
+///
+/// The configuration written by `vendorDeps` only replaces the sources of the
+/// dependencies with the vendor directory, and we read the vendored packages
+/// from there directly, so it's not needed.
+pub(crate) fn cargo_ctx(
+    workspace_root: &Path,
+    _vendor_dir: &VendorDir,
+    _: &mut Context,
+) -> Result<CargoConfig, ResolveBuildGraphError> {
+    CargoConfig::new(workspace_root).map_err(|err| {
+        ResolveBuildGraphError::ConfigureCargoContext(err.into())
+    })
+}
+
+/// Resolves the dependencies of the given roots (and of their dependencies,
+/// recursively) from the workspace's `Cargo.lock`, returning them together
+/// with the packages in the graph that aren't workspace members.
+#[expect(clippy::too_many_arguments)]
+fn resolve_lock(
+    workspace: &Workspace,
+    root_ids: &[PackageId],
+    args: &ResolveBuildGraphArgs,
+    target_data: &RustcTargetData,
+    package_dir: impl FnMut(&str) -> Result<PathBuf, NixError>,
+) -> Result<(ResolvedDeps, HashMap<PackageId, Package>), ResolveBuildGraphError>
+{
+    let lock_path = workspace.root().join("Cargo.lock");
+
+    let cargo_lock = fs::read_to_string(&lock_path).map_err(|err| {
+        let err = LockResolveError::ReadLock { path: lock_path, err };
+        ResolveBuildGraphError::ResolveWorkspace(err.into())
+    })?;
+
+    let mut resolver = LockResolver::new(
+        &cargo_lock,
+        workspace,
+        target_data,
+        args.compile_kind(),
+        package_dir,
+    )
+    .map_err(|err| ResolveBuildGraphError::ResolveWorkspace(err.into()))?;
+
+    // Cargo only warns about unused patches, but since they're usually
+    // meant to fix a dependency we'd rather not silently build the
+    // unpatched one.
+    let unused_patches = resolver.unused_patches();
+    if !unused_patches.is_empty() {
+        return Err(ResolveBuildGraphError::UnusedPatches(unused_patches));
+    }
+
+    resolver
+        .resolve(root_ids, args.dev_dependencies)
+        .map_err(|err| ResolveBuildGraphError::ResolveWorkspace(err.into()))?;
+
+    Ok((resolver.deps, resolver.packages))
+}
+
+impl CliFeatures {
+    /// Returns the features requested on the given root package.
+    fn requested(&self, package: &Package) -> Vec<FeatureValue> {
+        let feature_map = package.manifest().features();
+
+        let mut features = self.features.clone();
+
+        if self.all_features {
+            features
+                .extend(feature_map.keys().copied().map(FeatureValue::Feature));
+        }
+
+        if self.uses_default_features && feature_map.contains_key("default") {
+            features.push(FeatureValue::Feature("default".into()));
+        }
+
+        features
+    }
+}
+
+impl ResolvedFeatures {
+    fn activated_features(
+        &self,
+        pkg_id: PackageId,
+        for_host: bool,
+    ) -> Vec<InternedString> {
+        self.activated_features
+            .get(&(pkg_id, for_host && self.decouples_host_deps))
+            .map(|features| features.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    fn is_dep_activated(
+        &self,
+        pkg_id: PackageId,
+        for_host: bool,
+        dep_name: InternedString,
+    ) -> bool {
+        self.activated_deps
+            .get(&(pkg_id, for_host && self.decouples_host_deps))
+            .is_some_and(|deps| deps.contains(&dep_name))
+    }
+}
+
+impl<'lock, 'a, 'ws, F> LockResolver<'lock, 'a, 'ws, F>
+where
+    F: FnMut(&str) -> Result<PathBuf, NixError>,
+{
+    #[expect(clippy::too_many_arguments)]
+    fn new(
+        cargo_lock: &'lock str,
+        workspace: &'a Workspace<'ws>,
+        target_data: &'a RustcTargetData,
+        compile_kind: CompileKind,
+        package_dir: F,
+    ) -> Result<Self, LockResolveError> {
+        let entries = CargoLockParser::new(cargo_lock)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(LockResolveError::Parse)?;
+
+        let mut entries_by_name = HashMap::<_, Vec<_>>::new();
+
+        for (idx, entry) in entries.iter().enumerate() {
+            entries_by_name.entry(entry.name).or_default().push(idx);
+        }
+
+        let mut this = Self {
+            compile_kind,
+            deps: HashMap::new(),
+            entry_ids: vec![None; entries.len()],
+            entries,
+            entries_by_name,
+            id_to_entry: HashMap::new(),
+            packages: HashMap::new(),
+            path_ids: HashMap::new(),
+            package_dir,
+            target_data,
+            workspace,
+        };
+
+        this.read_path_packages()?;
+
+        Ok(this)
+    }
+
+    /// Returns the ID of the package of the entry at the given index, reading
+    /// its manifest from the vendor directory if it's not a path package.
+    fn entry_id(&mut self, idx: usize) -> Result<PackageId, LockResolveError> {
+        if let Some(pkg_id) = self.entry_ids[idx] {
+            return Ok(pkg_id);
+        }
+
+        let entry = &self.entries[idx];
+
+        let source_id = self.entry_source_id(idx)?;
+
+        // Like `cargo vendor`, `vendorDeps` places every package in a
+        // directory named after its name and version.
+        let package_dir =
+            (self.package_dir)(&format!("{}-{}", entry.name, entry.version))
+                .map_err(|err| LockResolveError::FetchPackage {
+                    name: entry.name.to_owned(),
+                    version: entry.version.to_owned(),
+                    err,
+                })?;
+
+        let package =
+            Package::read(&package_dir.join("Cargo.toml"), source_id, None)
+                .map_err(LockResolveError::Manifest)?;
+
+        let pkg_id = package.package_id();
+        self.packages.insert(pkg_id, package);
+        self.entry_ids[idx] = Some(pkg_id);
+        self.id_to_entry.insert(pkg_id, idx);
+        Ok(pkg_id)
+    }
+
+    /// Returns the ID of the package of the entry at the given index as
+    /// recorded in the `Cargo.lock`, without reading its manifest.
+    fn locked_entry_id(
+        &self,
+        idx: usize,
+    ) -> Result<PackageId, LockResolveError> {
+        if let Some(pkg_id) = self.entry_ids[idx] {
+            return Ok(pkg_id);
+        }
+
+        let entry = &self.entries[idx];
+
+        let source_id = self.entry_source_id(idx)?;
+
+        let version = semver::Version::parse(entry.version).map_err(|_| {
+            LockResolveError::InvalidVersion {
+                name: entry.name.to_owned(),
+                version: entry.version.to_owned(),
+            }
+        })?;
+
+        Ok(PackageId::new(entry.name.into(), version, source_id))
+    }
+
+    /// Returns the source of the entry at the given index, which must not be
+    /// a path package since those are all read upfront.
+    fn entry_source_id(
+        &self,
+        idx: usize,
+    ) -> Result<SourceId, LockResolveError> {
+        let entry = &self.entries[idx];
+
+        let Some(raw_source) = entry.raw_source else {
+            return Err(LockResolveError::MissingPathPackage {
+                name: entry.name.to_owned(),
+                version: entry.version.to_owned(),
+            });
+        };
+
+        SourceId::from_lock(raw_source).ok_or_else(|| {
+            LockResolveError::UnsupportedSource(raw_source.to_owned())
+        })
+    }
+
+    /// Returns the index of the entry that the given element of a
+    /// `dependencies` array (or a `replace` field) refers to.
+    fn find_entry(&self, dep: PackageDependency) -> Option<usize> {
+        let mut candidates =
+            self.entries_by_name.get(dep.name)?.iter().copied().filter(
+                |&idx| {
+                    let entry = &self.entries[idx];
+                    !entry.is_unused_patch
+                        && dep
+                            .version
+                            .is_none_or(|version| version == entry.version)
+                        && dep.source.is_none_or(|source| {
+                            entry.raw_source == Some(source)
+                        })
+                },
+            );
+
+        let first = candidates.next()?;
+
+        // The source is omitted for path packages, even if there's another
+        // package with the same name and version (like the one a path
+        // package replaces).
+        if dep.source.is_none()
+            && self.entries[first].raw_source.is_some()
+            && let Some(path_idx) =
+                candidates.find(|&idx| self.entries[idx].raw_source.is_none())
+        {
+            return Some(path_idx);
+        }
+
+        Some(first)
+    }
+
+    /// Returns the index of the entry that's actually used in place of the
+    /// given one, following its `replace` field (if any).
+    fn follow_replace(&self, idx: usize) -> Result<usize, LockResolveError> {
+        let Some(replace) = self.entries[idx].replace else { return Ok(idx) };
+
+        self.find_entry(PackageDependency::parse(replace))
+            .filter(|&replacement_idx| replacement_idx != idx)
+            .ok_or_else(|| LockResolveError::MissingLockEntry {
+                package: self.entries[idx].name.to_owned(),
+                dependency: replace.to_owned(),
+            })
+    }
+
+    /// Returns the index of the entry among the given ones that the given
+    /// dependency resolved to.
+    fn match_dependency(
+        &self,
+        dep: &Dependency,
+        lock_deps: &[usize],
+    ) -> Option<usize> {
+        let mut candidates = lock_deps
+            .iter()
+            .copied()
+            .filter(|&idx| {
+                self.entries[idx].name == dep.package_name().as_str()
+            })
+            .collect::<Vec<_>>();
+
+        // Only narrow down the candidates if there's more than one, since
+        // the packages of `[patch]`es and `[replace]`s can come from a
+        // different source than the one in the dependency.
+        let mut narrow = |predicate: &dyn Fn(&PackageEntry) -> bool| {
+            if candidates.len() > 1
+                && candidates.iter().any(|&idx| predicate(&self.entries[idx]))
+            {
+                candidates.retain(|&idx| predicate(&self.entries[idx]));
+            }
+        };
+
+        narrow(&|entry| {
+            semver::Version::parse(entry.version)
+                .is_ok_and(|version| dep.version_req().matches(&version))
+        });
+
+        narrow(&|entry| match (dep.source(), entry.raw_source) {
+            (DependencySource::Path(_), source) => source.is_none(),
+            (DependencySource::Git(url), Some(source)) => source
+                .strip_prefix("git+")
+                .is_some_and(|source| source.starts_with(url.as_str())),
+            (DependencySource::Registry, Some(source)) => {
+                source.starts_with("registry+") || source.starts_with("sparse+")
+            },
+            (_, None) => false,
+        });
+
+        candidates.first().copied()
+    }
+
+    /// Reads all the path packages that can appear in the `Cargo.lock`, i.e.
+    /// the workspace members, the packages they (transitively) depend on via
+    /// `path` dependencies, and the ones used by `[patch]` and `[replace]`.
+    fn read_path_packages(&mut self) -> Result<(), LockResolveError> {
+        let mut read_dirs = HashSet::new();
+
+        let mut dirs = Vec::new();
+
+        for member in self.workspace.members() {
+            read_dirs.insert(member.root().to_owned());
+            dirs.extend(path_deps(member, true));
+            self.path_ids.insert(
+                (member.name(), member.version().clone()),
+                member.package_id(),
+            );
+        }
+
+        dirs.extend(self.workspace.patch_paths());
+
+        while let Some(dir) = dirs.pop() {
+            if !read_dirs.insert(dir.clone()) {
+                continue;
+            }
+
+            let workspace = find_workspace(&dir, None)
+                .map_err(LockResolveError::Manifest)?;
+
+            let inheritance = workspace.as_ref().map(|(root, workspace)| {
+                WorkspaceInheritance { workspace, root }
+            });
+
+            let package = Package::read(
+                &dir.join("Cargo.toml"),
+                SourceId::for_path(&dir),
+                inheritance,
+            )
+            .map_err(LockResolveError::Manifest)?;
+
+            dirs.extend(path_deps(&package, false));
+
+            self.path_ids.insert(
+                (package.name(), package.version().clone()),
+                package.package_id(),
+            );
+
+            self.packages.insert(package.package_id(), package);
+        }
+
+        for (idx, entry) in self.entries.iter().enumerate() {
+            if entry.raw_source.is_some() {
+                continue;
+            }
+
+            let version =
+                semver::Version::parse(entry.version).map_err(|_| {
+                    LockResolveError::InvalidVersion {
+                        name: entry.name.to_owned(),
+                        version: entry.version.to_owned(),
+                    }
+                })?;
+
+            if let Some(&pkg_id) =
+                self.path_ids.get(&(entry.name.into(), version))
+            {
+                self.entry_ids[idx] = Some(pkg_id);
+                self.id_to_entry.insert(pkg_id, idx);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Resolves the dependencies of the given roots, and of all the packages
+    /// they (transitively) depend on.
+    ///
+    /// Like Cargo, dev-dependencies are only resolved for workspace members,
+    /// and only if `has_dev_units` is true or the workspace doesn't decouple
+    /// host dependencies: with resolver v1, the features they enable are
+    /// unified with the others even when nothing is compiled with them.
+    fn resolve(
+        &mut self,
+        root_ids: &[PackageId],
+        has_dev_units: bool,
+    ) -> Result<(), LockResolveError> {
+        let mut queue = root_ids.to_vec();
+
+        let resolves_dev_deps =
+            has_dev_units || !self.workspace.decouples_host_deps();
+
+        while let Some(pkg_id) = queue.pop() {
+            if self.deps.contains_key(&pkg_id) {
+                continue;
+            }
+
+            let include_dev =
+                resolves_dev_deps && self.workspace.is_member_id(pkg_id);
+
+            let deps = self.resolve_deps(pkg_id, include_dev)?;
+
+            // Dependencies whose manifest wasn't read aren't used on any of
+            // the platforms we compile for, so their own dependencies don't
+            // matter.
+            queue.extend(
+                deps.iter().map(|&(dep_pkg_id, _)| dep_pkg_id).filter(
+                    |dep_pkg_id| {
+                        self.packages.contains_key(dep_pkg_id)
+                            || self.workspace.is_member_id(*dep_pkg_id)
+                    },
+                ),
+            );
+
+            self.deps.insert(pkg_id, deps);
+        }
+
+        Ok(())
+    }
+
+    /// Resolves the dependencies declared in the manifest of the given
+    /// package to the entries in its `dependencies` array.
+    fn resolve_deps(
+        &mut self,
+        pkg_id: PackageId,
+        include_dev: bool,
+    ) -> Result<Vec<(PackageId, usize)>, LockResolveError> {
+        // Workspace members and path packages are read before resolving, so
+        // they can be missing from a stale `Cargo.lock`.
+        let entry = self
+            .id_to_entry
+            .get(&pkg_id)
+            .map(|&idx| &self.entries[idx])
+            .ok_or_else(|| {
+                LockResolveError::UnlockedPackage(pkg_id.to_string())
+            })?;
+
+        let lock_deps = entry
+            .dependencies
+            .iter()
+            .map(|dep| {
+                self.find_entry(dep).ok_or_else(|| {
+                    LockResolveError::MissingLockEntry {
+                        package: pkg_id.to_string(),
+                        dependency: dep.name.to_owned(),
+                    }
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let package = self
+            .workspace
+            .members()
+            .find(|member| member.package_id() == pkg_id)
+            .or_else(|| self.packages.get(&pkg_id))
+            .ok_or_else(|| LockResolveError::MissingPathPackage {
+                name: pkg_id.name().to_string(),
+                version: pkg_id.version().to_string(),
+            })?;
+
+        // With resolver v2, dependencies that aren't used on any of the
+        // platforms we compile for can't affect the features of the others,
+        // so we don't need to read (and possibly fetch) their packages.
+        let decouples_host_deps = self.workspace.decouples_host_deps();
+
+        let is_used = |dep: &Dependency| {
+            !decouples_host_deps
+                || [CompileKind::Host, self.compile_kind].into_iter().any(
+                    |kind| self.target_data.dep_platform_activated(dep, kind),
+                )
+        };
+
+        let matched_deps = package
+            .dependencies()
+            .iter()
+            .enumerate()
+            .filter(|(_, dep)| {
+                include_dev || dep.kind() != DepKind::Development
+            })
+            .map(|(dep_idx, dep)| {
+                let lock_idx = self
+                    .match_dependency(dep, &lock_deps)
+                    .ok_or_else(|| LockResolveError::UnlockedDependency {
+                        package: pkg_id.to_string(),
+                        dependency: dep.name_in_toml().to_string(),
+                    })?;
+                Ok((self.follow_replace(lock_idx)?, dep_idx, is_used(dep)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut deps = matched_deps
+            .into_iter()
+            .map(|(lock_idx, dep_idx, is_used)| {
+                let dep_pkg_id = if is_used {
+                    self.entry_id(lock_idx)?
+                } else {
+                    self.locked_entry_id(lock_idx)?
+                };
+                Ok((dep_pkg_id, dep_idx))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Like Cargo's, the dependencies are sorted by package ID, and the
+        // ones resolving to the same package keep their manifest order.
+        deps.sort_by_key(|&(dep_pkg_id, _)| dep_pkg_id);
+
+        Ok(deps)
+    }
+
+    /// Returns the `[[patch.unused]]` entries, formatted like Cargo formats
+    /// package IDs.
+    fn unused_patches(&self) -> Vec<String> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.is_unused_patch)
+            .map(|entry| match entry.raw_source {
+                Some(source) => {
+                    format!("{} v{} ({source})", entry.name, entry.version)
+                },
+                None => format!("{} v{}", entry.name, entry.version),
+            })
+            .collect()
+    }
+}
+
+impl<'a, 'ws> FeatureResolver<'a, 'ws> {
+    /// Activates the requested features on every root of the given resolve,
+    /// and the ones they enable on their dependencies, recursively.
+    fn resolve(
+        resolve: &'a WorkspaceResolve<'ws>,
+        cli_features: &CliFeatures,
+    ) -> ResolvedFeatures {
+        let decouples_host_deps = resolve.workspace.decouples_host_deps();
+
+        let mut this = Self {
+            deferred_weak_deps: HashMap::new(),
+            processed_deps: HashSet::new(),
+            resolve,
+            resolved: ResolvedFeatures {
+                decouples_host_deps,
+                ..ResolvedFeatures::default()
+            },
+        };
+
+        for &root_id in &resolve.root_ids {
+            let Some(root) = resolve.package(root_id) else { continue };
+
+            // Proc-macros are compiled for the host even when they're roots.
+            let for_host =
+                decouples_host_deps && resolve.is_proc_macro(root_id);
+
+            this.activate_pkg(root_id, for_host, &cli_features.requested(root));
+        }
+
+        this.resolved
+    }
+
+    fn activate_pkg(
+        &mut self,
+        pkg_id: PackageId,
+        for_host: bool,
+        feature_values: &[FeatureValue],
+    ) {
+        self.resolved.activated_features.entry((pkg_id, for_host)).or_default();
+
+        for &feature_value in feature_values {
+            self.activate_fv(pkg_id, for_host, feature_value);
+        }
+
+        if !self.processed_deps.insert((pkg_id, for_host)) {
+            return;
+        }
+
+        for (dep_pkg_id, dep, dep_for_host) in self.deps(pkg_id, for_host) {
+            if !dep.is_optional() {
+                self.activate_pkg(
+                    dep_pkg_id,
+                    dep_for_host,
+                    &self.dependency_features(dep_pkg_id, dep),
+                );
+            }
+        }
+    }
+
+    fn activate_fv(
+        &mut self,
+        pkg_id: PackageId,
+        for_host: bool,
+        feature_value: FeatureValue,
+    ) {
+        match feature_value {
+            FeatureValue::Feature(feature) => {
+                self.activate_rec(pkg_id, for_host, feature);
+            },
+            FeatureValue::Dep { dep_name } => {
+                self.activate_dependency(pkg_id, for_host, dep_name);
+            },
+            FeatureValue::DepFeature { dep_name, dep_feature, weak } => {
+                self.activate_dep_feature(
+                    pkg_id,
+                    for_host,
+                    dep_name,
+                    dep_feature,
+                    weak,
+                );
+            },
+        }
+    }
+
+    /// Activates the given feature, and the ones it enables.
+    fn activate_rec(
+        &mut self,
+        pkg_id: PackageId,
+        for_host: bool,
+        feature: InternedString,
+    ) {
+        let is_new = self
+            .resolved
+            .activated_features
+            .entry((pkg_id, for_host))
+            .or_default()
+            .insert(feature);
+
+        if !is_new {
+            return;
+        }
+
+        let resolve = self.resolve;
+
+        let Some(feature_values) = resolve
+            .package(pkg_id)
+            .and_then(|package| package.manifest().features().get(&feature))
+        else {
+            return;
+        };
+
+        for &feature_value in feature_values {
+            self.activate_fv(pkg_id, for_host, feature_value);
+        }
+    }
+
+    /// Activates the optional dependency with the given name.
+    fn activate_dependency(
+        &mut self,
+        pkg_id: PackageId,
+        for_host: bool,
+        dep_name: InternedString,
+    ) {
+        self.resolved
+            .activated_deps
+            .entry((pkg_id, for_host))
+            .or_default()
+            .insert(dep_name);
+
+        let deferred =
+            self.deferred_weak_deps.remove(&(pkg_id, for_host, dep_name));
+
+        for (dep_pkg_id, dep, dep_for_host) in self.deps(pkg_id, for_host) {
+            if dep.name_in_toml() != dep_name {
+                continue;
+            }
+
+            for &dep_feature in deferred.iter().flatten() {
+                self.activate_pkg(
+                    dep_pkg_id,
+                    dep_for_host,
+                    &[FeatureValue::Feature(dep_feature)],
+                );
+            }
+
+            self.activate_pkg(
+                dep_pkg_id,
+                dep_for_host,
+                &self.dependency_features(dep_pkg_id, dep),
+            );
+        }
+    }
+
+    /// Activates a feature of a dependency (`dep/feature`), and the
+    /// dependency itself if it's optional, unless the feature is weak
+    /// (`dep?/feature`).
+    #[expect(clippy::too_many_arguments)]
+    fn activate_dep_feature(
+        &mut self,
+        pkg_id: PackageId,
+        for_host: bool,
+        dep_name: InternedString,
+        dep_feature: InternedString,
+        weak: bool,
+    ) {
+        let resolve = self.resolve;
+
+        for (dep_pkg_id, dep, dep_for_host) in self.deps(pkg_id, for_host) {
+            if dep.name_in_toml() != dep_name {
+                continue;
+            }
+
+            if dep.is_optional() {
+                let is_activated = self
+                    .resolved
+                    .activated_deps
+                    .get(&(pkg_id, for_host))
+                    .is_some_and(|deps| deps.contains(&dep_name));
+
+                // Weak features are only enabled once something else
+                // activates the dependency.
+                if weak && !is_activated {
+                    self.deferred_weak_deps
+                        .entry((pkg_id, for_host, dep_name))
+                        .or_default()
+                        .insert(dep_feature);
+                    continue;
+                }
+
+                self.activate_dependency(pkg_id, for_host, dep_name);
+
+                // Like Cargo, also enable the implicit feature of the
+                // optional dependency, unless it's hidden by a `dep:`.
+                let has_implicit_feature =
+                    resolve.package(pkg_id).is_some_and(|package| {
+                        package.manifest().features().contains_key(&dep_name)
+                    });
+
+                if !weak && has_implicit_feature {
+                    self.activate_rec(pkg_id, for_host, dep_name);
+                }
+            }
+
+            self.activate_pkg(
+                dep_pkg_id,
+                dep_for_host,
+                &[FeatureValue::Feature(dep_feature)],
+            );
+        }
+    }
+
+    /// Returns the features the given dependency enables on the package it
+    /// resolved to.
+    ///
+    /// Like Cargo, the `default` feature is only enabled if the package
+    /// defines one.
+    fn dependency_features(
+        &self,
+        dep_pkg_id: PackageId,
+        dep: &Dependency,
+    ) -> Vec<FeatureValue> {
+        let mut features = dep
+            .features()
+            .iter()
+            .map(|feature| FeatureValue::parse(feature))
+            .collect::<Vec<_>>();
+
+        let has_default = self.resolve.package(dep_pkg_id).is_some_and(|pkg| {
+            pkg.manifest().features().contains_key("default")
+        });
+
+        if dep.uses_default_features() && has_default {
+            features.push(FeatureValue::Feature("default".into()));
+        }
+
+        features
+    }
+
+    /// Returns the dependencies of the given package that can activate
+    /// features, together with whether each one is compiled for the host.
+    ///
+    /// With resolver v2, dependencies whose platform doesn't match are
+    /// filtered out, and build dependencies and proc-macros are compiled for
+    /// the host. With resolver v1, the dev-dependencies of workspace members
+    /// are always included (see [`LockResolver::resolve`]).
+    fn deps(
+        &self,
+        pkg_id: PackageId,
+        for_host: bool,
+    ) -> Vec<(PackageId, &'a Dependency, bool)> {
+        let resolve = self.resolve;
+
+        let decouples_host_deps = self.resolved.decouples_host_deps;
+
+        let dependencies =
+            resolve.package(pkg_id).map_or(&[][..], Package::dependencies);
+
+        resolve
+            .deps
+            .get(&pkg_id)
+            .into_iter()
+            .flatten()
+            .filter_map(|&(dep_pkg_id, dep_idx)| {
+                let dep = &dependencies[dep_idx];
+
+                if decouples_host_deps {
+                    let kind =
+                        resolve.unit_compile_kind(for_host || dep.is_build());
+                    if !resolve.target_data.dep_platform_activated(dep, kind) {
+                        return None;
+                    }
+                }
+
+                let dep_for_host = for_host
+                    || (decouples_host_deps
+                        && (dep.is_build()
+                            || resolve.is_proc_macro(dep_pkg_id)));
+
+                Some((dep_pkg_id, dep, dep_for_host))
+            })
+            .collect()
+    }
+}
+
+/// Returns the directories of the packages the given one depends on via
+/// `path` dependencies, including its dev-dependencies if `include_dev` is
+/// true.
+fn path_deps(
+    package: &Package,
+    include_dev: bool,
+) -> impl Iterator<Item = PathBuf> + '_ {
+    package
+        .dependencies()
+        .iter()
+        .filter(move |dep| include_dev || dep.kind() != DepKind::Development)
+        .filter_map(|dep| match dep.source() {
+            DependencySource::Path(path) => Some(path.clone()),
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::borrow::Cow;
+    use std::{env, process};
+
+    use super::*;
+
+    const CRATES_IO: &str =
+        "registry+https://github.com/rust-lang/crates.io-index";
+
+    /// A workspace written to a temporary directory, whose vendored packages
+    /// are in its `vendor` directory.
+    struct Fixture {
+        config: CargoConfig,
+        root: PathBuf,
+    }
+
+    impl Fixture {
+        fn new(files: &[(&str, &str)]) -> Self {
+            static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+            let root = env::temp_dir().join(format!(
+                "nix-jettison-lock-resolve-{}-{}",
+                process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed),
+            ));
+
+            for (path, contents) in files {
+                let path = root.join(path);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, contents).unwrap();
+            }
+
+            let config = CargoConfig::new(&root).unwrap();
+
+            Self { config, root }
+        }
+
+        fn try_resolve(
+            &self,
+            features: &[&str],
+        ) -> Result<WorkspaceResolve<'_>, ResolveBuildGraphError> {
+            let vendor_dir = self.root.join("vendor");
+
+            let args = ResolveBuildGraphArgs {
+                src: &self.root,
+                vendor_dir: VendorDir::Path(Cow::Borrowed(&vendor_dir)),
+                all_features: false,
+                compile_target: None,
+                dev_dependencies: false,
+                features: features.iter().map(|&f| f.to_owned()).collect(),
+                no_default_features: false,
+                package: None,
+                profile: CompactString::const_new("dev"),
+            };
+
+            let workspace = Workspace::new(&self.root, &self.config).unwrap();
+
+            let root_ids =
+                workspace.members().map(Package::package_id).collect();
+
+            let target_data = RustcTargetData::for_host(
+                "x86_64-unknown-linux-gnu",
+                &["unix", "target_os=\"linux\""],
+            );
+
+            WorkspaceResolve::with_target_data(
+                workspace,
+                root_ids,
+                &args,
+                target_data,
+                |dir| Ok(vendor_dir.join(dir)),
+            )
+        }
+
+        fn resolve(&self, features: &[&str]) -> WorkspaceResolve<'_> {
+            self.try_resolve(features).unwrap()
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    /// Returns the ID of the package in the graph with the given name.
+    fn pkg_id(resolve: &WorkspaceResolve, name: &str) -> PackageId {
+        *resolve.deps.keys().find(|pkg_id| pkg_id.name() == name).unwrap()
+    }
+
+    fn features(
+        resolve: &WorkspaceResolve,
+        name: &str,
+        for_host: bool,
+    ) -> Vec<&'static str> {
+        resolve
+            .features(pkg_id(resolve, name), for_host)
+            .into_iter()
+            .map(InternedString::as_str)
+            .collect()
+    }
+
+    /// Returns the name in the manifest and the package ID of every
+    /// dependency of the package with the given name.
+    fn deps(
+        resolve: &WorkspaceResolve,
+        name: &str,
+        for_host: bool,
+    ) -> Vec<(&'static str, PackageId)> {
+        resolve
+            .deps(pkg_id(resolve, name), for_host)
+            .map(|(dep_pkg_id, dep)| (dep.name_in_toml().as_str(), dep_pkg_id))
+            .collect()
+    }
+
+    fn vendored_manifest(name: &str, features: &[&str]) -> String {
+        let features = features
+            .iter()
+            .map(|feature| format!("{feature} = []\n"))
+            .collect::<String>();
+
+        format!(
+            "[package]\nname = \"{name}\"\nversion = \
+             \"1.0.0\"\n\n[features]\n{features}"
+        )
+    }
+
+    #[test]
+    fn weak_dependency_features() {
+        let a = vendored_manifest("a", &["x"]);
+
+        let fixture = Fixture::new(&[
+            (
+                "Cargo.toml",
+                r#"
+[package]
+name = "root"
+version = "0.1.0"
+edition = "2021"
+
+[dependencies]
+a = { version = "1", optional = true }
+
+[features]
+default = ["a?/x"]
+"#,
+            ),
+            ("src/lib.rs", ""),
+            (
+                "Cargo.lock",
+                &format!(
+                    r#"
+[[package]]
+name = "a"
+version = "1.0.0"
+source = "{CRATES_IO}"
+checksum = "0000"
+
+[[package]]
+name = "root"
+version = "0.1.0"
+dependencies = [
+ "a",
+]
+"#
+                ),
+            ),
+            ("vendor/a-1.0.0/Cargo.toml", &a),
+            ("vendor/a-1.0.0/src/lib.rs", ""),
+        ]);
+
+        // The weak feature alone doesn't activate the dependency.
+        let resolve = fixture.resolve(&[]);
+        assert_eq!(features(&resolve, "root", false), ["default"]);
+        assert!(deps(&resolve, "root", false).is_empty());
+
+        // Activating the dependency also enables the feature.
+        let resolve = fixture.resolve(&["a"]);
+        assert_eq!(features(&resolve, "root", false), ["a", "default"]);
+        assert_eq!(deps(&resolve, "root", false).len(), 1);
+        assert_eq!(features(&resolve, "a", false), ["x"]);
+    }
+
+    #[test]
+    fn resolver_v1_unifies_dev_dependency_features() {
+        let b = vendored_manifest("b", &["normal", "dev"]);
+
+        for (resolver, expected) in
+            [("1", &["dev", "normal"][..]), ("2", &["normal"][..])]
+        {
+            let manifest = format!(
+                r#"
+[package]
+name = "root"
+version = "0.1.0"
+edition = "2021"
+resolver = "{resolver}"
+
+[dependencies]
+b = {{ version = "1", features = ["normal"] }}
+
+[dev-dependencies]
+b = {{ version = "1", features = ["dev"] }}
+"#
+            );
+
+            let cargo_lock = format!(
+                r#"
+[[package]]
+name = "b"
+version = "1.0.0"
+source = "{CRATES_IO}"
+checksum = "0000"
+
+[[package]]
+name = "root"
+version = "0.1.0"
+dependencies = [
+ "b",
+]
+"#
+            );
+
+            let fixture = Fixture::new(&[
+                ("Cargo.toml", &manifest),
+                ("src/lib.rs", ""),
+                ("Cargo.lock", &cargo_lock),
+                ("vendor/b-1.0.0/Cargo.toml", &b),
+                ("vendor/b-1.0.0/src/lib.rs", ""),
+            ]);
+
+            // The dev-dependencies aren't compiled without `dev_dependencies`,
+            // but with resolver v1 their features are still unified.
+            let resolve = fixture.resolve(&[]);
+            assert!(!resolve.has_dev_units());
+            assert_eq!(features(&resolve, "b", false), expected);
+        }
+    }
+
+    #[test]
+    fn explicit_dep_features() {
+        let a = vendored_manifest("a", &["x"]);
+        let b = vendored_manifest("b", &["y"]);
+
+        let fixture = Fixture::new(&[
+            (
+                "Cargo.toml",
+                r#"
+[package]
+name = "root"
+version = "0.1.0"
+edition = "2021"
+
+[dependencies]
+a = { version = "1", optional = true }
+b = { version = "1", optional = true }
+
+[features]
+with-a = ["dep:a"]
+b-y = ["b/y"]
+"#,
+            ),
+            ("src/lib.rs", ""),
+            (
+                "Cargo.lock",
+                &format!(
+                    r#"
+[[package]]
+name = "a"
+version = "1.0.0"
+source = "{CRATES_IO}"
+checksum = "0000"
+
+[[package]]
+name = "b"
+version = "1.0.0"
+source = "{CRATES_IO}"
+checksum = "0000"
+
+[[package]]
+name = "root"
+version = "0.1.0"
+dependencies = [
+ "a",
+ "b",
+]
+"#
+                ),
+            ),
+            ("vendor/a-1.0.0/Cargo.toml", &a),
+            ("vendor/a-1.0.0/src/lib.rs", ""),
+            ("vendor/b-1.0.0/Cargo.toml", &b),
+            ("vendor/b-1.0.0/src/lib.rs", ""),
+        ]);
+
+        let resolve = fixture.resolve(&["with-a", "b-y"]);
+
+        // `dep:a` hides the implicit `a` feature, while `b/y` enables the
+        // implicit `b` feature together with the dependency.
+        assert_eq!(features(&resolve, "root", false), ["b", "b-y", "with-a"]);
+        assert_eq!(features(&resolve, "a", false), Vec::<&str>::new());
+        assert_eq!(features(&resolve, "b", false), ["y"]);
+
+        // Features can't enable `dep:` values from the command line.
+        assert!(matches!(
+            fixture.try_resolve(&["dep:a"]),
+            Err(ResolveBuildGraphError::ParseFeatures(_)),
+        ));
+    }
+
+    /// Returns the files of a workspace whose root package depends on `b`
+    /// both as a normal and as a build dependency with different features,
+    /// and on the proc-macro `m`, which depends on `b` too.
+    fn host_deps_files(resolver: &str) -> Vec<(&'static str, String)> {
+        let b = vendored_manifest("b", &["normal", "build", "macro"]);
+
+        vec![
+            (
+                "Cargo.toml",
+                format!(
+                    r#"
+[package]
+name = "root"
+version = "0.1.0"
+edition = "2021"
+resolver = "{resolver}"
+
+[dependencies]
+b = {{ version = "1", features = ["normal"] }}
+m = "1"
+
+[build-dependencies]
+b = {{ version = "1", features = ["build"] }}
+"#
+                ),
+            ),
+            ("src/lib.rs", String::new()),
+            ("build.rs", String::new()),
+            (
+                "Cargo.lock",
+                format!(
+                    r#"
+[[package]]
+name = "b"
+version = "1.0.0"
+source = "{CRATES_IO}"
+checksum = "0000"
+
+[[package]]
+name = "m"
+version = "1.0.0"
+source = "{CRATES_IO}"
+checksum = "0000"
+dependencies = [
+ "b",
+]
+
+[[package]]
+name = "root"
+version = "0.1.0"
+dependencies = [
+ "b",
+ "m",
+]
+"#
+                ),
+            ),
+            ("vendor/b-1.0.0/Cargo.toml", b),
+            ("vendor/b-1.0.0/src/lib.rs", String::new()),
+            (
+                "vendor/m-1.0.0/Cargo.toml",
+                r#"
+[package]
+name = "m"
+version = "1.0.0"
+
+[lib]
+proc-macro = true
+
+[dependencies]
+b = { version = "1", features = ["macro"] }
+"#
+                .to_owned(),
+            ),
+            ("vendor/m-1.0.0/src/lib.rs", String::new()),
+        ]
+    }
+
+    #[test]
+    fn resolver_v2_decouples_host_deps() {
+        let files = host_deps_files("2");
+        let files = files
+            .iter()
+            .map(|(path, contents)| (*path, &**contents))
+            .collect::<Vec<_>>();
+        let resolve = Fixture::new(&files).resolve(&[]);
+
+        assert_eq!(features(&resolve, "b", false), ["normal"]);
+        assert_eq!(features(&resolve, "b", true), ["build", "macro"]);
+        assert_eq!(features(&resolve, "m", true), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn resolver_v1_unifies_host_deps() {
+        let files = host_deps_files("1");
+        let files = files
+            .iter()
+            .map(|(path, contents)| (*path, &**contents))
+            .collect::<Vec<_>>();
+        let resolve = Fixture::new(&files).resolve(&[]);
+
+        let all = ["build", "macro", "normal"];
+        assert_eq!(features(&resolve, "b", false), all);
+        assert_eq!(features(&resolve, "b", true), all);
+    }
+
+    #[test]
+    fn dependencies_on_different_versions() {
+        let fixture = Fixture::new(&[
+            (
+                "Cargo.toml",
+                r#"
+[package]
+name = "root"
+version = "0.1.0"
+
+[dependencies]
+a = "1"
+a2 = { package = "a", version = "2" }
+"#,
+            ),
+            ("src/lib.rs", ""),
+            (
+                "Cargo.lock",
+                &format!(
+                    r#"
+[[package]]
+name = "a"
+version = "1.0.0"
+source = "{CRATES_IO}"
+checksum = "0000"
+
+[[package]]
+name = "a"
+version = "2.0.0"
+source = "{CRATES_IO}"
+checksum = "0000"
+
+[[package]]
+name = "root"
+version = "0.1.0"
+dependencies = [
+ "a 1.0.0",
+ "a 2.0.0",
+]
+"#
+                ),
+            ),
+            ("vendor/a-1.0.0/Cargo.toml", &vendored_manifest("a", &[])),
+            ("vendor/a-1.0.0/src/lib.rs", ""),
+            (
+                "vendor/a-2.0.0/Cargo.toml",
+                "[package]\nname = \"a\"\nversion = \"2.0.0\"\n",
+            ),
+            ("vendor/a-2.0.0/src/lib.rs", ""),
+        ]);
+
+        let resolve = fixture.resolve(&[]);
+
+        let versions = deps(&resolve, "root", false)
+            .into_iter()
+            .map(|(name, pkg_id)| (name, pkg_id.version().to_string()))
+            .collect::<BTreeSet<_>>();
+
+        assert_eq!(
+            versions,
+            BTreeSet::from([
+                ("a", "1.0.0".to_owned()),
+                ("a2", "2.0.0".to_owned()),
+            ]),
+        );
+    }
+
+    #[test]
+    fn patched_dependency() {
+        let fixture = Fixture::new(&[
+            (
+                "Cargo.toml",
+                r#"
+[package]
+name = "root"
+version = "0.1.0"
+
+[dependencies]
+a = "1"
+c = "1"
+
+[patch.crates-io]
+a = { path = "patched-a" }
+"#,
+            ),
+            ("src/lib.rs", ""),
+            (
+                "patched-a/Cargo.toml",
+                "[package]\nname = \"a\"\nversion = \"1.0.1\"\n",
+            ),
+            ("patched-a/src/lib.rs", ""),
+            (
+                "Cargo.lock",
+                &format!(
+                    r#"
+[[package]]
+name = "a"
+version = "1.0.1"
+
+[[package]]
+name = "c"
+version = "1.0.0"
+source = "{CRATES_IO}"
+checksum = "0000"
+dependencies = [
+ "a",
+]
+
+[[package]]
+name = "root"
+version = "0.1.0"
+dependencies = [
+ "a",
+ "c",
+]
+"#
+                ),
+            ),
+            (
+                "vendor/c-1.0.0/Cargo.toml",
+                r#"
+[package]
+name = "c"
+version = "1.0.0"
+
+[dependencies]
+a = "1"
+"#,
+            ),
+            ("vendor/c-1.0.0/src/lib.rs", ""),
+        ]);
+
+        let resolve = fixture.resolve(&[]);
+
+        // Both the root and the vendored package use the patched `a`.
+        for name in ["root", "c"] {
+            let (_, a) = deps(&resolve, name, false)
+                .into_iter()
+                .find(|(dep_name, _)| *dep_name == "a")
+                .unwrap();
+            assert!(a.source_id().is_path());
+            assert_eq!(a.version().to_string(), "1.0.1");
+        }
+    }
+
+    #[test]
+    fn replaced_dependency() {
+        let fixture = Fixture::new(&[
+            (
+                "Cargo.toml",
+                r#"
+[package]
+name = "root"
+version = "0.1.0"
+
+[dependencies]
+a = "1"
+
+[replace]
+"a:1.0.0" = { path = "replacement" }
+"#,
+            ),
+            ("src/lib.rs", ""),
+            (
+                "replacement/Cargo.toml",
+                "[package]\nname = \"a\"\nversion = \"1.0.0\"\n",
+            ),
+            ("replacement/src/lib.rs", ""),
+            (
+                "Cargo.lock",
+                &format!(
+                    r#"
+[[package]]
+name = "a"
+version = "1.0.0"
+source = "{CRATES_IO}"
+replace = "a 1.0.0"
+
+[[package]]
+name = "a"
+version = "1.0.0"
+
+[[package]]
+name = "root"
+version = "0.1.0"
+dependencies = [
+ "a 1.0.0 ({CRATES_IO})",
+]
+"#
+                ),
+            ),
+        ]);
+
+        let resolve = fixture.resolve(&[]);
+
+        let [(_, a)] = deps(&resolve, "root", false)[..] else {
+            panic!("expected a single dependency");
+        };
+        assert!(a.source_id().is_path());
+    }
+
+    #[test]
+    fn stale_lockfile() {
+        let fixture = Fixture::new(&[
+            (
+                "Cargo.toml",
+                r#"
+[package]
+name = "root"
+version = "0.1.0"
+
+[dependencies]
+a = "1"
+"#,
+            ),
+            ("src/lib.rs", ""),
+            (
+                "Cargo.lock",
+                r#"
+[[package]]
+name = "root"
+version = "0.1.0"
+"#,
+            ),
+        ]);
+
+        let Err(err) = fixture.try_resolve(&[]) else {
+            panic!("expected an error");
+        };
+        assert!(err.to_string().contains("doesn't lock it"), "{err}");
+
+        // A workspace member missing from the lockfile.
+        fs::write(fixture.root.join("Cargo.lock"), "").unwrap();
+
+        let Err(err) = fixture.try_resolve(&[]) else {
+            panic!("expected an error");
+        };
+        assert!(
+            err.to_string().contains("no entry in the Cargo.lock"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn inactive_platform_deps_are_not_read() {
+        let a = vendored_manifest("a", &[]);
+
+        // `b` isn't vendored, so resolving fails if its manifest is read.
+        let fixture = Fixture::new(&[
+            (
+                "Cargo.toml",
+                r#"
+[package]
+name = "root"
+version = "0.1.0"
+edition = "2021"
+
+[dependencies]
+a = "1"
+
+[target.'cfg(windows)'.dependencies]
+b = "1"
+"#,
+            ),
+            ("src/lib.rs", ""),
+            ("vendor/a-1.0.0/Cargo.toml", &a),
+            ("vendor/a-1.0.0/src/lib.rs", ""),
+            (
+                "Cargo.lock",
+                &format!(
+                    r#"
+[[package]]
+name = "a"
+version = "1.0.0"
+source = "{CRATES_IO}"
+
+[[package]]
+name = "b"
+version = "1.0.0"
+source = "{CRATES_IO}"
+
+[[package]]
+name = "root"
+version = "0.1.0"
+dependencies = [
+ "a",
+ "b",
+]
+"#
+                ),
+            ),
+        ]);
+
+        let resolve = fixture.resolve(&[]);
+
+        let [("a", _)] = deps(&resolve, "root", false)[..] else {
+            panic!("expected a single dependency on `a`");
+        };
+        assert!(resolve.packages.keys().all(|pkg_id| pkg_id.name() != "b"));
+    }
+}
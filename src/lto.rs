@@ -0,0 +1,335 @@
+use compact_str::{CompactString, format_compact};
+
+use crate::build_graph::LibraryFormat;
+use crate::cargo_types::{InternedString, profiles};
+
+/// How a single crate takes part in link-time optimization.
+///
+/// Cargo doesn't simply forward the profile's `lto` setting to every `rustc`
+/// invocation. Instead, it walks the unit graph from the final artifacts down
+/// to their dependencies: binaries (and other crate types producing a linked
+/// artifact) run LTO, while the rlibs they link against only need to contain
+/// LLVM bitcode, object code, or both. Since every crate is compiled in its
+/// own derivation, we mirror that logic (see `cargo::core::compiler::lto`)
+/// when building the [`BuildGraph`](crate::build_graph::BuildGraph).
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Lto {
+    /// LTO is run for this crate, with the given `-C lto` value (`None`
+    /// meaning plain `-C lto`).
+    Run(Option<InternedString>),
+
+    /// LTO has been explicitly disabled for this crate and all its
+    /// dependencies.
+    Off,
+
+    /// This crate only needs to contain bitcode, since its only dependents
+    /// run LTO.
+    OnlyBitcode,
+
+    /// This crate needs to contain both object code and bitcode, since some
+    /// of its dependents run LTO while others link against it normally.
+    ObjectAndBitcode,
+
+    /// This crate only needs to contain object code.
+    OnlyObject,
+}
+
+/// The properties of a crate's types that are relevant to computing its LTO
+/// requirements.
+#[derive(Copy, Clone)]
+pub(crate) struct LtoCrateTypes {
+    /// Whether all the crate types can run LTO.
+    all_can_lto: bool,
+
+    /// Whether all the crate types are `dylib`s.
+    all_dylib: bool,
+
+    /// Whether at least one of the crate types needs object code.
+    needs_object: bool,
+}
+
+impl Lto {
+    /// Returns the LTO requirements of a crate that's one of the roots of the
+    /// build graph, i.e. one that was explicitly requested by the user.
+    pub(crate) fn for_root(
+        profile_lto: profiles::Lto,
+        crate_types: LtoCrateTypes,
+        is_for_host: bool,
+    ) -> Self {
+        let root_lto = match profile_lto {
+            // LTO not requested, no need for bitcode.
+            profiles::Lto::Bool(false) => Self::OnlyObject,
+            profiles::Lto::Off => Self::Off,
+            _ if is_for_host => Self::OnlyObject,
+            _ if crate_types.needs_object => {
+                Self::when_needs_object(crate_types)
+            },
+            // This may or may not participate in LTO, so start with the
+            // minimum requirements. These can be expanded by merging them
+            // with the requirements coming from the crate's dependents.
+            _ => Self::OnlyBitcode,
+        };
+
+        root_lto.for_dependency(profile_lto, crate_types, is_for_host)
+    }
+
+    /// Returns the LTO requirements of a crate whose dependent has `self` as
+    /// its requirements.
+    pub(crate) fn for_dependency(
+        self,
+        profile_lto: profiles::Lto,
+        crate_types: LtoCrateTypes,
+        is_for_host: bool,
+    ) -> Self {
+        if is_for_host {
+            // LTO is only really wanted for the final artifacts, not for build
+            // scripts or proc-macros.
+            Self::OnlyObject
+        } else if crate_types.all_can_lto {
+            // This ignores the requirements of the dependent because this
+            // crate isn't linked into it.
+            match profile_lto {
+                profiles::Lto::Named(name) => Self::Run(Some(name)),
+                profiles::Lto::Off => Self::Off,
+                profiles::Lto::Bool(true) => Self::Run(None),
+                profiles::Lto::Bool(false) => Self::OnlyObject,
+            }
+        } else {
+            match (self, crate_types.needs_object) {
+                // An rlib whose dependent is running LTO only needs bitcode.
+                (Self::Run(_), false) => Self::OnlyBitcode,
+                (Self::Run(_) | Self::OnlyBitcode, true) => {
+                    Self::when_needs_object(crate_types)
+                },
+                (Self::Off, _) => Self::Off,
+                // If this doesn't have any requirements, or the requirements
+                // are already satisfied, then stay with the dependent's.
+                (_, false)
+                | (Self::OnlyObject | Self::ObjectAndBitcode, true) => self,
+            }
+        }
+    }
+
+    /// Merges the requirements coming from two different dependents of the
+    /// same crate.
+    pub(crate) fn merge(self, other: Self) -> Self {
+        match (self, other) {
+            (Self::OnlyBitcode, Self::OnlyBitcode) => Self::OnlyBitcode,
+            (Self::OnlyObject, Self::OnlyObject) => Self::OnlyObject,
+            // Once we're running LTO we keep running LTO.
+            (Self::Run(name), _) | (_, Self::Run(name)) => Self::Run(name),
+            // Off means off!
+            (Self::Off, _) | (_, Self::Off) => Self::Off,
+            (Self::ObjectAndBitcode, _)
+            | (_, Self::ObjectAndBitcode)
+            | (Self::OnlyObject, Self::OnlyBitcode)
+            | (Self::OnlyBitcode, Self::OnlyObject) => Self::ObjectAndBitcode,
+        }
+    }
+
+    /// Returns the codegen options (i.e. the values of `-C`) to pass to
+    /// `rustc`.
+    pub(crate) fn codegen_opts(self) -> impl Iterator<Item = CompactString> {
+        let (first, second) = match self {
+            Self::Run(None) => (Some(CompactString::const_new("lto")), None),
+            Self::Run(Some(name)) => {
+                (Some(format_compact!("lto={name}")), None)
+            },
+            Self::Off => (
+                Some(CompactString::const_new("lto=off")),
+                Some(CompactString::const_new("embed-bitcode=no")),
+            ),
+            // This is rustc's default.
+            Self::ObjectAndBitcode => (None, None),
+            Self::OnlyBitcode => {
+                (Some(CompactString::const_new("linker-plugin-lto")), None)
+            },
+            Self::OnlyObject => {
+                (Some(CompactString::const_new("embed-bitcode=no")), None)
+            },
+        };
+
+        first.into_iter().chain(second)
+    }
+
+    pub(crate) fn name(self) -> CompactString {
+        match self {
+            Self::Run(None) => CompactString::const_new("run"),
+            Self::Run(Some(name)) => format_compact!("run={name}"),
+            Self::Off => CompactString::const_new("off"),
+            Self::OnlyBitcode => CompactString::const_new("only-bitcode"),
+            Self::ObjectAndBitcode => {
+                CompactString::const_new("object-and-bitcode")
+            },
+            Self::OnlyObject => CompactString::const_new("only-object"),
+        }
+    }
+
+    fn when_needs_object(crate_types: LtoCrateTypes) -> Self {
+        if crate_types.all_dylib {
+            // rustc doesn't support LTO with dylibs, so bitcode is not needed.
+            Self::OnlyObject
+        } else {
+            // Mixed rlib with a dylib or cdylib whose dependent is running
+            // LTO. This needs both bitcode and object code.
+            Self::ObjectAndBitcode
+        }
+    }
+}
+
+impl LtoCrateTypes {
+    /// The crate types of binaries and test harnesses.
+    pub(crate) const BIN: Self =
+        Self { all_can_lto: true, all_dylib: false, needs_object: true };
+
+    /// Returns the crate types of a library with the given formats.
+    pub(crate) fn library(formats: &[LibraryFormat]) -> Self {
+        // Like Cargo's, Rust dylibs can't run LTO themselves, since they can
+        // be linked against other Rust crates.
+        let can_lto = |format: &LibraryFormat| {
+            matches!(format, LibraryFormat::Cdylib | LibraryFormat::Staticlib)
+        };
+
+        let is_dynamic = |format: &LibraryFormat| {
+            matches!(
+                format,
+                LibraryFormat::Cdylib
+                    | LibraryFormat::Dylib
+                    | LibraryFormat::ProcMacro
+            )
+        };
+
+        Self {
+            all_can_lto: formats.iter().all(can_lto),
+            all_dylib: formats.iter().all(|f| *f == LibraryFormat::Dylib),
+            needs_object: formats.iter().any(|f| can_lto(f) || is_dynamic(f)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRUE: profiles::Lto = profiles::Lto::Bool(true);
+    const FALSE: profiles::Lto = profiles::Lto::Bool(false);
+    const OFF: profiles::Lto = profiles::Lto::Off;
+
+    fn thin() -> profiles::Lto {
+        profiles::Lto::Named("thin".into())
+    }
+
+    fn rlib() -> LtoCrateTypes {
+        LtoCrateTypes::library(&[LibraryFormat::Lib])
+    }
+
+    /// Returns the `-C` flags passed to `rustc`.
+    fn opts(lto: Lto) -> Vec<CompactString> {
+        lto.codegen_opts().collect()
+    }
+
+    /// Returns the flags of a binary and of an rlib it depends on, which
+    /// itself depends on another rlib.
+    fn bin_with_rlib_deps(
+        profile_lto: profiles::Lto,
+    ) -> [Vec<CompactString>; 3] {
+        let bin = Lto::for_root(profile_lto, LtoCrateTypes::BIN, false);
+        let dep = bin.for_dependency(profile_lto, rlib(), false);
+        let transitive_dep = dep.for_dependency(profile_lto, rlib(), false);
+        [opts(bin), opts(dep), opts(transitive_dep)]
+    }
+
+    #[test]
+    fn bin_root_with_lto_true() {
+        assert_eq!(
+            bin_with_rlib_deps(TRUE),
+            [vec!["lto"], vec!["linker-plugin-lto"], vec!["linker-plugin-lto"]]
+        );
+    }
+
+    #[test]
+    fn bin_root_with_thin_lto() {
+        assert_eq!(
+            bin_with_rlib_deps(thin()),
+            [
+                vec!["lto=thin"],
+                vec!["linker-plugin-lto"],
+                vec!["linker-plugin-lto"],
+            ]
+        );
+    }
+
+    #[test]
+    fn bin_root_with_lto_off() {
+        let off = vec!["lto=off", "embed-bitcode=no"];
+        assert_eq!(bin_with_rlib_deps(OFF), [off.clone(), off.clone(), off]);
+    }
+
+    #[test]
+    fn bin_root_without_lto() {
+        let no_bitcode = vec!["embed-bitcode=no"];
+        assert_eq!(
+            bin_with_rlib_deps(FALSE),
+            [no_bitcode.clone(), no_bitcode.clone(), no_bitcode]
+        );
+    }
+
+    #[test]
+    fn cdylib_root() {
+        let cdylib = LtoCrateTypes::library(&[LibraryFormat::Cdylib]);
+        let root = Lto::for_root(TRUE, cdylib, false);
+        let dep = root.for_dependency(TRUE, rlib(), false);
+        assert_eq!(opts(root), ["lto"]);
+        assert_eq!(opts(dep), ["linker-plugin-lto"]);
+
+        let root = Lto::for_root(thin(), cdylib, false);
+        assert_eq!(opts(root), ["lto=thin"]);
+
+        let root = Lto::for_root(OFF, cdylib, false);
+        assert_eq!(opts(root), ["lto=off", "embed-bitcode=no"]);
+    }
+
+    #[test]
+    fn cdylib_and_rlib_root() {
+        // The rlib can be linked into other crates, so the library can't run
+        // LTO itself, and it needs both object code and bitcode.
+        let formats = [LibraryFormat::Lib, LibraryFormat::Cdylib];
+        let crate_types = LtoCrateTypes::library(&formats);
+        let root = Lto::for_root(TRUE, crate_types, false);
+        let dep = root.for_dependency(TRUE, rlib(), false);
+        assert!(opts(root).is_empty());
+        assert!(opts(dep).is_empty());
+    }
+
+    #[test]
+    fn dylib_in_graph() {
+        // rustc doesn't support LTO with dylibs, so neither the dylib nor its
+        // dependencies need bitcode.
+        let dylib = LtoCrateTypes::library(&[LibraryFormat::Dylib]);
+        let bin = Lto::for_root(TRUE, LtoCrateTypes::BIN, false);
+        let dylib_lto = bin.for_dependency(TRUE, dylib, false);
+        let dep = dylib_lto.for_dependency(TRUE, rlib(), false);
+        assert_eq!(opts(bin), ["lto"]);
+        assert_eq!(opts(dylib_lto), ["embed-bitcode=no"]);
+        assert_eq!(opts(dep), ["embed-bitcode=no"]);
+
+        // As a root, a dylib doesn't run LTO either.
+        let root = Lto::for_root(TRUE, dylib, false);
+        assert_eq!(opts(root), ["embed-bitcode=no"]);
+    }
+
+    #[test]
+    fn proc_macro_dep() {
+        let proc_macro = LtoCrateTypes::library(&[LibraryFormat::ProcMacro]);
+        let bin = Lto::for_root(TRUE, LtoCrateTypes::BIN, false);
+        let macro_lto = bin.for_dependency(TRUE, proc_macro, true);
+        let macro_dep = macro_lto.for_dependency(TRUE, rlib(), true);
+        assert_eq!(opts(macro_lto), ["embed-bitcode=no"]);
+        assert_eq!(opts(macro_dep), ["embed-bitcode=no"]);
+
+        // An rlib used both by the binary and by the proc-macro (when they
+        // share a node) needs both bitcode and object code.
+        let dep = bin.for_dependency(TRUE, rlib(), false);
+        assert!(opts(dep.merge(macro_dep)).is_empty());
+    }
+}
@@ -1,21 +1,18 @@
 use core::fmt::Write;
 use core::iter;
 use std::borrow::Cow;
-use std::env::consts::DLL_EXTENSION;
 
-use cargo::core::Edition;
-use cargo::core::compiler::CompileTarget;
+use cargo_util_schemas::manifest::TomlDebugInfo;
 use compact_str::{CompactString, ToCompactString, format_compact};
-use either::Either;
-use indoc::{formatdoc, writedoc};
+use indoc::{formatdoc, indoc, writedoc};
 use nix_bindings::prelude::*;
-use sha2::Digest;
 
 use crate::build_graph::{
     BinaryCrate,
     BuildGraphNode,
     BuildOpts,
     BuildScript,
+    ConfigEnvVar,
     DependencyRename,
     DependencyRenames,
     LibraryCrate,
@@ -23,17 +20,46 @@ use crate::build_graph::{
     PackageAttrs,
     PackageSource,
     RenameWithVersion,
+    TestCrate,
+    TestKind,
+    Tests,
     edition_as_str,
 };
 use crate::build_package::BuildPackageArgs;
+use crate::cargo_types::profiles::{
+    PanicStrategy,
+    Profile,
+    ProfileRoot,
+    StripInner,
+};
+use crate::cargo_types::{CompileKind, CompileTarget, Edition};
 use crate::vendor_deps::VendoredSources;
 
+/// The name of the file in every `-deps` derivation listing the `-L
+/// dependency=` search paths of all the transitive Rust dependencies, in the
+/// format of `rustc`'s argument files.
+const DEPENDENCY_SEARCH_PATHS: &str = "dependency-search-paths";
+
+/// The value of `CARGO_TARGET_TMPDIR` when compiling integration tests, which
+/// they can use to store temporary data while running.
+const CARGO_TARGET_TMPDIR: &str = "$NIX_BUILD_TOP/target/tmp";
+
 pub(crate) enum DerivationType<'graph> {
-    BuildScript(&'graph BuildScript),
+    BuildScript {
+        build_script: &'graph BuildScript,
+        /// The derivations for the build scripts of the package's direct
+        /// dependencies that set the `links` manifest key, whose metadata is
+        /// passed to this build script.
+        links_dependencies: Vec<NixDerivation<'static>>,
+    },
     Library {
         /// The derivation for the package's build script, if it has one.
         build_script: Option<NixDerivation<'static>>,
         library: &'graph LibraryCrate,
+        /// Whether to only emit the library's metadata (i.e. its `.rmeta`
+        /// file), which is enough to compile the dependents that aren't
+        /// linked when pipelining.
+        metadata_only: bool,
     },
     Binaries {
         /// The derivation for the package's build script, if it has one.
@@ -42,46 +68,109 @@ pub(crate) enum DerivationType<'graph> {
         library: Option<NixDerivation<'static>>,
         binaries: &'graph [BinaryCrate],
     },
+    Tests {
+        /// The derivation for the package's build script, if it has one.
+        build_script: Option<NixDerivation<'static>>,
+        /// The derivation for the package's library crate, if it has one.
+        library: Option<NixDerivation<'static>>,
+        /// The derivation for the package's binary crates, if it has any.
+        binaries: Option<NixDerivation<'static>>,
+        tests: &'graph Tests,
+    },
 }
 
 #[derive(Clone)]
 pub(crate) struct GlobalArgs<'args, 'lock, 'builtins> {
+    /// The arguments for the crates compiled for the build platform (what
+    /// Cargo calls the host), i.e. build scripts, proc-macros and their
+    /// dependencies.
+    ///
+    /// This is only set when cross-compiling, otherwise those crates use the
+    /// [`target_platform`](Self::target_platform).
+    pub(crate) build_platform: Option<PlatformArgs<'args>>,
+
     /// The compilation `--target` to pass to `rustc`, if any.
     ///
     /// This should only be set when cross-compiling.
     pub(crate) compile_target: Option<CompileTarget>,
 
+    /// The linker to pass to `rustc` (via `-C linker`) when compiling for the
+    /// [`compile_target`](Self::compile_target), i.e. the prefixed `cc` of
+    /// the target platform's C compiler.
+    ///
+    /// This is only set when cross-compiling.
+    pub(crate) linker: Option<CompactString>,
+
+    /// The `builtins.path` function.
+    pub(crate) mk_path: NixLambda<'builtins>,
+
     /// The
-    /// [`BuildPackageArgs::crate_overrides`](crate::build_package::BuildPackageArgs::crate_overrides) field.
+    /// [`BuildPackageArgs::pipelined`](crate::build_package::BuildPackageArgs::pipelined) field.
+    pub(crate) pipelined: bool,
+
+    /// The derivation for the `parse-build-script-output` executable.
+    pub(crate) parse_build_script_output: NixDerivation<'args>,
+
+    /// The `rustc` derivation to include in the derivation's
+    /// `nativeBuildInputs`, which runs on the build platform.
+    pub(crate) rustc: NixDerivation<'args>,
+
+    /// The
+    /// [`BuildPackageArgs::rustflags`](crate::build_package::BuildPackageArgs::rustflags) field.
+    pub(crate) rustflags: Vec<CompactString>,
+
+    /// The
+    /// [`BuildPackageArgs::rustflags_members_only`](crate::build_package::BuildPackageArgs::rustflags_members_only) field.
+    pub(crate) rustflags_members_only: bool,
+
+    /// The arguments for the crates compiled for the platform the package is
+    /// built for.
+    pub(crate) target_platform: PlatformArgs<'args>,
+
+    /// TODO: docs.
+    pub(crate) vendored_sources: &'args VendoredSources<'lock>,
+}
+
+/// The arguments that depend on the platform a crate is compiled for, which
+/// are taken from the package set of that platform.
+#[derive(Clone)]
+pub(crate) struct PlatformArgs<'args> {
+    /// The
+    /// [`BuildPackageArgs::crate_overrides`](crate::build_package::BuildPackageArgs::crate_overrides) field,
+    /// evaluated against the platform's package set.
     pub(crate) crate_overrides: Option<NixAttrset<'args>>,
 
+    /// The extension of the dynamic libraries compiled for the platform
+    /// (without the leading dot), taken from
+    /// `stdenv.hostPlatform.extensions.sharedLibrary`.
+    pub(crate) dylib_extension: CompactString,
+
+    /// The prefix of the file names of the dynamic libraries compiled for the
+    /// platform, which is `lib` everywhere but on Windows.
+    pub(crate) dylib_prefix: &'static str,
+
     /// The `pkgs.lib.getLib` function.
     pub(crate) get_lib: NixLambda<'args>,
 
     /// The
-    /// [`BuildPackageArgs::global_overrides`](crate::build_package::BuildPackageArgs::global_overrides) field.
+    /// [`BuildPackageArgs::global_overrides`](crate::build_package::BuildPackageArgs::global_overrides) field,
+    /// evaluated against the platform's package set.
     pub(crate) global_overrides: Option<NixAttrset<'args>>,
 
     /// The `pkgs.stdenv.mkDerivation` function.
     pub(crate) mk_derivation: NixLambda<'args>,
 
-    /// The `builtins.path` function.
-    pub(crate) mk_path: NixLambda<'builtins>,
-
-    /// The derivation for the `parse-build-script-output` shell script.
-    pub(crate) parse_build_script_output: NixDerivation<'args>,
-
-    /// Whether the node should be built in release mode.
-    pub(crate) release: bool,
-
-    /// The `rustc` derivation to include in the derivation's `buildInputs`.
-    pub(crate) rustc: NixDerivation<'args>,
-
     /// A handle to Nixpkgs's standard build environment.
     pub(crate) stdenv: NixAttrset<'args>,
+}
 
-    /// TODO: docs.
-    pub(crate) vendored_sources: &'args VendoredSources<'lock>,
+/// The value of the `crateOverrides` and `globalOverrides` arguments, which
+/// can either be given directly or as a function of the package set the
+/// crate is compiled with.
+#[derive(Copy, Clone)]
+pub(crate) enum Overrides<'a> {
+    Attrset(NixAttrset<'a>),
+    Function(NixLambda<'a>),
 }
 
 struct Crate<'a> {
@@ -96,14 +185,15 @@ struct Crate<'a> {
 enum CrateType<'a> {
     Binary,
     BuildScript,
-    Library { formats: &'a [LibraryFormat] },
+    Library { formats: &'a [LibraryFormat], metadata_only: bool },
+    Test { harness: bool, is_proc_macro: bool, kind: TestKind },
 }
 
 #[expect(clippy::too_many_lines)]
 pub(crate) fn make_deps<'dep>(
     package: &PackageAttrs,
     direct_deps: impl ExactSizeIterator<Item = NixDerivation<'dep>> + Clone,
-    args: &GlobalArgs,
+    platform: &PlatformArgs,
     ctx: &mut Context,
 ) -> Result<NixDerivation<'static>> {
     let mut install_phase = formatdoc!(
@@ -112,12 +202,19 @@ pub(crate) fn make_deps<'dep>(
             mkdir -p $out
             mkdir -p $out/native
             shopt -s nullglob
+            touch $TMPDIR/search-paths
         "
     )
     .to_owned();
 
-    // For every direct dependency, symlink all its native dependencies,
-    // pure-Rust dependencies, and its output .rlib/.so/.dylib files (if any).
+    // Instead of collecting the libraries of the whole transitive closure in
+    // a single directory, which would make the size of every `-deps`
+    // derivation linear in the number of transitive dependencies, we record
+    // one `-L dependency=` search path per transitive dependency in an
+    // argument file that's passed to `rustc` (the libraries' output paths in
+    // the file also keep them in the closure). For every direct dependency,
+    // we add its own output path and the search paths of its dependencies,
+    // and symlink all its native dependencies.
     for rust_dep in direct_deps.clone() {
         let out_path = rust_dep.out_path_as_string(ctx)?;
 
@@ -125,19 +222,23 @@ pub(crate) fn make_deps<'dep>(
             &mut install_phase,
             r#"
                 cp -rn {out_path}/deps/native/. $out/native
-                cp -rn {out_path}/deps/. $out
-                for dep in {out_path}/lib*.{{rlib,{DLL_EXTENSION}}}; do
-                  ln -sf $dep $out
-                done
+                echo "-Ldependency={out_path}" >> $TMPDIR/search-paths
+                cat {out_path}/deps/{DEPENDENCY_SEARCH_PATHS} >> $TMPDIR/search-paths
             "#,
         )
         .expect("writing to string can't fail");
     }
 
+    writeln!(
+        &mut install_phase,
+        "sort -u $TMPDIR/search-paths > $out/{DEPENDENCY_SEARCH_PATHS}"
+    )
+    .expect("writing to string can't fail");
+
     let build_inputs = apply_overrides(
         package,
-        args.global_overrides,
-        args.crate_overrides,
+        platform.global_overrides,
+        platform.crate_overrides,
         ctx,
     )?
     .map(|attrs| attrs.get_opt::<NixList>(c"buildInputs", ctx))
@@ -150,7 +251,7 @@ pub(crate) fn make_deps<'dep>(
         for idx in 0..list.len() {
             let build_input = list.get::<NixDerivation>(idx, ctx)?;
 
-            let native_dep = args
+            let native_dep = platform
                 .get_lib
                 .call(build_input, ctx)?
                 .force_into::<NixDerivation>(ctx)?;
@@ -188,13 +289,13 @@ pub(crate) fn make_deps<'dep>(
         phases: [ c"installPhase" ],
     };
 
-    args.mk_derivation.call(attrs, ctx)?.force_into(ctx)
+    platform.mk_derivation.call(attrs, ctx)?.force_into(ctx)
 }
 
 #[expect(clippy::too_many_arguments)]
 pub(crate) fn make_derivation<'a, Deps>(
     r#type: DerivationType<'a>,
-    node: &BuildGraphNode,
+    node: &'a BuildGraphNode,
     deps: NixDerivation<'a>,
     direct_deps: Deps,
     args: &'a GlobalArgs,
@@ -204,7 +305,8 @@ where
     Deps: ExactSizeIterator<Item = (&'a BuildGraphNode, NixDerivation<'a>)>
         + Clone,
 {
-    args.mk_derivation
+    args.platform(node.is_for_host)
+        .mk_derivation
         .call(
             make_derivation_args(r#type, node, deps, direct_deps, args, ctx)?,
             ctx,
@@ -215,7 +317,7 @@ where
 #[expect(clippy::too_many_arguments)]
 fn make_derivation_args<'a, Deps>(
     r#type: DerivationType<'a>,
-    node: &BuildGraphNode,
+    node: &'a BuildGraphNode,
     deps: NixDerivation<'a>,
     direct_deps: Deps,
     args: &'a GlobalArgs,
@@ -246,37 +348,65 @@ where
 
     let build_script_drv = r#type.build_script_drv();
 
+    // Build scripts are run in the environment of the platform their package
+    // is compiled for (so that e.g. the `cc` crate finds the right C
+    // compiler), even though they're compiled for the build platform.
+    let platform = args.platform(node.is_for_host);
+
     let configure_phase = configure_phase(
         &node.package_attrs,
         &version,
         build_script_drv,
-        r#type.is_library(),
-        args.release,
+        node.build_script_run_profile(),
         deps,
-        args.stdenv,
+        &node.config_env,
+        platform.stdenv,
         ctx,
     )?;
 
-    let build_phase = build_phase(
-        &r#type,
-        node,
-        &version,
-        direct_deps.clone(),
-        args.release,
-        args.compile_target.as_ref(),
-        ctx,
-    )?;
+    let build_phase =
+        build_phase(&r#type, node, direct_deps.clone(), args, ctx)?;
 
-    let install_phase =
-        install_phase(&node.package_attrs, &version, r#type.is_build_script());
+    let install_phase = install_phase(&r#type, node, platform, ctx)?;
+
+    let links_dependencies = r#type.links_dependencies().to_vec();
+
+    let main_program = r#type.main_program(&node.package_attrs);
+
+    let check_phase = match &r#type {
+        DerivationType::Tests { build_script, library, tests, .. } => {
+            Some(check_phase(
+                tests,
+                node,
+                *build_script,
+                library.map(|library| (node, library)),
+                direct_deps.clone(),
+                args,
+                ctx,
+            )?)
+        },
+        _ => None,
+    };
 
     let overrides = apply_overrides(
         &node.package_attrs,
-        args.global_overrides,
-        args.crate_overrides,
+        platform.global_overrides,
+        platform.crate_overrides,
         ctx,
     )?;
 
+    // When cross-compiling, the target platform's environment only provides
+    // a prefixed C compiler, so we also need the build platform's one to link
+    // the build script.
+    let build_cc = match (&r#type, &args.build_platform) {
+        (DerivationType::BuildScript { .. }, Some(build_platform))
+            if !node.is_for_host =>
+        {
+            Some(build_platform.stdenv.get::<NixDerivation>(c"cc", ctx)?)
+        },
+        _ => None,
+    };
+
     let extra_native_build_inputs = overrides
         .map(|attrs| attrs.get_opt::<NixList>(c"nativeBuildInputs", ctx))
         .transpose()?
@@ -298,6 +428,13 @@ where
         stripExclude: [ c"*.rlib" ],
         version,
     }
+    .merge(main_program.map(|main_program| {
+        attrset! { meta: attrset! { mainProgram: main_program } }
+    }))
+    .merge(check_phase.map(|check_phase| {
+        attrset! { doCheck: true, checkPhase: check_phase }
+    }))
+    .merge(build_cc.map(|cc| attrset! { depsBuildBuild: [cc] }))
     .merge(overrides)
     .merge(attrset! {
         nativeBuildInputs: [args.parse_build_script_output, args.rustc]
@@ -306,6 +443,8 @@ where
         buildInputs: build_script_drv
             .into_iter()
             .chain_exact(r#type.library_drv())
+            .chain_exact(r#type.binaries_drv())
+            .chain_exact(links_dependencies)
             .chain_exact(iter::once(deps.clone()))
             .chain_exact(direct_deps.clone().map(|(_node, drv)| drv))
             .concat(extra_build_inputs.into_list())
@@ -314,44 +453,110 @@ where
 }
 
 #[expect(clippy::too_many_arguments)]
+#[expect(clippy::too_many_lines)]
 fn build_phase<'dep, Deps>(
-    r#type: &DerivationType,
-    node: &BuildGraphNode,
-    version: &str,
+    r#type: &DerivationType<'dep>,
+    node: &'dep BuildGraphNode,
     direct_deps: Deps,
-    is_release: bool,
-    target: Option<&CompileTarget>,
+    args: &GlobalArgs,
     ctx: &mut Context,
 ) -> Result<String>
 where
     Deps: Iterator<Item = (&'dep BuildGraphNode, NixDerivation<'dep>)> + Clone,
 {
     let crates = match r#type {
-        DerivationType::Binaries { binaries, .. } => Either::Right(
-            binaries
+        DerivationType::Binaries { binaries, .. } => binaries
+            .iter()
+            .map(|bin| Crate::from_binary(bin, &node.dependency_renames))
+            .collect(),
+        DerivationType::Library { library, metadata_only, .. } => {
+            vec![Crate::from_library(
+                library,
+                &node.dependency_renames,
+                *metadata_only,
+            )]
+        },
+        DerivationType::BuildScript { build_script, .. } => {
+            vec![Crate::from_build_script(build_script)]
+        },
+        DerivationType::Tests { tests, .. } => {
+            let is_proc_macro =
+                node.library.as_ref().is_some_and(LibraryCrate::is_proc_macro);
+            tests
+                .targets
                 .iter()
-                .map(|bin| Crate::from_binary(bin, &node.dependency_renames)),
-        ),
-        DerivationType::Library { library, .. } => Either::Left(iter::once(
-            Crate::from_library(library, &node.dependency_renames),
-        )),
-        DerivationType::BuildScript(build_script) => {
-            Either::Left(iter::once(Crate::from_build_script(build_script)))
+                .map(|test| {
+                    Crate::from_test(
+                        test,
+                        &tests.dependency_renames,
+                        is_proc_macro,
+                    )
+                })
+                .collect::<Vec<_>>()
         },
     };
 
+    // Binaries and tests can link against the package's own library.
+    let own_library = r#type.library_drv().map(|library| (node, library));
+
+    // Integration tests can find the package's binaries via the
+    // `CARGO_BIN_EXE_<name>` environment variables.
+    let mut bin_exes_env = String::new();
+
+    if let DerivationType::Tests { binaries: Some(binaries), .. } = r#type {
+        let out_path = binaries.out_path_as_string(ctx)?;
+        for binary in &node.binaries {
+            let name = &binary.name;
+            write!(
+                &mut bin_exes_env,
+                " 'CARGO_BIN_EXE_{name}={out_path}/bin/{name}'"
+            )
+            .expect("writing to string can't fail");
+        }
+    }
+
+    let build_script_out_path = r#type
+        .build_script_drv()
+        .map(|build_script| build_script.out_path_as_string(ctx))
+        .transpose()?;
+
     let mut build_phase = "runHook preBuild\n".to_owned();
 
     for cr8 in crates {
-        build_phase.push_str("\nrustc");
+        // Set the environment variables that Cargo sets when compiling each
+        // crate. We can't `export` them because they differ between the
+        // crates in the same derivation, and because binary names are
+        // allowed to contain dashes.
+        write!(&mut build_phase, "\nenv CARGO_CRATE_NAME={}", cr8.name_arg())
+            .expect("writing to string can't fail");
+
+        if node.is_primary_package {
+            build_phase.push_str(" CARGO_PRIMARY_PACKAGE=1");
+        }
+
+        if cr8.r#type.is_executable() {
+            write!(&mut build_phase, " 'CARGO_BIN_NAME={}'", cr8.name)
+                .expect("writing to string can't fail");
+        }
+
+        if let CrateType::Test { kind: TestKind::Integration, .. } = cr8.r#type
+        {
+            build_phase.push_str(" CARGO_TARGET_TMPDIR=");
+            build_phase.push_str(CARGO_TARGET_TMPDIR);
+            build_phase.push_str(&bin_exes_env);
+        }
+
+        build_phase.push_str(" rustc");
+
+        let links_own_library = cr8.r#type.links_own_library();
 
         for rustc_arg in build_rustc_args(
             &cr8,
-            version,
-            direct_deps.clone(),
+            direct_deps
+                .clone()
+                .chain(own_library.filter(|_| links_own_library)),
             &node.package_attrs.features,
-            is_release,
-            target,
+            args,
             node.package_attrs.edition,
             ctx,
         ) {
@@ -359,11 +564,20 @@ where
             build_phase.push_str(rustc_arg.as_ref());
         }
 
-        build_phase
-            .push_str(" -L dependency=$out/deps -L native=$out/deps/native");
+        write!(
+            &mut build_phase,
+            " @$out/deps/{DEPENDENCY_SEARCH_PATHS} ${{NATIVE_SEARCH_PATHS:-}}"
+        )
+        .expect("writing to string can't fail");
 
-        // Append any extra arguments coming from build scripts.
-        build_phase.push_str(" ${EXTRA_RUSTC_ARGS:-}");
+        // Append the arguments coming from the package's build script.
+        if let (Some(out_path), Some(args_file)) = (
+            &build_script_out_path,
+            cr8.r#type.build_script_args_file(cr8.name),
+        ) {
+            write!(&mut build_phase, " @{out_path}/rustc-args/{args_file}")
+                .expect("writing to string can't fail");
+        }
     }
 
     build_phase.push_str("\nrunHook postBuild");
@@ -371,40 +585,99 @@ where
     Ok(build_phase)
 }
 
+#[expect(clippy::too_many_arguments)]
+fn check_phase<'dep, Deps>(
+    tests: &Tests,
+    node: &'dep BuildGraphNode,
+    build_script: Option<NixDerivation>,
+    own_library: Option<(&'dep BuildGraphNode, NixDerivation<'dep>)>,
+    direct_deps: Deps,
+    args: &GlobalArgs,
+    ctx: &mut Context,
+) -> Result<String>
+where
+    Deps: Iterator<Item = (&'dep BuildGraphNode, NixDerivation<'dep>)> + Clone,
+{
+    let mut check_phase = "runHook preCheck\n".to_owned();
+
+    writeln!(&mut check_phase, "mkdir -p {CARGO_TARGET_TMPDIR}")
+        .expect("writing to string can't fail");
+
+    for test in &tests.targets {
+        let cr8 = Crate::from_test(test, &tests.dependency_renames, false);
+
+        write!(&mut check_phase, "{}/{}", cr8.r#type.out_dir(), cr8.name_arg())
+            .expect("writing to string can't fail");
+
+        if test.harness {
+            check_phase.push_str(" --test-threads=$NIX_BUILD_CORES");
+        }
+
+        check_phase.push('\n');
+    }
+
+    if let (true, Some(library), Some(own_library)) =
+        (tests.doctests, &node.library, own_library)
+    {
+        let cr8 =
+            Crate::from_library(library, &tests.dependency_renames, false);
+
+        write!(
+            &mut check_phase,
+            "rustdoc --test {} --crate-name {} --edition {} \
+             @$out/deps/{DEPENDENCY_SEARCH_PATHS} ${{NATIVE_SEARCH_PATHS:-}}",
+            cr8.path,
+            cr8.name_arg(),
+            edition_as_str(node.package_attrs.edition),
+        )
+        .expect("writing to string can't fail");
+
+        for feature in &node.package_attrs.features {
+            write!(&mut check_phase, " --cfg feature=\\\"{feature}\\\"")
+                .expect("writing to string can't fail");
+        }
+
+        if let Some(build_script) = build_script {
+            write!(
+                &mut check_phase,
+                " @{}/rustc-args/doctest",
+                build_script.out_path_as_string(ctx)?
+            )
+            .expect("writing to string can't fail");
+        }
+
+        for arg in dependencies_rustc_args(
+            direct_deps.chain(iter::once(own_library)),
+            &tests.dependency_renames,
+            false,
+            args,
+            ctx,
+        ) {
+            check_phase.push(' ');
+            check_phase.push_str(&arg);
+        }
+
+        check_phase.push_str(" --test-args --test-threads=$NIX_BUILD_CORES\n");
+    }
+
+    check_phase.push_str("runHook postCheck");
+
+    Ok(check_phase)
+}
+
 #[expect(clippy::too_many_arguments)]
 fn configure_phase(
     package: &PackageAttrs,
     package_version: &str,
     build_script: Option<NixDerivation>,
-    is_library: bool,
-    is_release: bool,
+    profile: &Profile,
     deps: NixDerivation,
+    config_env: &[ConfigEnvVar],
     stdenv: NixAttrset,
     ctx: &mut Context,
 ) -> Result<String> {
     let host_platform = stdenv.get::<NixAttrset>(c"hostPlatform", ctx)?;
 
-    let cpu_platform =
-        host_platform.get::<NixAttrset>([c"parsed", c"cpu"], ctx)?;
-
-    let rust_platform =
-        host_platform.get::<NixAttrset>([c"rust", c"platform"], ctx)?;
-
-    let is_cpu_little_endian = cpu_platform
-        .get::<CompactString>([c"significantByte", c"name"], ctx)?
-        == "littleEndian";
-
-    let target_arch = rust_platform.get::<CompactString>(c"arch", ctx)?;
-    let target_endian = if is_cpu_little_endian { "little" } else { "big" };
-    let target_os = rust_platform.get::<CompactString>(c"os", ctx)?;
-    let target_pointer_width = if host_platform.get(c"isILP32", ctx)? {
-        32
-    } else {
-        cpu_platform.get::<u8>(c"bits", ctx)?
-    };
-    let target_vendor = host_platform
-        .get::<CompactString>([c"parsed", c"vendor", c"name"], ctx)?;
-
     let manifest_links = package.links.as_deref().unwrap_or("");
 
     let pkg_authors =
@@ -432,28 +705,24 @@ fn configure_phase(
     let pkg_version_patch = package.version.patch;
     let pkg_version_pre = package.version.pre.as_str();
 
-    let debug = if is_release { "1" } else { "" };
+    let debug = profile.debuginfo.into_inner() != TomlDebugInfo::None;
     let host = stdenv.get::<CompactString>(
         [c"buildPlatform", c"rust", c"rustcTargetSpec"],
         ctx,
     )?;
-    let opt_level = if is_release { 3 } else { 0 };
-    let profile = if is_release { "release" } else { "debug" };
+    let opt_level = profile.opt_level.as_str();
+    let profile = match profile.root {
+        ProfileRoot::Release => "release",
+        ProfileRoot::Debug => "debug",
+    };
     let target = host_platform
         .get::<CompactString>([c"rust", c"rustcTargetSpec"], ctx)?;
 
     let mut configure_phase = formatdoc!(
         r#"
             runHook preConfigure
-            export CARGO_CFG_TARGET_ARCH={target_arch}
-            export CARGO_CFG_TARGET_ENDIAN={target_endian}
-            export CARGO_CFG_TARGET_ENV="gnu"
-            export CARGO_CFG_TARGET_FAMILY="unix"
-            export CARGO_CFG_TARGET_OS={target_os}
-            export CARGO_CFG_TARGET_POINTER_WIDTH={target_pointer_width}
-            export CARGO_CFG_TARGET_VENDOR={target_vendor}
-            export CARGO_CFG_UNIX=1
             export CARGO_MANIFEST_DIR=$(pwd)
+            export CARGO_MANIFEST_PATH=$(pwd)/Cargo.toml
             export CARGO_MANIFEST_LINKS={manifest_links}
             export CARGO_PKG_AUTHORS="{pkg_authors}"
             export CARGO_PKG_DESCRIPTION={pkg_description}
@@ -480,6 +749,22 @@ fn configure_phase(
         "#
     );
 
+    // Like Cargo, only set the variables that aren't forced if they're not
+    // already set (e.g. by the derivation's overrides).
+    for var in config_env {
+        let name = &*var.name;
+        let value = shell_escape::escape(var.value.as_str().into());
+        if var.force {
+            writeln!(&mut configure_phase, "export {name}={value}")
+        } else {
+            writeln!(
+                &mut configure_phase,
+                "[ -n \"${{{name}+x}}\" ] || export {name}={value}"
+            )
+        }
+        .expect("writing to string can't fail");
+    }
+
     if let Some(build_script) = build_script {
         let build_script_out_path = build_script.out_path(ctx)?;
 
@@ -492,9 +777,8 @@ fn configure_phase(
 
         writeln!(
             &mut configure_phase,
-            "source {}/{}",
+            "source {}/env.sh",
             build_script_out_path.display(),
-            if is_library { "lib.sh" } else { "bin.sh" }
         )
         .expect("writing to string can't fail");
     }
@@ -506,47 +790,189 @@ fn configure_phase(
     )
     .expect("writing to string can't fail");
 
+    // Point rustc directly at the directories containing the native libraries
+    // instead of at their symlinks under `$out/deps/native`, so that the
+    // linker's rpath entries don't go through the `deps` symlink (which is
+    // removed from the final artifacts' outputs).
+    configure_phase.push_str(indoc! {r#"
+        NATIVE_SEARCH_PATHS=""
+        for lib in $out/deps/native/*; do
+          [ -e "$lib" ] || continue
+          NATIVE_SEARCH_PATHS+=" -L native=$(dirname "$(readlink -f "$lib")")"
+        done
+    "#});
+
     configure_phase.push_str("runHook postConfigure");
 
     Ok(configure_phase)
 }
 
+#[expect(clippy::too_many_lines)]
 fn install_phase(
-    package: &PackageAttrs,
-    package_version: &str,
-    is_build_script: bool,
-) -> String {
+    r#type: &DerivationType,
+    node: &BuildGraphNode,
+    platform: &PlatformArgs,
+    ctx: &mut Context,
+) -> Result<String> {
+    let package = &node.package_attrs;
+
     let mut install_phase = "runHook preInstall\n".to_owned();
 
-    if is_build_script {
-        for feature in &package.features {
-            let feature = feature.to_uppercase().replace('-', "_");
-            install_phase.push_str("export CARGO_FEATURE_");
-            install_phase.push_str(&*feature);
-            install_phase.push_str("=1\n");
-        }
+    match r#type {
+        DerivationType::BuildScript { build_script, links_dependencies } => {
+            for feature in &package.features {
+                let feature = feature.to_uppercase().replace('-', "_");
+                install_phase.push_str("export CARGO_FEATURE_");
+                install_phase.push_str(&*feature);
+                install_phase.push_str("=1\n");
+            }
+
+            // Sort the variables to keep the derivation's hash stable.
+            let mut cfg_env = build_script.cfg_env.iter().collect::<Vec<_>>();
+            cfg_env.sort_unstable();
+
+            for (name, value) in cfg_env {
+                writeln!(
+                    &mut install_phase,
+                    "export {name}={}",
+                    shell_escape::escape(value.as_str().into())
+                )
+                .expect("writing to string can't fail");
+            }
+
+            // The flags are separated by the ASCII unit separator.
+            let encoded_rustflags = build_script.rustflags.join("\x1f");
+
+            writeln!(
+                &mut install_phase,
+                "export CARGO_ENCODED_RUSTFLAGS={}",
+                shell_escape::escape(encoded_rustflags.into())
+            )
+            .expect("writing to string can't fail");
 
-        install_phase.push_str(&formatdoc!(
-            r"
+            let run_profile = node.build_script_run_profile();
+
+            if run_profile.debug_assertions {
+                install_phase.push_str("export CARGO_CFG_DEBUG_ASSERTIONS=\n");
+            }
+
+            // rustc reports the target's default panic strategy, but the
+            // package is compiled with the one from its profile.
+            writeln!(
+                &mut install_phase,
+                "export CARGO_CFG_PANIC={}",
+                run_profile.panic
+            )
+            .expect("writing to string can't fail");
+
+            // Set the `DEP_<LINKS>_<KEY>` variables from the metadata
+            // emitted by the dependencies' build scripts.
+            for dependency in links_dependencies {
+                writeln!(
+                    &mut install_phase,
+                    "source {}/metadata.sh",
+                    dependency.out_path_as_string(ctx)?
+                )
+                .expect("writing to string can't fail");
+            }
+
+            install_phase.push_str(indoc! {"
                 export OUT_DIR=$out/out
                 mkdir -p $OUT_DIR
                 $out/build_script_build | tee $out/build_script_output.txt
-                parse-build-script-output \
-                    $out/build_script_output.txt \
-                    $out/common.sh \
-                    $out/lib.sh \
-                    $out/bin.sh \
-                    EXTRA_RUSTC_ARGS \
-                    {package_name} \
-                    {package_version}
-            ",
-            package_name = package.name,
-        ));
+                parse-build-script-output $out/build_script_output.txt $out"
+            });
+
+            write!(
+                &mut install_phase,
+                " {}@{}",
+                shell_escape::escape(package.name.as_str().into()),
+                package.version,
+            )
+            .expect("writing to string can't fail");
+
+            if let Some(links) = &package.links {
+                write!(
+                    &mut install_phase,
+                    " --links {}",
+                    shell_escape::escape(links.as_str().into())
+                )
+                .expect("writing to string can't fail");
+            }
+
+            for binary in &node.binaries {
+                write!(
+                    &mut install_phase,
+                    " --bin {}",
+                    shell_escape::escape(binary.name.as_str().into())
+                )
+                .expect("writing to string can't fail");
+            }
+
+            install_phase.push('\n');
+        },
+
+        // The metadata is only used to compile the library's dependents.
+        DerivationType::Library { metadata_only: true, .. } => {},
+
+        DerivationType::Library { library, .. } => {
+            let lib_name = library.name.replace('-', "_");
+
+            // Move the C-compatible libraries under `$out/lib`, stripping the
+            // `-<metadata>` suffix we add to the file names of libraries that
+            // can also be used as Rust dependencies.
+            for &format in &library.formats {
+                let (prefix, extension) = match format {
+                    LibraryFormat::Cdylib => {
+                        (platform.dylib_prefix, &*platform.dylib_extension)
+                    },
+                    LibraryFormat::Staticlib => ("lib", "a"),
+                    _ => continue,
+                };
+
+                let file_name = format!("{prefix}{lib_name}.{extension}");
+
+                writedoc!(
+                    &mut install_phase,
+                    r#"
+                        mkdir -p $out/lib
+                        shopt -s nullglob
+                        libs=($out/{prefix}{lib_name}*.{extension})
+                        shopt -u nullglob
+                        if [ ${{#libs[@]}} -eq 0 ]; then
+                          echo "error: rustc didn't produce a {format} for {lib_name}" >&2
+                          exit 1
+                        fi
+                        for lib in "${{libs[@]}}"; do
+                          mv "$lib" $out/lib/{file_name}
+                        done
+                    "#,
+                    format = format.as_str(),
+                )
+                .expect("writing to string can't fail");
+            }
+
+            // Libraries that can't be depended on by other Rust crates are
+            // final artifacts, so there's no reason to keep their
+            // dependencies in the closure (the Rust dylibs they link, if any,
+            // are kept through their rpath, see `BuildGraph::resolve_rpath`).
+            if !library.is_usable_as_dependency() {
+                install_phase.push_str("rm $out/deps\n");
+            }
+        },
+
+        DerivationType::Binaries { .. } | DerivationType::Tests { .. } => {
+            // The binaries have been compiled directly into `$out/bin` (or
+            // `$out/tests`), so all that's left to do is to drop the
+            // dependencies from the closure. The Rust dylibs they link, if
+            // any, are kept through their rpath instead.
+            install_phase.push_str("rm $out/deps\n");
+        },
     }
 
     install_phase.push_str("runHook postInstall");
 
-    install_phase
+    Ok(install_phase)
 }
 
 fn apply_overrides<'a>(
@@ -575,11 +1001,9 @@ fn apply_overrides<'a>(
 #[expect(clippy::too_many_arguments)]
 fn build_rustc_args<'dep, Deps>(
     cr8: &Crate,
-    version: &str,
     direct_deps: Deps,
     features: &[CompactString],
-    is_release: bool,
-    target: Option<&CompileTarget>,
+    args: &GlobalArgs,
     edition: Edition,
     ctx: &mut Context,
 ) -> impl Iterator<Item = impl AsRef<str>>
@@ -591,27 +1015,22 @@ where
         "--crate-name",
         cr8.name_arg(),
         "--out-dir",
-        "$out",
+        cr8.r#type.out_dir(),
         "--edition",
         edition_as_str(edition),
         "--cap-lints allow", // Suppress all lints from dependencies.
         "--remap-path-prefix $NIX_BUILD_TOP=/",
         "--color always",
-        "-C",
-        if is_release { "opt-level=3" } else { "debuginfo=2" },
     ]
     .into_iter()
     .map(Into::into)
+    .chain(profile_rustc_args(cr8.build_opts))
     .chain(
-        cr8.r#type
-            .is_usable_as_dependency()
-            .then(|| {
-                let metadata = crate_metadata(
-                    cr8.name,
-                    version,
-                    features.iter(),
-                    cr8.build_opts,
-                );
+        cr8.build_opts
+            .metadata
+            .as_ref()
+            .filter(|_| cr8.r#type.is_usable_as_dependency())
+            .map(|metadata| {
                 [
                     CompactString::const_new("-C"),
                     format_compact!("metadata={metadata}"),
@@ -622,32 +1041,54 @@ where
             .into_iter()
             .flatten(),
     )
-    .chain([
-        CompactString::const_new("-C"),
-        format_compact!(
-            "codegen-units={}",
-            cr8.build_opts.codegen_units.unwrap_or(1)
-        ),
-        CompactString::const_new("--crate-type"),
-        cr8.r#type.types_arg(),
-    ])
+    .chain([cr8.r#type.crate_type_arg()])
+    .chain(
+        cr8.r#type
+            .is_metadata_only()
+            .then(|| CompactString::const_new("--emit=metadata")),
+    )
     .chain(
         cr8.r#type
             .is_proc_macro()
             .then(|| CompactString::const_new("--extern proc_macro")),
     )
-    .chain(dependencies_rustc_args(direct_deps, cr8.deps_renames, ctx))
+    .chain(dependencies_rustc_args(
+        direct_deps,
+        cr8.deps_renames,
+        cr8.r#type.is_compiled_against_metadata(args.pipelined),
+        args,
+        ctx,
+    ))
     .chain(
-        (match target {
-            Some(target) if cr8.r#type.is_compiled_for_host() => Some([
+        (match cr8.build_opts.compile_kind {
+            CompileKind::Target(target) => Some([
                 CompactString::const_new("--target"),
                 target.rustc_target().as_str().into(),
             ]),
-            _ => None,
+            CompileKind::Host => None,
         })
         .into_iter()
         .flatten(),
     )
+    .chain(
+        // The linker from the Cargo configuration takes precedence over the
+        // one of the target platform's C compiler.
+        cr8.build_opts
+            .linker
+            .as_ref()
+            .or(match cr8.build_opts.compile_kind {
+                CompileKind::Target(_) => args.linker.as_ref(),
+                CompileKind::Host => None,
+            })
+            .map(|linker| {
+                [
+                    CompactString::const_new("-C"),
+                    format_compact!("linker={linker}"),
+                ]
+            })
+            .into_iter()
+            .flatten(),
+    )
     .chain(features.iter().flat_map(|feature| {
         [
             CompactString::const_new("--cfg"),
@@ -657,33 +1098,106 @@ where
     .chain(cr8.build_opts.extra_rustc_args.iter().cloned())
 }
 
-fn crate_metadata(
-    crate_name: &str,
-    crate_version: &str,
-    crate_features: impl Iterator<Item = impl AsRef<str>>,
-    build_opts: &BuildOpts,
-) -> CompactString {
-    let mut hasher = sha2::Sha256::new();
+/// Returns the arguments Cargo would pass to `rustc` to compile a crate with
+/// the given profile and LTO requirements.
+fn profile_rustc_args(build_opts: &BuildOpts) -> Vec<CompactString> {
+    let profile = &build_opts.profile;
+
+    let mut args = Vec::new();
+
+    let mut push_codegen_opt = |opt: CompactString| {
+        args.push(CompactString::const_new("-C"));
+        args.push(opt);
+    };
+
+    let opt_level = profile.opt_level.as_str();
 
-    hasher.update(crate_name.as_bytes());
-    hasher.update(crate_version.as_bytes());
-    for feature in crate_features {
-        hasher.update(feature.as_ref().as_bytes());
+    if opt_level != "0" {
+        push_codegen_opt(format_compact!("opt-level={opt_level}"));
     }
-    for arg in &build_opts.extra_rustc_args {
-        hasher.update(arg.as_bytes());
+
+    if profile.panic != PanicStrategy::Unwind {
+        push_codegen_opt(format_compact!("panic={}", profile.panic));
     }
 
-    let hash = hasher.finalize();
-    let short_hash = u64::from_le_bytes(
-        hash[..8].try_into().expect("slice is 8 bytes long"),
-    );
-    format_compact!("{short_hash:x}")
+    for lto_opt in build_opts.lto.codegen_opts() {
+        push_codegen_opt(lto_opt);
+    }
+
+    if let Some(codegen_units) = profile.codegen_units {
+        push_codegen_opt(format_compact!("codegen-units={codegen_units}"));
+    }
+
+    let debuginfo = profile.debuginfo.into_inner();
+
+    if debuginfo != TomlDebugInfo::None {
+        push_codegen_opt(format_compact!("debuginfo={debuginfo}"));
+
+        if let Some(split_debuginfo) = profile.split_debuginfo {
+            push_codegen_opt(format_compact!(
+                "split-debuginfo={split_debuginfo}"
+            ));
+        }
+    }
+
+    // `-C overflow-checks` is implied by the setting of `-C debug-assertions`,
+    // so we only need to provide `-C overflow-checks` if it differs from the
+    // value of `-C debug-assertions` we would provide.
+    let (debug_assertions, overflow_checks) =
+        (profile.debug_assertions, profile.overflow_checks);
+
+    if opt_level != "0" {
+        if debug_assertions {
+            push_codegen_opt("debug-assertions=on".into());
+            if !overflow_checks {
+                push_codegen_opt("overflow-checks=off".into());
+            }
+        } else if overflow_checks {
+            push_codegen_opt("overflow-checks=on".into());
+        }
+    } else if !debug_assertions {
+        push_codegen_opt("debug-assertions=off".into());
+        if overflow_checks {
+            push_codegen_opt("overflow-checks=on".into());
+        }
+    } else if !overflow_checks {
+        push_codegen_opt("overflow-checks=off".into());
+    }
+
+    if profile.rpath {
+        push_codegen_opt("rpath".into());
+    }
+
+    // `profile.incremental` is deliberately ignored: the incremental cache
+    // would be thrown away together with the build sandbox, so writing it
+    // would only cost time and disk space.
+
+    let strip = profile.strip.into_inner();
+
+    if strip != StripInner::None {
+        push_codegen_opt(format_compact!("strip={strip}"));
+    }
+
+    if let Some(codegen_backend) = profile.codegen_backend {
+        args.push(CompactString::const_new("-Z"));
+        args.push(format_compact!("codegen-backend={codegen_backend}"));
+    }
+
+    args
 }
 
+/// Returns the `--extern` arguments for the given dependencies, pointing
+/// either at their full artifacts or, if `metadata` is true, at their
+/// `.rmeta` files (except for proc-macros, which are always needed in full).
+///
+/// The full artifact of a library is its `.rlib`, unless it's only compiled
+/// as a Rust dylib, in which case it's named after the conventions of the
+/// platform the dependency is compiled for (like proc-macros).
 fn dependencies_rustc_args<'dep, Deps>(
     dependencies: Deps,
     renames: &DependencyRenames,
+    metadata: bool,
+    args: &GlobalArgs,
     ctx: &mut Context,
 ) -> impl IntoIterator<Item = CompactString>
 where
@@ -714,17 +1228,26 @@ where
                 .out_path_as_string(ctx)
                 .expect("dependency derivation must have an output path");
 
-            let dep_metadata = crate_metadata(
-                &dep_lib.name,
-                &dep_node.package_attrs.version.to_compact_string(),
-                dep_node.package_attrs.features.iter(),
-                &dep_lib.build_opts,
-            );
+            let dep_metadata = dep_lib
+                .build_opts
+                .metadata
+                .as_deref()
+                .expect("the metadata of every library has been resolved");
+
+            let (prefix, extension) = if dep_lib.is_proc_macro()
+                || (!metadata && dep_lib.is_dylib())
+            {
+                let platform = args.platform(dep_node.is_for_host);
+                (platform.dylib_prefix, &*platform.dylib_extension)
+            } else if metadata {
+                ("lib", "rmeta")
+            } else {
+                ("lib", "rlib")
+            };
 
             let lib_path = format!(
-                "{out_path}/lib{}-{dep_metadata}.{}",
+                "{out_path}/{prefix}{}-{dep_metadata}.{extension}",
                 dep_lib.name,
-                if dep_lib.is_proc_macro() { DLL_EXTENSION } else { "rlib" }
             );
 
             (lib_name, lib_path)
@@ -743,30 +1266,48 @@ where
 }
 
 impl<'args, 'lock, 'builtins> GlobalArgs<'args, 'lock, 'builtins> {
+    #[expect(clippy::too_many_lines)]
     pub(crate) fn new(
         args: &BuildPackageArgs<'args>,
         vendored_sources: &'args VendoredSources<'lock>,
         ctx: &mut Context<'builtins>,
     ) -> Result<Self> {
-        let stdenv = args.pkgs.get::<NixAttrset>(c"stdenv", ctx)?;
+        let target_platform = PlatformArgs::new(args.pkgs, args, ctx)?;
+
+        // The package set whose packages run on the build platform, which is
+        // the same as `pkgs` when not cross-compiling.
+        let build_pkgs = args.pkgs.get::<NixAttrset>(c"buildPackages", ctx)?;
+
+        let rustc = match args.rustc {
+            Some(rustc) => rustc,
+            None => build_pkgs.get::<NixDerivation>(c"rustc", ctx)?,
+        };
 
-        let write_shell_script_bin =
-            args.pkgs.get::<NixLambda>(c"writeShellScriptBin", ctx)?;
+        let run_command = build_pkgs.get::<NixLambda>(c"runCommand", ctx)?;
 
-        let parse_build_script_output = write_shell_script_bin
+        let parse_build_script_output = run_command
             .call_multi(
                 (
                     c"parse-build-script-output",
-                    include_str!("./parse-build-script-output.sh"),
+                    attrset! {
+                        nativeBuildInputs: [rustc],
+                        passAsFile: [c"source"],
+                        source: include_str!("./build_script_output.rs"),
+                    },
+                    indoc! {"
+                        mkdir -p $out/bin
+                        cp $sourcePath build_script_output.rs
+                        rustc build_script_output.rs \
+                            --edition 2024 \
+                            -C opt-level=2 \
+                            -o $out/bin/parse-build-script-output
+                    "},
                 ),
                 ctx,
             )?
             .force_into::<NixDerivation>(ctx)?;
 
-        let rustc = match args.rustc {
-            Some(rustc) => rustc,
-            None => args.pkgs.get::<NixDerivation>(c"rustc", ctx)?,
-        };
+        let stdenv = target_platform.stdenv;
 
         let host_platform = stdenv.get::<NixAttrset>(c"hostPlatform", ctx)?;
 
@@ -786,51 +1327,208 @@ impl<'args, 'lock, 'builtins> GlobalArgs<'args, 'lock, 'builtins> {
             )
         };
 
+        let (build_platform, linker) = if compile_target.is_some() {
+            let target_prefix =
+                stdenv.get::<CompactString>([c"cc", c"targetPrefix"], ctx)?;
+            (
+                Some(PlatformArgs::new(build_pkgs, args, ctx)?),
+                Some(format_compact!("{target_prefix}cc")),
+            )
+        } else {
+            (None, None)
+        };
+
         Ok(Self {
+            build_platform,
             compile_target,
-            crate_overrides: args.crate_overrides,
-            get_lib: args.pkgs.get([c"lib", c"getLib"], ctx)?,
-            global_overrides: args.global_overrides,
-            mk_derivation: stdenv.get(c"mkDerivation", ctx)?,
+            linker,
             mk_path: ctx.builtins().path(ctx),
             parse_build_script_output,
-            release: args.release,
+            pipelined: args.pipelined,
             rustc,
-            stdenv,
+            rustflags: args.rustflags.clone(),
+            rustflags_members_only: args.rustflags_members_only,
+            target_platform,
             vendored_sources,
         })
     }
+
+    /// Returns the arguments for the crates compiled for the build platform
+    /// if `for_host` is true, or for the target platform otherwise.
+    pub(crate) fn platform(&self, for_host: bool) -> &PlatformArgs<'args> {
+        match &self.build_platform {
+            Some(build_platform) if for_host => build_platform,
+            _ => &self.target_platform,
+        }
+    }
+}
+
+impl<'args> PlatformArgs<'args> {
+    /// Returns the extra flags to pass to `rustc` when compiling the given
+    /// package, as set by the `rustflags` attribute of its overrides.
+    pub(crate) fn override_rustflags(
+        &self,
+        package: &PackageAttrs,
+        ctx: &mut Context,
+    ) -> Result<Vec<CompactString>> {
+        let overrides = apply_overrides(
+            package,
+            self.global_overrides,
+            self.crate_overrides,
+            ctx,
+        )?;
+
+        Ok(overrides
+            .map(|attrs| attrs.get_opt::<Vec<CompactString>>(c"rustflags", ctx))
+            .transpose()?
+            .flatten()
+            .unwrap_or_default())
+    }
+
+    /// Returns the arguments for the crates compiled with the given package
+    /// set.
+    fn new(
+        pkgs: NixAttrset<'args>,
+        args: &BuildPackageArgs<'args>,
+        ctx: &mut Context,
+    ) -> Result<Self> {
+        let stdenv = pkgs.get::<NixAttrset>(c"stdenv", ctx)?;
+
+        let host_platform = stdenv.get::<NixAttrset>(c"hostPlatform", ctx)?;
+
+        // Platforms without shared libraries (like the static ones of
+        // `pkgsStatic`) don't have an extension for them, but `rustc` still
+        // uses the ELF one when compiling cdylibs for them.
+        let dylib_extension = host_platform
+            .get_opt::<CompactString>([c"extensions", c"sharedLibrary"], ctx)?
+            .map_or(CompactString::const_new("so"), |extension| {
+                extension.trim_start_matches('.').into()
+            });
+
+        let dylib_prefix = if host_platform.get::<bool>(c"isWindows", ctx)? {
+            ""
+        } else {
+            "lib"
+        };
+
+        Ok(Self {
+            crate_overrides: args
+                .crate_overrides
+                .map(|overrides| overrides.evaluate(pkgs, ctx))
+                .transpose()?,
+            dylib_extension,
+            dylib_prefix,
+            get_lib: pkgs.get([c"lib", c"getLib"], ctx)?,
+            global_overrides: args
+                .global_overrides
+                .map(|overrides| overrides.evaluate(pkgs, ctx))
+                .transpose()?,
+            mk_derivation: stdenv.get(c"mkDerivation", ctx)?,
+            stdenv,
+        })
+    }
+}
+
+impl<'a> Overrides<'a> {
+    /// Returns the overrides to apply to the crates compiled with the given
+    /// package set.
+    fn evaluate(
+        self,
+        pkgs: NixAttrset<'a>,
+        ctx: &mut Context,
+    ) -> Result<NixAttrset<'a>> {
+        match self {
+            Self::Attrset(attrs) => Ok(attrs),
+            Self::Function(fun) => fun.call(pkgs, ctx)?.force_into(ctx),
+        }
+    }
+}
+
+impl<'a> TryFromValue<NixValue<'a>> for Overrides<'a> {
+    fn try_from_value(
+        mut value: NixValue<'a>,
+        ctx: &mut Context,
+    ) -> Result<Self> {
+        value.force_inline(ctx)?;
+
+        match value.kind() {
+            ValueKind::Attrset => {
+                NixAttrset::try_from_value(value, ctx).map(Self::Attrset)
+            },
+            ValueKind::Function => {
+                NixLambda::try_from_value(value, ctx).map(Self::Function)
+            },
+            _ => Err(Error::new(
+                ErrorKind::Nix,
+                c"expected overrides to be an attribute set or a function",
+            )),
+        }
+    }
 }
 
 impl<'a> DerivationType<'a> {
+    fn binaries_drv(&self) -> Option<NixDerivation<'a>> {
+        match self {
+            Self::Tests { binaries, .. } => binaries.clone(),
+            _ => None,
+        }
+    }
+
     fn build_script_drv(&self) -> Option<NixDerivation<'a>> {
         match self {
-            Self::BuildScript(_) => None,
+            Self::BuildScript { .. } => None,
             Self::Library { build_script, .. } => build_script.clone(),
             Self::Binaries { build_script, .. } => build_script.clone(),
+            Self::Tests { build_script, .. } => build_script.clone(),
         }
     }
 
     fn derivation_name_suffix(&self) -> &'static str {
         match self {
-            Self::BuildScript(_) => "build",
+            Self::BuildScript { .. } => "build",
+            Self::Library { metadata_only: true, .. } => "meta",
             Self::Library { .. } => "lib",
             Self::Binaries { binaries, .. } if binaries.len() > 1 => "bins",
             Self::Binaries { .. } => "bin",
+            Self::Tests { .. } => "tests",
         }
     }
 
-    fn is_build_script(&self) -> bool {
-        matches!(self, Self::BuildScript(_))
-    }
-
-    fn is_library(&self) -> bool {
-        matches!(self, Self::Library { .. })
+    fn links_dependencies(&self) -> &[NixDerivation<'a>] {
+        match self {
+            Self::BuildScript { links_dependencies, .. } => links_dependencies,
+            _ => &[],
+        }
     }
 
     fn library_drv(&self) -> Option<NixDerivation<'a>> {
         match self {
             Self::Binaries { library, .. } => library.clone(),
+            Self::Tests { library, .. } => library.clone(),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the binary that `nix run` should execute, which is
+    /// either the one set via the `default-run` field in the package's
+    /// manifest (if it's built), or the only binary target if the package
+    /// has just one.
+    fn main_program<'p>(
+        &'p self,
+        package: &'p PackageAttrs,
+    ) -> Option<&'p str> {
+        let Self::Binaries { binaries, .. } = self else { return None };
+
+        // The `default-run` binary isn't built if its `required-features`
+        // aren't enabled.
+        let default_run = package
+            .default_run
+            .as_deref()
+            .filter(|name| binaries.iter().any(|binary| binary.name == *name));
+
+        match (default_run, binaries) {
+            (Some(default_run), _) => Some(default_run),
+            (None, [binary]) => Some(&binary.name),
             _ => None,
         }
     }
@@ -866,18 +1564,42 @@ impl<'a> Crate<'a> {
     fn from_library(
         library: &'a LibraryCrate,
         deps_renames: &'a DependencyRenames,
+        metadata_only: bool,
     ) -> Self {
         let name = &library.name;
         Self {
             path: &library.path,
             name,
             name_arg: name.contains('-').then(|| name.replace('-', "_")),
-            r#type: CrateType::Library { formats: &library.formats },
+            r#type: CrateType::Library {
+                formats: &library.formats,
+                metadata_only,
+            },
             deps_renames,
             build_opts: &library.build_opts,
         }
     }
 
+    fn from_test(
+        test: &'a TestCrate,
+        deps_renames: &'a DependencyRenames,
+        is_proc_macro: bool,
+    ) -> Self {
+        let name = &test.name;
+        Self {
+            path: &test.path,
+            name,
+            name_arg: name.contains('-').then(|| name.replace('-', "_")),
+            r#type: CrateType::Test {
+                harness: test.harness,
+                is_proc_macro: is_proc_macro && test.kind == TestKind::Lib,
+                kind: test.kind,
+            },
+            deps_renames,
+            build_opts: &test.build_opts,
+        }
+    }
+
     /// Returns the crate name in the format expected by rustc's `--crate-name`
     /// (i.e., with dashes replaced by underscores).
     fn name_arg(&self) -> &str {
@@ -886,11 +1608,36 @@ impl<'a> Crate<'a> {
 }
 
 impl CrateType<'_> {
-    fn is_compiled_for_host(&self) -> bool {
+    /// Returns the name of the file under the build script's `rustc-args`
+    /// directory containing the extra arguments to compile the crate with
+    /// (see `parse-build-script-output`), if the crate is affected by the
+    /// package's build script.
+    fn build_script_args_file(&self, crate_name: &str) -> Option<String> {
         match self {
-            Self::Binary => true,
-            Self::BuildScript => false,
-            Self::Library { formats } => !LibraryFormat::is_proc_macro(formats),
+            Self::Binary | Self::Test { kind: TestKind::Bin, .. } => {
+                Some(format!("bin-{crate_name}"))
+            },
+            Self::BuildScript => None,
+            Self::Library { .. } => Some("lib".to_owned()),
+            Self::Test { kind: TestKind::Integration, .. } => {
+                Some("test".to_owned())
+            },
+            Self::Test { kind: TestKind::Lib, .. } => {
+                Some("lib-test".to_owned())
+            },
+        }
+    }
+
+    /// Returns the directory `rustc` should place the crate's artifacts in.
+    fn out_dir(&self) -> &'static str {
+        match self {
+            Self::Binary => "$out/bin",
+            Self::BuildScript | Self::Library { .. } => "$out",
+            Self::Test { kind: TestKind::Bin, .. } => "$out/tests/bin",
+            Self::Test { kind: TestKind::Integration, .. } => {
+                "$out/tests/integration"
+            },
+            Self::Test { kind: TestKind::Lib, .. } => "$out/tests/lib",
         }
     }
 
@@ -898,26 +1645,69 @@ impl CrateType<'_> {
         match self {
             Self::Binary => false,
             Self::BuildScript => false,
-            Self::Library { formats } => LibraryFormat::is_proc_macro(formats),
+            Self::Library { formats, .. } => {
+                LibraryFormat::is_proc_macro(formats)
+            },
+            Self::Test { is_proc_macro, .. } => *is_proc_macro,
+        }
+    }
+
+    fn is_metadata_only(&self) -> bool {
+        matches!(self, Self::Library { metadata_only: true, .. })
+    }
+
+    /// Returns whether the crate is compiled against the metadata of its
+    /// dependencies instead of their full artifacts, which can only happen
+    /// when pipelining and if the crate isn't linked.
+    fn is_compiled_against_metadata(&self, pipelined: bool) -> bool {
+        match self {
+            Self::Library { formats, metadata_only } => {
+                pipelined
+                    && (*metadata_only
+                        || !LibraryFormat::needs_linking(formats))
+            },
+            Self::Binary | Self::BuildScript | Self::Test { .. } => false,
+        }
+    }
+
+    /// Returns whether the crate is compiled from a binary target, which is
+    /// when Cargo sets `CARGO_BIN_NAME`.
+    fn is_executable(&self) -> bool {
+        matches!(self, Self::Binary | Self::Test { kind: TestKind::Bin, .. })
+    }
+
+    /// Returns whether the crate can link against the library target of the
+    /// package it belongs to.
+    fn links_own_library(&self) -> bool {
+        match self {
+            Self::Binary => true,
+            Self::BuildScript | Self::Library { .. } => false,
+            Self::Test { kind, .. } => *kind != TestKind::Lib,
         }
     }
 
     fn is_usable_as_dependency(&self) -> bool {
         match self {
-            Self::Binary | Self::BuildScript => false,
-            Self::Library { formats } => formats
+            Self::Binary | Self::BuildScript | Self::Test { .. } => false,
+            Self::Library { formats, .. } => formats
                 .iter()
                 .any(|lib_format| lib_format.is_usable_as_dependency()),
         }
     }
 
-    fn types_arg(&self) -> CompactString {
+    /// Returns the argument telling `rustc` what kind of crate to produce.
+    fn crate_type_arg(&self) -> CompactString {
         match self {
-            Self::Binary | Self::BuildScript => CompactString::const_new("bin"),
-            Self::Library { formats } => formats.iter().fold(
-                CompactString::default(),
+            Self::Test { harness: true, .. } => {
+                CompactString::const_new("--test")
+            },
+            Self::Binary | Self::BuildScript | Self::Test { .. } => {
+                CompactString::const_new("--crate-type bin")
+            },
+            Self::Library { formats, .. } => formats.iter().fold(
+                CompactString::const_new("--crate-type "),
                 |mut acc, format| {
-                    if !acc.is_empty() {
+                    if !acc.ends_with(' ') {
                         acc.push(',');
                     }
                     acc.push_str(format.as_str());
@@ -0,0 +1,1273 @@
+use core::fmt;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::{fs, io};
+
+use cargo_platform::Platform;
+use cargo_util_schemas::manifest::{
+    InheritableDependency,
+    InheritableField,
+    RustVersion,
+    StringOrBool,
+    TomlDependency,
+    TomlManifest,
+    TomlPackageBuild,
+    TomlTarget,
+    TomlWorkspace,
+};
+use compact_str::CompactString;
+
+use crate::interning::{InternedString, intern};
+
+/// The manifest of a package, read without the `cargo` crate.
+pub(crate) struct Package {
+    package_id: PackageId,
+    manifest: Manifest,
+
+    /// The directory containing the package's `Cargo.toml`.
+    root: PathBuf,
+}
+
+/// The parts of a package's manifest that are needed to build it.
+pub(crate) struct Manifest {
+    default_run: Option<String>,
+    dependencies: Vec<Dependency>,
+    edition: Edition,
+    features: BTreeMap<InternedString, Vec<FeatureValue>>,
+    metadata: ManifestMetadata,
+    targets: Vec<Target>,
+}
+
+/// The fields of the `[package]` table that are exposed to the package's
+/// crates via the `CARGO_PKG_*` environment variables.
+pub(crate) struct ManifestMetadata {
+    pub(crate) authors: Vec<String>,
+    pub(crate) description: Option<String>,
+    pub(crate) homepage: Option<String>,
+    pub(crate) license: Option<String>,
+    pub(crate) license_file: Option<String>,
+    pub(crate) links: Option<String>,
+    pub(crate) readme: Option<String>,
+    pub(crate) repository: Option<String>,
+    pub(crate) rust_version: Option<RustVersion>,
+}
+
+/// Uniquely identifies a package by its name, version and source.
+///
+/// Like Cargo's, IDs are interned, so they're `Copy` and cheap to compare.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) struct PackageId(&'static PackageIdInner);
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct PackageIdInner {
+    name: InternedString,
+    version: semver::Version,
+    source_id: SourceId,
+}
+
+/// Where a package comes from.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) struct SourceId(&'static SourceIdInner);
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct SourceIdInner {
+    kind: SourceKind,
+
+    /// The source as written in the `Cargo.lock` (e.g.
+    /// `registry+https://github.com/rust-lang/crates.io-index`), or a
+    /// `path+file://` URL for path sources.
+    url: CompactString,
+}
+
+/// The kind of a [`SourceId`].
+///
+/// Unlike Cargo's, this doesn't have variants for `local-registry` and
+/// `directory` sources, since those are only ever used to replace other
+/// sources and never show up in a `Cargo.lock`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) enum SourceKind {
+    Path,
+
+    Git,
+
+    Registry,
+    SparseRegistry,
+}
+
+/// A dependency declared in a package's manifest.
+pub(crate) struct Dependency {
+    features: Vec<InternedString>,
+    is_optional: bool,
+    kind: DepKind,
+    name_in_toml: InternedString,
+    package_name: InternedString,
+    platform: Option<Platform>,
+    source: DependencySource,
+    uses_default_features: bool,
+    version_req: semver::VersionReq,
+}
+
+/// Where a [`Dependency`] is declared to come from.
+pub(crate) enum DependencySource {
+    Git(String),
+    Path(PathBuf),
+    Registry,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) enum DepKind {
+    Normal,
+    Development,
+    Build,
+}
+
+/// An element of the arrays in the `[features]` table.
+#[derive(Copy, Clone)]
+pub(crate) enum FeatureValue {
+    /// Enables another feature of the same package.
+    Feature(InternedString),
+
+    /// Enables an optional dependency (`dep:<name>`).
+    Dep { dep_name: InternedString },
+
+    /// Enables a feature of a dependency (`<name>/<feature>`), and the
+    /// dependency itself unless `weak` is true (`<name>?/<feature>`).
+    DepFeature {
+        dep_name: InternedString,
+        dep_feature: InternedString,
+        weak: bool,
+    },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) enum Edition {
+    Edition2015,
+    Edition2018,
+    Edition2021,
+    Edition2024,
+    EditionFuture,
+}
+
+/// A target (i.e. a crate) of a package.
+pub(crate) struct Target {
+    doctested: bool,
+    harness: bool,
+    kind: TargetKind,
+    name: String,
+    required_features: Option<Vec<String>>,
+    src_path: TargetSourcePath,
+    tested: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum TargetKind {
+    Lib(Vec<CrateType>),
+    Bin,
+    Test,
+    CustomBuild,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum CrateType {
+    Lib,
+    Rlib,
+    Dylib,
+    Cdylib,
+    Staticlib,
+    ProcMacro,
+    Other(String),
+}
+
+pub(crate) enum TargetSourcePath {
+    Path(PathBuf),
+
+    /// The build script generated from the `metabuild` field.
+    Metabuild,
+}
+
+/// The `[workspace]` table that `workspace = true` fields are inherited from.
+#[derive(Copy, Clone)]
+pub(crate) struct WorkspaceInheritance<'ws> {
+    pub(crate) workspace: &'ws TomlWorkspace,
+
+    /// The directory containing the workspace's root manifest.
+    pub(crate) root: &'ws Path,
+}
+
+/// The type of error that can occur when reading a manifest.
+#[derive(Debug, derive_more::Display, cauchy::Error)]
+pub(crate) enum ManifestError {
+    #[display("invalid {field} {value:?} in the manifest at {path:?}")]
+    InvalidField { path: PathBuf, field: &'static str, value: String },
+
+    #[display(
+        "the manifest at {path:?} inherits `{field}` from its workspace, but \
+         the workspace doesn't set it"
+    )]
+    MissingInheritedField { path: PathBuf, field: CompactString },
+
+    #[display("the manifest at {path:?} has no `[package]` table")]
+    MissingPackage { path: PathBuf },
+
+    #[display("failed to parse the manifest at {path:?}: {err}")]
+    Parse { path: PathBuf, err: toml::de::Error },
+
+    #[display("failed to read the manifest at {path:?}: {err}")]
+    Read { path: PathBuf, err: io::Error },
+}
+
+impl Package {
+    /// Reads the package whose manifest is at the given path, inheriting its
+    /// `workspace = true` fields from the given workspace.
+    pub(crate) fn read(
+        manifest_path: &Path,
+        source_id: SourceId,
+        workspace: Option<WorkspaceInheritance>,
+    ) -> Result<Self, ManifestError> {
+        let mut toml_manifest = read_manifest(manifest_path)?;
+        normalize_manifest(&mut toml_manifest, workspace, manifest_path)?;
+        Self::new(toml_manifest, manifest_path, source_id)
+    }
+
+    pub(crate) fn dependencies(&self) -> &[Dependency] {
+        &self.manifest.dependencies
+    }
+
+    pub(crate) fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    pub(crate) fn name(&self) -> InternedString {
+        self.package_id.name()
+    }
+
+    pub(crate) fn package_id(&self) -> PackageId {
+        self.package_id
+    }
+
+    pub(crate) fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub(crate) fn targets(&self) -> &[Target] {
+        &self.manifest.targets
+    }
+
+    pub(crate) fn version(&self) -> &semver::Version {
+        self.package_id.version()
+    }
+
+    /// Creates a package from its already normalized manifest.
+    fn new(
+        toml_manifest: TomlManifest,
+        manifest_path: &Path,
+        source_id: SourceId,
+    ) -> Result<Self, ManifestError> {
+        let root = manifest_path.parent().expect("manifest is in a directory");
+
+        let invalid =
+            |field, value: &dyn fmt::Display| ManifestError::InvalidField {
+                path: manifest_path.to_owned(),
+                field,
+                value: value.to_string(),
+            };
+
+        let package = toml_manifest.package.as_deref().ok_or_else(|| {
+            ManifestError::MissingPackage { path: manifest_path.to_owned() }
+        })?;
+
+        let name = package
+            .name
+            .as_deref()
+            .ok_or_else(|| invalid("package name", &""))?;
+
+        let version = match &package.version {
+            Some(InheritableField::Value(version)) => version.clone(),
+            _ => semver::Version::new(0, 0, 0),
+        };
+
+        let edition = match &package.edition {
+            Some(InheritableField::Value(edition)) => {
+                Edition::parse(edition)
+                    .ok_or_else(|| invalid("edition", edition))?
+            },
+            _ => Edition::Edition2015,
+        };
+
+        let package_id =
+            PackageId::new(name.as_str().into(), version, source_id);
+
+        let targets = discover_targets(&toml_manifest, name, edition, root);
+
+        let manifest = Manifest {
+            default_run: package.default_run.clone(),
+            dependencies: read_dependencies(&toml_manifest, root)
+                .map_err(|(field, value)| invalid(field, &value))?,
+            edition,
+            features: read_features(&toml_manifest),
+            metadata: ManifestMetadata::new(&toml_manifest, root),
+            targets,
+        };
+
+        Ok(Self { package_id, manifest, root: root.to_owned() })
+    }
+}
+
+impl Manifest {
+    pub(crate) fn default_run(&self) -> Option<&str> {
+        self.default_run.as_deref()
+    }
+
+    pub(crate) fn edition(&self) -> Edition {
+        self.edition
+    }
+
+    /// The `[features]` table, including the implicit features of the
+    /// optional dependencies that are never referred to with `dep:`.
+    pub(crate) fn features(
+        &self,
+    ) -> &BTreeMap<InternedString, Vec<FeatureValue>> {
+        &self.features
+    }
+
+    pub(crate) fn metadata(&self) -> &ManifestMetadata {
+        &self.metadata
+    }
+}
+
+impl ManifestMetadata {
+    fn new(toml_manifest: &TomlManifest, root: &Path) -> Self {
+        let package = toml_manifest.package.as_deref();
+
+        let string =
+            |field: Option<&Option<InheritableField<String>>>| match field?
+                .as_ref()?
+            {
+                InheritableField::Value(value) => Some(value.clone()),
+                InheritableField::Inherit(_) => None,
+            };
+
+        // Like Cargo, look for a README in the package root if the manifest
+        // doesn't set one.
+        let readme = match package.and_then(|pkg| pkg.readme.as_ref()) {
+            Some(InheritableField::Value(StringOrBool::String(readme))) => {
+                Some(readme.clone())
+            },
+            Some(InheritableField::Value(StringOrBool::Bool(true))) => {
+                Some("README.md".to_owned())
+            },
+            Some(_) => None,
+            None => ["README.md", "README.txt", "README"]
+                .into_iter()
+                .find(|file_name| root.join(file_name).is_file())
+                .map(Into::into),
+        };
+
+        Self {
+            authors: match package.and_then(|pkg| pkg.authors.as_ref()) {
+                Some(InheritableField::Value(authors)) => authors.clone(),
+                _ => Vec::new(),
+            },
+            description: string(package.map(|pkg| &pkg.description)),
+            homepage: string(package.map(|pkg| &pkg.homepage)),
+            license: string(package.map(|pkg| &pkg.license)),
+            license_file: string(package.map(|pkg| &pkg.license_file)),
+            links: package.and_then(|pkg| pkg.links.clone()),
+            readme,
+            repository: string(package.map(|pkg| &pkg.repository)),
+            rust_version: match package
+                .and_then(|pkg| pkg.rust_version.as_ref())
+            {
+                Some(InheritableField::Value(version)) => Some(version.clone()),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl PackageId {
+    pub(crate) fn new(
+        name: InternedString,
+        version: semver::Version,
+        source_id: SourceId,
+    ) -> Self {
+        static IDS: Mutex<BTreeSet<&'static PackageIdInner>> =
+            Mutex::new(BTreeSet::new());
+
+        let inner = PackageIdInner { name, version, source_id };
+
+        Self(intern(&IDS, &inner, |inner| Box::leak(Box::new(inner.clone()))))
+    }
+
+    pub(crate) fn name(self) -> InternedString {
+        self.0.name
+    }
+
+    pub(crate) fn source_id(self) -> SourceId {
+        self.0.source_id
+    }
+
+    pub(crate) fn version(self) -> &'static semver::Version {
+        &self.0.version
+    }
+}
+
+impl SourceId {
+    /// Returns the source of the packages at the given (absolute) path.
+    pub(crate) fn for_path(path: &Path) -> Self {
+        let url = format!("path+file://{}", path.display());
+        Self::new(SourceKind::Path, url.into())
+    }
+
+    /// Parses a source as written in the `source` field of a `Cargo.lock`
+    /// entry, returning `None` if its kind isn't recognized.
+    pub(crate) fn from_lock(source: &str) -> Option<Self> {
+        let (kind, _) = source.split_once('+')?;
+
+        let kind = match kind {
+            "git" => SourceKind::Git,
+            "registry" => SourceKind::Registry,
+            "sparse" => SourceKind::SparseRegistry,
+            _ => return None,
+        };
+
+        Some(Self::new(kind, source.into()))
+    }
+
+    /// Returns the source as written in a `Cargo.lock`, or as a
+    /// `path+file://` URL for path sources.
+    pub(crate) fn as_url(self) -> &'static str {
+        &self.0.url
+    }
+
+    pub(crate) fn is_path(self) -> bool {
+        self.0.kind == SourceKind::Path
+    }
+
+    pub(crate) fn kind(self) -> &'static SourceKind {
+        &self.0.kind
+    }
+
+    /// Returns the path of a path source.
+    pub(crate) fn local_path(self) -> Option<PathBuf> {
+        self.0.url.strip_prefix("path+file://").map(PathBuf::from)
+    }
+
+    fn new(kind: SourceKind, url: CompactString) -> Self {
+        static IDS: Mutex<BTreeSet<&'static SourceIdInner>> =
+            Mutex::new(BTreeSet::new());
+
+        let inner = SourceIdInner { kind, url };
+
+        Self(intern(&IDS, &inner, |inner| Box::leak(Box::new(inner.clone()))))
+    }
+}
+
+impl Dependency {
+    /// Returns the name of the dependency in the manifest if it was renamed
+    /// via the `package` field, or `None` otherwise.
+    pub(crate) fn explicit_name_in_toml(&self) -> Option<InternedString> {
+        (self.name_in_toml != self.package_name).then_some(self.name_in_toml)
+    }
+
+    /// The features explicitly enabled on the dependency.
+    pub(crate) fn features(&self) -> &[InternedString] {
+        &self.features
+    }
+
+    pub(crate) fn is_build(&self) -> bool {
+        self.kind == DepKind::Build
+    }
+
+    pub(crate) fn is_optional(&self) -> bool {
+        self.is_optional
+    }
+
+    pub(crate) fn kind(&self) -> DepKind {
+        self.kind
+    }
+
+    /// The name of the dependency in the manifest, which is the one used in
+    /// the `[features]` table.
+    pub(crate) fn name_in_toml(&self) -> InternedString {
+        self.name_in_toml
+    }
+
+    pub(crate) fn package_name(&self) -> InternedString {
+        self.package_name
+    }
+
+    /// The platform the dependency is restricted to via a
+    /// `[target.<platform>]` table, if any.
+    pub(crate) fn platform(&self) -> Option<&Platform> {
+        self.platform.as_ref()
+    }
+
+    pub(crate) fn source(&self) -> &DependencySource {
+        &self.source
+    }
+
+    pub(crate) fn uses_default_features(&self) -> bool {
+        self.uses_default_features
+    }
+
+    pub(crate) fn version_req(&self) -> &semver::VersionReq {
+        &self.version_req
+    }
+}
+
+impl Edition {
+    fn parse(edition: &str) -> Option<Self> {
+        Some(match edition {
+            "2015" => Self::Edition2015,
+            "2018" => Self::Edition2018,
+            "2021" => Self::Edition2021,
+            "2024" => Self::Edition2024,
+            "future" => Self::EditionFuture,
+            _ => return None,
+        })
+    }
+}
+
+impl Target {
+    pub(crate) fn doctested(&self) -> bool {
+        self.doctested
+    }
+
+    pub(crate) fn harness(&self) -> bool {
+        self.harness
+    }
+
+    pub(crate) fn is_bin(&self) -> bool {
+        self.kind == TargetKind::Bin
+    }
+
+    pub(crate) fn is_custom_build(&self) -> bool {
+        self.kind == TargetKind::CustomBuild
+    }
+
+    /// Whether other crates can link against the target.
+    pub(crate) fn is_linkable(&self) -> bool {
+        match &self.kind {
+            TargetKind::Lib(crate_types) => {
+                crate_types.iter().any(CrateType::is_linkable)
+            },
+            _ => false,
+        }
+    }
+
+    pub(crate) fn kind(&self) -> &TargetKind {
+        &self.kind
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn proc_macro(&self) -> bool {
+        self.kind == TargetKind::Lib(vec![CrateType::ProcMacro])
+    }
+
+    pub(crate) fn required_features(&self) -> Option<&Vec<String>> {
+        self.required_features.as_ref()
+    }
+
+    pub(crate) fn src_path(&self) -> &TargetSourcePath {
+        &self.src_path
+    }
+
+    pub(crate) fn tested(&self) -> bool {
+        self.tested
+    }
+
+    /// Creates a target from its table in the manifest.
+    fn from_toml(
+        kind: TargetKind,
+        name: String,
+        path: PathBuf,
+        toml_target: Option<&TomlTarget>,
+    ) -> Self {
+        let is_lib = matches!(kind, TargetKind::Lib(_));
+        Self {
+            doctested: is_lib
+                && toml_target.and_then(|t| t.doctest).unwrap_or(true),
+            harness: toml_target.and_then(|t| t.harness).unwrap_or(true),
+            tested: toml_target.and_then(|t| t.test).unwrap_or(true),
+            required_features: toml_target
+                .and_then(|t| t.required_features.clone()),
+            src_path: TargetSourcePath::Path(path),
+            kind,
+            name,
+        }
+    }
+}
+
+impl CrateType {
+    fn is_linkable(&self) -> bool {
+        matches!(self, Self::Lib | Self::Rlib | Self::Dylib | Self::ProcMacro)
+    }
+
+    fn parse(crate_type: &str) -> Self {
+        match crate_type {
+            "lib" => Self::Lib,
+            "rlib" => Self::Rlib,
+            "dylib" => Self::Dylib,
+            "cdylib" => Self::Cdylib,
+            "staticlib" => Self::Staticlib,
+            "proc-macro" => Self::ProcMacro,
+            other => Self::Other(other.to_owned()),
+        }
+    }
+}
+
+impl fmt::Debug for PackageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl fmt::Display for PackageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} v{} ({})", self.0.name, self.0.version, self.0.source_id)
+    }
+}
+
+impl fmt::Debug for SourceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl fmt::Display for SourceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0.url)
+    }
+}
+
+/// Reads and parses the manifest at the given path.
+pub(crate) fn read_manifest(
+    manifest_path: &Path,
+) -> Result<TomlManifest, ManifestError> {
+    let contents = fs::read_to_string(manifest_path).map_err(|err| {
+        ManifestError::Read { path: manifest_path.to_owned(), err }
+    })?;
+
+    toml::from_str::<TomlManifest>(&contents).map_err(|err| {
+        ManifestError::Parse { path: manifest_path.to_owned(), err }
+    })
+}
+
+/// Resolves all the `workspace = true` fields of the given manifest from
+/// the given workspace, and moves the dependencies declared under the legacy
+/// `dev_dependencies` and `build_dependencies` tables into the dashed ones.
+///
+/// Paths inherited from the workspace (i.e. `license-file`, `readme`, and
+/// the `path` of dependencies) are made relative to the package's root.
+pub(crate) fn normalize_manifest(
+    manifest: &mut TomlManifest,
+    workspace: Option<WorkspaceInheritance>,
+    manifest_path: &Path,
+) -> Result<(), ManifestError> {
+    let package_root =
+        manifest_path.parent().expect("manifest is in a directory");
+
+    let missing = |field: &str| ManifestError::MissingInheritedField {
+        path: manifest_path.to_owned(),
+        field: field.into(),
+    };
+
+    let ws_package = workspace.and_then(|ws| ws.workspace.package.as_ref());
+
+    let relative_to_package = |path: &str| {
+        let ws_root = workspace.map_or(package_root, |ws| ws.root);
+        relative_path(package_root, &ws_root.join(path)).display().to_string()
+    };
+
+    if let Some(package) = manifest.package.as_deref_mut() {
+        macro_rules! inherit {
+            ($($field:ident),*) => {$(
+                if let Some(InheritableField::Inherit(_)) = package.$field {
+                    let value = ws_package
+                        .and_then(|ws_pkg| ws_pkg.$field.clone())
+                        .ok_or_else(|| missing(stringify!($field)))?;
+                    package.$field = Some(InheritableField::Value(value));
+                }
+            )*};
+        }
+
+        inherit!(
+            authors,
+            categories,
+            description,
+            documentation,
+            edition,
+            exclude,
+            homepage,
+            include,
+            keywords,
+            license,
+            publish,
+            repository,
+            rust_version,
+            version
+        );
+
+        if let Some(InheritableField::Inherit(_)) = package.license_file {
+            let license_file = ws_package
+                .and_then(|ws_pkg| ws_pkg.license_file.as_deref())
+                .ok_or_else(|| missing("license-file"))?;
+            package.license_file = Some(InheritableField::Value(
+                relative_to_package(license_file),
+            ));
+        }
+
+        if let Some(InheritableField::Inherit(_)) = package.readme {
+            let readme = match ws_package.and_then(|pkg| pkg.readme.as_ref()) {
+                Some(StringOrBool::String(readme)) => {
+                    StringOrBool::String(relative_to_package(readme))
+                },
+                Some(StringOrBool::Bool(readme)) => StringOrBool::Bool(*readme),
+                None => return Err(missing("readme")),
+            };
+            package.readme = Some(InheritableField::Value(readme));
+        }
+
+        package.workspace = None;
+    }
+
+    if let Some(lints) = &mut manifest.lints
+        && lints.workspace
+    {
+        lints.lints = workspace
+            .and_then(|ws| ws.workspace.lints.clone())
+            .ok_or_else(|| missing("lints"))?;
+        lints.workspace = false;
+    }
+
+    if let Some(build_deps) = manifest.build_dependencies2.take() {
+        manifest.build_dependencies.get_or_insert_default().extend(build_deps);
+    }
+
+    if let Some(dev_deps) = manifest.dev_dependencies2.take() {
+        manifest.dev_dependencies.get_or_insert_default().extend(dev_deps);
+    }
+
+    for platform in manifest.target.iter_mut().flat_map(BTreeMap::values_mut) {
+        if let Some(build_deps) = platform.build_dependencies2.take() {
+            platform
+                .build_dependencies
+                .get_or_insert_default()
+                .extend(build_deps);
+        }
+        if let Some(dev_deps) = platform.dev_dependencies2.take() {
+            platform.dev_dependencies.get_or_insert_default().extend(dev_deps);
+        }
+    }
+
+    let platform_deps =
+        manifest.target.iter_mut().flat_map(BTreeMap::values_mut).flat_map(
+            |platform| {
+                [
+                    &mut platform.dependencies,
+                    &mut platform.build_dependencies,
+                    &mut platform.dev_dependencies,
+                ]
+            },
+        );
+
+    let deps = [
+        &mut manifest.dependencies,
+        &mut manifest.build_dependencies,
+        &mut manifest.dev_dependencies,
+    ]
+    .into_iter()
+    .chain(platform_deps)
+    .flatten()
+    .flat_map(BTreeMap::iter_mut);
+
+    for (name, dep) in deps {
+        let InheritableDependency::Inherit(inherited) = dep else { continue };
+
+        let mut ws_dep = workspace
+            .and_then(|ws| ws.workspace.dependencies.as_ref()?.get(name))
+            .cloned()
+            .ok_or_else(|| missing(&format!("dependencies.{name}")))?;
+
+        let detailed = match &mut ws_dep {
+            TomlDependency::Simple(version) => {
+                ws_dep = TomlDependency::Detailed(Default::default());
+                let TomlDependency::Detailed(detailed) = &mut ws_dep else {
+                    unreachable!("just set it");
+                };
+                detailed.version = Some(version.clone());
+                detailed
+            },
+            TomlDependency::Detailed(detailed) => detailed,
+        };
+
+        if let Some(path) = &detailed.path {
+            detailed.path = Some(relative_to_package(path));
+        }
+
+        if let Some(features) = &inherited.features {
+            detailed
+                .features
+                .get_or_insert_default()
+                .extend_from_slice(features);
+        }
+
+        if let Some(optional) = inherited.optional {
+            detailed.optional = Some(optional);
+        }
+
+        // Like Cargo, a member can only turn the default features back on if
+        // the workspace turned them off.
+        if inherited.default_features() == Some(true) {
+            detailed.default_features = Some(true);
+            detailed.default_features2 = None;
+        }
+
+        *dep = InheritableDependency::Value(ws_dep);
+    }
+
+    Ok(())
+}
+
+/// Returns the path to `to` relative to `from`, assuming both are absolute
+/// and lexically normalized.
+pub(crate) fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let mut from_components = from.components().peekable();
+    let mut to_components = to.components().peekable();
+
+    while let (Some(lhs), Some(rhs)) =
+        (from_components.peek(), to_components.peek())
+        && lhs == rhs
+    {
+        from_components.next();
+        to_components.next();
+    }
+
+    from_components.map(|_| Component::ParentDir).chain(to_components).collect()
+}
+
+/// Lexically normalizes the given path, i.e. removes all the `.` components
+/// and resolves the `..` ones without accessing the filesystem.
+pub(crate) fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => {},
+            Component::ParentDir => {
+                normalized.pop();
+            },
+            other => normalized.push(other),
+        }
+    }
+
+    normalized
+}
+
+/// Reads all the dependencies declared in the given (normalized) manifest,
+/// returning the name and value of the first invalid field on error.
+fn read_dependencies(
+    manifest: &TomlManifest,
+    root: &Path,
+) -> Result<Vec<Dependency>, (&'static str, String)> {
+    let platform_tables = manifest
+        .target
+        .iter()
+        .flatten()
+        .map(|(platform, tables)| {
+            platform
+                .parse::<Platform>()
+                .map(|platform| (Some(platform), tables))
+                .map_err(|_| ("target platform", platform.clone()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let tables = [
+        (None, DepKind::Normal, &manifest.dependencies),
+        (None, DepKind::Development, &manifest.dev_dependencies),
+        (None, DepKind::Build, &manifest.build_dependencies),
+    ]
+    .into_iter()
+    .chain(platform_tables.iter().flat_map(|(platform, tables)| {
+        [
+            (platform.clone(), DepKind::Normal, &tables.dependencies),
+            (platform.clone(), DepKind::Development, &tables.dev_dependencies),
+            (platform.clone(), DepKind::Build, &tables.build_dependencies),
+        ]
+    }));
+
+    let mut dependencies = Vec::new();
+
+    for (platform, kind, table) in tables {
+        for (name, dep) in table.iter().flatten() {
+            let InheritableDependency::Value(dep) = dep else {
+                unreachable!("manifest has been normalized");
+            };
+
+            let name_in_toml = InternedString::new(name);
+
+            let dependency = match dep {
+                TomlDependency::Simple(version) => Dependency {
+                    features: Vec::new(),
+                    is_optional: false,
+                    kind,
+                    name_in_toml,
+                    package_name: name_in_toml,
+                    platform: platform.clone(),
+                    source: DependencySource::Registry,
+                    uses_default_features: true,
+                    version_req: parse_version_req(Some(version))?,
+                },
+
+                TomlDependency::Detailed(detailed) => Dependency {
+                    features: detailed
+                        .features
+                        .iter()
+                        .flatten()
+                        .map(|feature| InternedString::new(feature))
+                        .collect(),
+                    is_optional: detailed.optional.unwrap_or(false),
+                    kind,
+                    name_in_toml,
+                    package_name: detailed
+                        .package
+                        .as_deref()
+                        .map_or(name_in_toml, |pkg| pkg.as_str().into()),
+                    platform: platform.clone(),
+                    source: match (&detailed.path, &detailed.git) {
+                        (Some(path), _) => DependencySource::Path(
+                            normalize_path(&root.join(path)),
+                        ),
+                        (None, Some(git)) => DependencySource::Git(git.clone()),
+                        (None, None) => DependencySource::Registry,
+                    },
+                    uses_default_features: detailed
+                        .default_features()
+                        .unwrap_or(true),
+                    version_req: parse_version_req(detailed.version.as_ref())?,
+                },
+            };
+
+            dependencies.push(dependency);
+        }
+    }
+
+    Ok(dependencies)
+}
+
+fn parse_version_req(
+    version: Option<&String>,
+) -> Result<semver::VersionReq, (&'static str, String)> {
+    match version {
+        Some(version) => {
+            version.parse().map_err(|_| ("dependency version", version.clone()))
+        },
+        None => Ok(semver::VersionReq::STAR),
+    }
+}
+
+/// Reads the `[features]` table of the given manifest, adding an implicit
+/// feature for every optional dependency that's never referred to with
+/// `dep:`.
+fn read_features(
+    manifest: &TomlManifest,
+) -> BTreeMap<InternedString, Vec<FeatureValue>> {
+    let mut features = manifest
+        .features
+        .iter()
+        .flatten()
+        .map(|(name, values)| {
+            let values = values
+                .iter()
+                .map(|value| FeatureValue::parse(value))
+                .collect::<Vec<_>>();
+            (InternedString::new(name), values)
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    let explicit_deps = features
+        .values()
+        .flatten()
+        .filter_map(|value| match value {
+            FeatureValue::Dep { dep_name } => Some(*dep_name),
+            _ => None,
+        })
+        .collect::<BTreeSet<_>>();
+
+    let platform_tables =
+        manifest.target.iter().flat_map(BTreeMap::values).flat_map(|tables| {
+            [
+                &tables.dependencies,
+                &tables.dev_dependencies,
+                &tables.build_dependencies,
+            ]
+        });
+
+    let optional_deps = [
+        &manifest.dependencies,
+        &manifest.dev_dependencies,
+        &manifest.build_dependencies,
+    ]
+    .into_iter()
+    .chain(platform_tables)
+    .flatten()
+    .flatten()
+    .filter(|(_, dep)| match dep {
+        InheritableDependency::Value(TomlDependency::Detailed(detailed)) => {
+            detailed.optional == Some(true)
+        },
+        _ => false,
+    })
+    .map(|(name, _)| InternedString::new(name));
+
+    for dep_name in optional_deps {
+        if !explicit_deps.contains(&dep_name) {
+            features
+                .entry(dep_name)
+                .or_insert_with(|| vec![FeatureValue::Dep { dep_name }]);
+        }
+    }
+
+    features
+}
+
+impl FeatureValue {
+    /// Parses a feature value as written in the `[features]` table, or in
+    /// the `features` of a dependency.
+    pub(crate) fn parse(value: &str) -> Self {
+        if let Some(dep_name) = value.strip_prefix("dep:") {
+            return Self::Dep { dep_name: dep_name.into() };
+        }
+
+        match value.split_once('/') {
+            Some((dep_name, dep_feature)) => {
+                let (dep_name, weak) = match dep_name.strip_suffix('?') {
+                    Some(dep_name) => (dep_name, true),
+                    None => (dep_name, false),
+                };
+                Self::DepFeature {
+                    dep_name: dep_name.into(),
+                    dep_feature: dep_feature.into(),
+                    weak,
+                }
+            },
+            None => Self::Feature(value.into()),
+        }
+    }
+}
+
+/// Returns the targets of the package with the given (normalized) manifest,
+/// discovering the ones that aren't explicitly declared in the same way as
+/// Cargo.
+///
+/// Examples and benchmarks are never built, so they're not discovered.
+fn discover_targets(
+    manifest: &TomlManifest,
+    package_name: &str,
+    edition: Edition,
+    root: &Path,
+) -> Vec<Target> {
+    let package = manifest.package.as_deref();
+
+    // With the 2015 edition, declaring any target of a kind disables the
+    // auto-discovery of the other targets of that kind.
+    let is_auto = |auto: Option<bool>, explicit: bool| {
+        auto.unwrap_or(edition != Edition::Edition2015 || !explicit)
+    };
+
+    let mut targets = Vec::new();
+
+    let lib_path = manifest
+        .lib
+        .as_ref()
+        .and_then(|lib| lib.path.as_ref())
+        .map(|path| root.join(&path.0))
+        .or_else(|| {
+            let is_autolib = package.and_then(|pkg| pkg.autolib) != Some(false);
+            let path = root.join("src").join("lib.rs");
+            (is_autolib && path.is_file()).then_some(path)
+        });
+
+    if let Some(path) = lib_path {
+        let lib = manifest.lib.as_ref();
+
+        let crate_types = match lib.and_then(TomlTarget::crate_types) {
+            Some(crate_types) => {
+                crate_types.iter().map(|ty| CrateType::parse(ty)).collect()
+            },
+            None if lib.and_then(TomlTarget::proc_macro) == Some(true) => {
+                vec![CrateType::ProcMacro]
+            },
+            None => vec![CrateType::Lib],
+        };
+
+        let name = lib
+            .and_then(|lib| lib.name.clone())
+            .unwrap_or_else(|| package_name.replace('-', "_"));
+
+        targets.push(Target::from_toml(
+            TargetKind::Lib(crate_types),
+            name,
+            path,
+            lib,
+        ));
+    }
+
+    let explicit_bins = manifest.bin.as_deref().unwrap_or_default();
+
+    let mut bins = explicit_bins
+        .iter()
+        .filter_map(|bin| {
+            let name = bin.name.clone()?;
+            let path = match &bin.path {
+                Some(path) => root.join(&path.0),
+                None => [
+                    root.join("src").join("bin").join(format!("{name}.rs")),
+                    root.join("src").join("bin").join(&name).join("main.rs"),
+                    root.join("src").join("main.rs"),
+                ]
+                .into_iter()
+                .find(|path| path.is_file())?,
+            };
+            Some((name, path, Some(bin)))
+        })
+        .collect::<Vec<_>>();
+
+    if is_auto(package.and_then(|pkg| pkg.autobins), !explicit_bins.is_empty())
+    {
+        let main_rs = root.join("src").join("main.rs");
+        let inferred = main_rs
+            .is_file()
+            .then(|| (package_name.to_owned(), main_rs))
+            .into_iter()
+            .chain(infer_from_dir(&root.join("src").join("bin")));
+        push_inferred(&mut bins, inferred);
+    }
+
+    targets.extend(bins.into_iter().map(|(name, path, bin)| {
+        Target::from_toml(TargetKind::Bin, name, path, bin)
+    }));
+
+    let explicit_tests = manifest.test.as_deref().unwrap_or_default();
+
+    let mut tests = explicit_tests
+        .iter()
+        .filter_map(|test| {
+            let name = test.name.clone()?;
+            let path = match &test.path {
+                Some(path) => root.join(&path.0),
+                None => [
+                    root.join("tests").join(format!("{name}.rs")),
+                    root.join("tests").join(&name).join("main.rs"),
+                ]
+                .into_iter()
+                .find(|path| path.is_file())?,
+            };
+            Some((name, path, Some(test)))
+        })
+        .collect::<Vec<_>>();
+
+    if is_auto(
+        package.and_then(|pkg| pkg.autotests),
+        !explicit_tests.is_empty(),
+    ) {
+        push_inferred(&mut tests, infer_from_dir(&root.join("tests")));
+    }
+
+    targets.extend(tests.into_iter().map(|(name, path, test)| {
+        Target::from_toml(TargetKind::Test, name, path, test)
+    }));
+
+    targets.extend(build_script_targets(manifest, root));
+
+    targets
+}
+
+/// Returns the build script targets of the package with the given manifest.
+fn build_script_targets(manifest: &TomlManifest, root: &Path) -> Vec<Target> {
+    let Some(package) = manifest.package.as_deref() else { return Vec::new() };
+
+    let build_script = |name: &str, src_path| Target {
+        doctested: false,
+        harness: false,
+        kind: TargetKind::CustomBuild,
+        name: format!("build-script-{name}"),
+        required_features: None,
+        src_path,
+        tested: false,
+    };
+
+    if package.metabuild.is_some() {
+        return vec![build_script("metabuild", TargetSourcePath::Metabuild)];
+    }
+
+    let paths = match &package.build {
+        None => {
+            let build_rs = root.join("build.rs");
+            if build_rs.is_file() { vec![build_rs] } else { Vec::new() }
+        },
+        Some(TomlPackageBuild::Auto(true)) => vec![root.join("build.rs")],
+        Some(TomlPackageBuild::Auto(false)) => Vec::new(),
+        Some(TomlPackageBuild::SingleScript(path)) => vec![root.join(path)],
+        Some(TomlPackageBuild::MultipleScript(paths)) => {
+            paths.iter().map(|path| root.join(path)).collect()
+        },
+    };
+
+    paths
+        .into_iter()
+        .map(|path| {
+            let stem = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            build_script(&stem, TargetSourcePath::Path(path))
+        })
+        .collect()
+}
+
+/// Returns the targets inferred from the given directory, i.e. one for every
+/// `<name>.rs` file and every `<name>/main.rs` file in it.
+fn infer_from_dir(dir: &Path) -> Vec<(String, PathBuf)> {
+    let Ok(entries) = fs::read_dir(dir) else { return Vec::new() };
+
+    let mut inferred = entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let path = entry.path();
+            let file_name = entry.file_name().into_string().ok()?;
+            if path.is_dir() {
+                let main_rs = path.join("main.rs");
+                main_rs.is_file().then_some((file_name, main_rs))
+            } else {
+                let name = file_name.strip_suffix(".rs")?.to_owned();
+                Some((name, path))
+            }
+        })
+        .collect::<Vec<_>>();
+
+    inferred.sort_unstable();
+    inferred
+}
+
+/// Adds the inferred targets to the explicitly declared ones, skipping the
+/// ones with the same name or path as an explicit target.
+fn push_inferred<'a>(
+    targets: &mut Vec<(String, PathBuf, Option<&'a TomlTarget>)>,
+    inferred: impl IntoIterator<Item = (String, PathBuf)>,
+) {
+    for (name, path) in inferred {
+        let is_explicit =
+            targets.iter().any(|(explicit_name, explicit_path, _)| {
+                *explicit_name == name || *explicit_path == path
+            });
+        if !is_explicit {
+            targets.push((name, path, None));
+        }
+    }
+}
@@ -0,0 +1,707 @@
+use core::fmt;
+use std::env;
+
+use cargo_util_schemas::manifest::{
+    ProfilePackageSpec,
+    StringOrBool,
+    TomlDebugInfo,
+    TomlProfile,
+    TomlProfiles,
+};
+
+use crate::interning::InternedString;
+use crate::manifest::PackageId;
+use crate::target_data::CompileKind;
+
+/// The profile a single unit is compiled with, after all the overrides have
+/// been applied.
+#[derive(Clone, Debug)]
+pub(crate) struct Profile {
+    pub(crate) name: InternedString,
+    pub(crate) opt_level: InternedString,
+    pub(crate) root: ProfileRoot,
+    pub(crate) lto: Lto,
+    pub(crate) codegen_backend: Option<InternedString>,
+    pub(crate) codegen_units: Option<u32>,
+    pub(crate) debuginfo: DebugInfo,
+    pub(crate) split_debuginfo: Option<InternedString>,
+    pub(crate) debug_assertions: bool,
+    pub(crate) overflow_checks: bool,
+    pub(crate) rpath: bool,
+    pub(crate) incremental: bool,
+    pub(crate) panic: PanicStrategy,
+    pub(crate) strip: Strip,
+    pub(crate) rustflags: Vec<InternedString>,
+}
+
+/// The built-in profile a profile ultimately inherits from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum ProfileRoot {
+    Release,
+    Debug,
+}
+
+/// The `lto` setting of a profile.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Lto {
+    /// `lto = "off"`.
+    Off,
+
+    /// `lto = true` or `lto = false`.
+    Bool(bool),
+
+    /// Any other string, like `"thin"` or `"fat"`.
+    Named(InternedString),
+}
+
+/// The `debug` setting of a profile.
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum DebugInfo {
+    Resolved(TomlDebugInfo),
+
+    /// The debuginfo level of a unit compiled for the host, which Cargo only
+    /// uses if the unit is shared with the target.
+    Deferred(TomlDebugInfo),
+}
+
+/// The `strip` setting of a profile.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Strip {
+    /// The profile doesn't set `strip`, so it can still be changed depending
+    /// on the debuginfo level.
+    Deferred(StripInner),
+
+    Resolved(StripInner),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum StripInner {
+    None,
+    Named(InternedString),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum PanicStrategy {
+    Unwind,
+    Abort,
+}
+
+/// What a unit is compiled for, which determines the overrides applied to
+/// its profile.
+#[derive(Copy, Clone)]
+pub(crate) struct UnitFor {
+    /// Whether the unit is a build script or a proc-macro, or one of their
+    /// dependencies.
+    is_for_host: bool,
+}
+
+/// The profiles defined by a workspace and by the Cargo configuration.
+pub(crate) struct Profiles {
+    /// The default profile of the requested profile's root, with the name of
+    /// the requested profile.
+    default: Profile,
+
+    /// The host's target triple.
+    host_triple: InternedString,
+
+    /// The value of `CARGO_INCREMENTAL`, if set.
+    incremental: Option<bool>,
+
+    /// The settings of the requested profile, merged with the ones of all
+    /// the profiles it inherits from.
+    toml: Option<TomlProfile>,
+}
+
+/// The type of error that can occur when resolving the requested profile.
+#[derive(Debug, derive_more::Display, cauchy::Error)]
+pub(crate) enum ProfilesError {
+    #[display("profile {_0:?} inherits from itself")]
+    InheritsCycle(String),
+
+    #[display("profile {_0:?} must set `inherits`")]
+    MissingInherits(String),
+
+    #[display(
+        "profile {profile:?} inherits from {inherits:?}, which is not defined"
+    )]
+    UndefinedInherits { profile: String, inherits: String },
+
+    #[display(
+        "invalid `panic` setting {_0:?}, expected \"unwind\" or \"abort\""
+    )]
+    InvalidPanic(String),
+
+    #[display("profile {_0:?} is not defined")]
+    UndefinedProfile(String),
+}
+
+/// Like Cargo's, the default profile is an unnamed one inheriting from `dev`.
+impl Default for Profile {
+    fn default() -> Self {
+        Self::default_for_root(InternedString::new(""), ProfileRoot::Debug)
+    }
+}
+
+impl Profile {
+    fn default_for_root(name: InternedString, root: ProfileRoot) -> Self {
+        let is_debug = root == ProfileRoot::Debug;
+
+        Self {
+            name,
+            opt_level: if is_debug { "0" } else { "3" }.into(),
+            root,
+            lto: Lto::Bool(false),
+            codegen_backend: None,
+            codegen_units: None,
+            debuginfo: DebugInfo::Resolved(if is_debug {
+                TomlDebugInfo::Full
+            } else {
+                TomlDebugInfo::None
+            }),
+            split_debuginfo: None,
+            debug_assertions: is_debug,
+            overflow_checks: is_debug,
+            rpath: false,
+            incremental: is_debug,
+            panic: PanicStrategy::Unwind,
+            strip: Strip::Deferred(StripInner::None),
+            rustflags: Vec::new(),
+        }
+    }
+
+    /// Applies the settings of the given (already validated) profile table.
+    fn merge(&mut self, toml: &TomlProfile) {
+        if let Some(opt_level) = &toml.opt_level {
+            self.opt_level = opt_level.0.as_str().into();
+        }
+
+        match &toml.lto {
+            Some(StringOrBool::Bool(lto)) => self.lto = Lto::Bool(*lto),
+            Some(StringOrBool::String(lto)) if lto == "off" => {
+                self.lto = Lto::Off;
+            },
+            Some(StringOrBool::String(lto)) => {
+                self.lto = Lto::Named(lto.as_str().into());
+            },
+            None => {},
+        }
+
+        if let Some(codegen_backend) = &toml.codegen_backend {
+            self.codegen_backend = Some(codegen_backend.as_str().into());
+        }
+
+        if let Some(codegen_units) = toml.codegen_units {
+            self.codegen_units = Some(codegen_units);
+        }
+
+        if let Some(debuginfo) = toml.debug {
+            self.debuginfo = DebugInfo::Resolved(debuginfo);
+        }
+
+        if let Some(debug_assertions) = toml.debug_assertions {
+            self.debug_assertions = debug_assertions;
+        }
+
+        if let Some(split_debuginfo) = &toml.split_debuginfo {
+            self.split_debuginfo = Some(split_debuginfo.as_str().into());
+        }
+
+        if let Some(rpath) = toml.rpath {
+            self.rpath = rpath;
+        }
+
+        if let Some(panic) = &toml.panic {
+            self.panic = if panic == "abort" {
+                PanicStrategy::Abort
+            } else {
+                PanicStrategy::Unwind
+            };
+        }
+
+        if let Some(overflow_checks) = toml.overflow_checks {
+            self.overflow_checks = overflow_checks;
+        }
+
+        if let Some(incremental) = toml.incremental {
+            self.incremental = incremental;
+        }
+
+        if let Some(rustflags) = &toml.rustflags {
+            self.rustflags =
+                rustflags.iter().map(|flag| flag.as_str().into()).collect();
+        }
+
+        match &toml.strip {
+            Some(StringOrBool::Bool(true)) => {
+                self.strip =
+                    Strip::Resolved(StripInner::Named("symbols".into()));
+            },
+            Some(StringOrBool::Bool(false)) => {
+                self.strip = Strip::Resolved(StripInner::None);
+            },
+            Some(StringOrBool::String(strip)) if strip == "none" => {
+                self.strip = Strip::Resolved(StripInner::None);
+            },
+            Some(StringOrBool::String(strip)) => {
+                self.strip =
+                    Strip::Resolved(StripInner::Named(strip.as_str().into()));
+            },
+            None => {},
+        }
+    }
+}
+
+impl DebugInfo {
+    pub(crate) fn into_inner(self) -> TomlDebugInfo {
+        match self {
+            Self::Resolved(debuginfo) | Self::Deferred(debuginfo) => debuginfo,
+        }
+    }
+
+    fn is_turned_on(self) -> bool {
+        self.into_inner() != TomlDebugInfo::None
+    }
+}
+
+impl Strip {
+    pub(crate) fn into_inner(self) -> StripInner {
+        match self {
+            Self::Deferred(strip) | Self::Resolved(strip) => strip,
+        }
+    }
+}
+
+impl UnitFor {
+    /// Returns the `UnitFor` of a build script or a proc-macro.
+    ///
+    /// The arguments are only there to match Cargo's signature, since every
+    /// unit compiled for the host is treated the same.
+    pub(crate) fn new_host(_host_features: bool, _: CompileKind) -> Self {
+        Self { is_for_host: true }
+    }
+
+    /// Returns the `UnitFor` of a unit that's compiled for the target.
+    pub(crate) fn new_normal(_: CompileKind) -> Self {
+        Self { is_for_host: false }
+    }
+}
+
+impl Profiles {
+    /// Resolves the requested profile from the ones defined in the manifest
+    /// at the root of the workspace and in the Cargo configuration (which
+    /// takes precedence).
+    pub(crate) fn new(
+        manifest_profiles: Option<&TomlProfiles>,
+        config_profiles: Option<&TomlProfiles>,
+        requested: &str,
+        host_triple: InternedString,
+    ) -> Result<Self, ProfilesError> {
+        let get_toml =
+            |name: &str| merged_toml(manifest_profiles, config_profiles, name);
+
+        // The chain of profiles from the requested one to its root.
+        let mut chain = vec![(requested.to_owned(), get_toml(requested))];
+
+        let root = loop {
+            let (name, toml) = chain.last().expect("chain is not empty");
+
+            let inherits = match name.as_str() {
+                "dev" => break ProfileRoot::Debug,
+                "release" => break ProfileRoot::Release,
+                "test" => "dev".to_owned(),
+                "bench" => "release".to_owned(),
+                _ => match toml {
+                    Some(toml) => toml.inherits.clone().ok_or_else(|| {
+                        ProfilesError::MissingInherits(name.clone())
+                    })?,
+                    None => {
+                        return Err(ProfilesError::UndefinedProfile(
+                            name.clone(),
+                        ));
+                    },
+                },
+            };
+
+            if chain.iter().any(|(name, _)| *name == inherits) {
+                return Err(ProfilesError::InheritsCycle(inherits));
+            }
+
+            let inherits_toml = get_toml(&inherits);
+
+            if inherits_toml.is_none()
+                && !matches!(&*inherits, "dev" | "release" | "test" | "bench")
+            {
+                return Err(ProfilesError::UndefinedInherits {
+                    profile: name.clone(),
+                    inherits,
+                });
+            }
+
+            chain.push((inherits, inherits_toml));
+        };
+
+        // Merge the settings from the root down to the requested profile.
+        let toml = chain.into_iter().rev().filter_map(|(_, toml)| toml).reduce(
+            |mut merged, toml| {
+                merged.merge(&toml);
+                merged
+            },
+        );
+
+        if let Some(toml) = &toml {
+            validate(toml)?;
+        }
+
+        let incremental =
+            env::var("CARGO_INCREMENTAL").ok().map(|value| value == "1");
+
+        Ok(Self {
+            default: Profile::default_for_root(requested.into(), root),
+            host_triple,
+            incremental,
+            toml,
+        })
+    }
+
+    /// Returns the profile of a unit of the package with the given ID.
+    #[expect(clippy::too_many_arguments)]
+    #[expect(clippy::too_many_lines)]
+    pub(crate) fn get_profile(
+        &self,
+        pkg_id: PackageId,
+        is_member: bool,
+        is_local: bool,
+        unit_for: UnitFor,
+        kind: CompileKind,
+    ) -> Profile {
+        let mut profile = self.default.clone();
+
+        if let Some(toml) = &self.toml {
+            profile.merge(toml);
+        }
+
+        if unit_for.is_for_host {
+            // Like Cargo, compile build scripts, proc-macros and their
+            // dependencies quickly by default.
+            profile.opt_level = "0".into();
+            profile.codegen_units = None;
+            profile.debuginfo =
+                DebugInfo::Deferred(profile.debuginfo.into_inner());
+
+            if let Some(build_override) =
+                self.toml.as_ref().and_then(|t| t.build_override.as_deref())
+            {
+                profile.merge(build_override);
+            }
+        }
+
+        if let Some(overrides) =
+            self.toml.as_ref().and_then(|toml| toml.package.as_ref())
+        {
+            if !is_member
+                && let Some(all) = overrides.get(&ProfilePackageSpec::All)
+            {
+                profile.merge(all);
+            }
+
+            let spec_override = overrides.iter().find_map(|(spec, toml)| {
+                let ProfilePackageSpec::Spec(spec) = spec else { return None };
+                let is_match = spec.name() == pkg_id.name().as_str()
+                    && spec.partial_version().is_none_or(|version| {
+                        version.matches(pkg_id.version())
+                    });
+                is_match.then_some(toml)
+            });
+
+            if let Some(spec_override) = spec_override {
+                profile.merge(spec_override);
+            }
+        }
+
+        // Build scripts and proc-macros are loaded by the compiler, which
+        // relies on unwinding.
+        if unit_for.is_for_host {
+            profile.panic = PanicStrategy::Unwind;
+        }
+
+        // Only enable incremental compilation for sources the user can
+        // modify.
+        profile.incremental =
+            is_local && self.incremental.unwrap_or(profile.incremental);
+
+        // Like Cargo, store the debug information of Apple targets in the
+        // "unpacked" format by default.
+        let triple = match kind {
+            CompileKind::Host => self.host_triple,
+            CompileKind::Target(target) => target.rustc_target(),
+        };
+        if profile.debuginfo.is_turned_on()
+            && profile.split_debuginfo.is_none()
+            && triple.contains("-apple-")
+        {
+            profile.split_debuginfo = Some("unpacked".into());
+        }
+
+        // Strip the debug information if it's not requested and the profile
+        // doesn't say otherwise.
+        if !profile.debuginfo.is_turned_on()
+            && profile.strip == Strip::Deferred(StripInner::None)
+        {
+            profile.strip =
+                Strip::Deferred(StripInner::Named("debuginfo".into()));
+        }
+
+        profile
+    }
+}
+
+/// Returns the table of the profile with the given name, with the settings
+/// from the Cargo configuration applied over the ones from the manifest.
+fn merged_toml(
+    manifest_profiles: Option<&TomlProfiles>,
+    config_profiles: Option<&TomlProfiles>,
+    name: &str,
+) -> Option<TomlProfile> {
+    let manifest = manifest_profiles.and_then(|profiles| profiles.get(name));
+    let config = config_profiles.and_then(|profiles| profiles.get(name));
+
+    match (manifest, config) {
+        (Some(manifest), Some(config)) => {
+            let mut merged = manifest.clone();
+            merged.merge(config);
+            Some(merged)
+        },
+        (manifest, config) => manifest.or(config).cloned(),
+    }
+}
+
+/// Checks the settings of the given profile table (and of the tables nested
+/// in it) that Cargo would reject.
+fn validate(toml: &TomlProfile) -> Result<(), ProfilesError> {
+    if let Some(panic) = &toml.panic
+        && panic != "unwind"
+        && panic != "abort"
+    {
+        return Err(ProfilesError::InvalidPanic(panic.clone()));
+    }
+
+    toml.build_override
+        .as_deref()
+        .into_iter()
+        .chain(toml.package.iter().flat_map(|overrides| overrides.values()))
+        .try_for_each(validate)
+}
+
+impl fmt::Display for PanicStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Unwind => "unwind",
+            Self::Abort => "abort",
+        })
+    }
+}
+
+impl fmt::Display for StripInner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => f.write_str("none"),
+            Self::Named(name) => f.write_str(name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::SourceId;
+
+    const HOST: &str = "x86_64-unknown-linux-gnu";
+
+    fn toml_profiles(toml: &str) -> TomlProfiles {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn profiles(
+        manifest: &str,
+        config: &str,
+        requested: &str,
+    ) -> Result<Profiles, ProfilesError> {
+        Profiles::new(
+            Some(&toml_profiles(manifest)),
+            Some(&toml_profiles(config)),
+            requested,
+            HOST.into(),
+        )
+    }
+
+    fn get_profile(
+        profiles: &Profiles,
+        name: &str,
+        is_member: bool,
+        for_host: bool,
+    ) -> Profile {
+        let source_id = SourceId::from_lock(
+            "registry+https://github.com/rust-lang/crates.io-index",
+        )
+        .unwrap();
+
+        let pkg_id = PackageId::new(
+            name.into(),
+            semver::Version::new(1, 0, 0),
+            source_id,
+        );
+
+        let unit_for = if for_host {
+            UnitFor::new_host(false, CompileKind::Host)
+        } else {
+            UnitFor::new_normal(CompileKind::Host)
+        };
+
+        profiles.get_profile(
+            pkg_id,
+            is_member,
+            false,
+            unit_for,
+            CompileKind::Host,
+        )
+    }
+
+    #[test]
+    fn builtin_defaults() {
+        let release = profiles("", "", "release").unwrap();
+        let profile = get_profile(&release, "foo", true, false);
+        assert_eq!(profile.opt_level, "3");
+        assert_eq!(profile.root, ProfileRoot::Release);
+        assert_eq!(profile.lto, Lto::Bool(false));
+        assert!(!profile.debug_assertions);
+        assert_eq!(
+            profile.strip,
+            Strip::Deferred(StripInner::Named("debuginfo".into())),
+        );
+
+        let dev = profiles("", "", "dev").unwrap();
+        let profile = get_profile(&dev, "foo", true, false);
+        assert_eq!(profile.opt_level, "0");
+        assert_eq!(profile.root, ProfileRoot::Debug);
+        assert!(profile.debug_assertions);
+        assert_eq!(profile.strip, Strip::Deferred(StripInner::None));
+    }
+
+    #[test]
+    fn inherited_and_config_settings() {
+        let manifest = r#"
+[release]
+opt-level = 2
+lto = "thin"
+
+[dist]
+inherits = "release"
+codegen-units = 1
+panic = "abort"
+"#;
+
+        let config = r#"
+[dist]
+lto = "fat"
+"#;
+
+        let dist = profiles(manifest, config, "dist").unwrap();
+        let profile = get_profile(&dist, "foo", true, false);
+        assert_eq!(profile.name, "dist");
+        assert_eq!(profile.root, ProfileRoot::Release);
+        assert_eq!(profile.opt_level, "2");
+        assert_eq!(profile.lto, Lto::Named("fat".into()));
+        assert_eq!(profile.codegen_units, Some(1));
+        assert_eq!(profile.panic, PanicStrategy::Abort);
+    }
+
+    #[test]
+    fn host_units() {
+        let manifest = r#"
+[release]
+panic = "abort"
+codegen-units = 4
+"#;
+
+        let release = profiles(manifest, "", "release").unwrap();
+        let profile = get_profile(&release, "foo", false, true);
+        assert_eq!(profile.opt_level, "0");
+        assert_eq!(profile.codegen_units, None);
+        assert_eq!(profile.panic, PanicStrategy::Unwind);
+        assert!(matches!(profile.debuginfo, DebugInfo::Deferred(_)));
+
+        let manifest = r#"
+[release.build-override]
+opt-level = 1
+"#;
+
+        let release = profiles(manifest, "", "release").unwrap();
+        assert_eq!(get_profile(&release, "foo", false, true).opt_level, "1");
+        assert_eq!(get_profile(&release, "foo", false, false).opt_level, "3");
+    }
+
+    #[test]
+    fn package_overrides() {
+        let manifest = r#"
+[release.package."*"]
+opt-level = 1
+
+[release.package.foo]
+opt-level = "s"
+"#;
+
+        let release = profiles(manifest, "", "release").unwrap();
+
+        // `*` only applies to packages outside the workspace.
+        assert_eq!(get_profile(&release, "bar", true, false).opt_level, "3");
+        assert_eq!(get_profile(&release, "bar", false, false).opt_level, "1");
+        assert_eq!(get_profile(&release, "foo", true, false).opt_level, "s");
+        assert_eq!(get_profile(&release, "foo", false, false).opt_level, "s");
+    }
+
+    #[test]
+    fn apple_split_debuginfo() {
+        let dev =
+            Profiles::new(None, None, "dev", "aarch64-apple-darwin".into())
+                .unwrap();
+        let profile = get_profile(&dev, "foo", true, false);
+        assert_eq!(profile.split_debuginfo, Some("unpacked".into()));
+
+        let release = profiles("", "", "release").unwrap();
+        let profile = get_profile(&release, "foo", true, false);
+        assert_eq!(profile.split_debuginfo, None);
+    }
+
+    #[test]
+    fn invalid_profiles() {
+        let cycle = "[a]\ninherits = \"b\"\n\n[b]\ninherits = \"a\"\n";
+        assert!(matches!(
+            profiles(cycle, "", "a"),
+            Err(ProfilesError::InheritsCycle(_)),
+        ));
+
+        assert!(matches!(
+            profiles("[custom]\nopt-level = 1\n", "", "custom"),
+            Err(ProfilesError::MissingInherits(_)),
+        ));
+
+        assert!(matches!(
+            profiles("[custom]\ninherits = \"nope\"\n", "", "custom"),
+            Err(ProfilesError::UndefinedInherits { .. }),
+        ));
+
+        assert!(matches!(
+            profiles("", "", "nope"),
+            Err(ProfilesError::UndefinedProfile(_)),
+        ));
+
+        assert!(matches!(
+            profiles(
+                "[release.build-override]\npanic = \"oops\"\n",
+                "",
+                "release"
+            ),
+            Err(ProfilesError::InvalidPanic(_)),
+        ));
+    }
+}
@@ -1,24 +1,34 @@
 use core::result::Result;
 use std::borrow::Cow;
 use std::path::{Path, PathBuf};
-use std::{env, io};
 
-use cargo::core::compiler::{CompileKind, CompileTarget, RustcTargetData};
+#[cfg(not(feature = "forbid-cargo"))]
+use cargo::core::compiler::{CompileKind, RustcTargetData};
+#[cfg(not(feature = "forbid-cargo"))]
 use cargo::core::profiles::Profiles;
+#[cfg(not(feature = "forbid-cargo"))]
+use cargo::core::resolver::features::FeaturesFor;
+#[cfg(not(feature = "forbid-cargo"))]
 use cargo::core::resolver::{CliFeatures, ForceAllTargets, HasDevUnits};
-use cargo::core::{
-    Dependency,
-    MaybePackage,
-    Package,
-    PackageId,
-    Shell,
-    Workspace,
-};
+#[cfg(not(feature = "forbid-cargo"))]
+use cargo::core::{Dependency, PackageId, Shell, Workspace};
+#[cfg(not(feature = "forbid-cargo"))]
+use cargo::util::context::EnvConfig;
+#[cfg(not(feature = "forbid-cargo"))]
+use cargo::util::interning::InternedString;
+#[cfg(not(feature = "forbid-cargo"))]
 use cargo::{GlobalContext, ops};
 use compact_str::CompactString;
 use nix_bindings::prelude::{Error as NixError, *};
 
 use crate::build_graph::BuildGraph;
+#[cfg(not(feature = "forbid-cargo"))]
+use crate::build_graph::ConfigEnvVar;
+use crate::cargo_types::{CompileTarget, MaybePackage, Package};
+#[cfg(feature = "forbid-cargo")]
+pub(crate) use crate::lock_resolve::WorkspaceResolve;
+#[cfg(feature = "forbid-cargo")]
+use crate::lock_resolve::cargo_ctx;
 
 /// Resolves the build graph of a Rust package.
 #[derive(nix_bindings::PrimOp)]
@@ -31,11 +41,20 @@ pub(crate) struct ResolveBuildGraphArgs<'a> {
     /// The path to the root of the workspace the package is in.
     pub(crate) src: &'a Path,
 
-    /// The path to the directory containing all the vendored dependencies.
+    /// The directory containing all the vendored dependencies.
     ///
-    /// This can be obtained by calling `jettison.vendorDeps { ... }`.
+    /// This can be obtained by calling `jettison.vendorDeps { ... }`. Since
+    /// only the packages' manifests are read, it can be called with
+    /// `manifestsOnly = true` to avoid downloading the crates' sources into
+    /// the output.
+    ///
+    /// If it's a derivation, it's realised at evaluation time. With the
+    /// `cargo` feature, that's done for the whole directory, so the first
+    /// evaluation fetches every vendored crate. Otherwise, only the packages
+    /// that are reached while resolving the graph are realised, and only if
+    /// they're used on the platforms the package is compiled for.
     #[try_from(with = get_vendor_dir)]
-    pub(crate) vendor_dir: Cow<'a, Path>,
+    pub(crate) vendor_dir: VendorDir<'a>,
 
     /// Whether to enable all features (equivalent to calling Cargo with the
     /// `--all-features` CLI flag).
@@ -47,6 +66,11 @@ pub(crate) struct ResolveBuildGraphArgs<'a> {
     #[try_from(default, with = parse_compile_target)]
     pub(crate) compile_target: Option<CompileTarget>,
 
+    /// Whether to also resolve the dev-dependencies of the package, which are
+    /// needed to build its tests.
+    #[try_from(default)]
+    pub(crate) dev_dependencies: bool,
+
     /// The list of the package's features to enable.
     #[try_from(default)]
     pub(crate) features: Vec<String>,
@@ -65,10 +89,23 @@ pub(crate) struct ResolveBuildGraphArgs<'a> {
     pub(crate) profile: CompactString,
 }
 
+/// The directory containing the vendored dependencies.
+pub(crate) enum VendorDir<'a> {
+    /// A derivation (usually the one returned by `jettison.vendorDeps`),
+    /// which is only realised when the packages in it are read.
+    Derivation(NixDerivation<'a>),
+
+    /// A directory that's already in the store or on the file system.
+    Path(Cow<'a, Path>),
+}
+
+#[cfg(not(feature = "forbid-cargo"))]
 pub(crate) struct WorkspaceResolve<'ws> {
     inner: ops::WorkspaceResolve<'ws>,
     compile_kind: CompileKind,
-    package_id: PackageId,
+    config_env: Vec<ConfigEnvVar>,
+    has_dev_units: HasDevUnits,
+    root_ids: Vec<PackageId>,
     profiles: Profiles,
     target_data: RustcTargetData<'ws>,
     workspace: Workspace<'ws>,
@@ -81,16 +118,12 @@ pub(crate) enum ResolveBuildGraphError {
     /// Configuring the global Cargo context failed.
     ConfigureCargoContext(anyhow::Error),
 
-    /// Constructing the [`RustcTargetData`] failed.
+    /// Constructing the `RustcTargetData` failed.
     CreateTargetData(anyhow::Error),
 
-    /// Constructing the [`Workspace`] failed.
+    /// Constructing the `Workspace` failed.
     CreateWorkspace(anyhow::Error),
 
-    /// Getting the current working directory failed.
-    #[display("couldn't get the current directory of the process: {_0}")]
-    GetCwd(io::Error),
-
     /// The `package` argument provided by the user didn't match the name of
     /// any package in the workspace.
     #[display("no package named '{_0}' found in the workspace")]
@@ -99,15 +132,37 @@ pub(crate) enum ResolveBuildGraphError {
     /// A Nix runtime error occurred.
     Nix(#[from] NixError),
 
+    /// A crate compiled with the `abort` panic strategy depends on a Rust
+    /// dylib, which can't be linked into it.
+    #[display(
+        "{package} can't be compiled with `panic = \"abort\"` because it \
+         links the Rust dylib {dylib}, which is always linked to a standard \
+         library compiled with `panic = \"unwind\"`"
+    )]
+    PanicAbortWithDylib { package: String, dylib: String },
+
     /// Parsing the features failed.
     ParseFeatures(anyhow::Error),
 
-    /// Creating the [`Profiles`] failed.
+    /// Reading the `[env]` table of the Cargo configuration failed.
+    ReadEnvConfig(anyhow::Error),
+
+    /// Creating the `Profiles` failed.
     ResolveProfiles(anyhow::Error),
 
-    /// Resolving the [`Workspace`] failed.
+    /// Resolving the `Workspace` failed.
     ResolveWorkspace(anyhow::Error),
 
+    /// Some of the `[patch]` entries didn't match any package in the
+    /// dependency graph.
+    #[display(
+        "the following patches are not used by any dependency: {}; check that \
+         their versions are compatible with the ones required by the \
+         packages depending on them",
+        _0.join(", ")
+    )]
+    UnusedPatches(Vec<String>),
+
     /// The user didn't specify a package name, and the workspace manifest is a
     /// virtual manifest with no root package.
     #[display(
@@ -117,7 +172,18 @@ pub(crate) enum ResolveBuildGraphError {
     VirtualManifestNoRootPackage,
 }
 
+#[cfg(not(feature = "forbid-cargo"))]
 impl ResolveBuildGraphArgs<'_> {
+    fn workspace<'ctx>(
+        &self,
+        cargo_ctx: &'ctx GlobalContext,
+    ) -> Result<Workspace<'ctx>, ResolveBuildGraphError> {
+        let manifest_path = self.src.join("Cargo.toml");
+
+        Workspace::new(&manifest_path, cargo_ctx)
+            .map_err(ResolveBuildGraphError::CreateWorkspace)
+    }
+
     fn features(&self) -> Result<CliFeatures, ResolveBuildGraphError> {
         CliFeatures::from_command_line(
             &self.features,
@@ -128,22 +194,41 @@ impl ResolveBuildGraphArgs<'_> {
     }
 }
 
+#[cfg(not(feature = "forbid-cargo"))]
 impl<'ws> WorkspaceResolve<'ws> {
-    pub(crate) fn compile_kind(&self) -> CompileKind {
-        self.compile_kind
+    /// The environment variables set by the `[env]` table of the Cargo
+    /// configuration, sorted by name.
+    pub(crate) fn config_env(&self) -> &[ConfigEnvVar] {
+        &self.config_env
     }
 
+    /// Returns the dependencies of the given package when it's compiled for
+    /// the host (if `for_host` is true) or for the compile target, in the
+    /// same way Cargo computes the dependencies of a unit.
     pub(crate) fn deps(
         &self,
         pkg_id: PackageId,
+        for_host: bool,
     ) -> impl Iterator<Item = (PackageId, &Dependency)> {
         self.inner.targeted_resolve.deps(pkg_id).flat_map(
-            |(dep_pkg_id, dep_set)| {
-                // Filter out dependencies that don't match our target
-                // platform.
-                let deps = dep_set.iter().filter(|&dep| {
-                    self.target_data
-                        .dep_platform_activated(dep, self.compile_kind())
+            move |(dep_pkg_id, dep_set)| {
+                let deps = dep_set.iter().filter(move |&dep| {
+                    // Build dependencies are used by the build script, which
+                    // is always compiled for the host.
+                    let for_host = for_host || dep.is_build();
+
+                    // Filter out dependencies that don't match the platform
+                    // the package is compiled for, and optional dependencies
+                    // that the feature resolver didn't enable for it.
+                    self.target_data.dep_platform_activated(
+                        dep,
+                        self.unit_compile_kind(for_host),
+                    ) && (!dep.is_optional()
+                        || self.inner.resolved_features.is_dep_activated(
+                            pkg_id,
+                            FeaturesFor::from_for_host(for_host),
+                            dep.name_in_toml(),
+                        ))
                 });
 
                 deps.map(move |dep| (dep_pkg_id, dep))
@@ -151,11 +236,33 @@ impl<'ws> WorkspaceResolve<'ws> {
         )
     }
 
+    /// Returns the features that are enabled on the given package when it's
+    /// compiled for the host (if `for_host` is true) or for the compile
+    /// target.
+    ///
+    /// With resolver v2 these can differ, since the features of build
+    /// dependencies and proc-macros are unified separately.
     pub(crate) fn features(
         &self,
         pkg_id: PackageId,
-    ) -> impl Iterator<Item = &str> {
-        self.inner.targeted_resolve.features(pkg_id).iter().map(|s| s.as_str())
+        for_host: bool,
+    ) -> Vec<InternedString> {
+        self.inner
+            .resolved_features
+            .activated_features(pkg_id, FeaturesFor::from_for_host(for_host))
+    }
+
+    /// Whether the dev-dependencies of the root package have been resolved.
+    pub(crate) fn has_dev_units(&self) -> bool {
+        self.has_dev_units == HasDevUnits::Yes
+    }
+
+    /// Returns the linker set in the Cargo configuration for the platform
+    /// the given kind compiles for, if any.
+    pub(crate) fn linker(&self, kind: CompileKind) -> Option<CompactString> {
+        let linker = self.target_data.target_config(kind).linker.as_ref()?;
+        let path = linker.val.clone().resolve_program(self.workspace.gctx());
+        Some(path.display().to_string().into())
     }
 
     pub(crate) fn package(&self, pkg_id: PackageId) -> Option<&Package> {
@@ -166,53 +273,89 @@ impl<'ws> WorkspaceResolve<'ws> {
         &self.profiles
     }
 
-    /// The [`PackageId`] of the package at the root of the build graph.
-    pub(crate) fn root_id(&self) -> &PackageId {
-        &self.package_id
+    /// Returns whether the given package is one of the roots of the build
+    /// graph, i.e. one of the packages that were explicitly requested.
+    pub(crate) fn is_root(&self, pkg_id: PackageId) -> bool {
+        self.root_ids.contains(&pkg_id)
+    }
+
+    /// The [`PackageId`]s of the packages at the roots of the build graph.
+    pub(crate) fn root_ids(&self) -> &[PackageId] {
+        &self.root_ids
     }
 
     pub(crate) fn target_data(&self) -> &RustcTargetData<'ws> {
         &self.target_data
     }
 
+    /// Returns the kind of the units compiled for the host (if `for_host` is
+    /// true) or for the compile target.
+    pub(crate) fn unit_compile_kind(&self, for_host: bool) -> CompileKind {
+        if for_host { CompileKind::Host } else { self.compile_kind }
+    }
+
     pub(crate) fn workspace(&self) -> &Workspace<'ws> {
         &self.workspace
     }
 
     fn new(
         workspace: Workspace<'ws>,
-        package_id: PackageId,
+        root_ids: Vec<PackageId>,
         args: &ResolveBuildGraphArgs,
+        _: &mut Context,
     ) -> Result<Self, ResolveBuildGraphError> {
         let compile_kind = args
             .compile_target
             .map(CompileKind::Target)
             .unwrap_or(CompileKind::Host);
 
+        let has_dev_units = if args.dev_dependencies {
+            HasDevUnits::Yes
+        } else {
+            HasDevUnits::No
+        };
+
         let mut target_data = RustcTargetData::new(&workspace, &[compile_kind])
             .map_err(ResolveBuildGraphError::CreateTargetData)?;
 
+        let specs =
+            root_ids.iter().map(|pkg_id| pkg_id.to_spec()).collect::<Vec<_>>();
+
         let inner = ops::resolve_ws_with_opts(
             &workspace,
             &mut target_data,
             &[compile_kind],
             &args.features()?,
-            &[package_id.to_spec()],
-            HasDevUnits::No,
+            &specs,
+            has_dev_units,
             ForceAllTargets::No,
             true,
         )
         .map_err(ResolveBuildGraphError::ResolveWorkspace)?;
 
+        // Cargo only warns about unused patches, but since they're usually
+        // meant to fix a dependency we'd rather not silently build the
+        // unpatched one.
+        let unused_patches = inner.targeted_resolve.unused_patches();
+        if !unused_patches.is_empty() {
+            return Err(ResolveBuildGraphError::UnusedPatches(
+                unused_patches.iter().map(ToString::to_string).collect(),
+            ));
+        }
+
         let profiles = Profiles::new(&workspace, args.profile.as_str().into())
             .map_err(ResolveBuildGraphError::ResolveProfiles)?;
 
+        let config_env = read_config_env(workspace.gctx())?;
+
         Ok(Self {
             inner,
-            package_id,
+            has_dev_units,
+            root_ids,
             profiles,
             target_data,
             compile_kind,
+            config_env,
             workspace,
         })
     }
@@ -223,14 +366,11 @@ impl Function for ResolveBuildGraph {
 
     fn call<'a: 'a>(
         args: Self::Args<'a>,
-        _: &mut Context,
+        ctx: &mut Context,
     ) -> Result<BuildGraph, ResolveBuildGraphError> {
-        let manifest_path = args.src.join("Cargo.toml");
-
-        let cargo_ctx = cargo_ctx(args.vendor_dir.join(".cargo"))?;
+        let cargo_ctx = cargo_ctx(args.src, &args.vendor_dir, ctx)?;
 
-        let workspace = Workspace::new(&manifest_path, &cargo_ctx)
-            .map_err(ResolveBuildGraphError::CreateWorkspace)?;
+        let workspace = args.workspace(&cargo_ctx)?;
 
         let package =
             match args.package.as_deref() {
@@ -245,20 +385,58 @@ impl Function for ResolveBuildGraph {
 
                 None => match workspace.root_maybe() {
                     MaybePackage::Package(package) => package,
-                    MaybePackage::Virtual(_) => return Err(
+                    _ => return Err(
                         ResolveBuildGraphError::VirtualManifestNoRootPackage,
                     ),
                 },
             };
 
-        let package_id = package.package_id();
+        let root_ids = vec![package.package_id()];
 
-        let resolve = WorkspaceResolve::new(workspace, package_id, &args)?;
+        let resolve = WorkspaceResolve::new(workspace, root_ids, &args, ctx)?;
 
-        Ok(BuildGraph::new(package_id, &resolve, &*args.vendor_dir))
+        BuildGraph::new(&resolve)
     }
 }
 
+/// Resolves a single build graph whose roots are the given members of the
+/// workspace, or all of them if `members` is `None`.
+///
+/// Packages shared by more than one member are only resolved once, so the
+/// returned graph can be used to build the whole workspace at once.
+pub(crate) fn resolve_workspace_members(
+    args: &ResolveBuildGraphArgs,
+    members: Option<&[CompactString]>,
+    ctx: &mut Context,
+) -> Result<BuildGraph, ResolveBuildGraphError> {
+    let cargo_ctx = cargo_ctx(args.src, &args.vendor_dir, ctx)?;
+
+    let workspace = args.workspace(&cargo_ctx)?;
+
+    let root_ids = match members {
+        Some(members) => members
+            .iter()
+            .map(|member_name| {
+                workspace
+                    .members()
+                    .find(|package| package.name() == member_name.as_str())
+                    .map(Package::package_id)
+                    .ok_or_else(|| {
+                        ResolveBuildGraphError::InvalidPackageName(
+                            member_name.clone(),
+                        )
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+
+        None => workspace.members().map(Package::package_id).collect(),
+    };
+
+    let resolve = WorkspaceResolve::new(workspace, root_ids, args, ctx)?;
+
+    BuildGraph::new(&resolve)
+}
+
 impl From<ResolveBuildGraphError> for NixError {
     fn from(err: ResolveBuildGraphError) -> Self {
         match err {
@@ -268,38 +446,142 @@ impl From<ResolveBuildGraphError> for NixError {
     }
 }
 
+/// Creates the global Cargo context used to resolve the workspace at the
+/// given root.
+///
+/// Like when running Cargo from the workspace root, the configuration files
+/// are discovered from there upward, while the one written by `vendorDeps`
+/// is passed as if via `--config` so that its source replacements take
+/// precedence over any defined by the project.
+///
+/// Since Cargo reads the vendor directory as a whole, this realises all of it,
+/// which downloads the tarball of every package in the `Cargo.lock` (unlike
+/// the `forbid-cargo` resolver, which only realises the packages it reads).
+#[cfg(not(feature = "forbid-cargo"))]
 fn cargo_ctx(
-    cargo_home: PathBuf,
+    workspace_root: &Path,
+    vendor_dir: &VendorDir,
+    ctx: &mut Context,
 ) -> Result<GlobalContext, ResolveBuildGraphError> {
+    let vendor_dir = vendor_dir.realise(ctx)?;
+
     let shell = Shell::new();
 
-    let cwd = env::current_dir().map_err(ResolveBuildGraphError::GetCwd)?;
+    let cargo_home = vendor_dir.join(".cargo");
 
-    let mut ctx = GlobalContext::new(shell, cwd, cargo_home);
+    let vendor_config = cargo_home.join("config.toml").display().to_string();
 
-    ctx.configure(0, false, None, true, true, true, &None, &[], &[])
-        .map_err(ResolveBuildGraphError::ConfigureCargoContext)?;
+    let mut ctx =
+        GlobalContext::new(shell, workspace_root.to_owned(), cargo_home);
+
+    ctx.configure(
+        0,
+        false,
+        None,
+        true,
+        true,
+        true,
+        &None,
+        &[],
+        &[vendor_config],
+    )
+    .map_err(ResolveBuildGraphError::ConfigureCargoContext)?;
 
     Ok(ctx)
 }
 
+/// Reads the `[env]` table of the Cargo configuration.
+#[cfg(not(feature = "forbid-cargo"))]
+fn read_config_env(
+    cargo_ctx: &GlobalContext,
+) -> Result<Vec<ConfigEnvVar>, ResolveBuildGraphError> {
+    let env_config = cargo_ctx
+        .get::<EnvConfig>("env")
+        .map_err(ResolveBuildGraphError::ReadEnvConfig)?;
+
+    let mut config_env = env_config
+        .iter()
+        .map(|(name, value)| ConfigEnvVar {
+            name: name.as_str().into(),
+            value: value.resolve(cargo_ctx).to_string_lossy().into(),
+            force: value.is_force(),
+        })
+        .collect::<Vec<_>>();
+
+    config_env.sort_unstable_by(|lhs, rhs| lhs.name.cmp(&rhs.name));
+
+    Ok(config_env)
+}
+
+impl VendorDir<'_> {
+    /// Returns the path to the vendor directory, realising all of it first if
+    /// it's a derivation.
+    #[cfg(not(feature = "forbid-cargo"))]
+    pub(crate) fn realise(
+        &self,
+        ctx: &mut Context,
+    ) -> Result<Cow<'_, Path>, NixError> {
+        match self {
+            Self::Derivation(drv) => {
+                drv.realise(ctx)?;
+                drv.out_path(ctx).map(Cow::Owned)
+            },
+            Self::Path(path) => Ok(Cow::Borrowed(path)),
+        }
+    }
+
+    /// Returns the path to the vendored package in the directory with the
+    /// given name, realising only that package if the vendor directory is a
+    /// derivation.
+    ///
+    /// This relies on the `entries` that `pkgs.linkFarm` passes through,
+    /// falling back to realising the whole directory if there aren't any.
+    #[cfg(feature = "forbid-cargo")]
+    pub(crate) fn package_dir(
+        &self,
+        dir_name: &str,
+        ctx: &mut Context,
+    ) -> Result<PathBuf, NixError> {
+        let drv = match self {
+            Self::Derivation(drv) => *drv,
+            Self::Path(path) => return Ok(path.join(dir_name)),
+        };
+
+        let package_drv = drv
+            .get_opt::<NixAttrset>(c"entries", ctx)?
+            .map(|entries| entries.get_opt::<NixDerivation>(dir_name, ctx))
+            .transpose()?
+            .flatten();
+
+        match package_drv {
+            Some(package_drv) => {
+                package_drv.realise(ctx)?;
+                package_drv.out_path(ctx)
+            },
+            None => {
+                drv.realise(ctx)?;
+                drv.out_path(ctx).map(|path| path.join(dir_name))
+            },
+        }
+    }
+}
+
 fn get_vendor_dir<'a>(
     mut value: NixValue<'a>,
     ctx: &mut Context,
-) -> Result<Cow<'a, Path>, NixError> {
+) -> Result<VendorDir<'a>, NixError> {
     value.force_inline(ctx)?;
 
     match value.kind() {
-        ValueKind::Attrset => NixDerivation::try_from_value(value, ctx)
-            .and_then(|drv| drv.out_path(ctx))
-            .map(Cow::Owned),
-
-        ValueKind::Path => {
-            <&'a Path>::try_from_value(value, ctx).map(Cow::Borrowed)
+        ValueKind::Attrset => {
+            NixDerivation::try_from_value(value, ctx).map(VendorDir::Derivation)
         },
 
+        ValueKind::Path => <&'a Path>::try_from_value(value, ctx)
+            .map(|path| VendorDir::Path(Cow::Borrowed(path))),
+
         ValueKind::String => <String>::try_from_value(value, ctx)
-            .map(|s| Cow::Owned(PathBuf::from(s))),
+            .map(|s| VendorDir::Path(Cow::Owned(PathBuf::from(s)))),
 
         _ => Err(NixError::new(
             ErrorKind::Nix,
@@ -0,0 +1,316 @@
+use std::io;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use cargo_platform::Cfg;
+
+use crate::cargo_config::{CargoConfig, CargoConfigError};
+use crate::interning::InternedString;
+use crate::manifest::Dependency;
+
+/// The platform a unit is compiled for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) enum CompileKind {
+    /// The platform `rustc` is running on.
+    Host,
+
+    /// The given target, passed to `rustc` via `--target`.
+    Target(CompileTarget),
+}
+
+/// A `rustc` target triple (or the path to a target specification file).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) struct CompileTarget {
+    name: InternedString,
+}
+
+/// Information about the platforms the units are compiled for, obtained by
+/// querying `rustc`.
+pub(crate) struct RustcTargetData {
+    pub(crate) rustc: Rustc,
+    host_info: TargetInfo,
+    host_triple: InternedString,
+    target_info: Option<(CompileTarget, TargetInfo)>,
+}
+
+/// The `rustc` used to compile the units.
+pub(crate) struct Rustc {
+    path: PathBuf,
+
+    /// The output of `rustc -vV`.
+    pub(crate) verbose_version: String,
+}
+
+/// Information about a single platform.
+pub(crate) struct TargetInfo {
+    /// The `cfg`s that are set when compiling for the platform.
+    cfg: Vec<Cfg>,
+
+    /// The flags passed to `rustc` when compiling for the platform.
+    pub(crate) rustflags: Vec<String>,
+
+    /// The values of `-C split-debuginfo` that the platform supports.
+    supported_split_debuginfo: Vec<String>,
+}
+
+/// The type of error that can occur when querying `rustc`.
+#[derive(Debug, derive_more::Display, cauchy::Error)]
+pub(crate) enum TargetDataError {
+    #[display("{_0}")]
+    Config(CargoConfigError),
+
+    #[display("`{command}` failed: {stderr}")]
+    Failed { command: String, stderr: String },
+
+    #[display("invalid rustc target {_0:?}")]
+    InvalidTarget(String),
+
+    #[display("couldn't parse the output of `{command}`: {output:?}")]
+    Parse { command: String, output: String },
+
+    #[display("failed to run `{command}`: {err}")]
+    Run { command: String, err: io::Error },
+}
+
+impl CompileKind {
+    pub(crate) fn is_host(self) -> bool {
+        self == Self::Host
+    }
+}
+
+impl CompileTarget {
+    pub(crate) fn new(name: &str) -> Result<Self, TargetDataError> {
+        let name = name.trim();
+
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(TargetDataError::InvalidTarget(name.to_owned()));
+        }
+
+        Ok(Self { name: name.into() })
+    }
+
+    pub(crate) fn rustc_target(self) -> InternedString {
+        self.name
+    }
+}
+
+impl RustcTargetData {
+    /// Queries `rustc` about the host and the given target.
+    ///
+    /// Like Cargo, the flags from the configuration (or from `RUSTFLAGS`)
+    /// only apply to the host if we're not cross-compiling.
+    pub(crate) fn new(
+        config: &CargoConfig,
+        compile_kind: CompileKind,
+    ) -> Result<Self, TargetDataError> {
+        let rustc = Rustc::new(config.rustc())?;
+
+        let host_triple: InternedString = rustc
+            .verbose_version
+            .lines()
+            .find_map(|line| line.strip_prefix("host: "))
+            .ok_or_else(|| TargetDataError::Parse {
+                command: format!("{} -vV", rustc.path.display()),
+                output: rustc.verbose_version.clone(),
+            })?
+            .into();
+
+        let target_info = match compile_kind {
+            CompileKind::Host => None,
+            CompileKind::Target(target) => Some((
+                target,
+                TargetInfo::new(&rustc, target.name, Some(target), config)?,
+            )),
+        };
+
+        let host_info = if target_info.is_some() {
+            TargetInfo::query(&rustc, None, Vec::new())?
+        } else {
+            TargetInfo::new(&rustc, host_triple, None, config)?
+        };
+
+        Ok(Self { rustc, host_info, host_triple, target_info })
+    }
+
+    /// Returns the `cfg`s that are set when compiling for the given kind.
+    pub(crate) fn cfg(&self, kind: CompileKind) -> &[Cfg] {
+        &self.info(kind).cfg
+    }
+
+    /// Returns whether the given dependency is used when compiling for the
+    /// given kind, i.e. whether the platform in its `[target.<platform>]`
+    /// table (if any) matches.
+    pub(crate) fn dep_platform_activated(
+        &self,
+        dep: &Dependency,
+        kind: CompileKind,
+    ) -> bool {
+        dep.platform().is_none_or(|platform| {
+            platform.matches(&self.short_name(kind), self.cfg(kind))
+        })
+    }
+
+    pub(crate) fn get_info(&self, kind: CompileKind) -> Option<&TargetInfo> {
+        match kind {
+            CompileKind::Host => Some(&self.host_info),
+            CompileKind::Target(target) => self
+                .target_info
+                .as_ref()
+                .filter(|(info_target, _)| *info_target == target)
+                .map(|(_, info)| info),
+        }
+    }
+
+    pub(crate) fn info(&self, kind: CompileKind) -> &TargetInfo {
+        self.get_info(kind).expect("target data was created for this kind")
+    }
+
+    /// Returns the target triple of the given kind.
+    pub(crate) fn short_name(&self, kind: CompileKind) -> InternedString {
+        match kind {
+            CompileKind::Host => self.host_triple,
+            CompileKind::Target(target) => target.name,
+        }
+    }
+}
+
+#[cfg(test)]
+impl RustcTargetData {
+    /// Returns the target data of a host with the given triple and `cfg`s,
+    /// without querying `rustc`.
+    pub(crate) fn for_host(triple: &str, cfg: &[&str]) -> Self {
+        let host_info = TargetInfo {
+            cfg: cfg.iter().map(|cfg| cfg.parse().unwrap()).collect(),
+            rustflags: Vec::new(),
+            supported_split_debuginfo: Vec::new(),
+        };
+
+        Self {
+            rustc: Rustc {
+                path: PathBuf::from("rustc"),
+                verbose_version: String::new(),
+            },
+            host_info,
+            host_triple: triple.into(),
+            target_info: None,
+        }
+    }
+}
+
+impl Rustc {
+    fn new(path: PathBuf) -> Result<Self, TargetDataError> {
+        let mut command = Command::new(&path);
+        command.arg("-vV");
+        let verbose_version = run(command)?;
+        Ok(Self { path, verbose_version })
+    }
+}
+
+impl TargetInfo {
+    /// Returns whether the platform supports the given value of `-C
+    /// split-debuginfo`.
+    pub(crate) fn supports_debuginfo_split(
+        &self,
+        split_debuginfo: InternedString,
+    ) -> bool {
+        self.supported_split_debuginfo
+            .iter()
+            .any(|value| *value == *split_debuginfo)
+    }
+
+    /// Queries `rustc` about the platform with the given triple, which is the
+    /// host's if `target` is `None`, using the flags from the configuration.
+    ///
+    /// Since those can depend on the target's `cfg`s (via `[target.'cfg(..)']`
+    /// tables), this queries `rustc` a second time if the flags change once
+    /// the `cfg`s are known, like Cargo.
+    fn new(
+        rustc: &Rustc,
+        triple: InternedString,
+        target: Option<CompileTarget>,
+        config: &CargoConfig,
+    ) -> Result<Self, TargetDataError> {
+        let rustflags =
+            config.rustflags(&triple, &[]).map_err(TargetDataError::Config)?;
+
+        let info = Self::query(rustc, target, rustflags)?;
+
+        let rustflags = config
+            .rustflags(&triple, &info.cfg)
+            .map_err(TargetDataError::Config)?;
+
+        if rustflags == info.rustflags {
+            Ok(info)
+        } else {
+            Self::query(rustc, target, rustflags)
+        }
+    }
+
+    fn query(
+        rustc: &Rustc,
+        target: Option<CompileTarget>,
+        rustflags: Vec<String>,
+    ) -> Result<Self, TargetDataError> {
+        let mut command = Command::new(&rustc.path);
+
+        command.args([
+            "-",
+            "--crate-name",
+            "___",
+            "--print=split-debuginfo",
+            "--print=crate-name",
+            "--print=cfg",
+        ]);
+
+        if let Some(target) = target {
+            command.args(["--target", target.name.as_str()]);
+        }
+
+        command.args(&rustflags);
+
+        let command_str = format!("{command:?}");
+
+        let output = run(command)?;
+
+        // The supported split-debuginfo values come before the crate name,
+        // and the cfgs after it.
+        let mut lines = output.lines();
+
+        let supported_split_debuginfo = lines
+            .by_ref()
+            .take_while(|line| *line != "___")
+            .map(Into::into)
+            .collect();
+
+        let cfg = lines
+            .map(|line| line.parse::<Cfg>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| TargetDataError::Parse {
+                command: command_str,
+                output: output.clone(),
+            })?;
+
+        Ok(Self { cfg, rustflags, supported_split_debuginfo })
+    }
+}
+
+/// Runs the given command with an empty stdin, returning its stdout.
+fn run(mut command: Command) -> Result<String, TargetDataError> {
+    let command_str = format!("{command:?}");
+
+    let output = command.stdin(Stdio::null()).output().map_err(|err| {
+        TargetDataError::Run { command: command_str.clone(), err }
+    })?;
+
+    if !output.status.success() {
+        return Err(TargetDataError::Failed {
+            command: command_str,
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        });
+    }
+
+    String::from_utf8(output.stdout).map_err(|err| TargetDataError::Parse {
+        command: command_str,
+        output: String::from_utf8_lossy(err.as_bytes()).into_owned(),
+    })
+}
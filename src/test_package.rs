@@ -0,0 +1,118 @@
+use core::result::Result;
+
+use nix_bindings::prelude::*;
+
+use crate::build_graph::BuildGraph;
+use crate::build_package::{
+    BuildPackageArgs,
+    BuildPackageError,
+    CrateDerivations,
+    build_graph,
+};
+use crate::make_derivation::{
+    self,
+    DerivationType,
+    make_deps,
+    make_derivation,
+};
+use crate::resolve_build_graph::ResolveBuildGraph;
+
+/// Builds the tests of a Rust package, returning a derivation that compiles
+/// its unit and integration tests together with its dev-dependencies, and
+/// fails to build if any of them (or any of the library's doctests) fail.
+///
+/// It takes the same arguments as `buildPackage`, and the returned derivation
+/// is meant to be used in a flake's `checks` output.
+#[derive(nix_bindings::PrimOp)]
+pub(crate) struct TestPackage;
+
+impl Function for TestPackage {
+    type Args<'a> = BuildPackageArgs<'a>;
+
+    fn call<'a: 'a>(
+        args: Self::Args<'a>,
+        ctx: &mut Context,
+    ) -> Result<NixDerivation<'static>, BuildPackageError> {
+        build_graph(
+            args,
+            true,
+            <ResolveBuildGraph as Function>::call,
+            ctx,
+            build_tests,
+        )
+    }
+}
+
+/// Builds the derivation running the tests of the root of the given build
+/// graph, which has to have been resolved with its dev-dependencies.
+fn build_tests(
+    build_graph: &BuildGraph,
+    global_args: &make_derivation::GlobalArgs,
+    node_derivations: Vec<CrateDerivations>,
+    ctx: &mut Context,
+) -> Result<NixDerivation<'static>, BuildPackageError> {
+    let root_idx = build_graph.root_idx();
+    let root = &build_graph.nodes[root_idx];
+    let root_drvs = node_derivations[root_idx];
+    let edges = &build_graph.edges[root_idx];
+
+    let tests = root
+        .tests
+        .as_ref()
+        .expect("the root is resolved with its dev-dependencies");
+
+    // The tests can depend on both the normal and the dev-dependencies.
+    let test_deps = edges
+        .dependencies
+        .iter()
+        .chain(&edges.dev_dependencies)
+        .map(|&idx| {
+            let node = &build_graph.nodes[idx];
+            let drv = node_derivations[idx].expect_lib();
+            (node, drv)
+        })
+        .collect::<Vec<_>>();
+
+    // When pipelining, the tests are linked against the whole closure of
+    // their dependencies (see `build_package::build_nodes`).
+    let deps_drv = if global_args.pipelined {
+        let closure = build_graph.dependency_closure(
+            edges.dependencies.iter().chain(&edges.dev_dependencies).copied(),
+        );
+        make_deps(
+            &root.package_attrs,
+            closure
+                .into_iter()
+                .map(|idx| node_derivations[idx].expect_lib())
+                .chain_exact(root_drvs.lib),
+            global_args.platform(root.is_for_host),
+            ctx,
+        )?
+    } else {
+        make_deps(
+            &root.package_attrs,
+            test_deps
+                .iter()
+                .map(|&(_node, drv)| drv)
+                .chain_exact(root_drvs.lib),
+            global_args.platform(root.is_for_host),
+            ctx,
+        )?
+    };
+
+    let drv = make_derivation(
+        DerivationType::Tests {
+            build_script: root_drvs.build_script,
+            library: root_drvs.lib,
+            binaries: root_drvs.bin,
+            tests,
+        },
+        root,
+        deps_drv,
+        test_deps.iter().copied(),
+        global_args,
+        ctx,
+    )?;
+
+    Ok(drv)
+}
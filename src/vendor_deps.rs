@@ -1,16 +1,29 @@
 use core::cell::OnceCell;
 use core::cmp::Ordering;
-use core::fmt::{self, Write};
-use core::iter;
+use core::ffi::CStr;
 use core::result::Result;
+use core::{fmt, iter};
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 use std::{fs, io};
 
+#[cfg(not(feature = "forbid-cargo"))]
+use cargo::GlobalContext;
+#[cfg(not(feature = "forbid-cargo"))]
+use cargo::sources::RecursivePathSource;
+#[cfg(feature = "forbid-cargo")]
+use cargo_util_schemas::manifest::InheritableField;
+use cargo_util_schemas::manifest::{
+    InheritableDependency,
+    TomlDependency,
+    TomlManifest,
+};
 use compact_str::{CompactString, ToCompactString};
 use either::Either;
 use nix_bindings::prelude::{Error as NixError, *};
 
+use crate::cargo_config::{CargoConfig, CargoConfigError, ReplacementSource};
 use crate::cargo_lock_parser::{
     CargoLockParseError,
     CargoLockParser,
@@ -19,8 +32,12 @@ use crate::cargo_lock_parser::{
     PackageEntry,
     PackageSource,
     RegistryKind,
+    RegistryProtocol,
     RegistrySource,
+    file_url_to_path,
 };
+#[cfg(feature = "forbid-cargo")]
+use crate::{manifest, workspace};
 
 /// Vendors the dependencies of a Rust package.
 #[derive(nix_bindings::PrimOp)]
@@ -32,6 +49,40 @@ pub(crate) struct VendorDeps;
 pub(crate) struct VendorDepsArgs<'a> {
     pub(crate) pkgs: NixAttrset<'a>,
     pub(crate) cargo_lock: Cow<'a, Path>,
+
+    /// A map from the index URLs of the custom registries the packages in
+    /// the `Cargo.lock` come from (without the `registry+` or `sparse+`
+    /// prefix) to attribute sets with the following optional attributes:
+    ///
+    /// - `config`: the registry's `config.json`, either as an attribute set
+    ///   (of which only `dl` is used) or as a file containing it. If not
+    ///   given, it's fetched with `builtins.fetchurl` for sparse registries,
+    ///   while it's required for git-based ones and in pure evaluation mode,
+    ///   where files can't be fetched without knowing their hash;
+    ///
+    /// - `fetchurl`: the function used to download the registry's crates,
+    ///   which is called with the same arguments as `pkgs.fetchurl`. This can
+    ///   be used to authenticate with private registries.
+    #[try_from(default)]
+    pub(crate) registries: Option<NixAttrset<'a>>,
+
+    /// A map from the paths of the `local-registry` and `directory` sources
+    /// (either in the `Cargo.lock`, or replacing a registry via the
+    /// `replace-with` key of the `[source]` tables of the project's Cargo
+    /// configuration) to the paths to vendor their packages from, for when
+    /// they're not available at the same location at evaluation time.
+    ///
+    /// Sources that aren't in the map are read from their original path,
+    /// which isn't allowed in pure evaluation mode (e.g. in flakes), so every
+    /// source has to be in the map in that case.
+    #[try_from(default)]
+    pub(crate) local_sources: Option<NixAttrset<'a>>,
+
+    /// Whether to only vendor the packages' manifests, with all their other
+    /// files replaced by empty ones, which is enough for
+    /// `jettison.resolveBuildGraph`.
+    #[try_from(default)]
+    pub(crate) manifests_only: bool,
 }
 
 /// The type of error that can occur when vendoring dependencies fails.
@@ -41,13 +92,62 @@ pub(crate) enum VendorDepsError {
     #[display("{_0}")]
     Nix(#[from] NixError),
 
+    /// Reading the project's Cargo configuration failed.
+    #[display("{_0}")]
+    ReadConfig(#[from] CargoConfigError),
+
     /// Parsing the contents of the `Cargo.lock` failed.
     #[display("failed to parse Cargo.lock: {_0}")]
     ParseCargoLock(#[from] CargoLockParseError),
 
+    /// The `Cargo.lock` contains the same version of a package from more than
+    /// one source.
+    #[display(
+        "package {name:?} v{version} is locked from more than one source, but \
+         only one of them can be vendored: use a `[patch]` to make every \
+         dependency on it use the same source"
+    )]
+    ConflictingSources { name: CompactString, version: CompactString },
+
+    /// A git repository doesn't contain a package listed in the `Cargo.lock`
+    /// as coming from it.
+    #[display(
+        "package {name:?} v{version} not found in the git repository at \
+         {url:?}"
+    )]
+    MissingGitPackage {
+        name: CompactString,
+        version: CompactString,
+        url: CompactString,
+    },
+
+    /// A `local-registry` or `directory` source isn't in the `localSources`
+    /// argument, and its original path can't be read in pure evaluation mode.
+    #[display(
+        "the local source at {path:?} can't be read in pure evaluation mode: \
+         map it to a path in `localSources`"
+    )]
+    MissingLocalSource { path: PathBuf },
+
+    /// A custom registry doesn't have a `config` in the `registries`
+    /// argument, and it's either git-based or we're in pure evaluation mode,
+    /// where its `config.json` can't be fetched.
+    #[display(
+        "missing config for the registry at {url:?}: it has to be set in \
+         `registries` for git-based registries, and for every registry in \
+         pure evaluation mode"
+    )]
+    MissingRegistryConfig { url: CompactString },
+
     /// Reading the `Cargo.lock` into a string failed.
     #[display("failed to read Cargo.lock at {path:?}: {err}")]
     ReadCargoLock { path: PathBuf, err: io::Error },
+
+    /// Reading the packages in a git repository failed.
+    #[display(
+        "failed to read the packages in the git repository at {url:?}: {err:#}"
+    )]
+    ReadGitPackages { url: CompactString, err: anyhow::Error },
 }
 
 /// TODO: docs.
@@ -67,6 +167,80 @@ struct Source<'lock> {
     derivation: Thunk<'static>,
 }
 
+/// The state shared between the sources vendored by [`VendoredSources::new`].
+struct Vendorer<'lock, 'a, 'cfg> {
+    /// The project's Cargo configuration, whose `[source]` tables can replace
+    /// the registries in the `Cargo.lock` with local sources.
+    config: CargoConfig,
+
+    /// The contents of the `config.toml` replacing every source with the
+    /// vendored one.
+    config_dot_toml: &'cfg mut String,
+
+    fetch_git: NixLambda<'a>,
+
+    /// The git repositories checked out so far.
+    git_checkouts: Vec<GitCheckout<'lock>>,
+
+    /// Whether Nix is evaluating in pure mode, where paths outside the store
+    /// can't be read.
+    is_pure_eval: bool,
+
+    /// The `localSources` argument.
+    local_sources: Option<NixAttrset<'a>>,
+
+    /// The registries seen so far, starting with crates.io.
+    registries: Vec<Registry<'lock, 'a>>,
+
+    /// The `registries` argument.
+    registries_args: Option<NixAttrset<'a>>,
+
+    /// The name of the source that every other source is replaced with.
+    replace_with: &'cfg str,
+
+    run_command_local: NixLambda<'a>,
+}
+
+/// A git repository that some of the packages in the `Cargo.lock` come from,
+/// checked out at the locked revision.
+struct GitCheckout<'lock> {
+    source: GitSource<'lock>,
+
+    /// The result of calling `builtins.fetchGit` on the
+    /// [`source`](Self::source).
+    src: NixAttrset<'static>,
+
+    /// The packages contained in the repository.
+    packages: Vec<GitPackage>,
+}
+
+/// A package contained in a [`GitCheckout`].
+struct GitPackage {
+    name: CompactString,
+
+    version: CompactString,
+
+    /// The path to the package's root, relative to the root of the
+    /// repository.
+    relative_root: CompactString,
+
+    /// The package's manifest, normalized by [`normalize_manifest`].
+    manifest: String,
+}
+
+/// A registry that some of the packages in the `Cargo.lock` come from.
+struct Registry<'lock, 'a> {
+    /// The URL of the registry's index, or `None` for crates.io.
+    index_url: Option<&'lock str>,
+
+    /// The template of the URLs the registry's crates are downloaded from,
+    /// i.e. the `dl` field of its `config.json`.
+    dl: CompactString,
+
+    /// The function used to download the registry's crates.
+    fetchurl: Either<NixFunctor<'a>, NixLambda<'a>>,
+}
+
 impl VendorDeps {
     pub(crate) fn read_cargo_lock(
         cargo_lock_path: &Path,
@@ -88,9 +262,16 @@ impl<'lock> VendoredSources<'lock> {
             .map(|idx| self.sources[idx].derivation)
     }
 
+    /// Vendors every package in the given `Cargo.lock`, honoring the source
+    /// replacements in the Cargo configuration of the workspace at
+    /// `workspace_root`.
+    #[expect(clippy::too_many_arguments)]
     pub(crate) fn new(
         cargo_lock: &'lock str,
+        workspace_root: &Path,
         pkgs: NixAttrset,
+        registries_args: Option<NixAttrset>,
+        local_sources: Option<NixAttrset>,
         ctx: &mut Context,
     ) -> Result<Self, VendorDepsError> {
         let replace_with = "vendored-sources";
@@ -106,55 +287,111 @@ directory = "."
 "#
         );
 
-        let fetch_git = ctx.builtins().fetch_git(ctx);
-        let fetchurl = pkgs.get(c"fetchurl", ctx)?;
-        let run_command_local = pkgs.get(c"runCommandLocal", ctx)?;
+        let fetchurl = pkgs.get::<NixFunctor>(c"fetchurl", ctx)?;
+
+        let mut vendorer = Vendorer {
+            config: CargoConfig::new(workspace_root)?,
+            config_dot_toml: &mut config_dot_toml,
+            fetch_git: ctx.builtins().fetch_git(ctx),
+            git_checkouts: Vec::new(),
+            is_pure_eval: is_pure_eval(ctx)?,
+            local_sources,
+            registries: vec![Registry::crates_io(fetchurl)],
+            registries_args,
+            replace_with,
+            run_command_local: pkgs.get(c"runCommandLocal", ctx)?,
+        };
 
         for res in CargoLockParser::new(cargo_lock) {
-            let PackageEntry { name, version, source } = res?;
+            let PackageEntry { name, version, source, replace, .. } = res?;
+
+            // Replaced packages are never compiled, only their replacements.
+            if replace.is_some() {
+                continue;
+            }
 
             let Some(source) = source else { continue };
 
-            let derivation = match source {
-                PackageSource::Registry(source) => source.fetch(
-                    name,
-                    version,
-                    fetchurl,
-                    run_command_local,
-                    ctx,
-                )?,
-                PackageSource::Git(source) => {
-                    write!(
-                        &mut config_dot_toml,
-                        "{}",
-                        source.into_cargo_config_entry(replace_with)
-                    )
-                    .expect("writing to a String cannot fail");
-                    source.fetch(fetch_git, run_command_local, ctx)?
-                },
-            };
+            let derivation = vendorer.vendor(name, version, source, ctx)?;
 
             let source_id = SourceId {
                 package_name: name,
                 version: Cow::Borrowed(version),
             };
 
-            // Make sure the entries returned by the iterator are already
-            // sorted by source ID, so that we can just push to the vector.
-            debug_assert!(
-                sources.last().is_none_or(|last: &Source| last.id < source_id)
-            );
+            // The `[[package]]` entries are already sorted by source ID, so
+            // this almost always inserts at the end. The `[[patch.unused]]`
+            // entries come after all of them, so they need to be inserted in
+            // the middle.
+            //
+            // The vendor directory is keyed by name and version, so two
+            // packages only differing in their source (e.g. a crates.io
+            // dependency and a git dependency on a fork of it that wasn't
+            // `[patch]`ed in) can't both be vendored.
+            let idx = match sources
+                .binary_search_by(|probe: &Source| probe.id.cmp(&source_id))
+            {
+                Ok(_) => {
+                    return Err(VendorDepsError::ConflictingSources {
+                        name: name.into(),
+                        version: version.into(),
+                    });
+                },
+                Err(idx) => idx,
+            };
 
-            sources.push(Source { id: source_id, derivation });
+            sources.insert(idx, Source { id: source_id, derivation });
         }
 
         Ok(Self { sources, config_dot_toml })
     }
 
+    /// Returns the vendor directory, containing every vendored package and
+    /// the `.cargo/config.toml` replacing their sources with it.
     pub(crate) fn to_dir(
         &self,
         pkgs: NixAttrset,
         ctx: &mut Context,
+    ) -> Result<NixDerivation<'static>, NixError> {
+        let paths = self.sources.iter().map(|source| source.derivation);
+        self.link_farm(c"vendored-sources", paths.collect(), pkgs, ctx)
+    }
+
+    /// Like [`to_dir`](Self::to_dir), but every package only contains its
+    /// manifest, while all its other files are replaced by empty ones.
+    ///
+    /// That's all that's needed to resolve the build graph, since the only
+    /// other thing read from a package is whether its target sources and
+    /// README exist. Building it still needs the tarballs of all the
+    /// packages in the `Cargo.lock`, but once built, the stripped packages
+    /// don't depend on them anymore, so realising this directory again at
+    /// evaluation time is cheap as long as the `Cargo.lock` doesn't change.
+    pub(crate) fn to_manifests_dir(
+        &self,
+        pkgs: NixAttrset,
+        ctx: &mut Context,
+    ) -> Result<NixDerivation<'static>, NixError> {
+        let run_command_local =
+            pkgs.get::<NixLambda>(c"runCommandLocal", ctx)?;
+
+        let paths = self
+            .sources
+            .iter()
+            .map(|source| source.manifest_only(run_command_local, ctx))
+            .collect::<Result<_, _>>()?;
+
+        self.link_farm(c"vendored-manifests", paths, pkgs, ctx)
+    }
+
+    /// Links the given paths, one for every vendored source, into a single
+    /// directory together with the `.cargo/config.toml`.
+    #[expect(clippy::too_many_arguments)]
+    fn link_farm(
+        &self,
+        name: &CStr,
+        paths: Vec<Thunk<'static>>,
+        pkgs: NixAttrset,
+        ctx: &mut Context,
     ) -> Result<NixDerivation<'static>, NixError> {
         let write_text_file = pkgs.get::<NixLambda>(c"writeTextFile", ctx)?;
 
@@ -169,10 +406,11 @@ directory = "."
         let entries = self
             .sources
             .iter()
-            .map(|source| {
+            .zip(paths)
+            .map(|(source, path)| {
                 attrset! {
                     name: source.id.to_compact_string(),
-                    path: source.derivation,
+                    path: path,
                 }
             })
             .chain_exact(iter::once(attrset! {
@@ -181,18 +419,16 @@ directory = "."
             }));
 
         pkgs.get::<NixLambda>(c"linkFarm", ctx)?
-            .call_multi((c"vendored-sources", entries.into_value()), ctx)?
+            .call_multi((name, entries.into_value()), ctx)?
             .force_into(ctx)
     }
 }
 
-impl<'lock> RegistrySource<'lock> {
-    #[expect(clippy::too_many_arguments)]
-    fn fetch(
+impl Source<'_> {
+    /// Returns a copy of the vendored package where every file except the
+    /// manifest and the `.cargo-checksum.json` is empty.
+    fn manifest_only(
         &self,
-        pkg_name: &str,
-        pkg_version: &str,
-        fetchurl: NixFunctor,
         run_command_local: NixLambda,
         ctx: &mut Context,
     ) -> Result<Thunk<'static>, NixError> {
@@ -200,21 +436,421 @@ impl<'lock> RegistrySource<'lock> {
             static WRAP: OnceCell<NixLambda<'static>> = const { OnceCell::new() };
         }
 
-        match self.kind {
-            RegistryKind::CratesIo => {},
-            RegistryKind::Other { .. } => {
-                panic!("custom registries are not yet supported")
+        let strip_args = attrset! {
+            src: self.derivation,
+            name: format!("{}-manifest", self.id),
+            runCommandLocal: run_command_local,
+        };
+
+        let strip = WRAP.with(|cell| match cell.get().copied() {
+            Some(wrap) => Ok::<_, NixError>(wrap),
+            None => {
+                let wrap = ctx.eval::<NixLambda>(c"
+                    { src, name, runCommandLocal }:
+                    runCommandLocal name {} ''
+                      cd ${src}
+                      find . -type d | while read -r dir; do mkdir -p \"$out/$dir\"; done
+                      find . ! -type d | while read -r file; do touch \"$out/$file\"; done
+                      cp Cargo.toml .cargo-checksum.json $out
+                    ''
+                ")?;
+                Ok(*cell.get_or_init(|| wrap))
             },
+        })?;
+
+        strip.call(strip_args, ctx)
+    }
+}
+
+impl<'lock, 'a> Vendorer<'lock, 'a, '_> {
+    /// Returns the derivation of the given package, which will be placed in
+    /// the vendor directory.
+    fn vendor(
+        &mut self,
+        name: &str,
+        version: &str,
+        source: PackageSource<'lock>,
+        ctx: &mut Context,
+    ) -> Result<Thunk<'static>, VendorDepsError> {
+        let replace_with = self.replace_with;
+        let run_command_local = self.run_command_local;
+
+        let derivation = match source {
+            PackageSource::Registry(source) => {
+                self.add_config_entry(
+                    source.into_cargo_config_entry(replace_with),
+                );
+                self.vendor_registry_package(name, version, source, ctx)?
+            },
+            PackageSource::Git(source) => {
+                self.add_config_entry(
+                    source.into_cargo_config_entry(replace_with),
+                );
+                self.git_checkout(source, ctx)?.vendor_package(
+                    name,
+                    version,
+                    run_command_local,
+                    ctx,
+                )?
+            },
+            PackageSource::Directory(source) => {
+                self.add_config_entry(
+                    source.into_cargo_config_entry(replace_with),
+                );
+                let root =
+                    self.local_source_root(&file_url_to_path(source.url), ctx)?;
+                fetch_from_directory(
+                    name,
+                    version,
+                    root,
+                    source.checksum,
+                    run_command_local,
+                    ctx,
+                )?
+            },
+        };
+
+        Ok(derivation)
+    }
+
+    /// Returns the derivation of the given package from a registry, which is
+    /// read from the local source replacing the registry in the project's
+    /// Cargo configuration, if any.
+    fn vendor_registry_package(
+        &mut self,
+        name: &str,
+        version: &str,
+        source: RegistrySource<'lock>,
+        ctx: &mut Context,
+    ) -> Result<Thunk<'static>, VendorDepsError> {
+        let run_command_local = self.run_command_local;
+
+        let registry_url = match source.kind {
+            RegistryKind::CratesIo => None,
+            RegistryKind::Other { url, .. } => Some(url),
+            RegistryKind::Local { url } => {
+                let root =
+                    self.local_source_root(&file_url_to_path(url), ctx)?;
+                return source
+                    .fetch_local(name, version, root, run_command_local, ctx)
+                    .map_err(Into::into);
+            },
+        };
+
+        let derivation = match self.config.source_replacement(registry_url)? {
+            Some(ReplacementSource::LocalRegistry(root)) => {
+                let root = self.local_source_root(&root, ctx)?;
+                source.fetch_local(
+                    name,
+                    version,
+                    root,
+                    run_command_local,
+                    ctx,
+                )?
+            },
+            Some(ReplacementSource::Directory(root)) => {
+                let root = self.local_source_root(&root, ctx)?;
+                fetch_from_directory(
+                    name,
+                    version,
+                    root,
+                    Some(source.checksum),
+                    run_command_local,
+                    ctx,
+                )?
+            },
+            None => {
+                let registry = match source.kind {
+                    RegistryKind::Other { protocol, url } => {
+                        self.registry(protocol, url, ctx)?
+                    },
+                    _ => &self.registries[0],
+                };
+                source.fetch(name, version, registry, run_command_local, ctx)?
+            },
+        };
+
+        Ok(derivation)
+    }
+
+    /// Appends the given entry to the `config.toml`, unless another package
+    /// from the same source has already added it.
+    fn add_config_entry(&mut self, entry: impl fmt::Display) {
+        let entry = entry.to_string();
+        if !self.config_dot_toml.contains(&entry) {
+            self.config_dot_toml.push_str(&entry);
         }
+    }
+
+    /// Returns the root of the `local-registry` or `directory` source at the
+    /// given path, taking it from the `localSources` argument if it's
+    /// overridden there.
+    fn local_source_root(
+        &self,
+        path: &Path,
+        ctx: &mut Context,
+    ) -> Result<Either<NixValue<'a>, String>, VendorDepsError> {
+        let path_str = path.display().to_string();
+
+        let root = match self.local_sources {
+            Some(local_sources) => local_sources.get_opt(&*path_str, ctx)?,
+            None => None,
+        };
+
+        match root {
+            Some(root) => Ok(Either::Left(root)),
+            // `builtins.path` can't read paths outside the store in pure
+            // evaluation mode, even when their hash is known.
+            None if self.is_pure_eval => {
+                Err(VendorDepsError::MissingLocalSource {
+                    path: path.to_owned(),
+                })
+            },
+            None => Ok(Either::Right(path_str)),
+        }
+    }
+
+    /// Returns the checkout of the given git source, fetching it the first
+    /// time it's requested.
+    fn git_checkout(
+        &mut self,
+        source: GitSource<'lock>,
+        ctx: &mut Context,
+    ) -> Result<&GitCheckout<'lock>, VendorDepsError> {
+        let idx = match self.git_checkouts.iter().position(|checkout| {
+            checkout.source.url == source.url
+                && checkout.source.rev == source.rev
+        }) {
+            Some(idx) => idx,
+            None => {
+                let checkout = GitCheckout::new(source, self.fetch_git, ctx)?;
+                self.git_checkouts.push(checkout);
+                self.git_checkouts.len() - 1
+            },
+        };
+
+        Ok(&self.git_checkouts[idx])
+    }
+
+    /// Returns the custom registry with the given index URL, reading its
+    /// config the first time it's requested.
+    fn registry(
+        &mut self,
+        protocol: RegistryProtocol,
+        index_url: &'lock str,
+        ctx: &mut Context,
+    ) -> Result<&Registry<'lock, 'a>, VendorDepsError> {
+        let idx = match self
+            .registries
+            .iter()
+            .position(|reg| reg.index_url == Some(index_url))
+        {
+            Some(idx) => idx,
+            None => {
+                // Custom registries are fetched with the same function as
+                // crates.io unless they override it.
+                let default_fetchurl = self.registries[0].fetchurl;
+                let registry = Registry::new(
+                    protocol,
+                    index_url,
+                    self.registries_args,
+                    default_fetchurl,
+                    self.is_pure_eval,
+                    ctx,
+                )?;
+                self.registries.push(registry);
+                self.registries.len() - 1
+            },
+        };
+
+        Ok(&self.registries[idx])
+    }
+}
+
+impl<'lock, 'a> Registry<'lock, 'a> {
+    fn crates_io(fetchurl: NixFunctor<'a>) -> Self {
+        Self {
+            index_url: None,
+            dl: CompactString::const_new(
+                "https://static.crates.io/crates/{crate}/{crate}-{version}.crate",
+            ),
+            fetchurl: Either::Left(fetchurl),
+        }
+    }
+
+    /// Reads the config of the custom registry at the given index URL from
+    /// the `registries` argument, falling back to fetching its `config.json`
+    /// for sparse registries when not in pure evaluation mode.
+    #[expect(clippy::too_many_arguments)]
+    fn new(
+        protocol: RegistryProtocol,
+        index_url: &'lock str,
+        registries_args: Option<NixAttrset<'a>>,
+        default_fetchurl: Either<NixFunctor<'a>, NixLambda<'a>>,
+        is_pure_eval: bool,
+        ctx: &mut Context,
+    ) -> Result<Self, VendorDepsError> {
+        thread_local! {
+            static READ_DL: OnceCell<NixLambda<'static>> = const { OnceCell::new() };
+        }
+
+        let registry_args = match registries_args {
+            Some(args) => args.get_opt::<NixAttrset>(index_url, ctx)?,
+            None => None,
+        };
+
+        let config = match registry_args {
+            Some(args) => args.get_opt::<NixValue>(c"config", ctx)?,
+            None => None,
+        };
+
+        let fetchurl = match registry_args {
+            Some(args) => args
+                .get_opt::<NixValue>(c"fetchurl", ctx)?
+                .map(|fetchurl| fetcher_from_value(fetchurl, ctx))
+                .transpose()?,
+            None => None,
+        };
+
+        let config_dl = match config {
+            Some(config) => NixAttrset::try_from_value(config, ctx)
+                .ok()
+                .map(|config| config.get_opt::<CompactString>(c"dl", ctx))
+                .transpose()?
+                .flatten(),
+            None => None,
+        };
+
+        let dl = match (config_dl, config, protocol) {
+            (Some(dl), _, _) => dl,
+            // Only sparse registries serve their `config.json` over HTTP, and
+            // `builtins.fetchurl` can't be called without a hash in pure
+            // evaluation mode.
+            (None, None, protocol)
+                if is_pure_eval
+                    || matches!(protocol, RegistryProtocol::Registry) =>
+            {
+                return Err(VendorDepsError::MissingRegistryConfig {
+                    url: index_url.into(),
+                });
+            },
+            (None, config, _) => {
+                let read_dl = READ_DL.with(|cell| match cell.get().copied() {
+                    Some(read_dl) => Ok::<_, NixError>(read_dl),
+                    None => {
+                        let read_dl = ctx.eval::<NixLambda>(c"
+                            config: indexUrl:
+                            let
+                              file =
+                                if config != null then config
+                                else builtins.fetchurl \"${indexUrl}config.json\";
+                            in
+                            (builtins.fromJSON (builtins.readFile file)).dl
+                        ")?;
+                        Ok(*cell.get_or_init(|| read_dl))
+                    },
+                })?;
+
+                read_dl.call_multi((config, index_url), ctx)?.force_into(ctx)?
+            },
+        };
+
+        Ok(Self {
+            index_url: Some(index_url),
+            dl,
+            fetchurl: fetchurl.unwrap_or(default_fetchurl),
+        })
+    }
+}
+
+impl<'lock> RegistrySource<'lock> {
+    #[expect(clippy::too_many_arguments)]
+    fn fetch(
+        &self,
+        pkg_name: &str,
+        pkg_version: &str,
+        registry: &Registry,
+        run_command_local: NixLambda,
+        ctx: &mut Context,
+    ) -> Result<Thunk<'static>, NixError> {
+        let url =
+            download_url(&registry.dl, pkg_name, pkg_version, self.checksum);
 
         let fetchurl_args = attrset! {
             name: format!("{pkg_name}-{pkg_version}.tar.gz"),
-            url: format!("https://static.crates.io/crates/{pkg_name}/{pkg_name}-{pkg_version}.crate"),
+            url: url,
             sha256: self.checksum,
         };
 
+        let src = registry.fetchurl.call(fetchurl_args, ctx)?;
+
+        self.extract(src, pkg_name, pkg_version, run_command_local, ctx)
+    }
+
+    /// Like [`fetch`](Self::fetch), but for packages from a `local-registry`
+    /// source, whose `.crate` files are read from the given root directory
+    /// instead of being downloaded.
+    #[expect(clippy::too_many_arguments)]
+    fn fetch_local(
+        &self,
+        pkg_name: &str,
+        pkg_version: &str,
+        root: impl Value,
+        run_command_local: NixLambda,
+        ctx: &mut Context,
+    ) -> Result<Thunk<'static>, NixError> {
+        thread_local! {
+            static COPY: OnceCell<NixLambda<'static>> = const { OnceCell::new() };
+        }
+
+        let copy_args = attrset! {
+            root: root,
+            name: format!("{pkg_name}-{pkg_version}.crate"),
+            checksum: self.checksum,
+        };
+
+        // The `.crate` file is copied to the store as a flat fixed-output
+        // path, so its hash is checked against the one in the Cargo.lock.
+        let copy = COPY.with(|cell| match cell.get().copied() {
+            Some(copy) => Ok::<_, NixError>(copy),
+            None => {
+                let copy = ctx.eval::<NixLambda>(
+                    c"
+                    { root, name, checksum }:
+                    builtins.path {
+                      path = root + \"/${name}\";
+                      inherit name;
+                      recursive = false;
+                      sha256 = checksum;
+                    }
+                ",
+                )?;
+                Ok(*cell.get_or_init(|| copy))
+            },
+        })?;
+
+        let src = copy.call(copy_args, ctx)?;
+
+        self.extract(src, pkg_name, pkg_version, run_command_local, ctx)
+    }
+
+    /// Extracts the given `.crate` tarball, adding the
+    /// `.cargo-checksum.json` file Cargo expects in vendored packages.
+    #[expect(clippy::too_many_arguments)]
+    fn extract(
+        &self,
+        src: Thunk<'static>,
+        pkg_name: &str,
+        pkg_version: &str,
+        run_command_local: NixLambda,
+        ctx: &mut Context,
+    ) -> Result<Thunk<'static>, NixError> {
+        thread_local! {
+            static WRAP: OnceCell<NixLambda<'static>> = const { OnceCell::new() };
+        }
+
         let extract_and_add_checksum_args = attrset! {
-            src: fetchurl.call(fetchurl_args, ctx)?,
+            src: src,
             checksum: self.checksum,
             name: format!("{pkg_name}-{pkg_version}"),
             runCommandLocal: run_command_local,
@@ -239,22 +875,71 @@ impl<'lock> RegistrySource<'lock> {
     }
 }
 
-impl GitSource<'_> {
-    fn fetch(
-        &self,
+/// Copies the given package from the `directory` source with the given root,
+/// either listed in the `Cargo.lock` or replacing a registry.
+#[expect(clippy::too_many_arguments)]
+fn fetch_from_directory(
+    pkg_name: &str,
+    pkg_version: &str,
+    root: impl Value,
+    checksum: Option<&str>,
+    run_command_local: NixLambda,
+    ctx: &mut Context,
+) -> Result<Thunk<'static>, NixError> {
+    thread_local! {
+        static WRAP: OnceCell<NixLambda<'static>> = const { OnceCell::new() };
+    }
+
+    let copy_args = attrset! {
+        root: root,
+        pname: pkg_name,
+        version: pkg_version,
+        checksum: checksum,
+        runCommandLocal: run_command_local,
+    };
+
+    // Like `cargo vendor`, look for the package in a `<name>-<version>`
+    // directory first, and fall back to `<name>` if there isn't one.
+    let copy = WRAP.with(|cell| match cell.get().copied() {
+        Some(wrap) => Ok::<_, NixError>(wrap),
+        None => {
+            let wrap = ctx.eval::<NixLambda>(c"
+                { root, pname, version, checksum, runCommandLocal }:
+                let
+                  name = \"${pname}-${version}\";
+                  versioned = root + \"/${name}\";
+                  src = builtins.path {
+                    path =
+                      if builtins.pathExists versioned then versioned
+                      else root + \"/${pname}\";
+                    inherit name;
+                  };
+                in
+                runCommandLocal name {} ''
+                  cp -r ${src} $out
+                  chmod +w $out
+                  rm -f $out/.cargo-checksum.json
+                  echo '{\"package\":${builtins.toJSON checksum},\"files\":{}}' > $out/.cargo-checksum.json
+                ''
+            ")?;
+            Ok(*cell.get_or_init(|| wrap))
+        },
+    })?;
+
+    copy.call(copy_args, ctx)
+}
+
+impl<'lock> GitCheckout<'lock> {
+    fn new(
+        source: GitSource<'lock>,
         fetch_git: NixLambda,
-        run_command_local: NixLambda,
         ctx: &mut Context,
-    ) -> Result<Thunk<'static>, NixError> {
-        thread_local! {
-            static WRAP: OnceCell<NixLambda<'static>> = const { OnceCell::new() };
-        }
-
-        let r#ref = self.r#ref.and_then(GitSourceRef::format_for_fetch_git);
+    ) -> Result<Self, VendorDepsError> {
+        let r#ref = source.r#ref.and_then(GitSourceRef::format_for_fetch_git);
 
         let args = attrset! {
-            url: self.url,
-            rev: self.rev,
+            url: source.url,
+            rev: source.rev,
             submodules: true,
         }
         .merge(match r#ref {
@@ -262,21 +947,61 @@ impl GitSource<'_> {
             None => Either::Right(attrset! { allRefs: true }),
         });
 
-        let add_checksum_args = attrset! {
-            src: fetch_git.call(args, ctx)?,
-            name: format!("git-{}", &self.rev[..8]),
+        let src = fetch_git.call(args, ctx)?.force_into::<NixAttrset>(ctx)?;
+
+        let repo_root = src.get::<CompactString>(c"outPath", ctx)?;
+
+        let packages = read_git_packages(Path::new(&*repo_root), source)
+            .map_err(|err| VendorDepsError::ReadGitPackages {
+                url: source.url.into(),
+                err,
+            })?;
+
+        Ok(Self { source, src, packages })
+    }
+
+    /// Returns the vendored directory of the package with the given name and
+    /// version, which contains the package's files and its normalized
+    /// manifest (the original one is kept as `Cargo.toml.orig`).
+    fn vendor_package(
+        &self,
+        name: &str,
+        version: &str,
+        run_command_local: NixLambda,
+        ctx: &mut Context,
+    ) -> Result<Thunk<'static>, VendorDepsError> {
+        thread_local! {
+            static WRAP: OnceCell<NixLambda<'static>> = const { OnceCell::new() };
+        }
+
+        let package = self
+            .packages
+            .iter()
+            .find(|pkg| pkg.name == name && pkg.version == version)
+            .ok_or_else(|| VendorDepsError::MissingGitPackage {
+                name: name.into(),
+                version: version.into(),
+                url: self.source.url.into(),
+            })?;
+
+        let copy_args = attrset! {
+            src: self.src,
+            root: &*package.relative_root,
+            manifest: &*package.manifest,
+            name: format!("{name}-{version}"),
             runCommandLocal: run_command_local,
         };
 
-        let add_checksum = WRAP.with(|cell| match cell.get().copied() {
+        let copy = WRAP.with(|cell| match cell.get().copied() {
             Some(wrap) => Ok::<_, NixError>(wrap),
             None => {
-                let wrap = ctx.eval::<NixLambda>(
-                    c"
-                    { src, name, runCommandLocal }:
+                let wrap = ctx.eval::<NixLambda>(c"
+                    { src, root, manifest, name, runCommandLocal }:
                     runCommandLocal name {} ''
-                      cp -r ${src} $out
-                      chmod +w $out
+                      cp -r ${src}/${root} $out
+                      chmod -R +w $out
+                      mv $out/Cargo.toml $out/Cargo.toml.orig
+                      cp ${builtins.toFile \"Cargo.toml\" manifest} $out/Cargo.toml
                       echo '{\"package\":null,\"files\":{}}' > $out/.cargo-checksum.json
                     ''
                 ")?;
@@ -284,7 +1009,7 @@ impl GitSource<'_> {
             },
         })?;
 
-        add_checksum.call(add_checksum_args, ctx)
+        copy.call(copy_args, ctx).map_err(Into::into)
     }
 }
 
@@ -296,11 +1021,287 @@ impl Function for VendorDeps {
         ctx: &mut Context,
     ) -> Result<NixDerivation<'static>, VendorDepsError> {
         let cargo_lock = Self::read_cargo_lock(&args.cargo_lock)?;
-        let sources = VendoredSources::new(&cargo_lock, args.pkgs, ctx)?;
-        sources.to_dir(args.pkgs, ctx).map_err(Into::into)
+        let workspace_root = args.cargo_lock.parent().unwrap_or(Path::new(""));
+        let sources = VendoredSources::new(
+            &cargo_lock,
+            workspace_root,
+            args.pkgs,
+            args.registries,
+            args.local_sources,
+            ctx,
+        )?;
+        if args.manifests_only {
+            sources.to_manifests_dir(args.pkgs, ctx).map_err(Into::into)
+        } else {
+            sources.to_dir(args.pkgs, ctx).map_err(Into::into)
+        }
     }
 }
 
+/// Returns the URL to download the given package from a registry whose
+/// `config.json` has the given `dl` template.
+///
+/// This follows the same rules as Cargo for expanding the markers in the
+/// template, which is used as a prefix if it doesn't contain any of them.
+fn download_url(
+    dl: &str,
+    pkg_name: &str,
+    pkg_version: &str,
+    checksum: &str,
+) -> String {
+    const MARKERS: [&str; 5] = [
+        "{crate}",
+        "{version}",
+        "{prefix}",
+        "{lowerprefix}",
+        "{sha256-checksum}",
+    ];
+
+    if !MARKERS.iter().any(|marker| dl.contains(marker)) {
+        return format!("{dl}/{pkg_name}/{pkg_version}/download");
+    }
+
+    let prefix = match pkg_name.len() {
+        1 => Cow::Borrowed("1"),
+        2 => Cow::Borrowed("2"),
+        3 => Cow::Owned(format!("3/{}", &pkg_name[..1])),
+        _ => Cow::Owned(format!("{}/{}", &pkg_name[..2], &pkg_name[2..4])),
+    };
+
+    dl.replace("{crate}", pkg_name)
+        .replace("{version}", pkg_version)
+        .replace("{prefix}", &prefix)
+        .replace("{lowerprefix}", &prefix.to_lowercase())
+        .replace("{sha256-checksum}", checksum)
+}
+
+/// Returns whether Nix is evaluating in pure mode, where
+/// `builtins.currentSystem` isn't available.
+fn is_pure_eval(ctx: &mut Context) -> Result<bool, NixError> {
+    ctx.eval::<bool>(c"!(builtins ? currentSystem)")
+}
+
+/// Converts the value of a registry's `fetchurl` attribute into a callable,
+/// which can be either a function or a functor (like `pkgs.fetchurl` itself).
+fn fetcher_from_value<'a>(
+    mut value: NixValue<'a>,
+    ctx: &mut Context,
+) -> Result<Either<NixFunctor<'a>, NixLambda<'a>>, NixError> {
+    value.force_inline(ctx)?;
+
+    match value.kind() {
+        ValueKind::Attrset => {
+            NixFunctor::try_from_value(value, ctx).map(Either::Left)
+        },
+        ValueKind::Function => {
+            NixLambda::try_from_value(value, ctx).map(Either::Right)
+        },
+        _ => Err(NixError::new(
+            ErrorKind::Nix,
+            c"expected a registry's fetchurl to be a function",
+        )),
+    }
+}
+
+/// Reads all the packages in the git repository checked out at the given
+/// path.
+#[cfg(not(feature = "forbid-cargo"))]
+fn read_git_packages(
+    repo_root: &Path,
+    source: GitSource,
+) -> anyhow::Result<Vec<GitPackage>> {
+    let gctx = GlobalContext::default()?;
+
+    let source_id = cargo::core::SourceId::for_path(repo_root)?;
+
+    RecursivePathSource::new(repo_root, source_id, &gctx)
+        .read_packages()?
+        .iter()
+        .map(|package| {
+            let relative_root = package
+                .root()
+                .strip_prefix(repo_root)
+                .expect("package is in the repository")
+                .display()
+                .to_compact_string();
+
+            Ok(GitPackage {
+                name: package.name().as_str().into(),
+                version: package.version().to_compact_string(),
+                relative_root,
+                manifest: normalize_manifest(
+                    package.manifest().normalized_toml().clone(),
+                    source,
+                )?,
+            })
+        })
+        .collect()
+}
+
+/// Reads all the packages in the git repository checked out at the given
+/// path.
+///
+/// Like Cargo, this looks for manifests with a `[package]` table in every
+/// directory of the repository except the hidden ones and `target`, and
+/// resolves their `workspace = true` fields from the closest workspace in the
+/// repository.
+#[cfg(feature = "forbid-cargo")]
+fn read_git_packages(
+    repo_root: &Path,
+    source: GitSource,
+) -> anyhow::Result<Vec<GitPackage>> {
+    let mut packages = Vec::new();
+
+    let mut dirs = vec![repo_root.to_owned()];
+
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            if entry.file_type()?.is_dir()
+                && !file_name.to_string_lossy().starts_with('.')
+                && file_name != "target"
+            {
+                dirs.push(entry.path());
+            }
+        }
+
+        let manifest_path = dir.join("Cargo.toml");
+
+        if !manifest_path.is_file() {
+            continue;
+        }
+
+        let mut manifest = manifest::read_manifest(&manifest_path)?;
+
+        if manifest.package.is_none() {
+            continue;
+        }
+
+        let workspace = workspace::find_workspace(&dir, Some(repo_root))?;
+
+        let inheritance = workspace.as_ref().map(|(root, workspace)| {
+            manifest::WorkspaceInheritance { workspace, root }
+        });
+
+        manifest::normalize_manifest(
+            &mut manifest,
+            inheritance,
+            &manifest_path,
+        )?;
+
+        let package = manifest.package.as_deref().expect("checked above");
+
+        let Some(name) = package.name.as_deref() else {
+            anyhow::bail!(
+                "the manifest at {} has no package name",
+                manifest_path.display()
+            );
+        };
+
+        let version = match &package.version {
+            Some(InheritableField::Value(version)) => {
+                version.to_compact_string()
+            },
+            _ => CompactString::const_new("0.0.0"),
+        };
+
+        let relative_root = dir
+            .strip_prefix(repo_root)
+            .expect("package is in the repository")
+            .display()
+            .to_compact_string();
+
+        packages.push(GitPackage {
+            name: name.as_str().into(),
+            version,
+            relative_root,
+            manifest: normalize_manifest(manifest, source)?,
+        });
+    }
+
+    Ok(packages)
+}
+
+/// Turns the manifest of a package from a git repository, whose fields
+/// inherited from its workspace have already been resolved, into what Cargo
+/// expects to find in the packages of a directory source.
+///
+/// Unlike `cargo vendor`, dependencies on the other packages in the same
+/// repository are turned into git dependencies on the package's source rather
+/// than registry ones, so that they keep resolving to the packages in the
+/// `Cargo.lock`.
+fn normalize_manifest(
+    mut manifest: TomlManifest,
+    source: GitSource,
+) -> Result<String, toml::ser::Error> {
+    // These are only meaningful in the manifest at the root of a workspace.
+    manifest.workspace = None;
+    manifest.patch = None;
+    manifest.profile = None;
+    manifest.replace = None;
+
+    if let Some(package) = &mut manifest.package {
+        package.workspace = None;
+    }
+
+    let platform_deps =
+        manifest.target.iter_mut().flat_map(BTreeMap::values_mut).flat_map(
+            |platform| {
+                [
+                    &mut platform.dependencies,
+                    &mut platform.build_dependencies,
+                    &mut platform.dev_dependencies,
+                ]
+            },
+        );
+
+    let deps = [
+        &mut manifest.dependencies,
+        &mut manifest.build_dependencies,
+        &mut manifest.dev_dependencies,
+    ]
+    .into_iter()
+    .chain(platform_deps)
+    .flatten()
+    .flat_map(BTreeMap::values_mut);
+
+    for dep in deps {
+        let InheritableDependency::Value(TomlDependency::Detailed(dep)) = dep
+        else {
+            continue;
+        };
+
+        if dep.path.take().is_none() {
+            continue;
+        }
+
+        dep.git = Some(source.url.to_owned());
+
+        let decode = |value: &str| {
+            percent_encoding::percent_decode_str(value)
+                .decode_utf8_lossy()
+                .into_owned()
+        };
+
+        match source.r#ref {
+            Some(GitSourceRef::Branch(branch)) => {
+                dep.branch = Some(decode(branch));
+            },
+            Some(GitSourceRef::Tag(tag)) => dep.tag = Some(decode(tag)),
+            Some(GitSourceRef::Rev(rev)) => dep.rev = Some(decode(rev)),
+            None => {},
+        }
+    }
+
+    let manifest = toml::to_string_pretty(&manifest)?;
+
+    Ok(format!(
+        "# This manifest was normalized from the original one, which is kept \
+         as\n# Cargo.toml.orig.\n\n{manifest}"
+    ))
+}
+
 impl fmt::Display for SourceId<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}-{}", self.package_name, self.version)
@@ -337,3 +1338,70 @@ impl From<VendorDepsError> for NixError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHECKSUM: &str =
+        "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn download_url_without_markers() {
+        assert_eq!(
+            download_url(
+                "https://example.com/api/v1/crates",
+                "serde",
+                "1.0.0",
+                CHECKSUM
+            ),
+            "https://example.com/api/v1/crates/serde/1.0.0/download"
+        );
+    }
+
+    #[test]
+    fn download_url_prefix() {
+        let dl = "https://example.com/{prefix}/{crate}/{crate}-{version}.crate";
+
+        let url = |name| download_url(dl, name, "1.0.0", CHECKSUM);
+
+        assert_eq!(url("a"), "https://example.com/1/a/a-1.0.0.crate");
+        assert_eq!(url("ab"), "https://example.com/2/ab/ab-1.0.0.crate");
+        assert_eq!(url("abc"), "https://example.com/3/a/abc/abc-1.0.0.crate");
+        assert_eq!(
+            url("abcd"),
+            "https://example.com/ab/cd/abcd/abcd-1.0.0.crate"
+        );
+        assert_eq!(
+            url("serde"),
+            "https://example.com/se/rd/serde/serde-1.0.0.crate"
+        );
+    }
+
+    #[test]
+    fn download_url_lowerprefix() {
+        let dl = "https://example.com/{prefix}/{lowerprefix}/{crate}";
+
+        assert_eq!(
+            download_url(dl, "Serde", "1.0.0", CHECKSUM),
+            "https://example.com/Se/rd/se/rd/Serde"
+        );
+        assert_eq!(
+            download_url(dl, "Abc", "1.0.0", CHECKSUM),
+            "https://example.com/3/A/3/a/Abc"
+        );
+    }
+
+    #[test]
+    fn download_url_checksum() {
+        assert_eq!(
+            download_url(
+                "https://example.com/{sha256-checksum}",
+                "serde",
+                "1.0.0",
+                CHECKSUM
+            ),
+            format!("https://example.com/{CHECKSUM}")
+        );
+    }
+}
@@ -0,0 +1,303 @@
+use std::path::{Path, PathBuf};
+
+use cargo_util_schemas::manifest::{
+    TomlDependency,
+    TomlManifest,
+    TomlProfiles,
+    TomlWorkspace,
+};
+
+use crate::cargo_config::CargoConfig;
+use crate::manifest::{
+    DependencySource,
+    Edition,
+    ManifestError,
+    Package,
+    PackageId,
+    SourceId,
+    WorkspaceInheritance,
+    normalize_path,
+    read_manifest,
+};
+
+/// A Cargo workspace, read without the `cargo` crate.
+pub(crate) struct Workspace<'cfg> {
+    /// The Cargo configuration of the workspace.
+    config: &'cfg CargoConfig,
+
+    /// The members of the workspace, starting with the root package (if
+    /// any).
+    members: Vec<Package>,
+
+    /// Whether the manifest at the root of the workspace has a `[package]`.
+    has_root_package: bool,
+
+    /// The directory containing the workspace's root manifest.
+    root: PathBuf,
+
+    root_manifest: TomlManifest,
+}
+
+/// The package at the root of a workspace, if there is one.
+pub(crate) enum MaybePackage<'ws> {
+    Package(&'ws Package),
+    Virtual,
+}
+
+impl<'cfg> Workspace<'cfg> {
+    /// Reads the workspace whose root manifest is in the given directory.
+    pub(crate) fn new(
+        root: &Path,
+        config: &'cfg CargoConfig,
+    ) -> Result<Self, ManifestError> {
+        let root = normalize_path(root);
+
+        let root_manifest_path = root.join("Cargo.toml");
+
+        let root_manifest = read_manifest(&root_manifest_path)?;
+
+        let inheritance = root_manifest
+            .workspace
+            .as_ref()
+            .map(|workspace| WorkspaceInheritance { workspace, root: &root });
+
+        let mut members = Vec::new();
+
+        let has_root_package = root_manifest.package.is_some();
+
+        if has_root_package {
+            members.push(Package::read(
+                &root_manifest_path,
+                SourceId::for_path(&root),
+                inheritance,
+            )?);
+        }
+
+        if let Some(workspace) = &root_manifest.workspace {
+            let excluded = workspace
+                .exclude
+                .iter()
+                .flatten()
+                .map(|path| normalize_path(&root.join(path)))
+                .collect::<Vec<_>>();
+
+            let is_excluded = |dir: &Path| {
+                excluded.iter().any(|excluded| dir.starts_with(excluded))
+            };
+
+            // Like Cargo, the path dependencies of the members that are
+            // inside the workspace are members too.
+            let path_deps = |member: &Package| {
+                member
+                    .dependencies()
+                    .iter()
+                    .filter_map(|dep| match dep.source() {
+                        DependencySource::Path(path) => Some(path.clone()),
+                        _ => None,
+                    })
+                    .filter(|path| {
+                        path.starts_with(&root) && !is_excluded(path)
+                    })
+                    .collect::<Vec<_>>()
+            };
+
+            let mut member_dirs = workspace
+                .members
+                .iter()
+                .flatten()
+                .flat_map(|pattern| expand_glob(&root, pattern))
+                .filter(|dir| dir.join("Cargo.toml").is_file())
+                .filter(|dir| !is_excluded(dir))
+                .chain(members.first().map(&path_deps).into_iter().flatten())
+                .collect::<Vec<_>>();
+
+            member_dirs.reverse();
+
+            while let Some(dir) = member_dirs.pop() {
+                if members.iter().any(|member| *member.root() == *dir) {
+                    continue;
+                }
+
+                let member = Package::read(
+                    &dir.join("Cargo.toml"),
+                    SourceId::for_path(&dir),
+                    inheritance,
+                )?;
+
+                member_dirs.extend(path_deps(&member));
+
+                members.push(member);
+            }
+        }
+
+        Ok(Self { config, members, has_root_package, root, root_manifest })
+    }
+
+    pub(crate) fn config(&self) -> &'cfg CargoConfig {
+        self.config
+    }
+
+    /// Whether the features of build dependencies and proc-macros are
+    /// unified separately from the ones of normal dependencies, which is the
+    /// case with resolver v2 and later.
+    pub(crate) fn decouples_host_deps(&self) -> bool {
+        let resolver = self
+            .root_manifest
+            .workspace
+            .as_ref()
+            .and_then(|workspace| workspace.resolver.as_deref())
+            .or_else(|| {
+                self.root_manifest.package.as_ref()?.resolver.as_deref()
+            });
+
+        match resolver {
+            Some(resolver) => resolver != "1",
+            // The default resolver depends on the edition of the root
+            // package, and it's always v1 for virtual manifests.
+            None => self.members.first().is_some_and(|root| {
+                self.has_root_package
+                    && root.manifest().edition() >= Edition::Edition2021
+            }),
+        }
+    }
+
+    pub(crate) fn is_member_id(&self, pkg_id: PackageId) -> bool {
+        self.members.iter().any(|member| member.package_id() == pkg_id)
+    }
+
+    pub(crate) fn members(&self) -> impl Iterator<Item = &Package> {
+        self.members.iter()
+    }
+
+    /// The paths of the packages that the root manifest uses to patch or
+    /// replace other packages via `[patch]` or `[replace]`.
+    pub(crate) fn patch_paths(&self) -> impl Iterator<Item = PathBuf> {
+        let patches = self
+            .root_manifest
+            .patch
+            .iter()
+            .flat_map(|patch| patch.values())
+            .flat_map(|patches| patches.values());
+
+        let replacements = self
+            .root_manifest
+            .replace
+            .iter()
+            .flat_map(|replace| replace.values());
+
+        patches.chain(replacements).filter_map(|dep| match dep {
+            TomlDependency::Detailed(detailed) => detailed
+                .path
+                .as_ref()
+                .map(|path| normalize_path(&self.root.join(path))),
+            TomlDependency::Simple(_) => None,
+        })
+    }
+
+    /// The `[profile]` tables of the root manifest.
+    pub(crate) fn profiles(&self) -> Option<&TomlProfiles> {
+        self.root_manifest.profile.as_ref()
+    }
+
+    pub(crate) fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub(crate) fn root_maybe(&self) -> MaybePackage<'_> {
+        match self.members.first() {
+            Some(root) if self.has_root_package => MaybePackage::Package(root),
+            _ => MaybePackage::Virtual,
+        }
+    }
+}
+
+/// Finds the workspace the package in the given directory belongs to, i.e.
+/// the closest manifest with a `[workspace]` table in it or in one of its
+/// ancestors, stopping at the given `ceiling` directory (if any).
+///
+/// Returns the directory containing the manifest together with its
+/// `[workspace]` table.
+pub(crate) fn find_workspace(
+    package_root: &Path,
+    ceiling: Option<&Path>,
+) -> Result<Option<(PathBuf, TomlWorkspace)>, ManifestError> {
+    for dir in package_root.ancestors() {
+        let manifest_path = dir.join("Cargo.toml");
+
+        if manifest_path.is_file()
+            && let Some(workspace) = read_manifest(&manifest_path)?.workspace
+        {
+            return Ok(Some((dir.to_owned(), workspace)));
+        }
+
+        if ceiling.is_some_and(|ceiling| dir == ceiling) {
+            break;
+        }
+    }
+
+    Ok(None)
+}
+
+/// Expands a glob pattern in the `members` field of a `[workspace]` table
+/// into the directories it matches.
+///
+/// Only the `*` and `?` wildcards are supported, which are the only ones
+/// commonly used in practice.
+fn expand_glob(root: &Path, pattern: &str) -> Vec<PathBuf> {
+    let mut dirs = vec![root.to_owned()];
+
+    for component in Path::new(pattern).components() {
+        let component = component.as_os_str().to_string_lossy();
+
+        if !component.contains(['*', '?']) {
+            for dir in &mut dirs {
+                dir.push(&*component);
+            }
+            continue;
+        }
+
+        dirs = dirs
+            .iter()
+            .filter_map(|dir| dir.read_dir().ok())
+            .flatten()
+            .filter_map(Result::ok)
+            .filter(|entry| {
+                entry.file_type().is_ok_and(|file_type| file_type.is_dir())
+                    && matches_glob(
+                        &component,
+                        &entry.file_name().to_string_lossy(),
+                    )
+            })
+            .map(|entry| entry.path())
+            .collect();
+    }
+
+    dirs.iter_mut().for_each(|dir| *dir = normalize_path(dir));
+    dirs.sort_unstable();
+    dirs
+}
+
+/// Returns whether the given file name matches the given pattern, where `*`
+/// matches any sequence of characters and `?` any single character.
+fn matches_glob(pattern: &str, file_name: &str) -> bool {
+    match pattern.chars().next() {
+        None => file_name.is_empty(),
+        Some('*') => {
+            let rest = &pattern[1..];
+            file_name
+                .char_indices()
+                .map(|(idx, _)| idx)
+                .chain([file_name.len()])
+                .any(|idx| matches_glob(rest, &file_name[idx..]))
+        },
+        Some(first) => {
+            let mut chars = file_name.chars();
+            match chars.next() {
+                Some(char) if first == '?' || first == char => {
+                    matches_glob(&pattern[first.len_utf8()..], chars.as_str())
+                },
+                _ => false,
+            }
+        },
+    }
+}